@@ -2,6 +2,7 @@ use std::hint::black_box;
 
 use criterion::{criterion_group, criterion_main, Criterion};
 
+use cimdea::engine::TabulationEngine;
 use cimdea::request::{DataRequest, SimpleRequest};
 use cimdea::tabulate::tabulate;
 
@@ -22,6 +23,13 @@ fn tabulate_simple_request_benchmark(c: &mut Criterion) {
             tabulate(black_box(&ctx), black_box(rq.clone())).ok();
         })
     });
+
+    let engine = TabulationEngine::new(None);
+    c.bench_function("tabulate simple request on an engine", |b| {
+        b.iter(|| {
+            engine.tabulate(black_box(&ctx), black_box(rq.clone())).ok();
+        })
+    });
 }
 
 criterion_group!(benches, tabulate_simple_request_benchmark);
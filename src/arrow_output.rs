@@ -0,0 +1,415 @@
+//! Tabulation results as Arrow record batches.
+//!
+//! `tabulate::tabulate()` builds [Table]s of [Cell](crate::tabulate::Cell)s, which are
+//! convenient for formatting. Programs which do further analysis on the results can use
+//! [tabulate_arrow] instead. It reads the query results directly through DuckDB's Arrow
+//! interface and applies the same typing rules as the tables: counts are integers, weighted
+//! counts and `Fixed(n)` variables are exact decimals, and category bin codes are integers.
+//!
+//! An [ArrowTable] can be saved as Parquet or as an Arrow IPC file. The IPC bytes from
+//! [ArrowTable::to_ipc_bytes] can be read by other Arrow implementations. With the `polars`
+//! feature, `ArrowTable::to_polars` converts a table to a Polars `DataFrame`.
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::conventions::Context;
+use crate::ipums_metadata_model::IpumsDataType;
+use crate::mderror::MdError;
+use crate::query_gen::{tab_queries, DataPlatform, TabQuery};
+use crate::request::{DataRequest, InputType};
+use crate::tabulate::{table_heading, weighted_count_data_type, Cell, OutputColumn, Table};
+
+use arrow::array::{Array, ArrayRef, AsArray, Float64Array};
+use arrow::compute::kernels::numeric::div;
+use arrow::compute::{cast, concat_batches};
+use arrow::datatypes::{DataType, Decimal128Type, Field, Float64Type, Int64Type, Schema};
+use arrow::error::ArrowError;
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use duckdb::Connection;
+use parquet::arrow::ArrowWriter;
+use rust_decimal::Decimal;
+
+/// The largest precision of an Arrow `Decimal128`.
+const DECIMAL_PRECISION: u8 = 38;
+
+/// The results of a tabulation of one dataset as an Arrow [RecordBatch].
+///
+/// The columns of the batch have the same names and order as the heading of the
+/// corresponding [Table]. The dataset name is also stored in the schema metadata under the
+/// "dataset" key, so that it's saved along with the data by the writers.
+#[derive(Clone, Debug)]
+pub struct ArrowTable {
+    pub dataset: String,
+    pub heading: Vec<OutputColumn>,
+    pub batch: RecordBatch,
+}
+
+impl ArrowTable {
+    /// Save the table as a Parquet file.
+    pub fn write_parquet(&self, path: impl AsRef<Path>) -> Result<(), MdError> {
+        let file = File::create(path)?;
+        let mut writer = ArrowWriter::try_new(file, self.batch.schema(), None)
+            .map_err(|err| MdError::Msg(format!("Can't write Parquet output: {err}")))?;
+        writer
+            .write(&self.batch)
+            .map_err(|err| MdError::Msg(format!("Can't write Parquet output: {err}")))?;
+        writer
+            .close()
+            .map_err(|err| MdError::Msg(format!("Can't write Parquet output: {err}")))?;
+        Ok(())
+    }
+
+    /// Save the table as an Arrow IPC file.
+    pub fn write_ipc(&self, path: impl AsRef<Path>) -> Result<(), MdError> {
+        let file = File::create(path)?;
+        self.write_ipc_to(file)
+    }
+
+    /// Serialize the table in the Arrow IPC file format.
+    pub fn to_ipc_bytes(&self) -> Result<Vec<u8>, MdError> {
+        let mut bytes = Vec::new();
+        self.write_ipc_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_ipc_to<W: std::io::Write>(&self, out: W) -> Result<(), MdError> {
+        let write = || -> Result<(), ArrowError> {
+            let mut writer = FileWriter::try_new(out, &self.batch.schema())?;
+            writer.write(&self.batch)?;
+            writer.finish()
+        };
+        write().map_err(|err| MdError::Msg(format!("Can't write Arrow IPC output: {err}")))
+    }
+
+    /// Convert the table to a Polars `DataFrame` with the same columns and types. Weighted
+    /// counts and `Fixed(n)` variables stay exact decimals.
+    #[cfg(feature = "polars")]
+    pub fn to_polars(&self) -> Result<polars::frame::DataFrame, MdError> {
+        use polars::prelude::{IpcReader, SerReader};
+
+        let bytes = self.to_ipc_bytes()?;
+        IpcReader::new(std::io::Cursor::new(bytes))
+            .finish()
+            .map_err(|err| MdError::Msg(format!("Can't convert to a Polars DataFrame: {err}")))
+    }
+}
+
+/// Compute a tabulation and return the results as Arrow record batches, one per dataset.
+///
+/// This runs the same queries as `tabulate::tabulate()`, but the values are never
+/// converted to individual cells. Requests which fill in empty cells aren't supported.
+pub fn tabulate_arrow<R>(ctx: &Context, rq: R) -> Result<Vec<ArrowTable>, MdError>
+where
+    R: DataRequest,
+{
+    if rq.include_empty_cells() {
+        return Err(MdError::Msg(
+            "filling in empty cells isn't supported for Arrow output".to_string(),
+        ));
+    }
+    let requested_output_columns = rq
+        .get_request_variables()
+        .iter()
+        .map(|v| OutputColumn::RequestVar(Box::new(v.clone())))
+        .collect::<Vec<OutputColumn>>();
+
+    let sql_queries = tab_queries(ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)?;
+    let conn = Connection::open_in_memory()?;
+    let mut tables = Vec::new();
+    for q in sql_queries {
+        let mut stmt = conn.prepare(&q.sql)?;
+        let batches: Vec<RecordBatch> = stmt.query_arrow([])?.collect();
+        let batch = concat_batches(&stmt.schema(), &batches)
+            .map_err(|err| MdError::Msg(format!("Can't combine query results: {err}")))?;
+
+        let heading = table_heading(&q, &requested_output_columns);
+        let batch = typed_batch(&q, &heading, &batch)?;
+        tables.push(ArrowTable {
+            dataset: q.dataset,
+            heading,
+            batch,
+        });
+    }
+    Ok(tables)
+}
+
+/// Convert the record batch into a [Table] of cells, for example to format it as text.
+impl TryFrom<&ArrowTable> for Table {
+    type Error = MdError;
+
+    fn try_from(table: &ArrowTable) -> Result<Self, Self::Error> {
+        let mut rows = Vec::with_capacity(table.batch.num_rows());
+        for row in 0..table.batch.num_rows() {
+            let mut this_row = Vec::with_capacity(table.batch.num_columns());
+            for column in table.batch.columns() {
+                this_row.push(cell_at(column, row)?);
+            }
+            rows.push(this_row);
+        }
+        Ok(Table {
+            heading: table.heading.clone(),
+            rows,
+            disclosure: None,
+        })
+    }
+}
+
+fn cell_at(column: &ArrayRef, row: usize) -> Result<Cell, MdError> {
+    if column.is_null(row) {
+        return Ok(Cell::Null);
+    }
+    let cell = match column.data_type() {
+        DataType::Int64 => Cell::Integer(column.as_primitive::<Int64Type>().value(row)),
+        DataType::Float64 => Cell::Float(column.as_primitive::<Float64Type>().value(row)),
+        DataType::Decimal128(_, scale) => {
+            let raw = column.as_primitive::<Decimal128Type>().value(row);
+            match Decimal::try_from_i128_with_scale(raw, *scale as u32) {
+                Ok(d) => Cell::Decimal(d),
+                Err(err) => {
+                    return Err(MdError::Msg(format!("Can't convert decimal value: {err}")))
+                }
+            }
+        }
+        DataType::Utf8 => Cell::String(column.as_string::<i32>().value(row).to_string()),
+        other => {
+            return Err(MdError::Msg(format!(
+                "can't put a value of type {other} in a table cell"
+            )))
+        }
+    };
+    Ok(cell)
+}
+
+/// Give the query result columns their output names and types.
+fn typed_batch(
+    query: &TabQuery,
+    heading: &[OutputColumn],
+    batch: &RecordBatch,
+) -> Result<RecordBatch, MdError> {
+    if batch.num_columns() != heading.len() {
+        return Err(MdError::Msg(format!(
+            "Query returned {} columns but the table has {} columns",
+            batch.num_columns(),
+            heading.len()
+        )));
+    }
+
+    let convert = || -> Result<(Vec<Field>, Vec<ArrayRef>), ArrowError> {
+        let mut fields = Vec::with_capacity(heading.len());
+        let mut columns = Vec::with_capacity(heading.len());
+        for (column, array) in heading.iter().zip(batch.columns()) {
+            let name = column.name();
+            let array = if query.weight_name.is_some() && name == "weighted_ct" {
+                weighted_count_array(array, query.weight_divisor)?
+            } else {
+                typed_array(array, &column.data_type())?
+            };
+            fields.push(Field::new(name, array.data_type().clone(), true));
+            columns.push(array);
+        }
+        Ok((fields, columns))
+    };
+    let (fields, columns) =
+        convert().map_err(|err| MdError::Msg(format!("Can't convert query results: {err}")))?;
+
+    let metadata = HashMap::from([("dataset".to_string(), query.dataset.clone())]);
+    let schema = Arc::new(Schema::new_with_metadata(fields, metadata));
+    RecordBatch::try_new(schema, columns)
+        .map_err(|err| MdError::Msg(format!("Can't build record batch: {err}")))
+}
+
+/// Weighted counts are sums of raw weights. For divisors which are powers of ten the
+/// division only moves the decimal point, so the result is an exact decimal.
+fn weighted_count_array(array: &ArrayRef, divisor: usize) -> Result<ArrayRef, ArrowError> {
+    let places = decimal_places_for_divisor(divisor);
+    match places {
+        Some(places) if !is_float(array.data_type()) => implied_decimal(array, places),
+        _ => {
+            let sums = cast(array, &DataType::Float64)?;
+            let divisor = Float64Array::new_scalar(divisor as f64);
+            div(&sums, &divisor)
+        }
+    }
+}
+
+/// Convert a column to the Arrow type for its [IpumsDataType].
+fn typed_array(array: &ArrayRef, data_type: &IpumsDataType) -> Result<ArrayRef, ArrowError> {
+    match data_type {
+        IpumsDataType::Integer if !is_float(array.data_type()) => cast(array, &DataType::Int64),
+        IpumsDataType::Fixed(places) if !is_float(array.data_type()) => {
+            implied_decimal(array, *places)
+        }
+        IpumsDataType::Float | IpumsDataType::Integer | IpumsDataType::Fixed(_) => {
+            cast(array, &DataType::Float64)
+        }
+        IpumsDataType::String => Ok(array.clone()),
+    }
+}
+
+/// Reinterpret integer or decimal values as having `places` more implied decimal places.
+fn implied_decimal(array: &ArrayRef, places: usize) -> Result<ArrayRef, ArrowError> {
+    let scale = match array.data_type() {
+        DataType::Decimal128(_, scale) | DataType::Decimal256(_, scale) => *scale,
+        _ => 0,
+    };
+    let raw = cast(array, &DataType::Decimal128(DECIMAL_PRECISION, scale))?;
+    let new_scale = i8::try_from(places)
+        .ok()
+        .and_then(|places| scale.checked_add(places))
+        .filter(|new_scale| *new_scale <= DECIMAL_PRECISION as i8)
+        .ok_or_else(|| {
+            ArrowError::InvalidArgumentError(format!(
+                "can't add {places} decimal places to a decimal with scale {scale}"
+            ))
+        })?;
+    let decimals = raw
+        .as_primitive::<Decimal128Type>()
+        .clone()
+        .with_precision_and_scale(DECIMAL_PRECISION, new_scale)?;
+    Ok(Arc::new(decimals))
+}
+
+fn decimal_places_for_divisor(divisor: usize) -> Option<usize> {
+    match weighted_count_data_type(divisor) {
+        IpumsDataType::Fixed(places) => Some(places),
+        _ => None,
+    }
+}
+
+fn is_float(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Float16 | DataType::Float32 | DataType::Float64
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::request::SimpleRequest;
+    use arrow::ipc::reader::FileReader;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::io::Cursor;
+
+    fn vetstat_tables() -> Vec<ArrowTable> {
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us1940a"],
+            &["VETSTAT"],
+            None,
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to set up the request");
+        tabulate_arrow(&ctx, rq).expect("should be able to tabulate us1940a VETSTAT")
+    }
+
+    #[test]
+    fn test_tabulate_arrow_types() {
+        let tables = vetstat_tables();
+        assert_eq!(1, tables.len());
+        let table = &tables[0];
+        assert_eq!("us1940a", table.dataset);
+
+        let schema = table.batch.schema();
+        let names: Vec<_> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(vec!["ct", "weighted_ct", "VETSTAT"], names);
+        assert_eq!(
+            Some(&"us1940a".to_string()),
+            schema.metadata().get("dataset")
+        );
+        assert_eq!(&DataType::Int64, schema.field(0).data_type());
+        assert_eq!(&DataType::Decimal128(38, 2), schema.field(1).data_type());
+        assert_eq!(&DataType::Int64, schema.field(2).data_type());
+
+        let ct = table.batch.column(0).as_primitive::<Int64Type>();
+        let weighted_ct = table.batch.column(1).as_primitive::<Decimal128Type>();
+        let vetstat = table.batch.column(2).as_primitive::<Int64Type>();
+        assert_eq!(1118, ct.value(0));
+        assert_eq!(7_610_000, weighted_ct.value(0));
+        assert_eq!(0, vetstat.value(0));
+    }
+
+    #[test]
+    fn test_arrow_table_into_table() {
+        let tables = vetstat_tables();
+        let table = Table::try_from(&tables[0]).expect("should convert to a table");
+        assert_eq!(3, table.heading.len());
+        assert_eq!(Cell::Integer(1118), table.rows[0][0]);
+        assert_eq!(Cell::Decimal(Decimal::new(7_610_000, 2)), table.rows[0][1]);
+        assert_eq!(Cell::Integer(0), table.rows[0][2]);
+    }
+
+    #[test]
+    fn test_write_ipc_and_parquet() {
+        let tables = vetstat_tables();
+        let table = &tables[0];
+
+        let bytes = table.to_ipc_bytes().expect("should serialize as IPC");
+        let reader = FileReader::try_new(Cursor::new(bytes), None).unwrap();
+        let batches: Vec<RecordBatch> = reader.map(|b| b.unwrap()).collect();
+        assert_eq!(1, batches.len());
+        assert_eq!(table.batch, batches[0]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let ipc_path = dir.path().join("vetstat.arrow");
+        table
+            .write_ipc(&ipc_path)
+            .expect("should write an IPC file");
+        let reader = FileReader::try_new(File::open(&ipc_path).unwrap(), None).unwrap();
+        assert_eq!(
+            table.batch.num_rows(),
+            reader.map(|b| b.unwrap().num_rows()).sum::<usize>()
+        );
+
+        let parquet_path = dir.path().join("vetstat.parquet");
+        table
+            .write_parquet(&parquet_path)
+            .expect("should write a Parquet file");
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&parquet_path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.map(|b| b.unwrap()).collect();
+        assert_eq!(table.batch.columns(), batches[0].columns());
+    }
+
+    #[cfg(feature = "polars")]
+    #[test]
+    fn test_to_polars() {
+        use polars::prelude::DataType as PolarsType;
+
+        let tables = vetstat_tables();
+        let df = tables[0]
+            .to_polars()
+            .expect("should convert to a DataFrame");
+        assert_eq!(tables[0].batch.num_rows(), df.height());
+        let names: Vec<_> = df.get_column_names().iter().map(|n| n.as_str()).collect();
+        assert_eq!(vec!["ct", "weighted_ct", "VETSTAT"], names);
+        assert_eq!(&PolarsType::Int64, df.column("ct").unwrap().dtype());
+        assert!(matches!(
+            df.column("weighted_ct").unwrap().dtype(),
+            PolarsType::Decimal(_, 2)
+        ));
+        assert_eq!(
+            1118,
+            df.column("ct").unwrap().i64().unwrap().get(0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_weighted_count_array() {
+        let sums: ArrayRef = Arc::new(arrow::array::Int64Array::from(vec![Some(12345), None]));
+        let exact = weighted_count_array(&sums, 100).unwrap();
+        assert_eq!(&DataType::Decimal128(38, 2), exact.data_type());
+        assert_eq!(12345, exact.as_primitive::<Decimal128Type>().value(0));
+        assert!(exact.is_null(1));
+
+        let divided = weighted_count_array(&sums, 4).unwrap();
+        assert_eq!(&DataType::Float64, divided.data_type());
+        assert_eq!(3086.25, divided.as_primitive::<Float64Type>().value(0));
+    }
+}
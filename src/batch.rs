@@ -0,0 +1,235 @@
+//! Run many Abacus requests from a JSON Lines file.
+//!
+//! Each non-blank line of the input is one JSON Abacus request. A request may have a
+//! `request_id` field to identify its result; otherwise the result is identified by its line
+//! number. Requests run on a bounded number of worker threads and share loaded metadata through
+//! a [TabulationService]. A request which fails doesn't stop the batch, even if it panics. Its
+//! result records the error instead of the tables.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::mderror::MdError;
+use crate::service::TabulationService;
+use crate::tabulate::{Table, Tabulation};
+
+/// One request read from a batch file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchRequest {
+    pub request_id: String,
+    /// The line number in the batch file, starting from 1.
+    pub line: usize,
+    pub json: String,
+}
+
+/// The outcome of one request in a batch. Exactly one of `tables` and `error` is set.
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+    pub request_id: String,
+    pub line: usize,
+    pub elapsed_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tables: Option<Vec<Table>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchResult {
+    pub fn is_error(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
+/// Split JSON Lines input into requests, skipping blank lines.
+///
+/// This doesn't validate the requests. A line which isn't valid JSON still becomes a request,
+/// and running it produces an error result.
+pub fn read_batch(input: &str) -> Vec<BatchRequest> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, text)| !text.trim().is_empty())
+        .map(|(index, text)| {
+            let line = index + 1;
+            let request_id = serde_json::from_str::<serde_json::Value>(text)
+                .ok()
+                .and_then(|value| match value.get("request_id") {
+                    Some(serde_json::Value::String(id)) => Some(id.clone()),
+                    Some(serde_json::Value::Number(id)) => Some(id.to_string()),
+                    _ => None,
+                })
+                .unwrap_or_else(|| format!("line {line}"));
+            BatchRequest {
+                request_id,
+                line,
+                json: text.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Run the requests on up to `jobs` threads.
+///
+/// `on_result` gets each result in the same order as `requests`, as soon as that result and all
+/// of the results before it are ready. A request which panics gets an error result.
+pub fn run_batch<F>(
+    service: &TabulationService,
+    requests: &[BatchRequest],
+    jobs: usize,
+    on_result: F,
+) where
+    F: FnMut(BatchResult),
+{
+    run_each(
+        requests,
+        jobs,
+        |request| service.tabulate_json(&request.json),
+        on_result,
+    );
+}
+
+fn run_each<T, F>(requests: &[BatchRequest], jobs: usize, tabulate: T, mut on_result: F)
+where
+    T: Fn(&BatchRequest) -> Result<Tabulation, MdError> + Sync,
+    F: FnMut(BatchResult),
+{
+    let next_request = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, requests.len().max(1)) {
+            let sender = sender.clone();
+            let next_request = &next_request;
+            let tabulate = &tabulate;
+            scope.spawn(move || loop {
+                let index = next_request.fetch_add(1, Ordering::SeqCst);
+                let Some(request) = requests.get(index) else {
+                    break;
+                };
+                if sender.send((index, run_one(tabulate, request))).is_err() {
+                    break;
+                }
+            });
+        }
+        // Only the workers hold senders now, so the receiver stops once they all finish.
+        drop(sender);
+
+        let mut finished = BTreeMap::new();
+        let mut next_to_emit = 0;
+        for (index, result) in receiver {
+            finished.insert(index, result);
+            while let Some(result) = finished.remove(&next_to_emit) {
+                on_result(result);
+                next_to_emit += 1;
+            }
+        }
+    });
+}
+
+// A panic is caught here rather than left to end the worker, which would lose this result and
+// hold back every result after it.
+fn run_one<T>(tabulate: &T, request: &BatchRequest) -> BatchResult
+where
+    T: Fn(&BatchRequest) -> Result<Tabulation, MdError>,
+{
+    let start = Instant::now();
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| tabulate(request)));
+    let elapsed_ms = start.elapsed().as_millis();
+
+    let (tables, error) = match outcome {
+        Ok(Ok(tab)) => (Some(tab.into_inner()), None),
+        Ok(Err(err)) => (None, Some(err.to_string())),
+        Err(payload) => (None, Some(panic_message(payload.as_ref()))),
+    };
+    BatchResult {
+        request_id: request.request_id.clone(),
+        line: request.line,
+        elapsed_ms,
+        tables,
+        error,
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str));
+    match message {
+        Some(message) => format!("the request panicked: {message}"),
+        None => "the request panicked".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_batch() {
+        let input = "{\"request_id\": \"first\"}\n\n{\"request_id\": 7}\n{\"product\": \"usa\"}\nnot json\n";
+        let requests = read_batch(input);
+        let ids: Vec<_> = requests.iter().map(|r| r.request_id.as_str()).collect();
+        assert_eq!(ids, ["first", "7", "line 4", "line 5"]);
+        let lines: Vec<_> = requests.iter().map(|r| r.line).collect();
+        assert_eq!(lines, [1, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_run_batch_keeps_order_and_errors() {
+        let request = std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json")
+            .expect("should be able to read the test request");
+        let request: serde_json::Value = serde_json::from_str(&request).unwrap();
+        let request = request.to_string();
+        let input = format!("{request}\n{{\"product\": \"usa\"}}\n{request}\n");
+
+        let service = TabulationService::new(Some("tests/data_root".to_string()));
+        let mut results = Vec::new();
+        run_batch(&service, &read_batch(&input), 2, |result| {
+            results.push(result)
+        });
+
+        let lines: Vec<_> = results.iter().map(|r| r.line).collect();
+        assert_eq!(lines, [1, 2, 3]);
+        assert!(!results[0].is_error());
+        assert!(results[1].is_error());
+        assert!(results[1].tables.is_none());
+        assert!(!results[2].is_error());
+        assert_eq!(
+            results[0].tables.as_ref().unwrap()[0].rows,
+            results[2].tables.as_ref().unwrap()[0].rows
+        );
+    }
+
+    #[test]
+    fn test_run_batch_reports_panics() {
+        let requests = read_batch("{}\n{}\n{}\n{}\n");
+        let mut results = Vec::new();
+        run_each(
+            &requests,
+            2,
+            |request| {
+                if request.line == 2 {
+                    panic!("no tables for line {}", request.line);
+                }
+                Ok(Tabulation(Vec::new()))
+            },
+            |result| results.push(result),
+        );
+
+        let lines: Vec<_> = results.iter().map(|r| r.line).collect();
+        assert_eq!(lines, [1, 2, 3, 4]);
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some("the request panicked: no tables for line 2")
+        );
+        assert_eq!(1, results.iter().filter(|r| r.is_error()).count());
+    }
+}
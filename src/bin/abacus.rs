@@ -1,10 +1,25 @@
 use std::fs::File;
 use std::io::{self, BufRead, Write};
+use std::sync::Arc;
 
-use cimdea::request::{AbacusRequest, DataRequest, SimpleRequest};
-use cimdea::tabulate::{self, TableFormat};
+use cimdea::batch;
+use cimdea::catalog::{self, MetadataSource, VariableFilter, VariableSummary};
+use cimdea::conventions::Context;
+use cimdea::disclosure::DisclosureRules;
+use cimdea::input_schema_tabulation::{
+    self, BinGeneration, CategoryBin, GeneralDetailedSelection, RequestCaseSelection,
+    WeightSelection,
+};
+use cimdea::mderror::MdError;
+use cimdea::request::AbacusRequest;
+use cimdea::result_cache::{self, ResultCache};
+use cimdea::schema::SchemaKind;
+use cimdea::service::{self, TabulationService};
+use cimdea::tabulate::{self, FormatOptions, TableFormat};
+use cimdea::validation;
 
 use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
 
 fn get_from_stdin() -> String {
     let stdin = io::stdin();
@@ -18,6 +33,19 @@ fn get_from_stdin() -> String {
     }
 }
 
+fn read_input(input_file: Option<String>, description: &str) -> String {
+    match input_file {
+        None => get_from_stdin(),
+        Some(file) => match std::fs::read_to_string(&file) {
+            Ok(input) => input,
+            Err(err) => {
+                eprintln!("Can't access {description}: {err}");
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct CliRequest {
@@ -31,6 +59,37 @@ struct CliRequest {
     /// The output format
     #[arg(short, long, global = true, default_value = "text")]
     format: TableFormat,
+
+    /// Round weighted counts and other decimal values to this many places [default: exact values]
+    #[arg(long, global = true)]
+    decimals: Option<u32>,
+
+    /// Suppress the counts of cells with fewer than this many cases, and of complementary cells
+    #[arg(long, global = true)]
+    min_cell_count: Option<i64>,
+
+    /// Only suppress cells below the minimum cell count, without complementary suppression
+    #[arg(long, global = true)]
+    no_complementary_suppression: bool,
+
+    /// Round weighted counts to the nearest multiple of this number
+    #[arg(long, global = true)]
+    round_weighted_to: Option<u32>,
+
+    /// Refuse to tabulate restricted variables
+    #[arg(long, global = true)]
+    refuse_restricted: bool,
+}
+
+impl CliRequest {
+    fn disclosure_rules(&self) -> DisclosureRules {
+        DisclosureRules {
+            min_cell_count: self.min_cell_count,
+            complementary_suppression: !self.no_complementary_suppression,
+            weighted_count_base: self.round_weighted_to,
+            refuse_restricted_variables: self.refuse_restricted,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -39,19 +98,206 @@ enum CliCommand {
     Tab(TabArgs),
     /// Given a JSON Abacus request, compute the tabulation it describes
     Request(RequestArgs),
+    /// Given a JSON Abacus request, print the SQL, data sources, weights and query plan for each
+    /// sample without computing the tabulation
+    Explain(RequestArgs),
+    /// Given a JSON Abacus request, check it against metadata and list every problem with it,
+    /// exiting with an error if there are any errors
+    Validate(RequestArgs),
+    /// List the datasets under the data root and the formats they're available in
+    Datasets(DatasetsArgs),
+    /// List the variables in a dataset with their record types, data types, widths and labels
+    Variables(VariablesArgs),
+    /// Find variables whose names or labels contain some text
+    Search(SearchArgs),
+    /// Show which datasets under the data root have which variables
+    Availability(AvailabilityArgs),
+    /// Print the JSON Schema for Abacus requests or for the JSON output of tabulations
+    Schema(SchemaArgs),
+    /// Compute the tabulations for a JSON Lines file of Abacus requests, writing one JSON result per line
+    Batch(BatchArgs),
+    /// Run a local HTTP service which computes tabulations for JSON Abacus requests
+    Serve(ServeArgs),
 }
 
 #[derive(Args, Debug)]
 struct TabArgs {
     /// The name of the product (e.g. usa or ipumsi)
     product: String,
-    /// The name of the sample (e.g. us2015b or mx2016h)
+    /// The names of one or more samples, separated by commas (e.g. us2015b or us1940a,us1950a)
     sample: String,
     /// One or more variables to tabulate (e.g. AGE or MARST)
     variables: Vec<String>,
     /// The path to the data root, which contains layouts and parquet data [default: inferred from the product]
     #[arg(short, long)]
     data_root: Option<String>,
+    /// Category bins for a variable, either listed like AGE=0-17,18-64,65+ or generated from
+    /// its distribution like INCWAGE=quantiles:5. Generation methods are equal_width, quantiles
+    /// and nice_numbers. May be given more than once.
+    #[arg(short, long, value_name = "VARIABLE=BINS", value_parser = parse_bins)]
+    bins: Vec<(String, TabBins)>,
+    /// Only count cases where a variable has one of the given codes, like AGE=25-64 or
+    /// STATEFIP=6,48. Codes may be ranges like 25-64, 65+ or -17. May be given more than once;
+    /// cases must match every variable.
+    #[arg(short = 'w', long = "where", value_name = "VARIABLE=CODES", value_parser = parse_where)]
+    conditions: Vec<(String, Vec<RequestCaseSelection>)>,
+    /// Use the general codes of a variable, like EDUC=2 with the width of its general codes,
+    /// or just EDUC when the metadata gives that width. May be given more than once.
+    #[arg(short, long, value_name = "VARIABLE[=WIDTH]", value_parser = parse_general)]
+    general: Vec<(String, usize)>,
+    /// The unit of analysis, the record type to count (e.g. P or H)
+    #[arg(short, long, default_value = "P")]
+    uoa: String,
+    /// Weight the counts by this variable instead of the unit of analysis weight
+    #[arg(long, value_name = "VARIABLE", conflicts_with = "unweighted")]
+    weight: Option<String>,
+    /// Count records without weights
+    #[arg(long)]
+    unweighted: bool,
+    /// Print the SQL, data sources, weights and query plan for each sample instead of computing
+    /// the tabulation
+    #[arg(long)]
+    explain: bool,
+}
+
+#[derive(Clone, Debug)]
+enum TabBins {
+    Listed(Vec<CategoryBin>),
+    Generated(BinGeneration),
+}
+
+impl TabArgs {
+    /// Compile the arguments into the request a JSON file would give.
+    fn to_request(&self) -> Result<input_schema_tabulation::AbacusRequest, String> {
+        let mut request = input_schema_tabulation::AbacusRequest::new(&self.product, &self.uoa);
+        request.data_root = self.data_root.clone();
+        request.request_samples = self
+            .sample
+            .split(',')
+            .map(|name| input_schema_tabulation::RequestSample::new(name.trim()))
+            .collect();
+        request.request_variables = self
+            .variables
+            .iter()
+            .map(|name| input_schema_tabulation::RequestVariable::new(name))
+            .collect();
+        request.subpopulation = self
+            .conditions
+            .iter()
+            .map(|(name, selections)| {
+                let mut variable = input_schema_tabulation::RequestVariable::new(name);
+                variable.case_selection = true;
+                variable.request_case_selections = selections.clone();
+                variable
+            })
+            .collect();
+
+        for (name, width) in &self.general {
+            let mut found = false;
+            for variable in request
+                .request_variables
+                .iter_mut()
+                .chain(request.subpopulation.iter_mut())
+                .filter(|v| &v.variable_mnemonic == name)
+            {
+                variable.general_detailed_selection = GeneralDetailedSelection::General;
+                variable.extract_width = *width;
+                found = true;
+            }
+            if !found {
+                return Err(format!(
+                    "--general {name} isn't one of the tabulated or --where variables"
+                ));
+            }
+        }
+
+        for (name, bins) in &self.bins {
+            match bins {
+                TabBins::Listed(bins) => {
+                    request.category_bins.insert(name.clone(), bins.clone());
+                }
+                TabBins::Generated(method) => {
+                    request.generated_bins.insert(name.clone(), *method);
+                }
+            }
+        }
+
+        request.weight = match (&self.weight, self.unweighted) {
+            (Some(weight), _) => WeightSelection::Variable(weight.clone()),
+            (None, true) => WeightSelection::Unweighted,
+            (None, false) => WeightSelection::Automatic,
+        };
+        Ok(request)
+    }
+
+    /// Compile the arguments into a request and resolve it against the product's metadata.
+    fn resolve(&self) -> Result<(Context, AbacusRequest), MdError> {
+        let request = self.to_request().map_err(MdError::Msg)?;
+        let mut context =
+            Context::from_ipums_collection_name(&request.product, None, request.data_root.clone())?;
+        context.load_metadata_for_datasets(request.sample_names().as_slice())?;
+        self.check_general_widths(&context)?;
+        let request = AbacusRequest::try_from_input_request(&context, request)?;
+        Ok((context, request))
+    }
+
+    /// Check that the variables given to `--general` without a width have one in the
+    /// metadata. Layouts don't give general widths, so those need `--general NAME=WIDTH`.
+    fn check_general_widths(&self, ctx: &Context) -> Result<(), MdError> {
+        for (name, width) in &self.general {
+            if *width > 0 {
+                continue;
+            }
+            // Unknown variables get their usual error from the request.
+            let Ok(variable) = ctx.get_md_variable_by_name(name) else {
+                continue;
+            };
+            if variable.general_width.is_none() {
+                return Err(MdError::MetadataError(format!(
+                    "the metadata doesn't give a general width for {name}; give one like --general {name}=WIDTH"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn split_variable_arg<'a>(arg: &'a str, expected: &str) -> Result<(&'a str, &'a str), String> {
+    match arg.split_once('=') {
+        Some((variable, value)) if !variable.is_empty() && !value.is_empty() => {
+            Ok((variable, value))
+        }
+        _ => Err(format!("expected {expected}, got '{arg}'")),
+    }
+}
+
+fn parse_bins(arg: &str) -> Result<(String, TabBins), String> {
+    let (variable, bins) = split_variable_arg(arg, "VARIABLE=BINS")?;
+    let bins = if bins.contains(':') {
+        TabBins::Generated(bins.parse().map_err(|err: MdError| err.to_string())?)
+    } else {
+        TabBins::Listed(CategoryBin::parse_list(bins).map_err(|err| err.to_string())?)
+    };
+    Ok((variable.to_string(), bins))
+}
+
+fn parse_where(arg: &str) -> Result<(String, Vec<RequestCaseSelection>), String> {
+    let (variable, codes) = split_variable_arg(arg, "VARIABLE=CODES")?;
+    let selections = codes
+        .split(',')
+        .map(|codes| codes.parse().map_err(|err: MdError| err.to_string()))
+        .collect::<Result<_, _>>()?;
+    Ok((variable.to_string(), selections))
+}
+
+fn parse_general(arg: &str) -> Result<(String, usize), String> {
+    let Some((variable, width)) = arg.split_once('=') else {
+        return Ok((arg.to_string(), 0));
+    };
+    let width = width
+        .parse()
+        .map_err(|err| format!("invalid general width in '{arg}': {err}"))?;
+    Ok((variable.to_string(), width))
 }
 
 #[derive(Args, Debug)]
@@ -60,68 +306,480 @@ struct RequestArgs {
     input_file: Option<String>,
 }
 
+#[derive(Args, Debug)]
+struct DatasetsArgs {
+    /// The name of the product (e.g. usa or ipumsi)
+    product: String,
+    /// The path to the data root, which contains layouts and parquet data [default: inferred from the product]
+    #[arg(short, long)]
+    data_root: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct VariablesArgs {
+    /// The name of the product (e.g. usa or ipumsi)
+    product: String,
+    /// The name of the sample (e.g. us2015b or mx2016h)
+    sample: String,
+    /// The path to the data root, which contains layouts and parquet data [default: inferred from the product]
+    #[arg(short, long)]
+    data_root: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct SearchArgs {
+    /// The name of the product (e.g. usa or ipumsi)
+    product: String,
+    /// The text to look for in variable names and labels, ignoring case
+    text: String,
+    /// Only search these samples, separated by commas [default: every sample under the data root]
+    #[arg(short, long)]
+    samples: Option<String>,
+    /// The path to the data root, which contains layouts and parquet data [default: inferred from the product]
+    #[arg(short, long)]
+    data_root: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct AvailabilityArgs {
+    /// The name of the product (e.g. usa or ipumsi)
+    product: String,
+    /// Where to read the metadata: layouts or parquet
+    #[arg(short, long, default_value = "layouts")]
+    source: MetadataSource,
+    /// Only include variables on this record type (e.g. P or H)
+    #[arg(short, long)]
+    record_type: Option<String>,
+    /// Only include variables whose names match this pattern, like INC* or WAGE
+    #[arg(short, long)]
+    pattern: Option<String>,
+    /// The path to the data root, which contains layouts and parquet data [default: inferred from the product]
+    #[arg(short, long)]
+    data_root: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct SchemaArgs {
+    /// Which schema to print: request or tabulation
+    kind: SchemaKind,
+}
+
+#[derive(Args, Debug)]
+struct BatchArgs {
+    /// The path to the input JSON Lines file [default: read from stdin]
+    input_file: Option<String>,
+    /// The number of requests to run at once [default: the number of CPUs]
+    #[arg(short, long)]
+    jobs: Option<usize>,
+    /// The data root to use for requests which don't give one [default: inferred from the product]
+    #[arg(short, long)]
+    data_root: Option<String>,
+    /// The directory requests' CPI files are read from, with paths relative to it
+    #[arg(long, value_name = "DIRECTORY", default_value = ".")]
+    cpi_dir: String,
+}
+
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// The address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    address: String,
+    /// The number of requests to handle at once
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+    /// The data root to use for requests which don't give one [default: inferred from the product]
+    #[arg(short, long)]
+    data_root: Option<String>,
+    /// Let requests name this data root as well as the default. May be given more than once
+    #[arg(long, value_name = "DIRECTORY")]
+    allow_data_root: Vec<String>,
+    /// Cache up to this many tabulation results in memory [default: 128 with --cache-dir]
+    #[arg(long)]
+    cache_size: Option<usize>,
+    /// Also cache tabulation results in this directory
+    #[arg(long)]
+    cache_dir: Option<String>,
+    /// Let requests give CPI files in this directory, with paths relative to it [default: only
+    /// the built-in CPI-U table]
+    #[arg(long, value_name = "DIRECTORY")]
+    cpi_dir: Option<String>,
+}
+
+impl ServeArgs {
+    fn result_cache(&self) -> Option<ResultCache> {
+        if self.cache_size.is_none() && self.cache_dir.is_none() {
+            return None;
+        }
+        let cache = ResultCache::new(self.cache_size.unwrap_or(result_cache::DEFAULT_CAPACITY));
+        Some(match self.cache_dir {
+            Some(ref directory) => cache.with_directory(directory),
+            None => cache,
+        })
+    }
+}
+
 fn main() {
     let args = CliRequest::parse();
+    let disclosure_rules = args.disclosure_rules();
 
     let result = match args.command {
         CliCommand::Request(request_args) => {
-            let input = match request_args.input_file {
-                None => get_from_stdin(),
-                Some(file) => match std::fs::read_to_string(&file) {
-                    Ok(j) => j,
-                    Err(err) => {
-                        eprintln!("Can't access Abacus request file: {err}");
-                        std::process::exit(1);
-                    }
-                },
+            let input = read_input(request_args.input_file, "Abacus request file");
+
+            let (context, request) = match AbacusRequest::try_from_json(&input) {
+                Ok(data) => data,
+                Err(err) => exit_with_error(args.format, "Error parsing input JSON", &err),
             };
+            disclosure_rules
+                .check_request(&context, &request)
+                .and_then(|()| tabulate::tabulate(&context, request))
+        }
+        CliCommand::Explain(request_args) => {
+            let input = read_input(request_args.input_file, "Abacus request file");
 
             let (context, request) = match AbacusRequest::try_from_json(&input) {
                 Ok(data) => data,
-                Err(err) => {
-                    eprintln!("Error parsing input JSON: {err}");
-                    std::process::exit(1);
+                Err(err) => exit_with_error(args.format, "Error parsing input JSON", &err),
+            };
+            explain(&context, request, args.format, args.output);
+            return;
+        }
+        CliCommand::Validate(request_args) => {
+            let input = read_input(request_args.input_file, "Abacus request file");
+            let request = exit_on_error(
+                args.format,
+                AbacusRequest::parse_json(&input),
+                "parsing input JSON",
+            );
+            let diagnostics = exit_on_error(
+                args.format,
+                validation::validate_input(&request),
+                "validating the request",
+            );
+            let text = if let TableFormat::Json = args.format {
+                match serde_json::to_string_pretty(&diagnostics) {
+                    Ok(text) => text,
+                    Err(err) => {
+                        eprintln!("Cannot serialize diagnostics into json: {err}");
+                        std::process::exit(1);
+                    }
                 }
+            } else {
+                diagnostics
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+            write_output(args.output, &text);
+            if diagnostics.iter().any(|d| d.is_error()) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        CliCommand::Datasets(datasets_args) => {
+            let context =
+                catalog_context(args.format, &datasets_args.product, datasets_args.data_root);
+            let datasets =
+                exit_on_error(args.format, catalog::datasets(&context), "listing datasets");
+            let yes_no = |available: bool| if available { "yes" } else { "no" }.to_string();
+            let rows = datasets
+                .iter()
+                .map(|d| {
+                    vec![
+                        d.name.clone(),
+                        yes_no(d.layout),
+                        yes_no(d.parquet),
+                        yes_no(d.csv),
+                        yes_no(d.fixed_width),
+                    ]
+                })
+                .collect();
+            let text = catalog_output(
+                &datasets,
+                args.format,
+                &["dataset", "layout", "parquet", "csv", "fixed_width"],
+                rows,
+            );
+            write_output(args.output, &text);
+            return;
+        }
+        CliCommand::Variables(variables_args) => {
+            let context = catalog_context(
+                args.format,
+                &variables_args.product,
+                variables_args.data_root,
+            );
+            let variables = exit_on_error(
+                args.format,
+                catalog::variables(&context, &variables_args.sample),
+                "listing variables",
+            );
+            let rows = variables.iter().map(variable_row).collect();
+            let text = catalog_output(&variables, args.format, &VARIABLE_COLUMNS, rows);
+            write_output(args.output, &text);
+            return;
+        }
+        CliCommand::Search(search_args) => {
+            let context = catalog_context(args.format, &search_args.product, search_args.data_root);
+            let samples: Option<Vec<String>> = search_args
+                .samples
+                .map(|samples| samples.split(',').map(|s| s.trim().to_string()).collect());
+            let matches = exit_on_error(
+                args.format,
+                catalog::search(&context, &search_args.text, samples.as_deref()),
+                "searching variables",
+            );
+            let rows = matches
+                .iter()
+                .map(|m| {
+                    let mut row = variable_row(&m.variable);
+                    row.push(m.datasets.join(","));
+                    row
+                })
+                .collect();
+            let mut columns = VARIABLE_COLUMNS.to_vec();
+            columns.push("datasets");
+            let text = catalog_output(&matches, args.format, &columns, rows);
+            write_output(args.output, &text);
+            return;
+        }
+        CliCommand::Schema(schema_args) => {
+            let text = exit_on_error(
+                args.format,
+                schema_args.kind.to_json(),
+                "generating the schema",
+            );
+            write_output(args.output, &text);
+            return;
+        }
+        CliCommand::Availability(availability_args) => {
+            let context = catalog_context(
+                args.format,
+                &availability_args.product,
+                availability_args.data_root,
+            );
+            let filter = VariableFilter {
+                record_type: availability_args.record_type,
+                pattern: availability_args.pattern,
             };
-            tabulate::tabulate(&context, request)
+            let matrix = exit_on_error(
+                args.format,
+                catalog::availability(&context, availability_args.source, &filter),
+                "finding variable availability",
+            );
+            let text = exit_on_error(args.format, matrix.output(args.format), "formatting output");
+            write_output(args.output, &text);
+            return;
         }
         CliCommand::Tab(tab_args) => {
-            let variables: Vec<_> = tab_args.variables.iter().map(|v| v.as_str()).collect();
-            let (context, request) = match SimpleRequest::from_names(
-                &tab_args.product,
-                &[&tab_args.sample],
-                variables.as_slice(),
-                None,
-                None,
-                tab_args.data_root,
-            ) {
+            let (context, request) = match tab_args.resolve() {
                 Ok(data) => data,
-                Err(err) => {
-                    eprintln!("Error while setting up tabulation: {err}");
+                Err(err) => exit_with_error(args.format, "Error while setting up tabulation", &err),
+            };
+            if tab_args.explain {
+                explain(&context, request, args.format, args.output);
+                return;
+            }
+            disclosure_rules
+                .check_request(&context, &request)
+                .and_then(|()| tabulate::tabulate(&context, request))
+        }
+        CliCommand::Batch(batch_args) => {
+            let input = read_input(batch_args.input_file, "batch file");
+            let requests = batch::read_batch(&input);
+            let jobs = batch_args
+                .jobs
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            // The batch file is trusted like the command line, so its requests can name any
+            // data root.
+            let service = TabulationService::new(batch_args.data_root)
+                .with_any_data_root()
+                .with_disclosure_rules(disclosure_rules)
+                .with_cpi_directory(batch_args.cpi_dir);
+
+            let mut out: Box<dyn Write> = match args.output {
+                Some(file_name) => match File::create(file_name) {
+                    Ok(file) => Box::new(file),
+                    Err(err) => {
+                        eprintln!("Error while creating output file: {err}");
+                        std::process::exit(1);
+                    }
+                },
+                None => Box::new(io::stdout()),
+            };
+
+            let mut failures = 0;
+            batch::run_batch(&service, &requests, jobs, |result| {
+                if result.is_error() {
+                    failures += 1;
+                }
+                let line = match serde_json::to_string(&result) {
+                    Ok(line) => line,
+                    Err(err) => {
+                        eprintln!("Cannot serialize result into json: {err}");
+                        std::process::exit(1);
+                    }
+                };
+                if let Err(err) = writeln!(out, "{line}") {
+                    eprintln!("Error while writing output: {err}");
                     std::process::exit(1);
                 }
-            };
-            tabulate::tabulate(&context, request)
+            });
+
+            if failures > 0 {
+                eprintln!("{failures} of {} requests failed", requests.len());
+                std::process::exit(1);
+            }
+            return;
+        }
+        CliCommand::Serve(serve_args) => {
+            let mut service = TabulationService::new(serve_args.data_root.clone())
+                .with_allowed_data_roots(serve_args.allow_data_root.clone())
+                .with_disclosure_rules(disclosure_rules);
+            if let Some(cache) = serve_args.result_cache() {
+                service = service.with_result_cache(cache);
+            }
+            if let Some(ref directory) = serve_args.cpi_dir {
+                service = service.with_cpi_directory(directory);
+            }
+            let service = Arc::new(service);
+            eprintln!("Listening on http://{}", serve_args.address);
+            if let Err(err) = service::serve(service, &serve_args.address, serve_args.threads) {
+                eprintln!("Error while running the service: {err}");
+                std::process::exit(1);
+            }
+            return;
         }
     };
 
     let tab = match result {
         Ok(tab) => tab,
-        Err(err) => {
-            eprintln!("Error trying to tabulate: {err}");
-            std::process::exit(1);
+        Err(err) => exit_with_error(args.format, "Error trying to tabulate", &err),
+    };
+
+    let tab = if disclosure_rules == DisclosureRules::default() {
+        tab
+    } else {
+        match disclosure_rules.apply_to_tabulation(&tab) {
+            Ok(tab) => tab,
+            Err(err) => exit_with_error(args.format, "Error while applying disclosure rules", &err),
         }
     };
 
-    let output = match tab.output(args.format) {
+    let format_options = FormatOptions {
+        decimal_places: args.decimals,
+        ..FormatOptions::default()
+    };
+    let output = match tab.output_with_options(args.format, &format_options) {
         Ok(output) => output,
-        Err(err) => {
-            eprintln!("Error while formatting output: {err}");
-            std::process::exit(1);
+        Err(err) => exit_with_error(args.format, "Error while formatting output", &err),
+    };
+
+    write_output(args.output, &output);
+}
+
+fn explain(context: &Context, request: AbacusRequest, format: TableFormat, output: Option<String>) {
+    let explanations = match tabulate::explain(context, request) {
+        Ok(explanations) => explanations,
+        Err(err) => exit_with_error(format, "Error trying to explain the tabulation", &err),
+    };
+    let text = if let TableFormat::Json = format {
+        match serde_json::to_string_pretty(&explanations) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("Cannot serialize explanation into json: {err}");
+                std::process::exit(1);
+            }
         }
+    } else {
+        explanations
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n\n")
     };
+    write_output(output, &text);
+}
+
+const VARIABLE_COLUMNS: [&str; 5] = ["variable", "record_type", "type", "width", "label"];
+
+fn variable_row(variable: &VariableSummary) -> Vec<String> {
+    vec![
+        variable.name.clone(),
+        variable.record_type.clone(),
+        variable.data_type.clone(),
+        variable.width.map(|w| w.to_string()).unwrap_or_default(),
+        variable.label.clone().unwrap_or_default(),
+    ]
+}
+
+fn exit_on_error<T>(format: TableFormat, result: Result<T, MdError>, doing: &str) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => exit_with_error(format, &format!("Error while {doing}"), &err),
+    }
+}
+
+/// Print the error and exit. With JSON output, the error is printed to stderr as a JSON object
+/// with its message, code, and entity and request field when they're known.
+fn exit_with_error(format: TableFormat, message: &str, err: &MdError) -> ! {
+    match (format, serde_json::to_string(err)) {
+        (TableFormat::Json, Ok(json)) => eprintln!("{json}"),
+        _ => eprintln!("{message}: {err}"),
+    }
+    std::process::exit(1);
+}
+
+fn catalog_context(format: TableFormat, product: &str, data_root: Option<String>) -> Context {
+    exit_on_error(
+        format,
+        Context::from_ipums_collection_name(product, None, data_root),
+        "setting up the product",
+    )
+}
+
+/// Format catalog entries as JSON, or otherwise as text in aligned columns.
+fn catalog_output<T: Serialize>(
+    entries: &[T],
+    format: TableFormat,
+    columns: &[&str],
+    rows: Vec<Vec<String>>,
+) -> String {
+    if let TableFormat::Json = format {
+        return match serde_json::to_string_pretty(entries) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("Cannot serialize output into json: {err}");
+                std::process::exit(1);
+            }
+        };
+    }
+
+    let mut widths: Vec<usize> = columns.iter().map(|c| c.len()).collect();
+    for row in &rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.chars().count());
+        }
+    }
+    let header = columns.iter().map(|c| c.to_string()).collect();
+    std::iter::once(header)
+        .chain(rows)
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(value, width)| format!("{value:<width$}"))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-    if let Some(file_name) = args.output {
+fn write_output(output_file: Option<String>, output: &str) {
+    if let Some(file_name) = output_file {
         let mut file = match File::create(file_name) {
             Ok(file) => file,
             Err(err) => {
@@ -0,0 +1,382 @@
+//! Generate category bins for continuous variables from their distributions.
+//!
+//! Writing category bins by hand means knowing the range and shape of a variable's values
+//! before tabulating it. A [BinGeneration] method instead picks bin boundaries from the
+//! weighted distribution of the variable among the records the request counts:
+//!
+//! * Equal width bins split the range of values into bins of the same size.
+//! * Quantile bins hold roughly equal weighted counts.
+//! * Nice number bins have round boundaries like multiples of 10, 20 or 50.
+//!
+//! Every method produces a "or less" bin, zero or more ranges, and a "or more" bin, coded from
+//! 1 and labeled from their boundaries. Missing and not in universe codes are left out of the
+//! distribution, and no boundary falls above a top code or below a bottom code, so that the top
+//! and bottom coded values land in the open ended bins instead of being split across bins.
+use duckdb::Connection;
+
+use crate::conventions::Context;
+use crate::input_schema_tabulation::{BinGeneration, CategoryBin};
+use crate::ipums_metadata_model::{IpumsValue, IpumsVariable, UniversalCategoryType};
+use crate::mderror::{metadata_error, parsing_error, MdError};
+use crate::query_gen::{distribution_query, DataPlatform};
+use crate::request::{DataRequest, InputType, RequestVariable};
+use rust_decimal::prelude::*;
+
+/// The most bins a [BinGeneration] may ask for.
+pub const MAX_GENERATED_BINS: usize = 1000;
+
+/// The lowest and highest values which bins may separate, from bottom and top codes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CodedLimits {
+    pub bottom: Option<i64>,
+    pub top: Option<i64>,
+}
+
+impl CodedLimits {
+    /// The bottom and top codes in a variable's category metadata.
+    pub fn from_variable(var: &IpumsVariable) -> Self {
+        let mut limits = Self::default();
+        for c in var.categories.iter().flatten() {
+            let IpumsValue::Integer(value) = c.value else {
+                continue;
+            };
+            match c.meaning {
+                UniversalCategoryType::TopCode => {
+                    limits.top = Some(limits.top.map_or(value, |t| t.min(value)));
+                }
+                UniversalCategoryType::BottomCode => {
+                    limits.bottom = Some(limits.bottom.map_or(value, |b| b.max(value)));
+                }
+                _ => (),
+            }
+        }
+        limits
+    }
+
+    fn allows(&self, upper_bound: i64) -> bool {
+        self.top.is_none_or(|top| upper_bound < top)
+            && self.bottom.is_none_or(|bottom| upper_bound >= bottom)
+    }
+}
+
+/// Generate category bins from a weighted distribution of `(value, weight)` pairs sorted by
+/// value.
+///
+/// The method may ask for at most [MAX_GENERATED_BINS] bins, and no more bins than the
+/// distribution has distinct values.
+///
+/// ```
+/// use cimdea::bin_generation::{bins_from_distribution, CodedLimits};
+/// use cimdea::input_schema_tabulation::{BinGeneration, CategoryBin};
+///
+/// let distribution = [(0, 1.0), (5, 1.0), (10, 1.0), (15, 1.0)];
+/// let method = BinGeneration::EqualWidth { bins: 2 };
+/// let bins = bins_from_distribution(&method, &distribution, CodedLimits::default()).unwrap();
+/// assert_eq!(
+///     bins,
+///     vec![
+///         CategoryBin::LessThan { value: 7, code: 1, label: "7 or less".to_string() },
+///         CategoryBin::MoreThan { value: 8, code: 2, label: "8 or more".to_string() },
+///     ]
+/// );
+/// ```
+pub fn bins_from_distribution(
+    method: &BinGeneration,
+    distribution: &[(i64, f64)],
+    limits: CodedLimits,
+) -> Result<Vec<CategoryBin>, MdError> {
+    let bins = method.bins();
+    if bins == 0 {
+        return Err(parsing_error!("bin generation needs at least one bin"));
+    }
+    if bins > MAX_GENERATED_BINS {
+        return Err(parsing_error!(
+            "bin generation can make at most {MAX_GENERATED_BINS} bins, not {bins}"
+        ));
+    }
+    let (Some(&(min, _)), Some(&(max, _))) = (distribution.first(), distribution.last()) else {
+        return Err(metadata_error!(
+            "can't generate bins for a variable with no values"
+        ));
+    };
+    let distinct = 1 + distribution
+        .windows(2)
+        .filter(|pair| pair[0].0 != pair[1].0)
+        .count();
+    if bins > distinct {
+        return Err(parsing_error!(
+            "can't make {bins} bins from {distinct} distinct values"
+        ));
+    }
+
+    let upper_bounds = match method {
+        BinGeneration::EqualWidth { .. } => equal_width_bounds(min, max, bins),
+        BinGeneration::Quantiles { .. } => quantile_bounds(distribution, bins),
+        BinGeneration::NiceNumbers { .. } => nice_number_bounds(min, max, bins),
+    };
+    let mut upper_bounds: Vec<i64> = upper_bounds
+        .into_iter()
+        .filter(|&u| u >= min && u < max && limits.allows(u))
+        .collect();
+    upper_bounds.sort();
+    upper_bounds.dedup();
+    Ok(bins_from_upper_bounds(min, &upper_bounds))
+}
+
+/// Bins with the given inclusive upper bounds, plus a final bin for everything above them.
+fn bins_from_upper_bounds(min: i64, upper_bounds: &[i64]) -> Vec<CategoryBin> {
+    let Some((&first, _)) = upper_bounds.split_first() else {
+        return vec![CategoryBin::MoreThan {
+            value: min,
+            code: 1,
+            label: format!("{min} or more"),
+        }];
+    };
+    let mut bins = vec![CategoryBin::LessThan {
+        value: first,
+        code: 1,
+        label: format!("{first} or less"),
+    }];
+    for pair in upper_bounds.windows(2) {
+        let (low, high) = (pair[0] + 1, pair[1]);
+        let label = if low == high {
+            format!("{low}")
+        } else {
+            format!("{low} to {high}")
+        };
+        bins.push(CategoryBin::Range {
+            low,
+            high,
+            code: bins.len() as u64 + 1,
+            label,
+        });
+    }
+    let last = upper_bounds[upper_bounds.len() - 1] + 1;
+    bins.push(CategoryBin::MoreThan {
+        value: last,
+        code: bins.len() as u64 + 1,
+        label: format!("{last} or more"),
+    });
+    bins
+}
+
+fn equal_width_bounds(min: i64, max: i64, bins: usize) -> Vec<i64> {
+    let bins = bins as i64;
+    let width = ((max - min + bins) / bins).max(1);
+    (1..bins).map(|k| min + k * width - 1).collect()
+}
+
+/// The smallest values at which the cumulative weight reaches each of the `bins - 1` cut points.
+fn quantile_bounds(distribution: &[(i64, f64)], bins: usize) -> Vec<i64> {
+    let total: f64 = distribution.iter().map(|(_, weight)| weight).sum();
+    let mut bounds = Vec::new();
+    let mut cumulative = 0.0;
+    let mut k = 1;
+    for &(value, weight) in distribution {
+        cumulative += weight;
+        while k < bins && cumulative >= total * k as f64 / bins as f64 {
+            bounds.push(value);
+            k += 1;
+        }
+    }
+    bounds
+}
+
+/// Boundaries at multiples of 1, 2 or 5 times a power of ten, giving about `bins` bins.
+fn nice_number_bounds(min: i64, max: i64, bins: usize) -> Vec<i64> {
+    let raw_step = (max - min + 1) as f64 / bins as f64;
+    let magnitude = 10f64.powf(raw_step.log10().floor());
+    let nice = match raw_step / magnitude {
+        f if f <= 1.0 => 1.0,
+        f if f <= 2.0 => 2.0,
+        f if f <= 5.0 => 5.0,
+        _ => 10.0,
+    };
+    let step = ((nice * magnitude) as i64).max(1);
+    let start = min.div_euclid(step) * step;
+    (1..)
+        .map(|k| start + k * step - 1)
+        .take_while(|&u| u < max)
+        .collect()
+}
+
+/// Generate category bins for a request variable from its distribution in the records the
+/// request counts, with the request's subpopulation, subsample and weights.
+///
+/// When the variable is standardized to constant dollars, the bins are for the adjusted values,
+/// and top and bottom codes are adjusted the same way.
+pub fn generate_category_bins(
+    ctx: &Context,
+    request: &impl DataRequest,
+    rq: &RequestVariable,
+    method: &BinGeneration,
+) -> Result<Vec<CategoryBin>, MdError> {
+    let monetary_standardization = request
+        .get_monetary_standardization()
+        .filter(|_| rq.monetary_standardized);
+    let sql = distribution_query(ctx, request, rq, &InputType::Parquet, &DataPlatform::Duckdb)?;
+
+    let conn = Connection::open_in_memory()?;
+    let mut stmt = conn.prepare(&sql)?;
+    let distribution = stmt
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut limits = CodedLimits::from_variable(&rq.variable);
+    if let Some(ms) = monetary_standardization {
+        let mut adjusted = CodedLimits::default();
+        for sample in request.get_request_samples() {
+            let factor = ms.factor_for_dataset(&sample.sample)?;
+            let adjust = |code: i64| -> Result<i64, MdError> {
+                (Decimal::from(code) * factor)
+                    .round()
+                    .to_i64()
+                    .ok_or_else(|| metadata_error!("can't adjust the code {code} of {}", rq.name))
+            };
+            if let Some(top) = limits.top {
+                let top = adjust(top)?;
+                adjusted.top = Some(adjusted.top.map_or(top, |t| t.min(top)));
+            }
+            if let Some(bottom) = limits.bottom {
+                let bottom = adjust(bottom)?;
+                adjusted.bottom = Some(adjusted.bottom.map_or(bottom, |b| b.max(bottom)));
+            }
+        }
+        limits = adjusted;
+    }
+
+    bins_from_distribution(method, &distribution, limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uniform(values: impl Iterator<Item = i64>) -> Vec<(i64, f64)> {
+        values.map(|v| (v, 1.0)).collect()
+    }
+
+    fn bounds(bins: &[CategoryBin]) -> Vec<(Option<i64>, Option<i64>)> {
+        bins.iter()
+            .map(|b| match b {
+                CategoryBin::LessThan { value, .. } => (None, Some(*value)),
+                CategoryBin::Range { low, high, .. } => (Some(*low), Some(*high)),
+                CategoryBin::MoreThan { value, .. } => (Some(*value), None),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_equal_width_bins() {
+        let method = BinGeneration::EqualWidth { bins: 4 };
+        let bins =
+            bins_from_distribution(&method, &uniform(0..100), CodedLimits::default()).unwrap();
+        assert_eq!(
+            bounds(&bins),
+            vec![
+                (None, Some(24)),
+                (Some(25), Some(49)),
+                (Some(50), Some(74)),
+                (Some(75), None)
+            ]
+        );
+        let CategoryBin::Range { code, label, .. } = &bins[1] else {
+            panic!("expected a range bin");
+        };
+        assert_eq!(*code, 2);
+        assert_eq!(label, "25 to 49");
+    }
+
+    #[test]
+    fn test_quantile_bins() {
+        // Half the weight is at 0, so the first two quartiles share a boundary.
+        let mut distribution = vec![(0, 50.0)];
+        distribution.extend(uniform(1..=50));
+        let method = BinGeneration::Quantiles { bins: 4 };
+        let bins = bins_from_distribution(&method, &distribution, CodedLimits::default()).unwrap();
+        assert_eq!(
+            bounds(&bins),
+            vec![(None, Some(0)), (Some(1), Some(25)), (Some(26), None)]
+        );
+    }
+
+    #[test]
+    fn test_nice_number_bins() {
+        let method = BinGeneration::NiceNumbers { bins: 5 };
+        let bins =
+            bins_from_distribution(&method, &uniform(3..=9_870), CodedLimits::default()).unwrap();
+        assert_eq!(
+            bounds(&bins),
+            vec![
+                (None, Some(1_999)),
+                (Some(2_000), Some(3_999)),
+                (Some(4_000), Some(5_999)),
+                (Some(6_000), Some(7_999)),
+                (Some(8_000), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bins_respect_top_and_bottom_codes() {
+        let method = BinGeneration::EqualWidth { bins: 10 };
+        let limits = CodedLimits {
+            bottom: Some(20),
+            top: Some(70),
+        };
+        let bins = bins_from_distribution(&method, &uniform(0..100), limits).unwrap();
+        assert_eq!(
+            bins.first(),
+            Some(&CategoryBin::LessThan {
+                value: 29,
+                code: 1,
+                label: "29 or less".to_string()
+            })
+        );
+        assert_eq!(
+            bins.last(),
+            Some(&CategoryBin::MoreThan {
+                value: 70,
+                code: 6,
+                label: "70 or more".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_bin_generation_errors() {
+        let method = BinGeneration::Quantiles { bins: 0 };
+        assert!(bins_from_distribution(&method, &uniform(0..10), CodedLimits::default()).is_err());
+        let method = BinGeneration::Quantiles { bins: 3 };
+        assert!(bins_from_distribution(&method, &[], CodedLimits::default()).is_err());
+    }
+
+    #[test]
+    fn test_too_many_bins() {
+        // Rejected before any bounds are made for them.
+        let method = BinGeneration::EqualWidth {
+            bins: 100_000_000_000,
+        };
+        let err = bins_from_distribution(&method, &uniform(0..10_000), CodedLimits::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("at most 1000 bins"), "{err}");
+
+        let method = BinGeneration::Quantiles { bins: 11 };
+        let mut distribution = uniform(0..10);
+        distribution.push((9, 1.0));
+        let err =
+            bins_from_distribution(&method, &distribution, CodedLimits::default()).unwrap_err();
+        assert!(err.to_string().contains("10 distinct values"), "{err}");
+        let method = BinGeneration::Quantiles { bins: 10 };
+        assert!(bins_from_distribution(&method, &distribution, CodedLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn test_single_value_gets_one_bin() {
+        let method = BinGeneration::Quantiles { bins: 1 };
+        let bins = bins_from_distribution(&method, &[(7, 2.0)], CodedLimits::default()).unwrap();
+        assert_eq!(bins.len(), 1);
+        let method = BinGeneration::Quantiles { bins: 3 };
+        assert!(bins_from_distribution(&method, &[(7, 2.0)], CodedLimits::default()).is_err());
+    }
+}
@@ -0,0 +1,570 @@
+//! Discover the datasets and variables available under a data root.
+//!
+//! A data root has layout files in `layouts/` and data in `parquet/`, `csv/` or fixed-width
+//! files. Layouts give each variable's record type, width and data type. Parquet files may
+//! also carry IPUMS metadata like variable labels, and otherwise have a schema with the
+//! variables' names and types. The functions here combine what's available, so that you can
+//! find out what to request before requesting it. An [AvailabilityMatrix] shows which datasets
+//! have which variables, for planning tabulations across samples.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::conventions::Context;
+use crate::ipums_metadata_model::{IpumsDataType, IpumsDataset, IpumsDatasetId};
+use crate::layout::DatasetLayout;
+use crate::mderror::{metadata_error, parsing_error, MdError};
+use crate::parquet_metadata::ParquetMetadataReader;
+use crate::request::InputType;
+use crate::tabulate::TableFormat;
+
+/// A dataset under the data root and the formats it's available in.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DatasetSummary {
+    pub name: String,
+    pub layout: bool,
+    pub parquet: bool,
+    pub csv: bool,
+    pub fixed_width: bool,
+}
+
+/// What the data root knows about a variable in a dataset.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct VariableSummary {
+    pub name: String,
+    pub record_type: String,
+    pub data_type: String,
+    pub width: Option<usize>,
+    pub label: Option<String>,
+}
+
+/// A variable which matched a search, and the datasets it's in.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct VariableMatch {
+    #[serde(flatten)]
+    pub variable: VariableSummary,
+    pub datasets: Vec<String>,
+}
+
+/// The datasets with a layout file or data under the data root, sorted by name.
+pub fn datasets(ctx: &Context) -> Result<Vec<DatasetSummary>, MdError> {
+    let Some(ref data_root) = ctx.data_root else {
+        return Err(metadata_error!("No data root set."));
+    };
+
+    let mut names = BTreeSet::new();
+    if data_root.join("layouts").is_dir() {
+        names.extend(ctx.dataset_names_from_layouts()?);
+    }
+    for input_format in [InputType::Parquet, InputType::Csv] {
+        let Some(sub_dir) = input_format.data_sub_directory() else {
+            continue;
+        };
+        let Ok(entries) = std::fs::read_dir(data_root.join(sub_dir)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                names.extend(entry.file_name().to_str().map(String::from));
+            }
+        }
+    }
+
+    names
+        .into_iter()
+        .map(|name| {
+            Ok(DatasetSummary {
+                layout: layout_path(data_root, &name).exists(),
+                parquet: has_data(ctx, &name, &InputType::Parquet)?,
+                csv: has_data(ctx, &name, &InputType::Csv)?,
+                fixed_width: has_data(ctx, &name, &InputType::Fw)?,
+                name,
+            })
+        })
+        .collect()
+}
+
+/// The variables in a dataset, in layout order by record type.
+///
+/// Widths come from the layout, or from parquet metadata when there's no layout. Labels come
+/// from parquet metadata, since layouts don't have them.
+pub fn variables(ctx: &Context, dataset: &str) -> Result<Vec<VariableSummary>, MdError> {
+    let Some(ref data_root) = ctx.data_root else {
+        return Err(metadata_error!("No data root set."));
+    };
+
+    let mut summaries: Vec<VariableSummary> = Vec::new();
+    let layout_file = layout_path(data_root, dataset);
+    if layout_file.exists() {
+        let layout = DatasetLayout::try_from_layout_file(&layout_file)?;
+        // Layouts also describe data version information, which isn't on any record type.
+        let mut record_types: Vec<_> = layout
+            .record_types()
+            .into_iter()
+            .filter(|rt| ctx.settings.record_types.contains_key(rt))
+            .collect();
+        record_types.sort();
+        for record_type in record_types {
+            let Some(record_layout) = layout.for_rectype(&record_type) else {
+                continue;
+            };
+            summaries.extend(record_layout.sorted_vars_by_start().into_iter().map(|var| {
+                VariableSummary {
+                    name: var.name,
+                    record_type: var.rectype,
+                    data_type: var.data_type.to_string(),
+                    width: Some(var.width),
+                    label: None,
+                }
+            }));
+        }
+    }
+
+    let mut from_parquet = parquet_variables(ctx, dataset)?;
+    for summary in summaries.iter_mut() {
+        if let Some(parquet) = from_parquet.remove(&summary.name) {
+            summary.label = parquet.label;
+        }
+    }
+    summaries.extend(from_parquet.into_values());
+
+    if summaries.is_empty() {
+        return Err(metadata_error!(
+            "No layout or data for dataset '{dataset}' under {}",
+            data_root.display()
+        ));
+    }
+    Ok(summaries)
+}
+
+/// Find the variables whose names or labels contain the text, ignoring case, in the given
+/// datasets or in every dataset under the data root. Matches are sorted by name.
+pub fn search(
+    ctx: &Context,
+    text: &str,
+    datasets: Option<&[String]>,
+) -> Result<Vec<VariableMatch>, MdError> {
+    let dataset_names = match datasets {
+        Some(names) => names.to_vec(),
+        None => self::datasets(ctx)?.into_iter().map(|d| d.name).collect(),
+    };
+
+    let text = text.to_lowercase();
+    let mut matches: BTreeMap<String, VariableMatch> = BTreeMap::new();
+    for dataset in &dataset_names {
+        for variable in variables(ctx, dataset)? {
+            let label_matches = variable
+                .label
+                .as_ref()
+                .is_some_and(|label| label.to_lowercase().contains(&text));
+            if !label_matches && !variable.name.to_lowercase().contains(&text) {
+                continue;
+            }
+            let found = matches
+                .entry(variable.name.clone())
+                .or_insert_with(|| VariableMatch {
+                    variable: variable.clone(),
+                    datasets: Vec::new(),
+                });
+            if found.variable.label.is_none() {
+                found.variable.label = variable.label;
+            }
+            found.datasets.push(dataset.clone());
+        }
+    }
+    Ok(matches.into_values().collect())
+}
+
+/// Where to read the metadata for an availability matrix.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MetadataSource {
+    /// Every layout file in the data root's `layouts/`.
+    #[default]
+    Layouts,
+    /// Every dataset in the data root's `parquet/`.
+    Parquet,
+}
+
+impl FromStr for MetadataSource {
+    type Err = MdError;
+
+    /// Parse "layouts" or "parquet", ignoring case.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "layouts" => Ok(Self::Layouts),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(parsing_error!(
+                "unknown metadata source '{name}'; expected layouts or parquet"
+            )),
+        }
+    }
+}
+
+/// Which variables to include in an availability matrix.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VariableFilter {
+    /// Only variables on this record type.
+    pub record_type: Option<String>,
+    /// Only variables whose names match this pattern, ignoring case. A pattern with `*` or `?`
+    /// wildcards must match the whole name, like `INC*`; otherwise it may match any part of
+    /// the name.
+    pub pattern: Option<String>,
+}
+
+impl VariableFilter {
+    pub fn matches(&self, name: &str, record_type: &str) -> bool {
+        if self
+            .record_type
+            .as_ref()
+            .is_some_and(|rt| rt != record_type)
+        {
+            return false;
+        }
+        let Some(ref pattern) = self.pattern else {
+            return true;
+        };
+        let pattern = pattern.to_uppercase();
+        let name = name.to_uppercase();
+        if pattern.contains(['*', '?']) {
+            wildcard_match(pattern.as_bytes(), name.as_bytes())
+        } else {
+            name.contains(&pattern)
+        }
+    }
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| wildcard_match(rest, &name[skip..])),
+        Some((&c, rest)) => name
+            .split_first()
+            .is_some_and(|(&n, name)| (c == b'?' || c == n) && wildcard_match(rest, name)),
+    }
+}
+
+/// Which datasets have which variables.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AvailabilityMatrix {
+    /// The datasets, sorted by name, in the order of each variable's `available` flags.
+    pub datasets: Vec<String>,
+    /// The variables, sorted by name.
+    pub variables: Vec<VariableAvailability>,
+}
+
+/// A row of an [AvailabilityMatrix].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct VariableAvailability {
+    pub name: String,
+    pub record_type: String,
+    /// Whether each of the matrix's datasets has the variable.
+    pub available: Vec<bool>,
+}
+
+impl AvailabilityMatrix {
+    /// Build the matrix from the metadata loaded in the context. Variables on record types
+    /// which the product doesn't have, like the data version entries in layouts, are left out.
+    pub fn from_context(ctx: &Context, filter: &VariableFilter) -> Result<Self, MdError> {
+        let Some(ref md) = ctx.settings.metadata else {
+            return Err(metadata_error!("No metadata loaded."));
+        };
+
+        let mut dataset_order: Vec<&IpumsDataset> = md.datasets_index.iter().collect();
+        dataset_order.sort_by(|a, b| a.name.cmp(&b.name));
+        let column_by_id: HashMap<IpumsDatasetId, usize> = dataset_order
+            .iter()
+            .enumerate()
+            .map(|(column, ds)| (ds.id, column))
+            .collect();
+
+        let mut variables: Vec<VariableAvailability> = md
+            .variables_index
+            .iter()
+            .filter(|var| ctx.settings.record_types.contains_key(&var.record_type))
+            .filter(|var| filter.matches(&var.name, &var.record_type))
+            .map(|var| {
+                let mut available = vec![false; dataset_order.len()];
+                for ds_id in md
+                    .available_datasets
+                    .for_variable(var.id)
+                    .into_iter()
+                    .flatten()
+                {
+                    if let Some(&column) = column_by_id.get(ds_id) {
+                        available[column] = true;
+                    }
+                }
+                VariableAvailability {
+                    name: var.name.clone(),
+                    record_type: var.record_type.clone(),
+                    available,
+                }
+            })
+            .collect();
+        variables.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Self {
+            datasets: dataset_order
+                .into_iter()
+                .map(|ds| ds.name.clone())
+                .collect(),
+            variables,
+        })
+    }
+
+    /// Format the matrix as JSON, CSV with 1 for available and 0 otherwise, or text with an x
+    /// for available. HTML isn't supported.
+    pub fn output(&self, format: TableFormat) -> Result<String, MdError> {
+        match format {
+            TableFormat::Json => serde_json::to_string_pretty(self)
+                .map_err(|err| MdError::Msg(format!("Cannot serialize result into json: {err}"))),
+            TableFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                let csv_error = |err: csv::Error| MdError::Msg(format!("Cannot write CSV: {err}"));
+                writer.write_record(self.header()).map_err(csv_error)?;
+                for variable in &self.variables {
+                    let flags = variable
+                        .available
+                        .iter()
+                        .map(|&a| if a { "1" } else { "0" });
+                    writer
+                        .write_record(
+                            [variable.name.as_str(), variable.record_type.as_str()]
+                                .into_iter()
+                                .chain(flags),
+                        )
+                        .map_err(csv_error)?;
+                }
+                let bytes = writer
+                    .into_inner()
+                    .map_err(|err| MdError::Msg(format!("Cannot write CSV: {err}")))?;
+                String::from_utf8(bytes).map_err(|err| MdError::Msg(err.to_string()))
+            }
+            TableFormat::TextTable => {
+                let header = self.header();
+                let name_width = header[0].len().max(
+                    self.variables
+                        .iter()
+                        .map(|v| v.name.len())
+                        .max()
+                        .unwrap_or(0),
+                );
+                let mut lines = vec![header
+                    .iter()
+                    .enumerate()
+                    .map(|(column, h)| match column {
+                        0 => format!("{h:<name_width$}"),
+                        _ => h.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("  ")];
+                for variable in &self.variables {
+                    let mut cells = vec![
+                        format!("{:<name_width$}", variable.name),
+                        format!("{:<11}", variable.record_type),
+                    ];
+                    for (dataset, &available) in self.datasets.iter().zip(&variable.available) {
+                        let mark = if available { "x" } else { "" };
+                        cells.push(format!("{mark:<width$}", width = dataset.len()));
+                    }
+                    lines.push(cells.join("  ").trim_end().to_string());
+                }
+                Ok(lines.join("\n"))
+            }
+            TableFormat::Html => Err(MdError::Msg(
+                "Availability matrices can't be formatted as HTML.".to_string(),
+            )),
+        }
+    }
+
+    fn header(&self) -> Vec<&str> {
+        ["variable", "record_type"]
+            .into_iter()
+            .chain(self.datasets.iter().map(|d| d.as_str()))
+            .collect()
+    }
+}
+
+/// Load metadata for every dataset in the data root from the given source, and build the
+/// availability matrix of the variables which pass the filter.
+pub fn availability(
+    ctx: &Context,
+    source: MetadataSource,
+    filter: &VariableFilter,
+) -> Result<AvailabilityMatrix, MdError> {
+    let Some(ref data_root) = ctx.data_root else {
+        return Err(metadata_error!("No data root set."));
+    };
+    // Load into a copy, so that the caller's metadata is left as it is.
+    let mut ctx = ctx.clone();
+    match source {
+        MetadataSource::Layouts => {
+            let names = ctx.dataset_names_from_layouts()?;
+            let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+            ctx.settings
+                .load_metadata_for_selected_datasets_from_layouts(&names, data_root)?;
+        }
+        MetadataSource::Parquet => {
+            ctx.settings.metadata = None;
+            ctx.load_all_metadata_from_parquet()?;
+        }
+    }
+    AvailabilityMatrix::from_context(&ctx, filter)
+}
+
+fn layout_path(data_root: &Path, dataset: &str) -> std::path::PathBuf {
+    data_root
+        .join("layouts")
+        .join(format!("{dataset}.layout.txt"))
+}
+
+fn has_data(ctx: &Context, dataset: &str, input_format: &InputType) -> Result<bool, MdError> {
+    Ok(ctx
+        .paths_from_dataset_name(dataset, input_format)?
+        .values()
+        .any(|path| path.exists()))
+}
+
+/// The variables in a dataset's parquet files by name, with labels and widths when the files
+/// have IPUMS metadata.
+fn parquet_variables(
+    ctx: &Context,
+    dataset: &str,
+) -> Result<BTreeMap<String, VariableSummary>, MdError> {
+    let mut variables = BTreeMap::new();
+    for (record_type, path) in ctx.paths_from_dataset_name(dataset, &InputType::Parquet)? {
+        if !path.exists() {
+            continue;
+        }
+        if ParquetMetadataReader::has_ipums_metadata(&path) {
+            let (md_variables, _) =
+                ParquetMetadataReader::load_metadata_from_file(&path, &record_type)?;
+            for var in md_variables {
+                let data_type = var.data_type.unwrap_or(IpumsDataType::Integer);
+                variables.insert(
+                    var.name.clone(),
+                    VariableSummary {
+                        name: var.name,
+                        record_type: var.record_type,
+                        data_type: data_type.to_string(),
+                        width: var.formatting.map(|(_, width)| width),
+                        label: var.label,
+                    },
+                );
+            }
+        } else {
+            for (name, (data_type, _)) in ParquetMetadataReader::get_schema_info(&path)? {
+                variables.insert(
+                    name.clone(),
+                    VariableSummary {
+                        name,
+                        record_type: record_type.clone(),
+                        data_type: IpumsDataType::from(data_type.as_str()).to_string(),
+                        width: None,
+                        label: None,
+                    },
+                );
+            }
+        }
+    }
+    Ok(variables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> Context {
+        Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_datasets() {
+        let datasets = datasets(&context()).unwrap();
+        let us1900m = datasets.iter().find(|d| d.name == "us1900m").unwrap();
+        assert!(us1900m.layout && us1900m.parquet);
+        assert!(!us1900m.csv && !us1900m.fixed_width);
+
+        let us1850a = datasets.iter().find(|d| d.name == "us1850a").unwrap();
+        assert!(us1850a.layout && !us1850a.parquet);
+    }
+
+    #[test]
+    fn test_variables() {
+        let variables = variables(&context(), "us1900m").unwrap();
+        let age = variables.iter().find(|v| v.name == "AGE").unwrap();
+        assert_eq!(age.record_type, "P");
+        assert_eq!(age.width, Some(3));
+        // Household variables come before person variables.
+        assert_eq!(variables[0].record_type, "H");
+
+        assert!(super::variables(&context(), "us1776a").is_err());
+    }
+
+    #[test]
+    fn test_variable_filter() {
+        let filter = VariableFilter {
+            record_type: Some("P".to_string()),
+            pattern: Some("inc*".to_string()),
+        };
+        assert!(filter.matches("INCWAGE", "P"));
+        assert!(!filter.matches("INCWAGE", "H"));
+        assert!(!filter.matches("QINCWAGE", "P"));
+
+        let substring = VariableFilter {
+            record_type: None,
+            pattern: Some("wage".to_string()),
+        };
+        assert!(substring.matches("QINCWAGE", "P"));
+        let single = VariableFilter {
+            record_type: None,
+            pattern: Some("?INCWAGE".to_string()),
+        };
+        assert!(single.matches("QINCWAGE", "P"));
+        assert!(!single.matches("INCWAGE", "P"));
+    }
+
+    #[test]
+    fn test_availability() {
+        let filter = VariableFilter {
+            record_type: None,
+            pattern: Some("INCWAGE".to_string()),
+        };
+        let matrix = availability(&context(), MetadataSource::Parquet, &filter).unwrap();
+        assert_eq!(matrix.datasets, ["us1900m", "us1940a"]);
+        let incwage = matrix
+            .variables
+            .iter()
+            .find(|v| v.name == "INCWAGE")
+            .unwrap();
+        assert_eq!(incwage.available, [false, true]);
+
+        let csv = matrix.output(TableFormat::Csv).unwrap();
+        assert!(csv.starts_with("variable,record_type,us1900m,us1940a\n"));
+        assert!(csv.contains("\nINCWAGE,P,0,1\n"));
+        assert!(matrix.output(TableFormat::Html).is_err());
+
+        let from_layouts = availability(&context(), MetadataSource::Layouts, &filter).unwrap();
+        assert!(from_layouts.datasets.len() > 2);
+        assert!(from_layouts
+            .variables
+            .iter()
+            .all(|v| v.name.contains("INCWAGE")));
+    }
+
+    #[test]
+    fn test_search() {
+        let datasets = ["us1900m".to_string(), "us1940a".to_string()];
+        let matches = search(&context(), "incwage", Some(&datasets)).unwrap();
+        let names: Vec<_> = matches.iter().map(|m| m.variable.name.as_str()).collect();
+        assert_eq!(names, ["INCWAGE", "QINCWAGE"]);
+        assert_eq!(matches[0].datasets, ["us1940a"]);
+
+        let matches = search(&context(), "age", Some(&datasets)).unwrap();
+        let age = matches.iter().find(|m| m.variable.name == "AGE").unwrap();
+        assert_eq!(age.datasets, datasets);
+    }
+}
@@ -32,7 +32,7 @@ use crate::defaults;
 use crate::ipums_data_model::*;
 use crate::ipums_metadata_model::*;
 use crate::layout;
-use crate::mderror::{metadata_error, MdError};
+use crate::mderror::{metadata_error, Entity, MdError};
 use crate::parquet_metadata::ParquetMetadataReader;
 use crate::request::InputType;
 
@@ -75,6 +75,33 @@ impl MicroDataCollection {
         Some(weight.divisor)
     }
 
+    /// Whether the dataset has sample line questions, according to its metadata or else the
+    /// collection's defaults.
+    pub fn has_sample_line(&self, dataset_name: &str) -> bool {
+        self.metadata
+            .as_ref()
+            .and_then(|md| md.cloned_dataset_from_name(dataset_name))
+            .and_then(|ds| ds.sample_line)
+            .unwrap_or_else(|| {
+                defaults::sample_line_datasets(&self.name)
+                    .iter()
+                    .any(|ds| ds.eq_ignore_ascii_case(dataset_name))
+            })
+    }
+
+    /// Whether the dataset has the variable, or `None` when there's no metadata about the
+    /// dataset's variables.
+    pub fn dataset_has_variable(&self, dataset_name: &str, variable_name: &str) -> Option<bool> {
+        let md = self.metadata.as_ref()?;
+        let dataset_id = md.datasets_by_name.get(dataset_name)?;
+        let variables = md.available_variables.for_dataset(*dataset_id)?;
+        Some(
+            md.variables_by_name
+                .get(variable_name)
+                .is_some_and(|var_id| variables.contains(var_id)),
+        )
+    }
+
     pub fn base_filename_for_dataset(&self, dataset_name: &str) -> String {
         format!("{}_{}", dataset_name, &self.name.to_ascii_lowercase())
     }
@@ -107,9 +134,8 @@ impl MicroDataCollection {
                 &rt.name.to_ascii_lowercase()
             ))
         } else {
-            Err(MdError::Msg(format!(
-                "Can't create table name since {} is not a valid record type abbrevation.",
-                record_type_abbrev
+            Err(MdError::NotFound(Entity::RecordType(
+                record_type_abbrev.to_string(),
             )))
         }
     }
@@ -214,6 +240,9 @@ impl MicroDataCollection {
                             general_width: None,
                             description: None,
                             category_bins: None,
+                            restrictions: None,
+                            monetary: false,
+                            sample_line: None,
                             id: 0,
                         };
                         md.add_dataset_variable(dataset.clone(), ipums_var);
@@ -247,6 +276,32 @@ impl MicroDataCollection {
         Ok(())
     }
 
+    /// Like [load_metadata_for_selected_datasets_from_layouts](Self::load_metadata_for_selected_datasets_from_layouts),
+    /// but keep any metadata which is already loaded and only read layouts for datasets which
+    /// aren't loaded yet. This lets long-lived contexts pick up new datasets as they're requested.
+    pub fn extend_metadata_for_selected_datasets_from_layouts(
+        &mut self,
+        datasets: &[&str],
+        data_root: &Path,
+    ) -> Result<(), MdError> {
+        let md = self.metadata.get_or_insert_with(MetadataEntities::new);
+        let layouts_path = data_root.to_path_buf().join("layouts");
+        for ds in datasets {
+            if md.datasets_by_name.contains_key(*ds) {
+                continue;
+            }
+            let layout = layout::DatasetLayout::try_from_layout_file(
+                &layouts_path.join(format!("{}.layout.txt", ds)),
+            )?;
+            let ipums_dataset = IpumsDataset::from((ds.to_string(), md.datasets_index.len()));
+            for (index_v, var) in layout.all_variables().iter().enumerate() {
+                let ipums_var = IpumsVariable::from((var, index_v));
+                md.add_dataset_variable(ipums_dataset.clone(), ipums_var);
+            }
+        }
+        Ok(())
+    }
+
     /// Uses default product_root to find metadata database and load all metadata for given datasets.
     pub fn load_full_metadata_for_datasets(&mut self, _datasets: &[String]) {
         todo!("implement");
@@ -568,7 +623,7 @@ impl Context {
             if let Some(var) = md.cloned_variable_from_name(name) {
                 Ok(var)
             } else {
-                Err(metadata_error!("Variable '{name}' not in loaded metadata.",))
+                Err(MdError::NotFound(Entity::Variable(name.to_string())))
             }
         } else {
             Err(metadata_error!(
@@ -593,7 +648,7 @@ impl Context {
         let data_path = if let Some(ref data_root) = self.data_root {
             PathBuf::from(data_root)
         } else {
-            return Err(MdError::Msg("No data root set.".to_string()));
+            return Err(metadata_error!("No data root set."));
         };
 
         let mut all_paths = HashMap::new();
@@ -646,6 +701,63 @@ impl Context {
         }
     }
 
+    /// Load metadata for any of the given datasets which aren't loaded yet, keeping what's
+    /// already loaded. Use this instead of [load_metadata_for_datasets](Self::load_metadata_for_datasets)
+    /// when one context serves many requests.
+    pub fn extend_metadata_for_datasets(&mut self, datasets: &[&str]) -> Result<(), MdError> {
+        if !self.enable_full_metadata {
+            if let Some(ref data_root) = self.data_root {
+                self.settings
+                    .extend_metadata_for_selected_datasets_from_layouts(datasets, data_root)
+            } else {
+                Err(metadata_error!("Cannot load any metadata without a data_root or full metadata available ad the product_root."))
+            }
+        } else {
+            // Long-running services call this, so it mustn't panic.
+            Err(metadata_error!(
+                "Loading metadata from the metadata database isn't implemented yet."
+            ))
+        }
+    }
+
+    /// Check whether metadata for the named dataset has been loaded.
+    pub fn has_metadata_for_dataset(&self, dataset: &str) -> bool {
+        self.settings
+            .metadata
+            .as_ref()
+            .is_some_and(|md| md.datasets_by_name.contains_key(dataset))
+    }
+
+    /// The names of all datasets with a layout file in the data root, sorted by name.
+    pub fn dataset_names_from_layouts(&self) -> Result<Vec<String>, MdError> {
+        let Some(ref data_root) = self.data_root else {
+            return Err(metadata_error!("No data root set."));
+        };
+        let layouts_path = data_root.join("layouts");
+        let entries = std::fs::read_dir(&layouts_path).map_err(|e| {
+            metadata_error!(
+                "Failed to read layouts directory {}: {}",
+                layouts_path.display(),
+                e
+            )
+        })?;
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry =
+                entry.map_err(|e| metadata_error!("Failed to read directory entry: {}", e))?;
+            let file_name = entry.file_name();
+            if let Some(name) = file_name
+                .to_str()
+                .and_then(|n| n.strip_suffix(".layout.txt"))
+            {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
     /// Load metadata for datasets from parquet files
     /// This will extract metadata from the parquet files' key-value metadata if available,
     /// or fall back to schema information.
@@ -829,6 +941,60 @@ mod test {
         assert!(result.is_err(), "expected an error but got {result:?}");
     }
 
+    /// Extending metadata keeps the datasets which were already loaded.
+    #[test]
+    fn test_context_extend_metadata_for_datasets() {
+        let data_root = Some(String::from("tests/data_root"));
+        let mut usa_ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+
+        usa_ctx
+            .load_metadata_for_datasets(&["us1940a"])
+            .expect("should load metadata for us1940a");
+        usa_ctx
+            .extend_metadata_for_datasets(&["us1940a", "us1900m"])
+            .expect("should extend metadata with us1900m");
+
+        assert!(usa_ctx.has_metadata_for_dataset("us1940a"));
+        assert!(usa_ctx.has_metadata_for_dataset("us1900m"));
+        assert!(!usa_ctx.has_metadata_for_dataset("us2015b"));
+
+        let md = usa_ctx
+            .settings
+            .metadata
+            .expect("metadata should be loaded");
+        assert_eq!(md.datasets_index.len(), 2);
+    }
+
+    #[test]
+    fn test_context_extend_full_metadata_is_an_error() {
+        let data_root = Some(String::from("tests/data_root"));
+        let mut usa_ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+        usa_ctx.enable_full_metadata = true;
+        let result = usa_ctx.extend_metadata_for_datasets(&["us1900m"]);
+        assert!(matches!(result, Err(MdError::MetadataError(_))));
+    }
+
+    #[test]
+    fn test_dataset_names_from_layouts() {
+        let data_root = Some(String::from("tests/data_root"));
+        let usa_ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+        let names = usa_ctx
+            .dataset_names_from_layouts()
+            .expect("should list the test layouts");
+
+        assert!(names.contains(&"us2015b".to_string()));
+        assert!(
+            !names.iter().any(|n| n.contains("input_layout")),
+            "input layouts are not datasets"
+        );
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted, "dataset names should be sorted");
+    }
+
     #[test]
     fn test_load_metadata_from_parquet() {
         let data_root = Some(String::from("tests/data_root"));
@@ -10,7 +10,7 @@
 
 use crate::conventions::*;
 use crate::ipums_data_model::*;
-use crate::mderror::MdError;
+use crate::mderror::{parsing_error, MdError};
 use std::collections::HashMap;
 
 fn household(_product: &str) -> RecordType {
@@ -66,6 +66,14 @@ fn usa_sample_line_weight() -> RecordWeight {
     RecordWeight::new("SLWT", 100)
 }
 
+/// Datasets with a sample line, for when the dataset metadata doesn't say.
+pub fn sample_line_datasets(product: &str) -> &'static [&'static str] {
+    match product.to_lowercase().as_ref() {
+        "usa" => &["us1940a", "us1940b", "us1950a", "us1950b"],
+        _ => &[],
+    }
+}
+
 fn default_hierarchy() -> RecordHierarchy {
     let mut hierarchy = RecordHierarchy::new("H");
     let result = hierarchy.add_member("P", "H");
@@ -109,7 +117,7 @@ pub fn defaults_for(product: &str) -> Result<MicroDataCollection, MdError> {
         "usa" => Ok(default_settings_named("USA")),
         "cps" => Ok(default_settings_named("cps")),
         "ipumsi" => Ok(default_settings_named("ipumsi")),
-        _ => Err(MdError::Msg(format!("Product '{product}' not supported"))),
+        _ => Err(parsing_error!("Product '{product}' not supported")),
     }
 }
 
@@ -0,0 +1,758 @@
+//! Derived variables defined by expressions over the variables in the data.
+//!
+//! A request can define new variables like per-capita family income from existing variables:
+//!
+//! ```text
+//! FTOTINC / FAMSIZE
+//! case when AGE < 18 then 1 when AGE < 65 then 2 else 3 end
+//! ```
+//!
+//! Expressions support numbers, variable names, the arithmetic operators `+ - * / %`,
+//! comparisons `= != < <= > >=`, `and`, `or`, `not`, parentheses and `case when ... then ...
+//! else ... end`. A condition on its own, like `AGE >= 65`, gives 1 when it's true and 0 when
+//! it's false.
+//!
+//! Each expression is parsed and checked against the metadata when the request is resolved:
+//! every name must be a numeric variable, and all of the variables in one expression must come
+//! from the same record type. The checked expression is compiled into SQL when the tabulation
+//! query's select clause is built. Missing and not in universe codes of the input variables
+//! become null, so they don't turn into nonsense values like a per-capita income of 4,999,999.
+//! Division by zero gives null as well.
+use std::fmt;
+
+use crate::conventions::Context;
+use crate::ipums_metadata_model::{IpumsDataType, IpumsVariable, VariableRestrictions};
+use crate::mderror::{metadata_error, parsing_error, MdError};
+use crate::query_gen::CompareOperation;
+
+/// The width of derived variables in text output.
+pub(crate) const DERIVED_VARIABLE_WIDTH: usize = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(String),
+    Name(String),
+    Symbol(&'static str),
+    LeftParen,
+    RightParen,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Number(n) | Self::Name(n) => write!(f, "{n}"),
+            Self::Symbol(s) => write!(f, "{s}"),
+            Self::LeftParen => write!(f, "("),
+            Self::RightParen => write!(f, ")"),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 11] = ["<=", ">=", "!=", "+", "-", "*", "/", "%", "=", "<", ">"];
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, MdError> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() || c == '.' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let number = &rest[..len];
+            if number.parse::<f64>().is_err() {
+                return Err(parsing_error!("invalid number '{number}' in expression"));
+            }
+            tokens.push(Token::Number(number.to_string()));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..len].to_string()));
+            len
+        } else if c == '(' {
+            tokens.push(Token::LeftParen);
+            1
+        } else if c == ')' {
+            tokens.push(Token::RightParen);
+            1
+        } else if let Some(symbol) = SYMBOLS.iter().copied().find(|s| rest.starts_with(s)) {
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        } else {
+            return Err(parsing_error!("unexpected character '{c}' in expression"));
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// A parsed expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(String),
+    Variable(String),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, &'static str, Box<Expr>),
+    Case {
+        branches: Vec<(Expr, Expr)>,
+        otherwise: Option<Box<Expr>>,
+    },
+}
+
+/// The kinds of values expressions produce.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ValueType {
+    Integer,
+    Float,
+    Boolean,
+}
+
+impl ValueType {
+    fn is_number(&self) -> bool {
+        matches!(self, Self::Integer | Self::Float)
+    }
+}
+
+const KEYWORDS: [&str; 8] = ["and", "or", "not", "case", "when", "then", "else", "end"];
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(n)) if n.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), MdError> {
+        if self.at_keyword(keyword) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{keyword}'")))
+        }
+    }
+
+    fn at_symbol(&self, symbols: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Symbol(s)) if symbols.contains(s) => Some(*s),
+            _ => None,
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> MdError {
+        match self.peek() {
+            Some(token) => parsing_error!("expected {expected} but found '{token}' in expression"),
+            None => parsing_error!("expected {expected} but the expression ended"),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, MdError> {
+        let mut lhs = self.parse_and()?;
+        while self.at_keyword("or") {
+            self.position += 1;
+            lhs = Expr::Binary(Box::new(lhs), "or", Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, MdError> {
+        let mut lhs = self.parse_not()?;
+        while self.at_keyword("and") {
+            self.position += 1;
+            lhs = Expr::Binary(Box::new(lhs), "and", Box::new(self.parse_not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, MdError> {
+        if self.at_keyword("not") {
+            self.position += 1;
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, MdError> {
+        let lhs = self.parse_sum()?;
+        if let Some(op) = self.at_symbol(&["=", "!=", "<", "<=", ">", ">="]) {
+            self.position += 1;
+            return Ok(Expr::Binary(Box::new(lhs), op, Box::new(self.parse_sum()?)));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr, MdError> {
+        let mut lhs = self.parse_product()?;
+        while let Some(op) = self.at_symbol(&["+", "-"]) {
+            self.position += 1;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.parse_product()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_product(&mut self) -> Result<Expr, MdError> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(op) = self.at_symbol(&["*", "/", "%"]) {
+            self.position += 1;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, MdError> {
+        if self.at_symbol(&["-"]).is_some() {
+            self.position += 1;
+            return Ok(Expr::Negate(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, MdError> {
+        if self.at_keyword("case") {
+            return self.parse_case();
+        }
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Name(name)) if KEYWORDS.iter().any(|k| name.eq_ignore_ascii_case(k)) => {
+                self.position -= 1;
+                Err(self.unexpected("a number, variable or '('"))
+            }
+            Some(Token::Name(name)) => Ok(Expr::Variable(name.to_uppercase())),
+            Some(Token::LeftParen) => {
+                let inner = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RightParen) => Ok(inner),
+                    _ => {
+                        self.position -= 1;
+                        Err(self.unexpected("')'"))
+                    }
+                }
+            }
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected("a number, variable or '('"))
+            }
+        }
+    }
+
+    fn parse_case(&mut self) -> Result<Expr, MdError> {
+        self.expect_keyword("case")?;
+        let mut branches = Vec::new();
+        while self.at_keyword("when") {
+            self.position += 1;
+            let condition = self.parse_or()?;
+            self.expect_keyword("then")?;
+            branches.push((condition, self.parse_or()?));
+        }
+        if branches.is_empty() {
+            return Err(self.unexpected("'when'"));
+        }
+        let otherwise = if self.at_keyword("else") {
+            self.position += 1;
+            Some(Box::new(self.parse_or()?))
+        } else {
+            None
+        };
+        self.expect_keyword("end")?;
+        Ok(Expr::Case {
+            branches,
+            otherwise,
+        })
+    }
+}
+
+impl Expr {
+    /// Parse an expression.
+    ///
+    /// ```
+    /// use cimdea::derived::Expr;
+    ///
+    /// let expr = Expr::parse("FTOTINC / famsize").unwrap();
+    /// assert_eq!(expr.variables(), vec!["FTOTINC", "FAMSIZE"]);
+    /// assert!(Expr::parse("FTOTINC /").is_err());
+    /// ```
+    pub fn parse(expression: &str) -> Result<Self, MdError> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?,
+            position: 0,
+        };
+        let expr = parser.parse_or()?;
+        if parser.peek().is_some() {
+            return Err(parser.unexpected("the end of the expression"));
+        }
+        Ok(expr)
+    }
+
+    /// The names of the variables in the expression, in order of first appearance.
+    pub fn variables(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        self.collect_variables(&mut names);
+        names
+    }
+
+    fn collect_variables(&self, names: &mut Vec<String>) {
+        match self {
+            Self::Number(_) => (),
+            Self::Variable(name) => {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+            Self::Negate(inner) | Self::Not(inner) => inner.collect_variables(names),
+            Self::Binary(lhs, _, rhs) => {
+                lhs.collect_variables(names);
+                rhs.collect_variables(names);
+            }
+            Self::Case {
+                branches,
+                otherwise,
+            } => {
+                for (condition, value) in branches {
+                    condition.collect_variables(names);
+                    value.collect_variables(names);
+                }
+                if let Some(otherwise) = otherwise {
+                    otherwise.collect_variables(names);
+                }
+            }
+        }
+    }
+
+    /// Check that operators get the kinds of values they need, given the types of the
+    /// variables.
+    fn value_type(&self, variables: &[IpumsVariable]) -> Result<ValueType, MdError> {
+        let number = |expr: &Expr, context: &str| -> Result<ValueType, MdError> {
+            let value_type = expr.value_type(variables)?;
+            if value_type.is_number() {
+                Ok(value_type)
+            } else {
+                Err(parsing_error!("{context} needs a number, not a condition"))
+            }
+        };
+        let condition = |expr: &Expr, context: &str| -> Result<(), MdError> {
+            match expr.value_type(variables)? {
+                ValueType::Boolean => Ok(()),
+                _ => Err(parsing_error!("{context} needs a condition, not a number")),
+            }
+        };
+
+        match self {
+            Self::Number(n) if n.contains('.') => Ok(ValueType::Float),
+            Self::Number(_) => Ok(ValueType::Integer),
+            Self::Variable(name) => {
+                let Some(var) = variables.iter().find(|v| &v.name == name) else {
+                    return Err(metadata_error!("no variable named {name}"));
+                };
+                match var.data_type {
+                    Some(IpumsDataType::String) => Err(metadata_error!(
+                        "{name} is a string variable; expressions only work with numbers"
+                    )),
+                    Some(IpumsDataType::Float) | Some(IpumsDataType::Fixed(_)) => {
+                        Ok(ValueType::Float)
+                    }
+                    _ => Ok(ValueType::Integer),
+                }
+            }
+            Self::Negate(inner) => number(inner, "'-'"),
+            Self::Not(inner) => condition(inner, "'not'").map(|_| ValueType::Boolean),
+            Self::Binary(lhs, op @ ("and" | "or"), rhs) => {
+                condition(lhs, &format!("'{op}'"))?;
+                condition(rhs, &format!("'{op}'"))?;
+                Ok(ValueType::Boolean)
+            }
+            Self::Binary(lhs, op @ ("=" | "!=" | "<" | "<=" | ">" | ">="), rhs) => {
+                number(lhs, &format!("'{op}'"))?;
+                number(rhs, &format!("'{op}'"))?;
+                Ok(ValueType::Boolean)
+            }
+            Self::Binary(lhs, op, rhs) => {
+                let lhs = number(lhs, &format!("'{op}'"))?;
+                let rhs = number(rhs, &format!("'{op}'"))?;
+                if *op == "/" || lhs == ValueType::Float || rhs == ValueType::Float {
+                    Ok(ValueType::Float)
+                } else {
+                    Ok(ValueType::Integer)
+                }
+            }
+            Self::Case {
+                branches,
+                otherwise,
+            } => {
+                let mut value_type = ValueType::Integer;
+                let values = branches
+                    .iter()
+                    .map(|(_, value)| value)
+                    .chain(otherwise.as_deref());
+                for (condition_expr, _) in branches {
+                    condition(condition_expr, "'when'")?;
+                }
+                for value in values {
+                    if number(value, "'then' and 'else'")? == ValueType::Float {
+                        value_type = ValueType::Float;
+                    }
+                }
+                Ok(value_type)
+            }
+        }
+    }
+
+    /// Compile the expression into SQL. Variables become their values with missing codes
+    /// replaced by null.
+    fn to_sql(&self, variables: &[IpumsVariable]) -> String {
+        match self {
+            Self::Number(n) => n.clone(),
+            Self::Variable(name) => {
+                let missing_codes = variables
+                    .iter()
+                    .find(|v| &v.name == name)
+                    .map(|v| v.missing_codes())
+                    .unwrap_or_default();
+                if missing_codes.is_empty() {
+                    name.clone()
+                } else {
+                    let codes = missing_codes.iter().map(|c| c.to_string()).collect();
+                    format!(
+                        "(case when {} then null else {} end)",
+                        CompareOperation::In(codes).to_sql(name),
+                        name
+                    )
+                }
+            }
+            Self::Negate(inner) => format!("(-{})", inner.to_sql(variables)),
+            Self::Not(inner) => format!("(not {})", inner.to_sql(variables)),
+            Self::Binary(lhs, "/", rhs) => format!(
+                "({} / nullif({}, 0))",
+                lhs.to_sql(variables),
+                rhs.to_sql(variables)
+            ),
+            Self::Binary(lhs, "%", rhs) => format!(
+                "({} % nullif({}, 0))",
+                lhs.to_sql(variables),
+                rhs.to_sql(variables)
+            ),
+            Self::Binary(lhs, op, rhs) => format!(
+                "({} {} {})",
+                lhs.to_sql(variables),
+                op,
+                rhs.to_sql(variables)
+            ),
+            Self::Case {
+                branches,
+                otherwise,
+            } => {
+                let mut sql = "(case".to_string();
+                for (condition, value) in branches {
+                    sql += &format!(
+                        " when {} then {}",
+                        condition.to_sql(variables),
+                        value.to_sql(variables)
+                    );
+                }
+                if let Some(otherwise) = otherwise {
+                    sql += &format!(" else {}", otherwise.to_sql(variables));
+                }
+                sql + " end)"
+            }
+        }
+    }
+}
+
+/// An expression checked against the metadata, ready to compile into SQL.
+#[derive(Clone, Debug)]
+pub struct Expression {
+    text: String,
+    expr: Expr,
+    value_type: ValueType,
+    /// The variables the expression uses.
+    pub inputs: Vec<IpumsVariable>,
+}
+
+impl Expression {
+    /// Parse an expression and check it against the variables in the context's metadata.
+    ///
+    /// ```
+    /// use cimdea::conventions::Context;
+    /// use cimdea::derived::Expression;
+    ///
+    /// let mut ctx =
+    ///     Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+    ///         .unwrap();
+    /// ctx.load_metadata_for_datasets(&["us1900m"]).unwrap();
+    /// let old = Expression::try_new(&ctx, "age >= 65").unwrap();
+    /// assert!(old.is_condition());
+    /// assert_eq!(old.to_sql(), "cast((AGE >= 65) as integer)");
+    /// assert_eq!(old.record_type(), "P");
+    /// ```
+    pub fn try_new(ctx: &Context, text: &str) -> Result<Self, MdError> {
+        let expr = Expr::parse(text)?;
+        let inputs = expr
+            .variables()
+            .iter()
+            .map(|v| ctx.get_md_variable_by_name(v))
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(first) = inputs.first() else {
+            return Err(parsing_error!(
+                "the expression must use at least one variable"
+            ));
+        };
+        if let Some(other) = inputs.iter().find(|v| v.record_type != first.record_type) {
+            return Err(metadata_error!(
+                "all variables must have the same record type, but {} is a {} variable and {} is a {} variable",
+                first.name,
+                first.record_type,
+                other.name,
+                other.record_type
+            ));
+        }
+        let value_type = expr.value_type(&inputs)?;
+        Ok(Self {
+            text: text.to_string(),
+            expr,
+            value_type,
+            inputs,
+        })
+    }
+
+    /// The record type of the variables in the expression.
+    pub fn record_type(&self) -> &str {
+        &self.inputs[0].record_type
+    }
+
+    /// Whether the expression is a condition rather than a number.
+    pub fn is_condition(&self) -> bool {
+        self.value_type == ValueType::Boolean
+    }
+
+    /// The type of the expression's values. Conditions are integers, 1 or 0.
+    pub fn data_type(&self) -> IpumsDataType {
+        match self.value_type {
+            ValueType::Float => IpumsDataType::Float,
+            ValueType::Integer | ValueType::Boolean => IpumsDataType::Integer,
+        }
+    }
+
+    /// The combined restrictions of the input variables, or `None` if some of them don't
+    /// have restriction metadata.
+    pub fn restrictions(&self) -> Option<VariableRestrictions> {
+        self.inputs
+            .iter()
+            .try_fold(VariableRestrictions::default(), |all, v| {
+                Some(all.union(v.restrictions.as_ref()?))
+            })
+    }
+
+    /// The SQL which computes the expression. Conditions are converted to 1 and 0.
+    pub fn to_sql(&self) -> String {
+        let sql = self.condition_sql();
+        match self.value_type {
+            ValueType::Boolean => format!("cast({sql} as integer)"),
+            _ => sql,
+        }
+    }
+
+    /// The SQL for the expression as it is, so conditions can go in where clauses.
+    pub fn condition_sql(&self) -> String {
+        self.expr.to_sql(&self.inputs)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Check that a name for a computed variable is a plain identifier: a letter or underscore
+/// followed by letters, digits and underscores. The name goes into SQL as a column name.
+pub(crate) fn check_identifier(name: &str) -> Result<(), MdError> {
+    let mut chars = name.chars();
+    let starts_well = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    if starts_well && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(())
+    } else {
+        Err(parsing_error!(
+            "'{name}' isn't a valid name; use letters, digits and underscores, starting with a letter or underscore"
+        ))
+    }
+}
+
+/// A variable computed from an expression over variables in the data.
+#[derive(Clone, Debug)]
+pub struct DerivedVariable {
+    pub expression: Expression,
+}
+
+impl DerivedVariable {
+    /// Parse and check a derived variable's expression.
+    ///
+    /// Returns the derived variable and a metadata variable to stand in for it in requests.
+    pub fn try_new(
+        ctx: &Context,
+        name: &str,
+        expression: &str,
+        label: Option<String>,
+    ) -> Result<(Self, IpumsVariable), MdError> {
+        check_identifier(name).map_err(|err| err.in_request("name"))?;
+        if ctx.get_md_variable_by_name(name).is_ok() {
+            return Err(metadata_error!(
+                "derived variable {name} has the same name as a variable in the data"
+            ));
+        }
+        let expression =
+            Expression::try_new(ctx, expression).map_err(|err| err.in_request("expression"))?;
+        let variable = IpumsVariable {
+            name: name.to_string(),
+            data_type: Some(expression.data_type()),
+            label: Some(label.unwrap_or_else(|| expression.to_string())),
+            record_type: expression.record_type().to_string(),
+            categories: None,
+            formatting: Some((1, DERIVED_VARIABLE_WIDTH)),
+            general_width: None,
+            description: None,
+            category_bins: None,
+            restrictions: expression.restrictions(),
+            monetary: false,
+            sample_line: IpumsVariable::any_sample_line(&expression.inputs),
+            id: 0,
+        };
+        Ok((Self { expression }, variable))
+    }
+
+    /// The SQL which computes the variable.
+    pub fn to_sql(&self) -> String {
+        self.expression.to_sql()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mderror::ErrorCode;
+
+    fn variable(name: &str, data_type: IpumsDataType) -> IpumsVariable {
+        IpumsVariable {
+            name: name.to_string(),
+            data_type: Some(data_type),
+            label: None,
+            record_type: "P".to_string(),
+            categories: None,
+            formatting: None,
+            general_width: None,
+            description: None,
+            category_bins: None,
+            restrictions: None,
+            monetary: false,
+            sample_line: None,
+            id: 0,
+        }
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let expr = Expr::parse("1 + A * 2 > 3 and not B = 0 or C").unwrap();
+        let vars = [
+            variable("A", IpumsDataType::Integer),
+            variable("B", IpumsDataType::Integer),
+            variable("C", IpumsDataType::Integer),
+        ];
+        assert_eq!(
+            expr.to_sql(&vars),
+            "((((1 + (A * 2)) > 3) and (not (B = 0))) or C)"
+        );
+        // C is a number, so it can't be one side of 'or'.
+        assert!(expr.value_type(&vars).is_err());
+    }
+
+    #[test]
+    fn test_case_expression() {
+        let expr =
+            Expr::parse("CASE WHEN age < 18 THEN 1 when AGE < 65 then 2 ELSE 3 end").unwrap();
+        let vars = [variable("AGE", IpumsDataType::Integer)];
+        assert_eq!(
+            expr.to_sql(&vars),
+            "(case when (AGE < 18) then 1 when (AGE < 65) then 2 else 3 end)"
+        );
+        assert_eq!(expr.value_type(&vars).unwrap(), ValueType::Integer);
+    }
+
+    #[test]
+    fn test_value_types() {
+        let vars = [
+            variable("INC", IpumsDataType::Integer),
+            variable("N", IpumsDataType::Integer),
+            variable("NAME", IpumsDataType::String),
+        ];
+        let value_type = |e: &str| Expr::parse(e).unwrap().value_type(&vars);
+        assert_eq!(value_type("INC - N * 2").unwrap(), ValueType::Integer);
+        assert_eq!(value_type("INC / N").unwrap(), ValueType::Float);
+        assert_eq!(value_type("INC * 1.5").unwrap(), ValueType::Float);
+        assert_eq!(value_type("INC > 0 and N > 1").unwrap(), ValueType::Boolean);
+        assert!(value_type("NAME + 1").is_err());
+        assert!(value_type("(INC > 0) + 1").is_err());
+        assert!(value_type("case when INC then 1 end").is_err());
+        assert!(value_type("MISSING * 2").is_err());
+    }
+
+    #[test]
+    fn test_division_guards_zero() {
+        let expr = Expr::parse("INC / N").unwrap();
+        let vars = [
+            variable("INC", IpumsDataType::Integer),
+            variable("N", IpumsDataType::Integer),
+        ];
+        assert_eq!(expr.to_sql(&vars), "(INC / nullif(N, 0))");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for bad in [
+            "",
+            "A +",
+            "(A",
+            "A B",
+            "case end",
+            "case when A then end",
+            "A ; drop",
+        ] {
+            assert!(Expr::parse(bad).is_err(), "expected '{bad}' to be an error");
+        }
+    }
+
+    #[test]
+    fn test_derived_variable_names() {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .unwrap();
+        ctx.load_metadata_for_datasets(&["us1900m"]).unwrap();
+        assert!(DerivedVariable::try_new(&ctx, "_OLD_AGE2", "AGE >= 65", None).is_ok());
+        for bad in [
+            "",
+            "OLD AGE",
+            "2OLD",
+            "X, (select 42) as Y",
+            "OLD-AGE",
+            "\"X\"",
+        ] {
+            let err = DerivedVariable::try_new(&ctx, bad, "AGE >= 65", None)
+                .expect_err(&format!("expected '{bad}' to be an invalid name"));
+            assert_eq!(err.code(), ErrorCode::Parsing);
+            assert_eq!(err.field(), Some("name"));
+        }
+    }
+}
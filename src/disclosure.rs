@@ -0,0 +1,617 @@
+//! Statistical disclosure control for published tabulations.
+//!
+//! Small cells in a tabulation can identify individuals, so tables meant for publication must
+//! be checked before release. [DisclosureRules] describes the checks to apply and
+//! [DisclosureRules::apply] carries them out on a [Table]:
+//!
+//! * Refusal of restricted variables. A request which uses a variable with any
+//!   [VariableRestrictions](crate::ipums_metadata_model::VariableRestrictions), or a variable
+//!   without restriction metadata, is an error.
+//! * Primary suppression. The counts of cells with fewer than `min_cell_count` unweighted
+//!   cases are removed.
+//! * Complementary suppression. When a cell is the only suppressed cell along some dimension
+//!   of the table, its value could be recovered by subtracting the other cells from the
+//!   marginal total. So another cell along that dimension is suppressed too, choosing the one
+//!   with the smallest count.
+//! * Rounding of weighted counts to a multiple of `weighted_count_base`.
+//!
+//! Suppressed counts become [Cell::Null]. The table records what was done in its
+//! [DisclosureReport], which is included in JSON output and summarized after text output.
+use std::collections::HashMap;
+
+use rust_decimal::prelude::*;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::conventions::Context;
+use crate::input_schema_tabulation::WeightSelection;
+use crate::ipums_metadata_model::IpumsVariable;
+use crate::mderror::{Entity, MdError};
+use crate::request::{DataRequest, RequestVariable};
+use crate::tabulate::{Cell, OutputColumn, Table, Tabulation};
+
+/// Disclosure rules to apply to tables before publishing them.
+///
+/// The default rules do nothing, except that complementary suppression is on whenever
+/// `min_cell_count` is set.
+///
+/// ```
+/// use cimdea::disclosure::DisclosureRules;
+///
+/// let rules = DisclosureRules {
+///     min_cell_count: Some(10),
+///     weighted_count_base: Some(100),
+///     refuse_restricted_variables: true,
+///     ..DisclosureRules::default()
+/// };
+/// assert!(rules.complementary_suppression);
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DisclosureRules {
+    /// Suppress the counts of cells with fewer than this many unweighted cases.
+    pub min_cell_count: Option<i64>,
+    /// Suppress additional cells so that primary suppressions can't be recovered from totals.
+    pub complementary_suppression: bool,
+    /// Round weighted counts to the nearest multiple of this base.
+    pub weighted_count_base: Option<u32>,
+    /// Refuse to produce tables which include restricted variables.
+    pub refuse_restricted_variables: bool,
+}
+
+impl Default for DisclosureRules {
+    fn default() -> Self {
+        Self {
+            min_cell_count: None,
+            complementary_suppression: true,
+            weighted_count_base: None,
+            refuse_restricted_variables: false,
+        }
+    }
+}
+
+/// Why the counts of a cell were suppressed.
+#[derive(Clone, Copy, Debug, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuppressionReason {
+    /// The cell had fewer cases than the minimum cell count.
+    Primary,
+    /// The cell was suppressed to protect another suppressed cell.
+    Complementary,
+}
+
+/// A table cell whose counts were suppressed.
+#[derive(Clone, Debug, JsonSchema, PartialEq, Serialize)]
+pub struct SuppressedCell {
+    /// The index of the row in the table.
+    pub row: usize,
+    /// The values of the request variables which identify the cell.
+    pub values: Vec<Cell>,
+    pub reason: SuppressionReason,
+}
+
+/// A record of the disclosure rules applied to a table and the cells they suppressed.
+#[derive(Clone, Debug, Default, JsonSchema, PartialEq, Serialize)]
+pub struct DisclosureReport {
+    pub min_cell_count: Option<i64>,
+    pub weighted_count_base: Option<u32>,
+    pub suppressed: Vec<SuppressedCell>,
+}
+
+impl DisclosureReport {
+    /// A one-line summary of the report for text output.
+    pub fn summary(&self) -> String {
+        let primary = self
+            .suppressed
+            .iter()
+            .filter(|cell| cell.reason == SuppressionReason::Primary)
+            .count();
+        let complementary = self.suppressed.len() - primary;
+        let mut summary = format!(
+            "Suppressed {} cells ({primary} primary, {complementary} complementary)",
+            self.suppressed.len()
+        );
+        if let Some(min_cell_count) = self.min_cell_count {
+            summary.push_str(&format!(" with a minimum cell count of {min_cell_count}"));
+        }
+        if let Some(base) = self.weighted_count_base {
+            summary.push_str(&format!(
+                "; weighted counts rounded to a multiple of {base}"
+            ));
+        }
+        summary
+    }
+}
+
+impl DisclosureRules {
+    /// Check every variable a request uses before running it, so that a request using a
+    /// restricted variable fails without touching the data. This covers the request variables,
+    /// the subpopulation and the weight variable, along with the pointers of attached variables
+    /// and the inputs of derived variables and roll-ups.
+    pub fn check_request(&self, ctx: &Context, rq: &impl DataRequest) -> Result<(), MdError> {
+        let request_variables = rq.get_request_variables();
+        let subpopulation = rq.get_subpopulation();
+        self.check_variables(request_variables.iter().chain(&subpopulation))?;
+        self.check_weight(ctx, &rq.weight_selection())
+    }
+
+    /// Check a weight variable named by the request. Automatic weights come from the
+    /// collection's configuration rather than the request, so they aren't checked.
+    pub fn check_weight(&self, ctx: &Context, weight: &WeightSelection) -> Result<(), MdError> {
+        match weight {
+            WeightSelection::Variable(name) if self.refuse_restricted_variables => ctx
+                .get_md_variable_by_name(name)
+                .and_then(|variable| self.check_used([&variable]))
+                .map_err(|err| err.in_request("weight")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the variables and everything they're computed from. Variables without restriction
+    /// metadata, like those loaded from layout files, are refused too, since there's no way to
+    /// tell whether they're restricted.
+    pub fn check_variables<'a>(
+        &self,
+        variables: impl IntoIterator<Item = &'a RequestVariable>,
+    ) -> Result<(), MdError> {
+        self.check_used(variables.into_iter().flat_map(used_variables))
+    }
+
+    fn check_used<'a>(
+        &self,
+        variables: impl IntoIterator<Item = &'a IpumsVariable>,
+    ) -> Result<(), MdError> {
+        if !self.refuse_restricted_variables {
+            return Ok(());
+        }
+        for var in variables {
+            let reason = match var.restrictions {
+                None => {
+                    "has no restriction metadata, so it can't be checked for a published tabulation"
+                }
+                Some(ref restrictions) if restrictions.any() => {
+                    "is restricted and can't be included in a published tabulation"
+                }
+                Some(_) => continue,
+            };
+            return Err(MdError::Restricted {
+                entity: Entity::Variable(var.name.clone()),
+                reason: reason.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Apply the rules to a table, returning a copy with suppressed and rounded counts and a
+    /// [DisclosureReport].
+    pub fn apply(&self, table: &Table) -> Result<Table, MdError> {
+        let request_variables: Vec<RequestVariable> = table
+            .heading
+            .iter()
+            .filter_map(|column| match column {
+                OutputColumn::RequestVar(v) => Some(*v.clone()),
+                OutputColumn::Constructed { .. } => None,
+            })
+            .collect();
+        self.check_variables(&request_variables)?;
+
+        let ct_column = self.column_index(table, "ct")?;
+        let weighted_ct_column = table.heading.iter().position(|column| {
+            matches!(column, OutputColumn::Constructed { name, .. } if name == "weighted_ct")
+        });
+        let variable_columns: Vec<usize> = table
+            .heading
+            .iter()
+            .enumerate()
+            .filter(|(_, column)| matches!(column, OutputColumn::RequestVar(_)))
+            .map(|(index, _)| index)
+            .collect();
+
+        let counts: Vec<Option<i64>> = table
+            .rows
+            .iter()
+            .map(|row| match row.get(ct_column) {
+                Some(Cell::Integer(ct)) => Some(*ct),
+                _ => None,
+            })
+            .collect();
+
+        let mut reasons: Vec<Option<SuppressionReason>> = vec![None; table.rows.len()];
+        if let Some(min_cell_count) = self.min_cell_count {
+            for (row, count) in counts.iter().enumerate() {
+                if matches!(count, Some(ct) if *ct < min_cell_count) {
+                    reasons[row] = Some(SuppressionReason::Primary);
+                }
+            }
+            if self.complementary_suppression {
+                complementary_suppression(table, &variable_columns, &counts, &mut reasons);
+            }
+        }
+
+        let mut report = DisclosureReport {
+            min_cell_count: self.min_cell_count,
+            weighted_count_base: self.weighted_count_base,
+            suppressed: Vec::new(),
+        };
+        let mut rows = Vec::with_capacity(table.rows.len());
+        for (index, row) in table.rows.iter().enumerate() {
+            let mut row = row.clone();
+            if let Some(reason) = reasons[index] {
+                row[ct_column] = Cell::Null;
+                if let Some(weighted_ct_column) = weighted_ct_column {
+                    row[weighted_ct_column] = Cell::Null;
+                }
+                report.suppressed.push(SuppressedCell {
+                    row: index,
+                    values: variable_columns.iter().map(|c| row[*c].clone()).collect(),
+                    reason,
+                });
+            } else if let (Some(base), Some(weighted_ct_column)) =
+                (self.weighted_count_base, weighted_ct_column)
+            {
+                row[weighted_ct_column] = round_to_base(&row[weighted_ct_column], base);
+            }
+            rows.push(row);
+        }
+
+        Ok(Table {
+            heading: table.heading.clone(),
+            rows,
+            disclosure: Some(report),
+        })
+    }
+
+    /// Apply the rules to every table in the tabulation.
+    pub fn apply_to_tabulation(&self, tabulation: &Tabulation) -> Result<Tabulation, MdError> {
+        let tables = tabulation
+            .0
+            .iter()
+            .map(|table| self.apply(table))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Tabulation(tables))
+    }
+
+    fn column_index(&self, table: &Table, name: &str) -> Result<usize, MdError> {
+        table
+            .heading
+            .iter()
+            .position(|column| column.name() == name)
+            .ok_or_else(|| MdError::Msg(format!("Table has no '{name}' column")))
+    }
+}
+
+/// The variables a request variable reads: the inputs it's computed from and the pointer to the
+/// family member it's attached from, then the variable itself. Inputs come first so that errors
+/// name the variable in the data rather than the computed one.
+fn used_variables(rq: &RequestVariable) -> Vec<&IpumsVariable> {
+    let mut variables = Vec::new();
+    if let Some(ref derived) = rq.derived {
+        variables.extend(&derived.expression.inputs);
+    }
+    if let Some(ref rollup) = rq.rollup {
+        variables.extend(
+            rollup
+                .value
+                .iter()
+                .chain(&rollup.condition)
+                .flat_map(|e| &e.inputs),
+        );
+    }
+    variables.extend(&rq.attached_variable_pointer);
+    variables.push(&rq.variable);
+    variables
+}
+
+/// Repeatedly look along each dimension of the table for groups of cells with exactly one
+/// suppression, and suppress the unsuppressed cell with the smallest count in each.
+///
+/// A dimension's groups are the rows which share values for all of the other variables. With a
+/// single variable the whole table is one group.
+fn complementary_suppression(
+    table: &Table,
+    variable_columns: &[usize],
+    counts: &[Option<i64>],
+    reasons: &mut [Option<SuppressionReason>],
+) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for dimension in variable_columns {
+            let mut groups: HashMap<Vec<String>, Vec<usize>> = HashMap::new();
+            for (index, row) in table.rows.iter().enumerate() {
+                let key = variable_columns
+                    .iter()
+                    .filter(|column| *column != dimension)
+                    .map(|column| row[*column].to_string())
+                    .collect();
+                groups.entry(key).or_default().push(index);
+            }
+
+            // Sort the groups so that the choice of complementary cells is repeatable.
+            let mut groups: Vec<_> = groups.into_values().collect();
+            groups.sort();
+            for group in groups {
+                let suppressed = group.iter().filter(|row| reasons[**row].is_some()).count();
+                if suppressed != 1 {
+                    continue;
+                }
+                let complement = group
+                    .iter()
+                    .filter(|row| reasons[**row].is_none())
+                    .min_by_key(|row| (counts[**row].unwrap_or(i64::MAX), **row));
+                if let Some(row) = complement {
+                    reasons[*row] = Some(SuppressionReason::Complementary);
+                    changed = true;
+                }
+            }
+        }
+    }
+}
+
+/// Round a count to the nearest multiple of the base, with half way values rounded away from
+/// zero.
+fn round_to_base(cell: &Cell, base: u32) -> Cell {
+    let value = match cell {
+        Cell::Integer(i) => Decimal::from(*i),
+        Cell::Decimal(d) => *d,
+        Cell::Float(f) => match Decimal::from_f64(*f) {
+            Some(d) => d,
+            None => return cell.clone(),
+        },
+        Cell::String(_) | Cell::Null => return cell.clone(),
+    };
+    if base == 0 {
+        return cell.clone();
+    }
+    let base = Decimal::from(base);
+    let rounded =
+        (value / base).round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero) * base;
+    match rounded.to_i64() {
+        Some(i) => Cell::Integer(i),
+        None => Cell::Decimal(rounded),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conventions::Context;
+    use crate::ipums_metadata_model::{IpumsDataType, VariableRestrictions};
+    use crate::mderror::ErrorCode;
+    use crate::request::{AbacusRequest, SimpleRequest};
+
+    fn count_column(name: &str) -> OutputColumn {
+        OutputColumn::Constructed {
+            name: name.to_string(),
+            width: 10,
+            data_type: IpumsDataType::Integer,
+        }
+    }
+
+    /// A table of counts by two variables, with the given (var1, var2, ct) rows. The
+    /// weighted counts are ten times the counts.
+    fn two_way_table(cells: &[(i64, i64, i64)]) -> Table {
+        let (_, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us1900m"],
+            &["RACE", "HISPAN"],
+            None,
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to set up the request");
+        let mut heading = vec![count_column("ct"), count_column("weighted_ct")];
+        heading.extend(
+            rq.get_request_variables()
+                .into_iter()
+                .map(|v| OutputColumn::RequestVar(Box::new(v))),
+        );
+        let rows = cells
+            .iter()
+            .map(|(a, b, ct)| {
+                vec![
+                    Cell::Integer(*ct),
+                    Cell::Integer(ct * 10),
+                    Cell::Integer(*a),
+                    Cell::Integer(*b),
+                ]
+            })
+            .collect();
+        Table {
+            heading,
+            rows,
+            disclosure: None,
+        }
+    }
+
+    fn suppressed_rows(table: &Table) -> Vec<(usize, SuppressionReason)> {
+        let report = table.disclosure.as_ref().expect("should have a report");
+        report
+            .suppressed
+            .iter()
+            .map(|cell| (cell.row, cell.reason))
+            .collect()
+    }
+
+    #[test]
+    fn test_default_rules_change_nothing() {
+        let table = two_way_table(&[(1, 1, 2), (1, 2, 50)]);
+        let applied = DisclosureRules::default().apply(&table).unwrap();
+        assert_eq!(table.rows, applied.rows);
+        assert!(suppressed_rows(&applied).is_empty());
+    }
+
+    #[test]
+    fn test_primary_and_complementary_suppression() {
+        // 2x2 table: the one small cell needs a complement in its row and its column, and
+        // then the cell diagonal to it needs suppressing as well.
+        let table = two_way_table(&[(1, 1, 3), (1, 2, 40), (2, 1, 30), (2, 2, 50)]);
+        let rules = DisclosureRules {
+            min_cell_count: Some(5),
+            ..DisclosureRules::default()
+        };
+        let applied = rules.apply(&table).unwrap();
+        let suppressed = suppressed_rows(&applied);
+        assert_eq!(4, suppressed.len());
+        assert!(suppressed.contains(&(0, SuppressionReason::Primary)));
+        assert!(suppressed.contains(&(1, SuppressionReason::Complementary)));
+        assert!(suppressed.contains(&(2, SuppressionReason::Complementary)));
+        assert!(suppressed.contains(&(3, SuppressionReason::Complementary)));
+        assert_eq!(Cell::Null, applied.rows[0][0]);
+        assert_eq!(Cell::Null, applied.rows[0][1]);
+        assert_eq!(Cell::Integer(1), applied.rows[0][2]);
+    }
+
+    #[test]
+    fn test_complement_is_smallest_cell() {
+        let table = two_way_table(&[(1, 1, 2), (2, 1, 30), (3, 1, 8), (4, 1, 90)]);
+        let rules = DisclosureRules {
+            min_cell_count: Some(5),
+            ..DisclosureRules::default()
+        };
+        let applied = rules.apply(&table).unwrap();
+        assert_eq!(
+            vec![
+                (0, SuppressionReason::Primary),
+                (2, SuppressionReason::Complementary)
+            ],
+            suppressed_rows(&applied)
+        );
+        assert_eq!(Cell::Integer(30), applied.rows[1][0]);
+    }
+
+    #[test]
+    fn test_no_complementary_suppression() {
+        let table = two_way_table(&[(1, 1, 2), (2, 1, 30)]);
+        let rules = DisclosureRules {
+            min_cell_count: Some(5),
+            complementary_suppression: false,
+            ..DisclosureRules::default()
+        };
+        let applied = rules.apply(&table).unwrap();
+        assert_eq!(
+            vec![(0, SuppressionReason::Primary)],
+            suppressed_rows(&applied)
+        );
+    }
+
+    #[test]
+    fn test_round_weighted_counts() {
+        let table = two_way_table(&[(1, 1, 14), (2, 1, 15)]);
+        let rules = DisclosureRules {
+            weighted_count_base: Some(100),
+            ..DisclosureRules::default()
+        };
+        let applied = rules.apply(&table).unwrap();
+        assert_eq!(Cell::Integer(14), applied.rows[0][0]);
+        assert_eq!(Cell::Integer(100), applied.rows[0][1]);
+        assert_eq!(Cell::Integer(200), applied.rows[1][1]);
+
+        let exact = Cell::Decimal(Decimal::new(76149, 1));
+        assert_eq!(Cell::Integer(7610), round_to_base(&exact, 10));
+    }
+
+    #[test]
+    fn test_refuse_restricted_variables() {
+        let mut table = two_way_table(&[(1, 1, 14)]);
+        for column in &mut table.heading[2..] {
+            if let OutputColumn::RequestVar(ref mut v) = column {
+                v.variable.restrictions = Some(VariableRestrictions::default());
+            }
+        }
+        if let OutputColumn::RequestVar(ref mut v) = table.heading[2] {
+            v.variable.restrictions = Some(VariableRestrictions {
+                is_restricted: true,
+                ..VariableRestrictions::default()
+            });
+        }
+        let rules = DisclosureRules {
+            refuse_restricted_variables: true,
+            ..DisclosureRules::default()
+        };
+        let err = rules
+            .apply(&table)
+            .expect_err("should refuse the restricted variable");
+        assert!(err.to_string().contains("variable RACE is restricted"));
+        assert_eq!(ErrorCode::Restricted, err.code());
+        assert_eq!(Some(&Entity::Variable("RACE".to_string())), err.entity());
+
+        assert!(DisclosureRules::default().apply(&table).is_ok());
+    }
+
+    #[test]
+    fn test_check_every_variable_in_request() {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .unwrap();
+        ctx.load_metadata_for_datasets(&["us1900m"]).unwrap();
+        let mut rq = AbacusRequest::builder(&ctx)
+            .sample("us1900m")
+            .derived("OLD", "AGE >= 65")
+            .var("OLD")
+            .filter("STATEFIP", ["48".parse().unwrap()])
+            .build()
+            .unwrap();
+        let rules = DisclosureRules {
+            refuse_restricted_variables: true,
+            ..DisclosureRules::default()
+        };
+
+        // Layouts don't record restrictions, so there's nothing to check the variables with.
+        let err = rules
+            .check_request(&ctx, &rq)
+            .expect_err("should refuse variables without restriction metadata");
+        assert!(err
+            .to_string()
+            .contains("variable AGE has no restriction metadata"));
+        assert_eq!(ErrorCode::Restricted, err.code());
+        assert_eq!(Some(&Entity::Variable("AGE".to_string())), err.entity());
+
+        let unrestricted = Some(VariableRestrictions::default());
+        let restricted = Some(VariableRestrictions {
+            is_restricted: true,
+            ..VariableRestrictions::default()
+        });
+        for v in rq.request_variables.iter_mut().chain(&mut rq.subpopulation) {
+            v.variable.restrictions = unrestricted.clone();
+            if let Some(ref mut derived) = v.derived {
+                for input in &mut derived.expression.inputs {
+                    input.restrictions = unrestricted.clone();
+                }
+            }
+        }
+        assert!(rules.check_request(&ctx, &rq).is_ok());
+
+        // The weight variable is read from the data too, and this one comes from a layout.
+        rq.weight = WeightSelection::Variable("PERWT".to_string());
+        let err = rules.check_request(&ctx, &rq).unwrap_err();
+        assert_eq!(Some("weight"), err.field());
+        assert_eq!(Some(&Entity::Variable("PERWT".to_string())), err.entity());
+        rq.weight = WeightSelection::Automatic;
+
+        rq.subpopulation[0].variable.restrictions = restricted.clone();
+        let err = rules.check_request(&ctx, &rq).unwrap_err();
+        assert!(err.to_string().contains("variable STATEFIP is restricted"));
+
+        rq.subpopulation[0].variable.restrictions = unrestricted;
+        let derived = rq.request_variables[0].derived.as_mut().unwrap();
+        derived.expression.inputs[0].restrictions = restricted;
+        let err = rules.check_request(&ctx, &rq).unwrap_err();
+        assert!(err.to_string().contains("variable AGE is restricted"));
+    }
+
+    #[test]
+    fn test_report_summary() {
+        let table = two_way_table(&[(1, 1, 2), (2, 1, 30)]);
+        let rules = DisclosureRules {
+            min_cell_count: Some(5),
+            weighted_count_base: Some(10),
+            ..DisclosureRules::default()
+        };
+        let applied = rules.apply(&table).unwrap();
+        assert_eq!(
+            "Suppressed 2 cells (1 primary, 1 complementary) with a minimum cell count of 5; \
+             weighted counts rounded to a multiple of 10",
+            applied.disclosure.unwrap().summary()
+        );
+    }
+}
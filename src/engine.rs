@@ -0,0 +1,555 @@
+//! A reusable tabulation engine.
+//!
+//! [tabulate](crate::tabulate::tabulate) opens a new DuckDB connection for every call and reads
+//! the data files by path in each query. A [TabulationEngine] instead keeps one DuckDB database
+//! per product and data root, with a view for each dataset and record type which is created the
+//! first time a request needs it. Connections to the database are pooled, and the queries for
+//! the datasets in a request run concurrently.
+//!
+//! The engine also keeps metadata loaded across requests in a [ContextCache], and keeps the
+//! queries it compiles for incoming requests so that a repeated request goes straight to DuckDB.
+//! It keeps a bounded number of databases and compiled requests, dropping the least recently
+//! used.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use duckdb::Connection;
+use serde_json::Value;
+
+use crate::conventions::Context;
+use crate::input_schema_tabulation;
+use crate::mderror::MdError;
+use crate::query_gen::{DataPlatform, DataSource, TabQuery};
+use crate::request::{AbacusRequest, DataRequest, InputType, RequestVariable};
+use crate::result_cache::CacheKey;
+use crate::service::ContextCache;
+use crate::tabulate::{CompiledTabulation, Table, Tabulation};
+
+/// The most compiled requests the engine keeps. When it's full, the oldest is dropped.
+const MAX_PREPARED: usize = 256;
+
+/// The most warehouses the engine keeps, one per product and data root. When it's full, the
+/// least recently used is dropped along with the requests compiled for it.
+const MAX_WAREHOUSES: usize = 8;
+
+/// Warehouses are keyed by lowercase product name and data root.
+type WarehouseKey = (String, Option<PathBuf>);
+
+fn lock<'a, T>(mutex: &'a Mutex<T>, what: &str) -> Result<MutexGuard<'a, T>, MdError> {
+    mutex
+        .lock()
+        .map_err(|_| MdError::Msg(format!("The {what} lock was poisoned.")))
+}
+
+/// An in-memory DuckDB database with views over one data root's parquet files, and a pool of
+/// idle connections to it.
+#[derive(Debug)]
+struct Warehouse {
+    /// New connections are cloned from this one, so they share its database.
+    base: Mutex<Connection>,
+    idle: Mutex<Vec<Connection>>,
+    /// The names of the views created so far.
+    views: Mutex<HashSet<String>>,
+}
+
+impl Warehouse {
+    fn new() -> Result<Self, MdError> {
+        Ok(Self {
+            base: Mutex::new(Connection::open_in_memory()?),
+            idle: Mutex::new(Vec::new()),
+            views: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Create a view for each of the dataset's record types which has data, named with the
+    /// default table name that queries for `InputType::NativeDb` read from.
+    ///
+    /// Record types whose files are missing are tried again on the next call, so their views
+    /// are created once the files are there.
+    fn register_dataset(&self, ctx: &Context, dataset: &str) -> Result<(), MdError> {
+        let mut views = lock(&self.views, "warehouse views")?;
+        let base = lock(&self.base, "warehouse connection")?;
+        for source in DataSource::for_dataset(ctx, dataset, &InputType::Parquet)?.values() {
+            let table_name = source.table_name();
+            if views.contains(&table_name) {
+                continue;
+            }
+            // A record type without data can't be queried either way; leaving out its view
+            // means a query which needs it fails with the missing table's name.
+            if let DataSource::Parquet { full_path, .. } = source {
+                if !full_path.exists() {
+                    continue;
+                }
+            }
+            base.execute_batch(&format!(
+                "create or replace view {table_name} as select * from {}",
+                source.for_platform(&DataPlatform::Duckdb)
+            ))?;
+            views.insert(table_name);
+        }
+        Ok(())
+    }
+
+    /// Take an idle connection, or open another one to the database.
+    fn connection(&self) -> Result<Connection, MdError> {
+        if let Some(conn) = lock(&self.idle, "connection pool")?.pop() {
+            return Ok(conn);
+        }
+        Ok(lock(&self.base, "warehouse connection")?.try_clone()?)
+    }
+
+    fn release(&self, conn: Connection) {
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.push(conn);
+        }
+    }
+
+    /// Run one query on a pooled connection.
+    fn run_query(&self, compiled: &CompiledTabulation, q: &TabQuery) -> Result<Table, MdError> {
+        let conn = self.connection()?;
+        let table = compiled.run_query(&conn, q);
+        self.release(conn);
+        table
+    }
+}
+
+/// A request compiled by a [TabulationEngine], ready to run any number of times.
+#[derive(Debug)]
+pub struct PreparedTabulation {
+    warehouse: Arc<Warehouse>,
+    compiled: CompiledTabulation,
+}
+
+impl PreparedTabulation {
+    pub fn request_variables(&self) -> &[RequestVariable] {
+        &self.compiled.request_variables
+    }
+
+    /// The variables which select the cases to count.
+    pub fn subpopulation(&self) -> &[RequestVariable] {
+        &self.compiled.subpopulation
+    }
+
+    /// The query for each dataset in the request, which read from the engine's views.
+    pub fn queries(&self) -> &[TabQuery] {
+        &self.compiled.queries
+    }
+
+    /// Run the queries, one per dataset, concurrently on pooled connections.
+    pub fn run(&self) -> Result<Tabulation, MdError> {
+        let tables = match self.compiled.queries.as_slice() {
+            [q] => vec![self.warehouse.run_query(&self.compiled, q)?],
+            queries => thread::scope(|scope| {
+                let handles: Vec<_> = queries
+                    .iter()
+                    .map(|q| scope.spawn(move || self.warehouse.run_query(&self.compiled, q)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle.join().unwrap_or_else(|_| {
+                            Err(MdError::Msg("A tabulation query panicked.".to_string()))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })?,
+        };
+        self.compiled.finish(tables)
+    }
+}
+
+/// Compiled requests by the hash of the incoming request's [CacheKey], which includes the
+/// versions of the data it reads, oldest first.
+#[derive(Debug, Default)]
+struct PreparedCache {
+    prepared: HashMap<String, Arc<PreparedTabulation>>,
+    order: VecDeque<String>,
+}
+
+impl PreparedCache {
+    fn insert(&mut self, key: String, prepared: Arc<PreparedTabulation>) {
+        if self.prepared.insert(key.clone(), prepared).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > MAX_PREPARED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.prepared.remove(&oldest);
+            }
+        }
+    }
+
+    /// Drop the requests compiled for a warehouse.
+    fn remove_warehouse(&mut self, warehouse: &Arc<Warehouse>) {
+        self.prepared
+            .retain(|_, prepared| !Arc::ptr_eq(&prepared.warehouse, warehouse));
+        let prepared = &self.prepared;
+        self.order.retain(|key| prepared.contains_key(key));
+    }
+}
+
+/// Warehouses by product and data root, with their last uses.
+#[derive(Debug, Default)]
+struct Warehouses {
+    warehouses: HashMap<WarehouseKey, (Arc<Warehouse>, u64)>,
+    clock: u64,
+}
+
+/// Tabulates requests on warm DuckDB connections, sharing metadata, views and compiled queries
+/// between requests.
+///
+/// The engine is safe to share between threads. A compiled request is reused until the data
+/// it reads changes or it's dropped to make room, so a request with generated bins keeps the
+/// bins generated from the data when it was compiled.
+#[derive(Debug)]
+pub struct TabulationEngine {
+    contexts: ContextCache,
+    warehouses: Mutex<Warehouses>,
+    prepared: Mutex<PreparedCache>,
+}
+
+impl TabulationEngine {
+    /// Create an engine. When a request doesn't give a data root, use `default_data_root`, and
+    /// if that's `None` fall back to the product's default data root.
+    pub fn new(default_data_root: Option<String>) -> Self {
+        Self {
+            contexts: ContextCache::new(default_data_root),
+            warehouses: Mutex::new(Warehouses::default()),
+            prepared: Mutex::new(PreparedCache::default()),
+        }
+    }
+
+    /// The contexts the engine has loaded metadata into.
+    pub fn contexts(&self) -> &ContextCache {
+        &self.contexts
+    }
+
+    fn warehouse_for(&self, ctx: &Context) -> Result<Arc<Warehouse>, MdError> {
+        let key = (ctx.name.to_lowercase(), ctx.data_root.clone());
+        let mut warehouses = lock(&self.warehouses, "warehouse")?;
+        warehouses.clock += 1;
+        let clock = warehouses.clock;
+        if let Some((warehouse, used)) = warehouses.warehouses.get_mut(&key) {
+            *used = clock;
+            return Ok(Arc::clone(warehouse));
+        }
+
+        let warehouse = Arc::new(Warehouse::new()?);
+        warehouses
+            .warehouses
+            .insert(key, (Arc::clone(&warehouse), clock));
+        if warehouses.warehouses.len() > MAX_WAREHOUSES {
+            let oldest = warehouses
+                .warehouses
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some((dropped, _)) = oldest.and_then(|key| warehouses.warehouses.remove(&key)) {
+                lock(&self.prepared, "compiled request")?.remove_warehouse(&dropped);
+            }
+        }
+        Ok(warehouse)
+    }
+
+    /// Compile a request resolved against a context into queries on the engine's views,
+    /// creating views for its datasets if they don't have them yet.
+    pub fn prepare<R>(&self, ctx: &Context, rq: R) -> Result<PreparedTabulation, MdError>
+    where
+        R: DataRequest,
+    {
+        let warehouse = self.warehouse_for(ctx)?;
+        for sample in rq.get_request_samples() {
+            warehouse.register_dataset(ctx, &sample.name)?;
+        }
+        let compiled = CompiledTabulation::compile(ctx, rq, &InputType::NativeDb)?;
+        Ok(PreparedTabulation {
+            warehouse,
+            compiled,
+        })
+    }
+
+    /// Resolve and compile an incoming request, or get it from the requests already compiled.
+    pub fn prepare_input(
+        &self,
+        request: input_schema_tabulation::AbacusRequest,
+    ) -> Result<Arc<PreparedTabulation>, MdError> {
+        let ctx = self.contexts.context_for(
+            &request.product,
+            request.data_root.clone(),
+            &request.sample_names(),
+        )?;
+        self.prepare_input_in(&ctx, request)
+    }
+
+    /// Like [prepare_input](Self::prepare_input), with a context from the engine's
+    /// [ContextCache] which already has metadata for the request's samples.
+    pub fn prepare_input_in(
+        &self,
+        ctx: &Context,
+        request: input_schema_tabulation::AbacusRequest,
+    ) -> Result<Arc<PreparedTabulation>, MdError> {
+        // A request whose key can't be computed is compiled without being kept.
+        let key = CacheKey::new(ctx, &request, Value::Null)
+            .ok()
+            .map(|key| key.hash().to_string());
+        if let Some(ref key) = key {
+            if let Some(prepared) = lock(&self.prepared, "compiled request")?.prepared.get(key) {
+                return Ok(Arc::clone(prepared));
+            }
+        }
+
+        let request = AbacusRequest::try_from_input_request(ctx, request)?;
+        let prepared = Arc::new(self.prepare(ctx, request)?);
+        if let Some(key) = key {
+            lock(&self.prepared, "compiled request")?.insert(key, Arc::clone(&prepared));
+        }
+        Ok(prepared)
+    }
+
+    /// Parse a JSON Abacus request and prepare it with [prepare_input](Self::prepare_input).
+    pub fn prepare_json(&self, input: &str) -> Result<Arc<PreparedTabulation>, MdError> {
+        self.prepare_input(AbacusRequest::parse_json(input)?)
+    }
+
+    /// Compute a tabulation for a request resolved against a context.
+    pub fn tabulate<R>(&self, ctx: &Context, rq: R) -> Result<Tabulation, MdError>
+    where
+        R: DataRequest,
+    {
+        self.prepare(ctx, rq)?.run()
+    }
+
+    /// Compute the tabulation described by a JSON Abacus request.
+    pub fn tabulate_json(&self, input: &str) -> Result<Tabulation, MdError> {
+        self.prepare_json(input)?.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tabulate::{self, TableFormat};
+    use std::path::Path;
+
+    fn json(tab: &Tabulation) -> String {
+        tab.output(TableFormat::Json).unwrap()
+    }
+
+    #[test]
+    fn test_matches_tabulate() {
+        let engine = TabulationEngine::new(None);
+        for path in [
+            "tests/requests/race_hispan_subpop_statefip.json",
+            "tests/requests/sex_attached_spouse.json",
+            "tests/requests/incwage_category_bins_us1940a.json",
+        ] {
+            let input = std::fs::read_to_string(path).unwrap();
+            let (ctx, request) = AbacusRequest::try_from_json(&input).unwrap();
+            let expected = tabulate::tabulate(&ctx, request).unwrap();
+            let tab = engine.tabulate_json(&input).unwrap();
+            assert_eq!(json(&tab), json(&expected), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_runs_datasets_concurrently() {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .unwrap();
+        ctx.load_metadata_for_datasets(&["us1900m", "us1940a"])
+            .unwrap();
+        let request = || {
+            AbacusRequest::builder(&ctx)
+                .sample("us1900m")
+                .sample("us1940a")
+                .var("MARST")
+                .var("SEX")
+                .include_empty_cells(true)
+                .build()
+                .unwrap()
+        };
+        let expected = tabulate::tabulate(&ctx, request()).unwrap();
+        let engine = TabulationEngine::new(None);
+        let tab = engine.tabulate(&ctx, request()).unwrap();
+        assert_eq!(tab.0.len(), 2);
+        assert_eq!(json(&tab), json(&expected));
+    }
+
+    #[test]
+    fn test_reuses_compiled_requests_and_views() {
+        let engine = TabulationEngine::new(None);
+        let input =
+            std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json").unwrap();
+        let first = engine.prepare_json(&input).unwrap();
+        let second = engine.prepare_json(&input).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(first.queries()[0].sql.contains("us1900m_usa_person"));
+        assert!(!first.queries()[0].sql.contains(".parquet"));
+        assert_eq!(json(&first.run().unwrap()), json(&second.run().unwrap()));
+        assert_eq!(engine.warehouses.lock().unwrap().warehouses.len(), 1);
+        assert_eq!(first.warehouse.idle.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let engine = TabulationEngine::new(None);
+        let input =
+            std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json").unwrap();
+        let expected = json(&engine.tabulate_json(&input).unwrap());
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| json(&engine.tabulate_json(&input).unwrap())))
+                .collect();
+            for handle in handles {
+                assert_eq!(handle.join().unwrap(), expected);
+            }
+        });
+    }
+
+    #[test]
+    fn test_missing_data() {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .unwrap();
+        ctx.load_metadata_for_datasets(&["us1900m"]).unwrap();
+        let request = AbacusRequest::builder(&ctx)
+            .sample("us1900m")
+            .var("MARST")
+            .build()
+            .unwrap();
+        let engine = TabulationEngine::new(None);
+        assert!(engine.tabulate(&ctx, request).is_ok());
+
+        let mut ctx = ctx.clone();
+        ctx.data_root = Some(PathBuf::from("tests/no_such_data_root"));
+        let request = AbacusRequest::builder(&ctx)
+            .sample("us1900m")
+            .var("MARST")
+            .build()
+            .unwrap();
+        assert!(engine.tabulate(&ctx, request).is_err());
+    }
+
+    #[test]
+    fn test_drops_least_recently_used_warehouse() {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .unwrap();
+        ctx.load_metadata_for_datasets(&["us1900m"]).unwrap();
+        let engine = TabulationEngine::new(None);
+        let first = engine.warehouse_for(&ctx).unwrap();
+        let input =
+            std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json").unwrap();
+        engine.prepare_json(&input).unwrap();
+        let add_warehouse = |i: usize| {
+            let mut other = ctx.clone();
+            other.data_root = Some(PathBuf::from(format!("tests/data_root/{i}")));
+            engine.warehouse_for(&other).unwrap();
+        };
+        let has_first = || {
+            engine
+                .warehouses
+                .lock()
+                .unwrap()
+                .warehouses
+                .values()
+                .any(|(warehouse, _)| Arc::ptr_eq(warehouse, &first))
+        };
+
+        for i in 0..MAX_WAREHOUSES - 1 {
+            add_warehouse(i);
+        }
+        // Using the first warehouse again keeps it when the next is added.
+        assert!(Arc::ptr_eq(&first, &engine.warehouse_for(&ctx).unwrap()));
+        add_warehouse(MAX_WAREHOUSES);
+        assert!(has_first());
+        assert_eq!(engine.prepared.lock().unwrap().prepared.len(), 1);
+
+        for i in 0..MAX_WAREHOUSES {
+            add_warehouse(MAX_WAREHOUSES + 1 + i);
+        }
+        assert!(!has_first());
+        assert_eq!(
+            engine.warehouses.lock().unwrap().warehouses.len(),
+            MAX_WAREHOUSES
+        );
+        assert!(engine.prepared.lock().unwrap().prepared.is_empty());
+    }
+
+    /// A data root with the us1900m layout and its data for the given record types.
+    fn data_root_with(record_types: &[&str]) -> tempfile::TempDir {
+        let data_root = tempfile::tempdir().unwrap();
+        let layouts = data_root.path().join("layouts");
+        std::fs::create_dir_all(&layouts).unwrap();
+        std::fs::copy(
+            "tests/data_root/layouts/us1900m.layout.txt",
+            layouts.join("us1900m.layout.txt"),
+        )
+        .unwrap();
+        copy_data(&data_root, record_types);
+        data_root
+    }
+
+    fn copy_data(data_root: &tempfile::TempDir, record_types: &[&str]) {
+        let dataset = data_root.path().join("parquet").join("us1900m");
+        std::fs::create_dir_all(&dataset).unwrap();
+        for rt in record_types {
+            let name = format!("us1900m_usa.{rt}.parquet");
+            std::fs::copy(
+                Path::new("tests/data_root/parquet/us1900m").join(&name),
+                dataset.join(&name),
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_creates_views_once_data_is_there() {
+        let data_root = data_root_with(&["H"]);
+        let mut ctx = Context::from_ipums_collection_name(
+            "usa",
+            None,
+            Some(data_root.path().display().to_string()),
+        )
+        .unwrap();
+        ctx.load_metadata_for_datasets(&["us1900m"]).unwrap();
+        let engine = TabulationEngine::new(None);
+        let warehouse = engine.warehouse_for(&ctx).unwrap();
+        warehouse.register_dataset(&ctx, "us1900m").unwrap();
+        assert_eq!(warehouse.views.lock().unwrap().len(), 1);
+
+        copy_data(&data_root, &["P"]);
+        warehouse.register_dataset(&ctx, "us1900m").unwrap();
+        assert_eq!(warehouse.views.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_recompiles_when_data_changes() {
+        let data_root = data_root_with(&["H", "P"]);
+        let engine = TabulationEngine::new(Some(data_root.path().display().to_string()));
+        let mut request: serde_json::Value = serde_json::from_str(include_str!(
+            "../tests/requests/race_hispan_subpop_statefip.json"
+        ))
+        .unwrap();
+        request.as_object_mut().unwrap().remove("data_root");
+        let input = request.to_string();
+        let first = engine.prepare_json(&input).unwrap();
+        assert!(Arc::ptr_eq(&first, &engine.prepare_json(&input).unwrap()));
+
+        // Re-release the household records with a new version.
+        let dataset = data_root.path().join("parquet").join("us1900m");
+        let households = dataset.join("us1900m_usa.H.parquet");
+        let released = dataset.join("released.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "copy (select * from '{}') to '{}' (format parquet, kv_metadata {{dcp_release_number: '90.0.0'}})",
+            households.display(),
+            released.display()
+        ))
+        .unwrap();
+        std::fs::rename(&released, &households).unwrap();
+        assert!(!Arc::ptr_eq(&first, &engine.prepare_json(&input).unwrap()));
+    }
+}
@@ -1,24 +1,376 @@
 //! Models and parsing logic for incoming JSON tabulation requests.
 
 use std::collections::BTreeMap;
+use std::str::FromStr;
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::mderror::{parsing_error, MdError};
 
-#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+/// A request for a tabulation of some variables over some samples.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 pub struct AbacusRequest {
+    /// The name of the product, like usa or ipumsi.
     pub product: String,
+    /// The path to the data root, which contains layouts and parquet data. Defaults to the
+    /// product's data root.
     pub data_root: Option<String>,
+    /// The record type to count, like P for persons or H for households.
     pub uoa: String,
     pub output_format: String,
+    /// Variables which select the cases to count.
     pub subpopulation: Vec<RequestVariable>,
+    /// Category bins for request variables, by variable mnemonic.
     pub category_bins: BTreeMap<String, Vec<CategoryBin>>,
+    /// Category bins to generate from the data, by variable mnemonic, for variables which
+    /// don't have bins in `category_bins`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub generated_bins: BTreeMap<String, BinGeneration>,
+    /// Recodes which collapse a categorical variable's codes into groups, by variable
+    /// mnemonic. A recode applies wherever the variable appears, in the request variables and
+    /// in the subpopulation.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub recodes: BTreeMap<String, Vec<RecodeGroup>>,
+    /// Variables computed from expressions over other variables. Request and subpopulation
+    /// variables can use them by name like any other variable.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub derived_variables: Vec<DerivedVariable>,
+    /// Variables which summarize child records, like the number of children in a household,
+    /// for use on their parent records.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rollups: Vec<Rollup>,
     pub request_samples: Vec<RequestSample>,
     pub request_variables: Vec<RequestVariable>,
+    /// Adjust monetary variables to constant dollars.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monetary_standardization: Option<MonetaryStandardization>,
+    /// What to do with request variables' missing and not in universe codes.
+    #[serde(default)]
+    pub missing_code_handling: MissingCodeHandling,
+    /// Missing and not in universe codes by variable mnemonic, in addition to those the
+    /// metadata marks. Layout files don't have category metadata, so this is the only way to
+    /// give the codes when tabulating with layouts.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub missing_codes: BTreeMap<String, Vec<i64>>,
+    /// Report every combination of the request variables' known categories, with counts of
+    /// zero for combinations without any cases, so that the tables for different samples
+    /// have the same rows.
+    #[serde(default)]
+    pub include_empty_cells: bool,
+    /// The weight to apply to the counts.
+    #[serde(default)]
+    pub weight: WeightSelection,
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+/// The weight applied to the counts of a tabulation.
+///
+/// In JSON this is `"automatic"`, `"unweighted"` or `{"variable": "HHWT"}`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightSelection {
+    /// The unit of analysis record type's weight. When a dataset has a sample line and the
+    /// request uses sample line variables, the sample line weight.
+    #[default]
+    Automatic,
+    /// Count records without weights. In datasets with a sample line, counts of sample line
+    /// variables are restricted to the flat sample line subsample, where SELFWTSL is 2.
+    Unweighted,
+    /// The named weight variable.
+    Variable(String),
+}
+
+/// What to do with the codes of request variables which mean the value is missing or the
+/// person is not in the variable's universe, like 9999999 for FTOTINC.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingCodeHandling {
+    /// Tabulate the codes like any other value.
+    #[default]
+    Include,
+    /// Drop records with the codes from the tabulation.
+    Exclude,
+    /// Report the codes in rows of their own, outside of any category bins.
+    Separate,
+}
+
+/// Adjust monetary variables to the dollars of a base year.
+///
+/// Variables which metadata marks as monetary are always adjusted. Layout files don't mark
+/// variables as monetary, so `variables` can name more variables to adjust, like INCWAGE.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub struct MonetaryStandardization {
+    pub base_year: usize,
+    /// A CSV file with `year` and `cpi` columns to use instead of the built-in CPI-U table.
+    /// The tabulation service only reads CPI files in its CPI directory, given relative to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpi_file: Option<String>,
+    #[serde(default)]
+    pub variables: Vec<String>,
+}
+
+impl AbacusRequest {
+    /// A request for a product and unit of analysis with no samples or variables yet.
+    pub fn new(product: &str, uoa: &str) -> Self {
+        Self {
+            product: product.to_string(),
+            data_root: None,
+            uoa: uoa.to_string(),
+            output_format: "json".to_string(),
+            subpopulation: Vec::new(),
+            category_bins: BTreeMap::new(),
+            generated_bins: BTreeMap::new(),
+            recodes: BTreeMap::new(),
+            derived_variables: Vec::new(),
+            rollups: Vec::new(),
+            request_samples: Vec::new(),
+            request_variables: Vec::new(),
+            monetary_standardization: None,
+            missing_code_handling: MissingCodeHandling::default(),
+            missing_codes: BTreeMap::new(),
+            include_empty_cells: false,
+            weight: WeightSelection::default(),
+        }
+    }
+
+    /// The names of the requested samples, in request order.
+    pub fn sample_names(&self) -> Vec<&str> {
+        self.request_samples
+            .iter()
+            .map(|rs| rs.name.as_str())
+            .collect()
+    }
+}
+
+/// A way to generate category bins for a continuous variable from the distribution of its
+/// values in the requested samples.
+///
+/// In JSON, the method is named by the `method` field, like
+/// `{"method": "quantiles", "bins": 5}`. On the command line, it's written like `quantiles:5`.
+/// A method may ask for at most 1000 bins, and no more than the variable has distinct values.
+///
+/// ```
+/// use cimdea::input_schema_tabulation::BinGeneration;
+///
+/// let method: BinGeneration = "nice_numbers:4".parse().unwrap();
+/// assert_eq!(method, BinGeneration::NiceNumbers { bins: 4 });
+/// ```
+#[derive(Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum BinGeneration {
+    /// Bins of equal width covering the range of values.
+    EqualWidth { bins: usize },
+    /// Bins with roughly equal weighted counts.
+    Quantiles { bins: usize },
+    /// About this many bins with round boundaries, like multiples of 5,000.
+    NiceNumbers { bins: usize },
+}
+
+impl BinGeneration {
+    /// The requested number of bins.
+    pub fn bins(&self) -> usize {
+        match self {
+            Self::EqualWidth { bins } | Self::Quantiles { bins } | Self::NiceNumbers { bins } => {
+                *bins
+            }
+        }
+    }
+}
+
+impl FromStr for BinGeneration {
+    type Err = MdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((method, bins)) = s.split_once(':') else {
+            return Err(parsing_error!(
+                "expected a bin generation method and number of bins like 'quantiles:5', got '{s}'"
+            ));
+        };
+        let bins = bins
+            .parse::<usize>()
+            .map_err(|err| parsing_error!("invalid number of bins '{bins}': {err}"))?;
+        match method {
+            "equal_width" => Ok(Self::EqualWidth { bins }),
+            "quantiles" => Ok(Self::Quantiles { bins }),
+            "nice_numbers" => Ok(Self::NiceNumbers { bins }),
+            _ => Err(parsing_error!(
+                "unknown bin generation method '{method}'; expected equal_width, quantiles or nice_numbers"
+            )),
+        }
+    }
+}
+
+/// A variable defined by an expression, like `FTOTINC / FAMSIZE`. See [crate::derived] for
+/// what expressions can do.
+#[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub struct DerivedVariable {
+    /// The variable's name: letters, digits and underscores, starting with a letter or
+    /// underscore.
+    pub name: String,
+    pub expression: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// A variable on a parent record computed from its child records, like the number of people
+/// in a household under 18. `value` and `condition` are expressions over the child record's
+/// variables; see [crate::derived] for what they can do.
+///
+/// ```
+/// use cimdea::input_schema_tabulation::{Rollup, RollupFunction};
+///
+/// let json = r#"{"name": "NKIDS", "record_type": "P", "function": "count", "condition": "AGE < 18"}"#;
+/// let rollup: Rollup = serde_json::from_str(json).unwrap();
+/// assert_eq!(rollup.function, RollupFunction::Count);
+/// assert_eq!(rollup.parent_record_type, None);
+/// ```
+#[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub struct Rollup {
+    /// The roll-up's name: letters, digits and underscores, starting with a letter or
+    /// underscore.
+    pub name: String,
+    /// The child record type to summarize.
+    pub record_type: String,
+    /// The record type to attach the summary to. Defaults to the child's parent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_record_type: Option<String>,
+    pub function: RollupFunction,
+    /// The value to summarize. Required for sum, min, max and mean.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// Only summarize the child records meeting this condition. Required for any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// How a [Rollup] summarizes child records.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RollupFunction {
+    /// The number of child records.
+    Count,
+    /// The total of the value over the child records.
+    Sum,
+    Min,
+    Max,
+    /// The average value over the child records.
+    Mean,
+    /// 1 when any child record meets the condition, otherwise 0.
+    Any,
+}
+
+impl RollupFunction {
+    /// Whether the function summarizes a value rather than only counting records.
+    pub fn needs_value(&self) -> bool {
+        matches!(self, Self::Sum | Self::Min | Self::Max | Self::Mean)
+    }
+}
+
+/// A group of a categorical variable's codes which a recode collapses into one new code.
+///
+/// In JSON, `codes` can mix single codes and ranges of codes:
+///
+/// ```
+/// use cimdea::input_schema_tabulation::{RecodeCodes, RecodeGroup};
+///
+/// let json = r#"{"code": 3, "value_label": "Asian", "codes": [4, {"low": 5, "high": 6}]}"#;
+/// let group: RecodeGroup = serde_json::from_str(json).unwrap();
+/// assert_eq!(group.codes[1], RecodeCodes::Range { low: 5, high: 6 });
+/// assert!(group.contains(5));
+/// assert!(!group.contains(7));
+/// ```
+#[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub struct RecodeGroup {
+    pub code: u64,
+    pub value_label: String,
+    pub codes: Vec<RecodeCodes>,
+}
+
+impl RecodeGroup {
+    pub fn contains(&self, test_value: i64) -> bool {
+        self.codes.iter().any(|c| c.contains(test_value))
+    }
+
+    /// Check that each group has codes, ranges aren't backwards, and no code or new code
+    /// belongs to more than one group.
+    pub fn validate_recode(groups: &[RecodeGroup]) -> Result<(), MdError> {
+        if groups.is_empty() {
+            return Err(parsing_error!(
+                "recodes: a recode must have at least one group"
+            ));
+        }
+        for (index, group) in groups.iter().enumerate() {
+            if group.codes.is_empty() {
+                return Err(parsing_error!(
+                    "recodes: group {} '{}' has no codes",
+                    group.code,
+                    group.value_label
+                ));
+            }
+            for codes in &group.codes {
+                if let RecodeCodes::Range { low, high } = codes {
+                    if high < low {
+                        return Err(parsing_error!(
+                            "recodes: a low of {low} and high of {high} do not satisfy low <= high"
+                        ));
+                    }
+                }
+            }
+            for other in &groups[index + 1..] {
+                if other.code == group.code {
+                    return Err(parsing_error!(
+                        "recodes: more than one group has the code {}",
+                        group.code
+                    ));
+                }
+                let overlaps = group
+                    .codes
+                    .iter()
+                    .any(|c| other.codes.iter().any(|o| c.overlaps(o)));
+                if overlaps {
+                    return Err(parsing_error!(
+                        "recodes: groups {} and {} share some codes",
+                        group.code,
+                        other.code
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Codes of a [RecodeGroup], either a single code or an inclusive range of codes.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum RecodeCodes {
+    Code(i64),
+    Range { low: i64, high: i64 },
+}
+
+impl RecodeCodes {
+    fn bounds(&self) -> (i64, i64) {
+        match *self {
+            Self::Code(code) => (code, code),
+            Self::Range { low, high } => (low, high),
+        }
+    }
+
+    pub fn contains(&self, test_value: i64) -> bool {
+        let (low, high) = self.bounds();
+        test_value >= low && test_value <= high
+    }
+
+    fn overlaps(&self, other: &RecodeCodes) -> bool {
+        let (low, high) = self.bounds();
+        let (other_low, other_high) = other.bounds();
+        low <= other_high && other_low <= high
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 #[serde(try_from = "CategoryBinRaw", into = "CategoryBinRaw")]
 pub enum CategoryBin {
     LessThan {
@@ -46,10 +398,11 @@ impl TryFrom<CategoryBinRaw> for CategoryBin {
         let code = value.code;
         let label = &value.value_label;
         match (value.low, value.high) {
-            (Some(low), Some(high)) if high < low => Err(MdError::Msg(format!(
+            (Some(low), Some(high)) if high < low => Err(parsing_error!(
                 "category_bins: a low of {} and high of {} do not satisfy low <= high",
-                low, high
-            ))),
+                low,
+                high
+            )),
             (Some(low), Some(high)) => Ok(Self::Range {
                 low,
                 high,
@@ -66,8 +419,8 @@ impl TryFrom<CategoryBinRaw> for CategoryBin {
                 code,
                 label: label.to_owned(),
             }),
-            (None, None) => Err(MdError::Msg(
-                "category_bins: must have low, high, or both set to some value".to_string(),
+            (None, None) => Err(parsing_error!(
+                "category_bins: must have low, high, or both set to some value"
             )),
         }
     }
@@ -81,9 +434,77 @@ impl CategoryBin {
             Self::MoreThan { value, .. } => test_value > *value,
         }
     }
+
+    /// The code tabulations report for values in the bin.
+    pub fn code(&self) -> u64 {
+        match self {
+            Self::LessThan { code, .. }
+            | Self::Range { code, .. }
+            | Self::MoreThan { code, .. } => *code,
+        }
+    }
+
+    /// Parse a comma-separated list of bins like `0-9999,10000-49999,50000+`. A bin is a range
+    /// of values `LOW-HIGH`, a single value, or `LOW+` for values of at least `LOW`. Bins get
+    /// codes 1, 2, 3 and so on in order, and are labeled with their text.
+    ///
+    /// ```
+    /// use cimdea::input_schema_tabulation::CategoryBin;
+    ///
+    /// let bins = CategoryBin::parse_list("0-17,18-64,65+").unwrap();
+    /// assert_eq!(bins.len(), 3);
+    /// assert_eq!(bins[2], CategoryBin::MoreThan { value: 65, code: 3, label: "65+".to_string() });
+    /// ```
+    pub fn parse_list(list: &str) -> Result<Vec<Self>, MdError> {
+        list.split(',')
+            .map(str::trim)
+            .enumerate()
+            .map(|(index, bin)| {
+                let code = index as u64 + 1;
+                let label = bin.to_string();
+                let parse = |value: &str| {
+                    value
+                        .trim()
+                        .parse::<i64>()
+                        .map_err(|err| parsing_error!("invalid category bin '{bin}': {err}"))
+                };
+                if let Some(low) = bin.strip_suffix('+') {
+                    return Ok(Self::MoreThan {
+                        value: parse(low)?,
+                        code,
+                        label,
+                    });
+                }
+                // Skip the first character so that a negative low value isn't split.
+                let split = bin
+                    .char_indices()
+                    .skip(1)
+                    .find(|(_, c)| *c == '-')
+                    .map(|(i, _)| (&bin[..i], &bin[i + 1..]));
+                let (low, high) = match split {
+                    Some((low, high)) => (parse(low)?, parse(high)?),
+                    None => (parse(bin)?, parse(bin)?),
+                };
+                if high < low {
+                    return Err(parsing_error!(
+                        "category bin '{bin}' has a high value less than its low value"
+                    ));
+                }
+                Ok(Self::Range {
+                    low,
+                    high,
+                    code,
+                    label,
+                })
+            })
+            .collect()
+    }
 }
 
-#[derive(Deserialize, Serialize)]
+/// A category bin as it appears in JSON. At least one of `low` and `high` must be set, and
+/// both bounds are inclusive.
+#[derive(Deserialize, JsonSchema, Serialize)]
+#[schemars(rename = "CategoryBin")]
 struct CategoryBinRaw {
     code: u64,
     value_label: String,
@@ -121,27 +542,107 @@ impl From<CategoryBin> for CategoryBinRaw {
     }
 }
 
-#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 pub struct RequestVariable {
     pub variable_mnemonic: String,
     pub mnemonic: String,
     #[serde(deserialize_with = "general_detailed_selection_from_nullable_field")]
+    #[schemars(with = "Option<GeneralDetailedSelection>")]
     pub general_detailed_selection: GeneralDetailedSelection,
-    pub attached_variable_pointer: (),
+    /// Take the variable's value from a family member instead of from the person themself.
+    pub attached_variable_pointer: Option<AttachedVariablePointer>,
     pub case_selection: bool,
     pub request_case_selections: Vec<RequestCaseSelection>,
     pub extract_start: usize,
     pub extract_width: usize,
 }
 
-#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+impl RequestVariable {
+    /// A request for the detailed codes of a variable, without case selections.
+    pub fn new(mnemonic: &str) -> Self {
+        Self {
+            variable_mnemonic: mnemonic.to_string(),
+            mnemonic: mnemonic.to_string(),
+            general_detailed_selection: GeneralDetailedSelection::Detailed,
+            attached_variable_pointer: None,
+            case_selection: false,
+            request_case_selections: Vec::new(),
+            extract_start: 0,
+            extract_width: 0,
+        }
+    }
+}
+
+/// An IPUMS family pointer variable, which holds the person number of a family member in the
+/// same household.
+///
+/// Requests name the pointer variable, like "MOMLOC". A request variable with a pointer gets its
+/// value from the family member the pointer refers to, so for example EDUC with the MOMLOC
+/// pointer is the educational attainment of the person's mother, named EDUC_MOM.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, JsonSchema, PartialEq, Serialize)]
+pub enum AttachedVariablePointer {
+    #[serde(rename = "MOMLOC")]
+    Mother,
+    #[serde(rename = "POPLOC")]
+    Father,
+    #[serde(rename = "SPLOC")]
+    Spouse,
+    #[serde(rename = "MOMLOC2")]
+    SecondMother,
+    #[serde(rename = "POPLOC2")]
+    SecondFather,
+}
+
+impl AttachedVariablePointer {
+    /// The name of the pointer variable, like MOMLOC.
+    pub fn pointer_variable(&self) -> &'static str {
+        match self {
+            Self::Mother => "MOMLOC",
+            Self::Father => "POPLOC",
+            Self::Spouse => "SPLOC",
+            Self::SecondMother => "MOMLOC2",
+            Self::SecondFather => "POPLOC2",
+        }
+    }
+
+    /// The suffix IPUMS adds to the names of attached variables, like MOM in EDUC_MOM.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Self::Mother => "MOM",
+            Self::Father => "POP",
+            Self::Spouse => "SP",
+            Self::SecondMother => "MOM2",
+            Self::SecondFather => "POP2",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 pub struct RequestSample {
     pub name: String,
+    /// Tabulate a systematic subsample of households, like "1/10", "0.1" or "10" for one in ten.
     pub custom_sampling_ratio: Option<String>,
+    /// The household number where the subsample starts, counting households in serial order
+    /// starting at 1. This is a position, not a SERIAL value. Defaults to 1.
     pub first_household_sampled: Option<usize>,
+    /// Scale weighted counts up by the inverse of the sampling ratio.
+    #[serde(default)]
+    pub rescale_weights: bool,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+impl RequestSample {
+    /// A request for the whole sample.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            custom_sampling_ratio: None,
+            first_household_sampled: None,
+            rescale_weights: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 #[serde(try_from = "RequestCaseSelectionRaw", into = "RequestCaseSelectionRaw")]
 pub enum RequestCaseSelection {
     LessEqual(u64),
@@ -164,6 +665,42 @@ impl RequestCaseSelection {
         }
     }
 }
+
+/// Parse a range of codes like `25-64`, a single code like `48`, `65+` for codes of at least 65
+/// or `-17` for codes of at most 17.
+///
+/// ```
+/// use cimdea::input_schema_tabulation::RequestCaseSelection;
+///
+/// let selection: RequestCaseSelection = "25-64".parse().unwrap();
+/// assert_eq!(selection, RequestCaseSelection::Between(25, 64));
+/// ```
+impl FromStr for RequestCaseSelection {
+    type Err = MdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |code: &str| {
+            code.trim()
+                .parse::<u64>()
+                .map_err(|err| parsing_error!("invalid case selection '{s}': {err}"))
+        };
+        let s = s.trim();
+        if let Some(low_code) = s.strip_suffix('+') {
+            return Self::try_new(Some(parse(low_code)?), None);
+        }
+        match s.split_once('-') {
+            Some(("", high_code)) => Self::try_new(None, Some(parse(high_code)?)),
+            Some((low_code, high_code)) => {
+                Self::try_new(Some(parse(low_code)?), Some(parse(high_code)?))
+            }
+            None => {
+                let code = parse(s)?;
+                Self::try_new(Some(code), Some(code))
+            }
+        }
+    }
+}
+
 impl TryFrom<RequestCaseSelectionRaw> for RequestCaseSelection {
     type Error = MdError;
 
@@ -194,7 +731,10 @@ impl TryFrom<RequestCaseSelectionRaw> for RequestCaseSelection {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+/// A range of codes to select, as it appears in JSON. The codes are strings of digits, and at
+/// least one of them must be set.
+#[derive(Deserialize, JsonSchema, Serialize)]
+#[schemars(rename = "RequestCaseSelection")]
 struct RequestCaseSelectionRaw {
     low_code: Option<String>,
     high_code: Option<String>,
@@ -219,7 +759,7 @@ impl From<RequestCaseSelection> for RequestCaseSelectionRaw {
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 pub enum GeneralDetailedSelection {
     #[serde(rename = "G")]
     General,
@@ -281,6 +821,55 @@ mod tests {
         assert_eq!(deserialized1, deserialized2);
     }
 
+    #[test]
+    fn test_deserialize_attached_variable_pointer() {
+        let json_str = include_str!("../tests/requests/sex_attached_spouse.json");
+        let request: AbacusRequest =
+            serde_json::from_str(json_str).expect("should deserialize into an AbacusRequest");
+
+        assert_eq!(request.request_variables[0].attached_variable_pointer, None);
+        assert_eq!(
+            request.request_variables[1].attached_variable_pointer,
+            Some(AttachedVariablePointer::Spouse)
+        );
+    }
+
+    #[test]
+    fn test_validate_recode() {
+        let json = r#"[
+            {"code": 1, "value_label": "White", "codes": [1]},
+            {"code": 2, "value_label": "Other", "codes": [{"low": 2, "high": 9}]}
+        ]"#;
+        let mut groups: Vec<RecodeGroup> = serde_json::from_str(json).unwrap();
+        assert!(RecodeGroup::validate_recode(&groups).is_ok());
+
+        groups[1].codes.push(RecodeCodes::Code(1));
+        let result = RecodeGroup::validate_recode(&groups);
+        assert!(matches!(result, Err(MdError::ParsingError(_))));
+
+        groups[1].codes = vec![RecodeCodes::Range { low: 9, high: 2 }];
+        let result = RecodeGroup::validate_recode(&groups);
+        assert!(matches!(result, Err(MdError::ParsingError(_))));
+
+        groups[1].codes = Vec::new();
+        let result = RecodeGroup::validate_recode(&groups);
+        assert!(matches!(result, Err(MdError::ParsingError(_))));
+    }
+
+    #[test]
+    fn test_deserialize_bin_generation() {
+        let method: BinGeneration =
+            serde_json::from_str(r#"{"method": "equal_width", "bins": 3}"#).unwrap();
+        assert_eq!(method, BinGeneration::EqualWidth { bins: 3 });
+    }
+
+    #[test]
+    fn test_bin_generation_from_str_errors() {
+        assert!("quantiles".parse::<BinGeneration>().is_err());
+        assert!("quantiles:many".parse::<BinGeneration>().is_err());
+        assert!("deciles:10".parse::<BinGeneration>().is_err());
+    }
+
     #[test]
     fn test_category_bin_try_from_less_than() {
         let raw_bin = CategoryBinRaw {
@@ -419,6 +1008,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_request_case_selection_from_str() {
+        let parse = |s: &str| s.parse::<RequestCaseSelection>();
+        assert_eq!(parse("48").unwrap(), RequestCaseSelection::Between(48, 48));
+        assert_eq!(
+            parse("65+").unwrap(),
+            RequestCaseSelection::GreaterEqual(65)
+        );
+        assert_eq!(parse("-17").unwrap(), RequestCaseSelection::LessEqual(17));
+        assert!(parse("64-25").is_err());
+        assert!(parse("old").is_err());
+    }
+
+    #[test]
+    fn test_category_bin_parse_list() {
+        let bins = CategoryBin::parse_list("-10--1, 0, 1-9999").unwrap();
+        assert_eq!(
+            bins,
+            vec![
+                CategoryBin::Range {
+                    low: -10,
+                    high: -1,
+                    code: 1,
+                    label: "-10--1".to_string()
+                },
+                CategoryBin::Range {
+                    low: 0,
+                    high: 0,
+                    code: 2,
+                    label: "0".to_string()
+                },
+                CategoryBin::Range {
+                    low: 1,
+                    high: 9999,
+                    code: 3,
+                    label: "1-9999".to_string()
+                },
+            ]
+        );
+        assert!(CategoryBin::parse_list("100-1").is_err());
+        assert!(CategoryBin::parse_list("0-9,,10+").is_err());
+    }
+
     #[test]
     fn test_deserialize_general_detailed_selection_g() {
         let gen_det: GeneralDetailedSelection = serde_json::from_str("\"G\"")
@@ -64,6 +64,10 @@ pub struct IpumsDataset {
     pub month: Option<usize>,
     pub label: Option<String>,
     pub sampling_density: Option<f64>,
+    /// Some of the dataset's questions were asked only of a sample of people, the "sample
+    /// line", and tabulations of them need the sample line weight. `None` when the metadata
+    /// doesn't say, as with layout files.
+    pub sample_line: Option<bool>,
     /// The 'id' fields in the models are generated when metadata structs get instantiated in order. They are
     /// used for indexing into the metadata storage.
     pub id: IpumsDatasetId, // auto-assigned in order loaded
@@ -78,6 +82,7 @@ impl From<(String, usize)> for IpumsDataset {
             month: None,
             label: None,
             sampling_density: None,
+            sample_line: None,
         }
     }
 }
@@ -94,9 +99,93 @@ pub struct IpumsVariable {
     pub general_width: Option<usize>,
     pub description: Option<ComprString>,
     pub category_bins: Option<Vec<CategoryBin>>,
+    /// Access restrictions on the variable. `None` when the metadata doesn't record them, as
+    /// with layout files.
+    pub restrictions: Option<VariableRestrictions>,
+    /// The variable holds dollar amounts, which can be adjusted for inflation. Layout files
+    /// don't say which variables are monetary.
+    pub monetary: bool,
+    /// The variable comes from a sample line question in datasets which have a sample line.
+    /// `None` when the metadata doesn't say, as with layout files.
+    pub sample_line: Option<bool>,
     pub id: IpumsVariableId, // auto-assigned in load order
 }
 
+/// Access restrictions on a variable. These come from the IPUMS metadata; layout files don't
+/// carry them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VariableRestrictions {
+    /// The variable is only available to users with restricted access.
+    pub is_restricted: bool,
+    /// Restrictions apply to some of the variable's values or to some datasets.
+    pub restrictions_apply: bool,
+    /// A non-zero hide status keeps the variable out of public data products.
+    pub hide_status: i32,
+}
+
+impl VariableRestrictions {
+    /// Whether any of the restrictions apply, so that the variable shouldn't appear in public
+    /// output.
+    ///
+    /// ```
+    /// use cimdea::ipums_metadata_model::VariableRestrictions;
+    ///
+    /// assert!(!VariableRestrictions::default().any());
+    /// let hidden = VariableRestrictions {
+    ///     hide_status: 1,
+    ///     ..VariableRestrictions::default()
+    /// };
+    /// assert!(hidden.any());
+    /// ```
+    pub fn any(&self) -> bool {
+        self.is_restricted || self.restrictions_apply || self.hide_status != 0
+    }
+
+    /// The restrictions of a value computed from variables with these and the other
+    /// restrictions.
+    pub fn union(&self, other: &VariableRestrictions) -> Self {
+        Self {
+            is_restricted: self.is_restricted || other.is_restricted,
+            restrictions_apply: self.restrictions_apply || other.restrictions_apply,
+            hide_status: self.hide_status.max(other.hide_status),
+        }
+    }
+}
+
+impl IpumsVariable {
+    /// Whether any of the variables come from sample line questions: `Some(true)` if one of
+    /// them does, `None` if it's unknown for some of them, and otherwise `Some(false)`.
+    pub fn any_sample_line<'a>(variables: impl IntoIterator<Item = &'a Self>) -> Option<bool> {
+        let mut known = true;
+        for v in variables {
+            match v.sample_line {
+                Some(true) => return Some(true),
+                Some(false) => (),
+                None => known = false,
+            }
+        }
+        known.then_some(false)
+    }
+
+    /// The integer codes of the variable's missing and not in universe categories. Variables
+    /// without category metadata have none.
+    pub fn missing_codes(&self) -> Vec<i64> {
+        let Some(ref categories) = self.categories else {
+            return Vec::new();
+        };
+        let mut codes: Vec<i64> = categories
+            .iter()
+            .filter(|c| c.meaning.is_missing())
+            .filter_map(|c| match c.value {
+                IpumsValue::Integer(code) => Some(code),
+                _ => None,
+            })
+            .collect();
+        codes.sort();
+        codes
+    }
+}
+
 impl From<(&LayoutVar, usize)> for IpumsVariable {
     fn from(value: (&LayoutVar, usize)) -> Self {
         Self {
@@ -110,6 +199,9 @@ impl From<(&LayoutVar, usize)> for IpumsVariable {
             formatting: Some((value.0.start, value.0.width)),
             general_width: None,
             description: None,
+            restrictions: None,
+            monetary: false,
+            sample_line: None,
         }
     }
 }
@@ -187,6 +279,17 @@ pub enum UniversalCategoryType {
     Value,
 }
 
+impl UniversalCategoryType {
+    /// Whether the category stands for a missing value or a person outside the variable's
+    /// universe rather than for a real value. Top and bottom codes are real values.
+    pub fn is_missing(&self) -> bool {
+        matches!(
+            self,
+            Self::NotInUniverse | Self::Missing | Self::NotApplicable
+        )
+    }
+}
+
 type IpumsCategoryId = usize;
 
 #[allow(dead_code)]
@@ -251,6 +354,39 @@ mod test {
         assert_eq!("second", cat3.label());
     }
 
+    #[test]
+    fn test_missing_codes() {
+        let layout_var = LayoutVar {
+            name: "INCWAGE".to_string(),
+            rectype: "P".to_string(),
+            start: 152,
+            width: 6,
+            col: 0,
+            data_type: IpumsDataType::Integer,
+        };
+        let mut ipums_var = IpumsVariable::from((&layout_var, 1));
+        assert!(ipums_var.missing_codes().is_empty());
+
+        ipums_var.categories = Some(vec![
+            IpumsCategory::new(
+                "N/A",
+                UniversalCategoryType::NotApplicable,
+                IpumsValue::Integer(999999),
+            ),
+            IpumsCategory::new(
+                "Missing",
+                UniversalCategoryType::Missing,
+                IpumsValue::Integer(999998),
+            ),
+            IpumsCategory::new(
+                "5001+",
+                UniversalCategoryType::TopCode,
+                IpumsValue::Integer(5001),
+            ),
+        ]);
+        assert_eq!(ipums_var.missing_codes(), [999998, 999999]);
+    }
+
     /// If IpumsDataType::from() doesn't recognize the input string, it defaults
     /// to the type Integer.
     #[test]
@@ -41,7 +41,7 @@ impl RecordLayout {
 
     pub fn sorted_vars_by_start(&self) -> Vec<LayoutVar> {
         let mut ordered_vars = self.vars.clone();
-        ordered_vars.sort_by(|a, b| a.start.cmp(&b.start));
+        ordered_vars.sort_by_key(|v| v.start);
         ordered_vars
     }
 
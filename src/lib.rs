@@ -40,22 +40,36 @@
 //! variables, subpopulations, or category bins, please see
 //! [AbacusRequest](request::AbacusRequest), which also implements `DataRequest`.
 
+pub mod arrow_output;
+pub mod batch;
+pub mod bin_generation;
+pub mod catalog;
 pub mod conventions;
 pub mod data_version;
 pub mod defaults;
 pub mod deployment;
+pub mod derived;
+pub mod disclosure;
+pub mod engine;
 pub mod fixed_width;
 pub mod input_schema_tabulation;
 pub mod ipums_data_model;
 pub mod ipums_metadata_model;
 pub mod layout;
 pub mod mderror;
+pub mod monetary;
 pub mod parquet_metadata;
 pub mod query_gen;
 pub mod remote;
 pub mod request;
+pub mod request_builder;
+pub mod result_cache;
+pub mod rollup;
+pub mod schema;
 pub mod server_status;
+pub mod service;
 pub mod tabulate;
+pub mod validation;
 
 // TODO: I have an idea for how to use this interner library.
 //use interner::global::{GlobalPool, GlobalString};
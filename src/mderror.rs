@@ -2,6 +2,9 @@
 
 use std::fmt;
 
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
 /// The cimdea error type.
 ///
 /// As a user, the most common thing to do with these errors is to convert them to strings and
@@ -9,6 +12,10 @@ use std::fmt;
 /// separately, but the variants in this struct are not yet stable. Several more variants may be
 /// added in the future, and the existing variants may change or be consolidated.
 ///
+/// Programs which need to react to particular errors should use [MdError::code],
+/// [MdError::entity] and [MdError::field] instead, or the JSON the error serializes to, which
+/// has the message, the code and, when they're known, the entity and the request field.
+///
 /// ```
 /// use cimdea::mderror::MdError;
 ///
@@ -29,6 +36,130 @@ pub enum MdError {
     DuckDBError(duckdb::Error),
     /// A generic cimdea error.
     Msg(String),
+    /// A variable, dataset or record type which isn't in the loaded metadata.
+    NotFound(Entity),
+    /// A variable which the disclosure rules refuse, because it's restricted or has no
+    /// restriction metadata to check it with. `reason` finishes a sentence about the entity.
+    Restricted {
+        entity: Entity,
+        reason: String,
+    },
+    /// An error caused by one part of a request. `field` is the path to that part, like
+    /// `request_variables[1]` or `category_bins.AGE`.
+    InRequest {
+        field: String,
+        error: Box<MdError>,
+    },
+}
+
+/// A stable code for each kind of error, for programs to match on instead of messages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Io,
+    Metadata,
+    SqlSyntax,
+    Parsing,
+    DataPlatform,
+    UnknownVariable,
+    UnknownDataset,
+    UnknownRecordType,
+    Restricted,
+    Other,
+}
+
+/// The thing in the metadata which an error is about.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "name", rename_all = "snake_case")]
+pub enum Entity {
+    Variable(String),
+    Dataset(String),
+    RecordType(String),
+}
+
+impl fmt::Display for Entity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Variable(name) => write!(f, "variable {name}"),
+            Self::Dataset(name) => write!(f, "dataset {name}"),
+            Self::RecordType(name) => write!(f, "record type '{name}'"),
+        }
+    }
+}
+
+impl MdError {
+    /// Wrap the error with the path to the part of a request which caused it.
+    ///
+    /// ```
+    /// use cimdea::mderror::{Entity, ErrorCode, MdError};
+    ///
+    /// let err = MdError::NotFound(Entity::Variable("AEG".to_string()))
+    ///     .in_request("request_variables[0]");
+    /// assert_eq!(err.code(), ErrorCode::UnknownVariable);
+    /// assert_eq!(err.field(), Some("request_variables[0]"));
+    /// assert_eq!(
+    ///     err.to_string(),
+    ///     "request_variables[0]: no variable AEG in the loaded metadata"
+    /// );
+    /// ```
+    ///
+    /// Wrapping an error which already has a field joins the paths, so the field is the whole
+    /// path from the top of the request, like `rollups[0].value`.
+    pub fn in_request(self, field: impl Into<String>) -> Self {
+        let field = field.into();
+        match self {
+            Self::InRequest {
+                field: inner,
+                error,
+            } => {
+                let separator = if inner.starts_with('[') { "" } else { "." };
+                Self::InRequest {
+                    field: format!("{field}{separator}{inner}"),
+                    error,
+                }
+            }
+            error => Self::InRequest {
+                field,
+                error: Box::new(error),
+            },
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        use MdError::*;
+
+        match self {
+            IoError(_) => ErrorCode::Io,
+            MetadataError(_) => ErrorCode::Metadata,
+            InvalidSQLSyntax(_) => ErrorCode::SqlSyntax,
+            ParsingError(_) => ErrorCode::Parsing,
+            DuckDBError(_) => ErrorCode::DataPlatform,
+            Msg(_) => ErrorCode::Other,
+            NotFound(Entity::Variable(_)) => ErrorCode::UnknownVariable,
+            NotFound(Entity::Dataset(_)) => ErrorCode::UnknownDataset,
+            NotFound(Entity::RecordType(_)) => ErrorCode::UnknownRecordType,
+            Restricted { .. } => ErrorCode::Restricted,
+            InRequest { error, .. } => error.code(),
+        }
+    }
+
+    /// The variable, dataset or record type the error is about, if it's known.
+    pub fn entity(&self) -> Option<&Entity> {
+        match self {
+            Self::NotFound(entity) => Some(entity),
+            Self::Restricted { entity, .. } => Some(entity),
+            Self::InRequest { error, .. } => error.entity(),
+            _ => None,
+        }
+    }
+
+    /// The path to the part of the request which caused the error, if it's known.
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            Self::InRequest { field, .. } => Some(field),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for MdError {
@@ -42,11 +173,42 @@ impl fmt::Display for MdError {
             ParsingError(msg) => write!(f, "parsing error: {msg}"),
             DuckDBError(err) => write!(f, "DuckDB error: {err}"),
             Msg(msg) => write!(f, "{msg}"),
+            NotFound(entity) => write!(f, "no {entity} in the loaded metadata"),
+            Restricted { entity, reason } => write!(f, "{entity} {reason}"),
+            InRequest { field, error } => write!(f, "{field}: {error}"),
+        }
+    }
+}
+
+/// Errors serialize as an object with the message under `error`, along with `code` and, when
+/// they're known, `entity` and `field`.
+impl Serialize for MdError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("MdError", 4)?;
+        state.serialize_field("error", &self.to_string())?;
+        state.serialize_field("code", &self.code())?;
+        match self.entity() {
+            Some(entity) => state.serialize_field("entity", entity)?,
+            None => state.skip_field("entity")?,
         }
+        match self.field() {
+            Some(field) => state.serialize_field("field", field)?,
+            None => state.skip_field("field")?,
+        }
+        state.end()
     }
 }
 
-impl std::error::Error for MdError {}
+impl std::error::Error for MdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(err) => Some(err),
+            Self::DuckDBError(err) => Some(err),
+            Self::InRequest { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for MdError {
     fn from(err: std::io::Error) -> Self {
@@ -83,6 +245,8 @@ pub(crate) use metadata_error;
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_parse_error_macro() {
         let variable = "AGE";
@@ -98,6 +262,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_serialize_to_json() {
+        let err = MdError::NotFound(Entity::Dataset("us1776a".to_string()))
+            .in_request("request_samples[1]");
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            serde_json::json!({
+                "error": "request_samples[1]: no dataset us1776a in the loaded metadata",
+                "code": "unknown_dataset",
+                "entity": {"kind": "dataset", "name": "us1776a"},
+                "field": "request_samples[1]",
+            })
+        );
+
+        let err = parsing_error!("no 'product' in request");
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            serde_json::json!({
+                "error": "parsing error: no 'product' in request",
+                "code": "parsing",
+            })
+        );
+    }
+
     #[test]
     fn test_metadata_error_macro() {
         let variable = "AGE";
@@ -113,4 +301,19 @@ mod tests {
 
         assert_eq!(err.to_string(), "metadata error: invalid widths for variable AGE: general width is 4 but detailed width is 3");
     }
+
+    #[test]
+    fn test_nested_fields_and_source() {
+        use std::error::Error;
+
+        let err = MdError::NotFound(Entity::Variable("AEG".to_string()))
+            .in_request("expression")
+            .in_request("derived_variables[2]");
+        assert_eq!(err.field(), Some("derived_variables[2].expression"));
+        assert_eq!(err.code(), ErrorCode::UnknownVariable);
+        assert_eq!(
+            err.source().map(|source| source.to_string()),
+            Some("no variable AEG in the loaded metadata".to_string())
+        );
+    }
 }
@@ -0,0 +1,237 @@
+//! Monetary standardization to constant dollars.
+//!
+//! Incomes from different census years aren't comparable until they're adjusted for inflation.
+//! [MonetaryStandardization] multiplies monetary variables by the ratio of the consumer price
+//! index in a chosen base year to the index in the year the income was earned. The adjusted
+//! values are what gets tabulated and sorted into category bins.
+//!
+//! The price index comes from a [CpiTable]. The crate ships with the BLS CPI-U annual averages
+//! (1982-84 = 100) for 1913 on; you can supply your own table as a CSV file with `year` and
+//! `cpi` columns.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+use rust_decimal::prelude::*;
+
+use crate::ipums_metadata_model::IpumsDataset;
+use crate::mderror::{metadata_error, parsing_error, MdError};
+
+const BUILTIN_CPI_U: &str = include_str!("cpi_u.csv");
+
+/// Adjustment factors are rounded to this many decimal places before they go into queries.
+const FACTOR_DECIMAL_PLACES: u32 = 6;
+
+/// A consumer price index by year.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpiTable {
+    /// Where the table came from, for documenting the adjustment.
+    pub source: String,
+    index: BTreeMap<usize, Decimal>,
+}
+
+impl CpiTable {
+    /// The BLS CPI-U annual averages which ship with the crate.
+    ///
+    /// ```
+    /// use cimdea::monetary::CpiTable;
+    ///
+    /// let cpi = CpiTable::builtin();
+    /// assert_eq!(cpi.get(1940).unwrap().to_string(), "14.0");
+    /// ```
+    pub fn builtin() -> Self {
+        Self::try_from_csv_reader("built-in CPI-U annual averages", BUILTIN_CPI_U.as_bytes())
+            .expect("the built-in CPI table should always parse")
+    }
+
+    /// Read a table from a CSV file with `year` and `cpi` columns.
+    pub fn try_from_csv_file(path: &Path) -> Result<Self, MdError> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| metadata_error!("Cannot open CPI table '{}': {err}", path.display()))?;
+        Self::try_from_csv_reader(&path.display().to_string(), file)
+    }
+
+    /// Errors give the row of a bad value but not the value itself, so that they don't show
+    /// what's in files which aren't CPI tables.
+    fn try_from_csv_reader<R: Read>(source: &str, reader: R) -> Result<Self, MdError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut index = BTreeMap::new();
+        for (line, record) in rdr.records().enumerate() {
+            let record = record
+                .map_err(|err| parsing_error!("CPI table {source}, row {}: {err}", line + 1))?;
+            let (Some(year), Some(cpi)) = (record.get(0), record.get(1)) else {
+                return Err(parsing_error!(
+                    "CPI table {source}, row {}: expected a year and a CPI value",
+                    line + 1
+                ));
+            };
+            let year = year.parse::<usize>().map_err(|err| {
+                parsing_error!("CPI table {source}, row {}: bad year: {err}", line + 1)
+            })?;
+            let cpi = Decimal::from_str(cpi).map_err(|err| {
+                parsing_error!("CPI table {source}, row {}: bad CPI value: {err}", line + 1)
+            })?;
+            if cpi <= Decimal::ZERO {
+                return Err(parsing_error!(
+                    "CPI table {source}, row {}: the CPI for {year} must be positive",
+                    line + 1
+                ));
+            }
+            index.insert(year, cpi);
+        }
+        if index.is_empty() {
+            return Err(parsing_error!("CPI table {source} has no rows"));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            index,
+        })
+    }
+
+    pub fn get(&self, year: usize) -> Option<Decimal> {
+        self.index.get(&year).copied()
+    }
+
+    /// The factor which converts dollars from `from_year` into dollars from `to_year`.
+    ///
+    /// ```
+    /// use cimdea::monetary::CpiTable;
+    ///
+    /// let cpi = CpiTable::builtin();
+    /// assert_eq!(cpi.factor(2010, 2010).unwrap().to_string(), "1.000000");
+    /// assert_eq!(cpi.factor(1939, 1949).unwrap().to_string(), "1.712230");
+    /// ```
+    pub fn factor(&self, from_year: usize, to_year: usize) -> Result<Decimal, MdError> {
+        let Some(from_cpi) = self.get(from_year) else {
+            return Err(metadata_error!(
+                "No CPI for {from_year} in the {}",
+                self.source
+            ));
+        };
+        let Some(to_cpi) = self.get(to_year) else {
+            return Err(metadata_error!(
+                "No CPI for {to_year} in the {}",
+                self.source
+            ));
+        };
+        let mut factor = (to_cpi / from_cpi).round_dp(FACTOR_DECIMAL_PLACES);
+        factor.rescale(FACTOR_DECIMAL_PLACES);
+        Ok(factor)
+    }
+}
+
+/// Adjust monetary variables to the dollars of a base year.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MonetaryStandardization {
+    pub base_year: usize,
+    pub cpi: CpiTable,
+}
+
+impl MonetaryStandardization {
+    /// Standardize to the given base year with the built-in CPI-U table.
+    pub fn new(base_year: usize) -> Self {
+        Self {
+            base_year,
+            cpi: CpiTable::builtin(),
+        }
+    }
+
+    /// The year the incomes in a dataset were earned.
+    ///
+    /// Census and survey income questions ask about the previous calendar year or the past 12
+    /// months, so this is the year before the dataset's year. When metadata doesn't give the
+    /// year, it comes from the dataset name, like 1940 for us1940a.
+    ///
+    /// ```
+    /// use cimdea::ipums_metadata_model::IpumsDataset;
+    /// use cimdea::monetary::MonetaryStandardization;
+    ///
+    /// let dataset = IpumsDataset::from(("us1940a".to_string(), 0));
+    /// assert_eq!(MonetaryStandardization::income_year(&dataset).unwrap(), 1939);
+    /// ```
+    pub fn income_year(dataset: &IpumsDataset) -> Result<usize, MdError> {
+        let year = dataset
+            .year
+            .or_else(|| dataset.name.get(2..6).and_then(|y| y.parse().ok()));
+        match year {
+            Some(year) if year > 0 => Ok(year - 1),
+            _ => Err(metadata_error!(
+                "Can't determine the year of dataset {} for monetary standardization",
+                dataset.name
+            )),
+        }
+    }
+
+    /// The factor which converts a dataset's dollars into base year dollars.
+    pub fn factor_for_dataset(&self, dataset: &IpumsDataset) -> Result<Decimal, MdError> {
+        self.cpi.factor(Self::income_year(dataset)?, self.base_year)
+    }
+}
+
+impl fmt::Display for MonetaryStandardization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "adjusted to {} dollars with the {}",
+            self.base_year, self.cpi.source
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpi_table_from_csv() {
+        let csv = "year,cpi\n1999, 166.6\n2000,172.2\n";
+        let cpi = CpiTable::try_from_csv_reader("test", csv.as_bytes()).unwrap();
+        assert_eq!(cpi.get(1999), Some(Decimal::new(1666, 1)));
+        assert_eq!(cpi.factor(1999, 2000).unwrap(), Decimal::new(1_033_613, 6));
+    }
+
+    #[test]
+    fn test_cpi_table_from_csv_errors() {
+        let no_rows = CpiTable::try_from_csv_reader("test", "year,cpi\n".as_bytes());
+        assert!(matches!(no_rows, Err(MdError::ParsingError(_))));
+
+        let zero = CpiTable::try_from_csv_reader("test", "year,cpi\n2000,0\n".as_bytes());
+        assert!(matches!(zero, Err(MdError::ParsingError(_))));
+
+        let bad_year = CpiTable::try_from_csv_reader("test", "year,cpi\nabc,1.0\n".as_bytes());
+        assert!(matches!(bad_year, Err(MdError::ParsingError(_))));
+
+        // Other files' contents don't end up in errors.
+        let secret =
+            CpiTable::try_from_csv_reader("test", "user,password\nroot,hunter2\n".as_bytes())
+                .unwrap_err()
+                .to_string();
+        assert!(
+            !secret.contains("root") && !secret.contains("hunter2"),
+            "{secret}"
+        );
+    }
+
+    #[test]
+    fn test_factor_missing_year_error() {
+        let cpi = CpiTable::builtin();
+        let result = cpi.factor(1850, 2010);
+        assert!(matches!(result, Err(MdError::MetadataError(_))));
+    }
+
+    #[test]
+    fn test_income_year_from_metadata() {
+        let mut dataset = IpumsDataset::from(("mx2015a".to_string(), 0));
+        dataset.year = Some(2016);
+        assert_eq!(
+            MonetaryStandardization::income_year(&dataset).unwrap(),
+            2015
+        );
+
+        let unknown = IpumsDataset::from(("custom".to_string(), 0));
+        assert!(MonetaryStandardization::income_year(&unknown).is_err());
+    }
+}
@@ -6,6 +6,7 @@
 
 use crate::ipums_metadata_model::{
     IpumsCategory, IpumsDataType, IpumsDataset, IpumsValue, IpumsVariable, UniversalCategoryType,
+    VariableRestrictions,
 };
 use crate::mderror::{metadata_error, MdError};
 use parquet::file::reader::{FileReader, SerializedFileReader};
@@ -55,6 +56,8 @@ pub struct ParquetVariableMetadata {
     #[serde(default)]
     pub restrictions_apply: bool,
     #[serde(default)]
+    pub sample_line: Option<bool>,
+    #[serde(default)]
     pub sort_order: i32,
     #[serde(default)]
     pub source_for: String,
@@ -64,6 +67,12 @@ pub struct ParquetVariableMetadata {
     pub tabulation_type: i32,
 }
 
+/// The monetary flag is a string in the extended metadata; an empty string or "0" means the
+/// variable isn't monetary.
+fn is_monetary(flag: &str) -> bool {
+    !matches!(flag.trim(), "" | "0")
+}
+
 fn deserialize_categories<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -267,6 +276,13 @@ impl ParquetMetadataReader {
                 general_width: metadata.general_width.or(metadata.column_width),
                 description: None,
                 category_bins: None,
+                restrictions: Some(VariableRestrictions {
+                    is_restricted: metadata.is_restricted,
+                    restrictions_apply: metadata.restrictions_apply,
+                    hide_status: metadata.hide_status,
+                }),
+                monetary: is_monetary(&metadata.monetary),
+                sample_line: metadata.sample_line,
                 id: 0, // Will be assigned when added to MetadataEntities
             };
             variables.push(ipums_var);
@@ -314,12 +330,15 @@ impl ParquetMetadataReader {
                         .and_then(|v| v.as_f64())
                 });
 
+            let sample_line = sample_value.get("sample_line").and_then(|v| v.as_bool());
+
             let dataset = IpumsDataset {
                 name: sample_name,
                 year,
                 month,
                 label,
                 sampling_density,
+                sample_line,
                 id: 0, // Will be assigned when added to MetadataEntities
             };
 
@@ -492,6 +511,43 @@ mod tests {
         assert_eq!(age_var.record_type, "P");
         assert_eq!(age_var.general_width, Some(3));
         assert_eq!(age_var.formatting, Some((58, 3)));
+        assert_eq!(age_var.restrictions, Some(VariableRestrictions::default()));
+    }
+
+    #[test]
+    fn test_parse_variable_metadata_restrictions() {
+        let json_str = r#"{
+            "COUNTYICP": {
+                "label": "County",
+                "data_type": "integer",
+                "is_restricted": true,
+                "hide_status": 2
+            }
+        }"#;
+
+        let variables = ParquetMetadataReader::parse_variable_metadata(json_str, "H").unwrap();
+        let restrictions = variables[0].restrictions.as_ref().unwrap();
+        assert!(restrictions.is_restricted);
+        assert!(!restrictions.restrictions_apply);
+        assert_eq!(restrictions.hide_status, 2);
+        assert!(restrictions.any());
+    }
+
+    #[test]
+    fn test_parse_sample_line_flags() {
+        let json_str = r#"{
+            "EDUC": {"label": "Education", "data_type": "integer", "sample_line": true},
+            "AGE": {"label": "Age", "data_type": "integer"}
+        }"#;
+        let variables = ParquetMetadataReader::parse_variable_metadata(json_str, "P").unwrap();
+        let educ = variables.iter().find(|v| v.name == "EDUC").unwrap();
+        let age = variables.iter().find(|v| v.name == "AGE").unwrap();
+        assert_eq!(educ.sample_line, Some(true));
+        assert_eq!(age.sample_line, None);
+
+        let samples = r#"{"us1940a": {"label": "1940 1%", "sample_line": true}}"#;
+        let datasets = ParquetMetadataReader::parse_samples_metadata(samples).unwrap();
+        assert_eq!(datasets[0].sample_line, Some(true));
     }
 
     #[test]
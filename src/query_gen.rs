@@ -13,17 +13,25 @@
 
 use crate::conventions::Context;
 
-use crate::input_schema_tabulation::{CategoryBin, RequestCaseSelection};
+use crate::input_schema_tabulation::{
+    CategoryBin, MissingCodeHandling, RecodeCodes, RecodeGroup, RequestCaseSelection,
+    WeightSelection,
+};
 use crate::ipums_metadata_model::{self, IpumsDataType, IpumsVariable};
-use crate::mderror::{metadata_error, MdError};
+use crate::mderror::{metadata_error, parsing_error, Entity, MdError};
 use crate::request::CaseSelectLogic;
 use crate::request::DataRequest;
 use crate::request::InputType;
 use crate::request::RequestVariable;
+use crate::request::Subsample;
+use rust_decimal::Decimal;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::path::PathBuf;
 
+/// The person number within the household, which family pointer variables like MOMLOC refer to.
+const PERSON_NUMBER: &str = "PERNUM";
+
 /// The TabBuilder is meant to assist with one or more tabulations from the same data product.
 #[allow(dead_code)]
 struct TabBuilder {
@@ -31,12 +39,18 @@ struct TabBuilder {
     input_format: InputType,
     dataset: String,
     data_sources: HashMap<String, DataSource>,
-    // If doing only an unweighted count you need to filter by SELFWTSL
-    // in us1940a; for a weighted count apply SLWT instead of PERWT if
-    // any variables are sample line questions.
-    // It doesn't seem possible to return both an accurate  unweighted count
-    // and a accurately weighted count for us1940a in one request.
-    unweighted_count_only: bool,
+}
+
+/// The records a request counts in one dataset, and how they're weighted.
+struct RecordSelection {
+    weight_name: Option<String>,
+    weight_divisor: usize,
+    /// The weights are multiplied by this before they're summed.
+    weight_multiplier: u64,
+    monetary_factor: Option<Decimal>,
+    from_clause: String,
+    /// Conditions which every counted record meets.
+    where_clauses: Vec<String>,
 }
 
 impl TabBuilder {
@@ -52,7 +66,6 @@ impl TabBuilder {
             dataset: dataset.to_string(),
             platform: platform.clone(),
             input_format: input_format.clone(),
-            unweighted_count_only: false,
         })
     }
 
@@ -63,13 +76,15 @@ impl TabBuilder {
         _dataset: &str,
         uoa: &str,
         all_rectypes: &HashSet<String>,
+        attached_variables: &[RequestVariable],
+        rollup_variables: &[RequestVariable],
     ) -> Result<String, MdError> {
         let lhs = match self.data_sources.get(uoa) {
             Some(lhs) => lhs,
             None => {
-                return Err(MdError::Msg(format!(
+                return Err(metadata_error!(
                     "no data source for unit of analysis '{uoa}'"
-                )));
+                ));
             }
         };
 
@@ -82,8 +97,8 @@ impl TabBuilder {
         // generated to connect any two tables where we have foreign and primary keys. Three or more
         // correct joins aren't yet supported.
         if self.data_sources.len() > 2 {
-            return Err(MdError::Msg(
-                "Tabulations across more than two record types not yet supported!".to_string(),
+            return Err(parsing_error!(
+                "Tabulations across more than two record types not yet supported!"
             ));
         }
         for (rt, ds) in &self.data_sources {
@@ -107,55 +122,299 @@ impl TabBuilder {
                 );
             }
         }
+        q += &self.help_attached_variable_joins(ctx, uoa, attached_variables)?;
+        q += &self.help_rollup_joins(ctx, rollup_variables)?;
         Ok(q)
     }
 
-    fn help_bucket(&self, rq: &RequestVariable) -> Result<String, MdError> {
+    /// Join each roll-up as a subquery which summarizes the child records by their parent's
+    /// id. For the number of children in each household,
+    ///
+    /// `left join (select SERIALP as NKIDS_key, count(*) as NKIDS from ... where (AGE < 18)
+    /// group by SERIALP) rollup_NKIDS on tbl_H.SERIAL = rollup_NKIDS.NKIDS_key`
+    fn help_rollup_joins(
+        &self,
+        ctx: &Context,
+        rollup_variables: &[RequestVariable],
+    ) -> Result<String, MdError> {
+        let mut joined = HashSet::new();
+        let mut joins = String::new();
+        for rollup in rollup_variables.iter().filter_map(|rq| rq.rollup.as_ref()) {
+            if !joined.insert(rollup.name.clone()) {
+                continue;
+            }
+            let child_rt = &rollup.child_record_type;
+            let parent_rt = &rollup.parent_record_type;
+            let (Some(child_ds), Some(parent_ds)) = (
+                self.data_sources.get(child_rt),
+                self.data_sources.get(parent_rt),
+            ) else {
+                return Err(metadata_error!(
+                    "no data source for record type '{child_rt}' or '{parent_rt}' of roll-up {}",
+                    rollup.name
+                ));
+            };
+            let child_key = Self::help_get_connecting_foreign_key(ctx, child_rt, parent_rt)?;
+            let parent_id = Self::help_get_id_for_record_type(ctx, parent_rt)?;
+            let where_clause = match rollup.condition {
+                Some(ref condition) => format!(" where {}", condition.condition_sql()),
+                None => String::new(),
+            };
+            let alias = rollup.table_alias();
+            joins += &format!(
+                "\n left join (select {} as {}_key, {} as {} from {}{} group by {}) {} on {}.{} = {}.{}_key",
+                child_key,
+                rollup.name,
+                rollup.aggregate_sql(),
+                rollup.name,
+                child_ds.for_platform(&self.platform),
+                where_clause,
+                child_key,
+                alias,
+                parent_ds.table_name(),
+                parent_id,
+                alias,
+                rollup.name
+            );
+        }
+        Ok(joins)
+    }
+
+    /// Join each family member that attached variables point to as a copy of their record type,
+    /// restricted to the attached variables and the keys needed to find the family member.
+    ///
+    /// Pointer variables hold the person number of the family member in the same household, so
+    /// EDUC with MOMLOC becomes
+    ///
+    /// ```text
+    /// left join (select SERIALP as SERIALP_MOMLOC, PERNUM as PERNUM_MOMLOC, EDUC as EDUC_MOM from ...) us1900m_person_MOMLOC
+    ///     on us1900m_person.SERIALP = us1900m_person_MOMLOC.SERIALP_MOMLOC
+    ///     and us1900m_person.MOMLOC = us1900m_person_MOMLOC.PERNUM_MOMLOC
+    /// ```
+    ///
+    /// People without that family member get nulls for the attached variables.
+    ///
+    /// When the unit of analysis is above the pointer's record type, like households, the first
+    /// person in each household is joined first and the family member is theirs. In IPUMS data
+    /// that's the householder, so SEX with SPLOC gives the sex of the householder's spouse.
+    fn help_attached_variable_joins(
+        &self,
+        ctx: &Context,
+        uoa: &str,
+        attached_variables: &[RequestVariable],
+    ) -> Result<String, MdError> {
+        // Keep the joins in the order the pointers first appear so the SQL is stable.
+        let mut by_pointer: Vec<(&IpumsVariable, Vec<&RequestVariable>)> = Vec::new();
+        for rq in attached_variables {
+            let Some(ref pointer) = rq.attached_variable_pointer else {
+                continue;
+            };
+            match by_pointer.iter_mut().find(|(p, _)| p.name == pointer.name) {
+                Some((_, vars)) => vars.push(rq),
+                None => by_pointer.push((pointer, vec![rq])),
+            }
+        }
+
+        let mut joined = HashSet::new();
+        let mut joins = String::new();
+        for (pointer, vars) in by_pointer {
+            let rt = &pointer.record_type;
+            let Some(ds) = self.data_sources.get(rt) else {
+                return Err(metadata_error!(
+                    "no data source for record type '{rt}' of pointer variable {}",
+                    pointer.name
+                ));
+            };
+            let household_rt = Self::help_get_household_record_type(ctx)?;
+            let household_key = Self::help_get_connecting_foreign_key(ctx, rt, &household_rt)?;
+
+            let table_alias = ds.table_name();
+            if rt != uoa && joined.insert(rt.clone()) {
+                let Some(uoa_ds) = self.data_sources.get(uoa) else {
+                    return Err(metadata_error!(
+                        "no data source for unit of analysis '{uoa}'"
+                    ));
+                };
+                let uoa_key = Self::help_get_connecting_foreign_key(ctx, rt, uoa)?;
+                let uoa_id = Self::help_get_id_for_record_type(ctx, uoa)?;
+                joins += &format!(
+                    "\n left join {} {} on {}.{} = {}.{} and {}.{} = 1",
+                    ds.for_platform(&self.platform),
+                    table_alias,
+                    uoa_ds.table_name(),
+                    uoa_id,
+                    table_alias,
+                    uoa_key,
+                    table_alias,
+                    PERSON_NUMBER
+                );
+            }
+            let member_alias = format!("{}_{}", table_alias, pointer.name);
+            let member_household_key = format!("{}_{}", household_key, pointer.name);
+            let member_person_number = format!("{}_{}", PERSON_NUMBER, pointer.name);
+
+            let mut columns = vec![
+                format!("{} as {}", household_key, member_household_key),
+                format!("{} as {}", PERSON_NUMBER, member_person_number),
+            ];
+            for rq in vars {
+                columns.push(format!("{} as {}", rq.variable.name, rq.source_column()));
+            }
+
+            joins += &format!(
+                "\n left join (select {} from {}) {} on {}.{} = {}.{} and {}.{} = {}.{}",
+                columns.join(", "),
+                ds.for_platform(&self.platform),
+                member_alias,
+                table_alias,
+                household_key,
+                member_alias,
+                member_household_key,
+                table_alias,
+                pointer.name,
+                member_alias,
+                member_person_number
+            );
+        }
+        Ok(joins)
+    }
+
+    /// The expression for a request variable's values. Monetary variables are adjusted to
+    /// constant dollars here, so bins and general codes see the adjusted amounts. Adjusted
+    /// amounts are rounded to whole units to keep the variable's type. Missing and not in
+    /// universe codes are never adjusted.
+    fn help_value_expr(rq: &RequestVariable, monetary_factor: Option<Decimal>) -> String {
+        let column = rq.source_column();
+        match monetary_factor {
+            Some(factor) if rq.monetary_standardized => {
+                let adjusted = format!("cast(round({} * {}) as bigint)", column, factor);
+                if rq.missing_codes.is_empty() {
+                    adjusted
+                } else {
+                    format!(
+                        "case when {} then {} else {} end",
+                        Self::help_missing_codes_condition(rq),
+                        column,
+                        adjusted
+                    )
+                }
+            }
+            _ => column,
+        }
+    }
+
+    /// The condition that a request variable has one of its missing or not in universe codes.
+    fn help_missing_codes_condition(rq: &RequestVariable) -> String {
+        let codes = rq
+            .missing_codes
+            .iter()
+            .map(|code| code.to_string())
+            .collect::<Vec<_>>();
+        CompareOperation::In(codes).to_sql(&rq.source_column())
+    }
+
+    /// The condition that a request variable doesn't have one of its missing or not in universe
+    /// codes. Nulls, like attached variables for people without that family member, aren't
+    /// missing codes.
+    fn help_not_missing_condition(rq: &RequestVariable) -> String {
+        format!(
+            "{} is null or not ({})",
+            rq.source_column(),
+            Self::help_missing_codes_condition(rq)
+        )
+    }
+
+    fn help_bucket(
+        &self,
+        rq: &RequestVariable,
+        monetary_factor: Option<Decimal>,
+        missing_code_handling: MissingCodeHandling,
+    ) -> Result<String, MdError> {
+        let cases = if let Some(ref groups) = rq.recode {
+            groups
+                .iter()
+                .map(|g| {
+                    format!(
+                        "\twhen {} then '{:03}'",
+                        recode_group_condition(&rq.source_column(), g),
+                        g.code
+                    )
+                })
+                .collect::<Vec<String>>()
+        } else {
+            Self::help_bin_cases(rq, monetary_factor)?
+        };
+        let mut sql = "case\n".to_string();
+        // Missing codes keep their own values instead of falling into a bin or recode group.
+        if missing_code_handling == MissingCodeHandling::Separate && !rq.missing_codes.is_empty() {
+            sql.push_str(&format!(
+                "\twhen {} then cast({} as varchar)\n",
+                Self::help_missing_codes_condition(rq),
+                rq.source_column()
+            ));
+        }
+        sql.push_str(&cases.join("\n"));
+        sql.push_str("\nelse '999' end ");
+        sql.push_str(&format!("as {}_bucketed", &rq.name));
+        Ok(sql)
+    }
+
+    /// The cases which sort a variable's values into its category bins.
+    fn help_bin_cases(
+        rq: &RequestVariable,
+        monetary_factor: Option<Decimal>,
+    ) -> Result<Vec<String>, MdError> {
         let Some(ref bins) = rq.category_bins else {
-            return Err(MdError::Msg("No category bins available.".to_string()));
+            return Err(metadata_error!("No category bins available."));
         };
         if bins.is_empty() {
-            return Err(MdError::Msg("Metadata marks this variable as having category bins but the list of bins is empty.".to_string()));
+            return Err(metadata_error!(
+                "Metadata marks this variable as having category bins but the list of bins is empty."
+            ));
         }
-        let mut sql = "case\n".to_string();
+        let value = Self::help_value_expr(rq, monetary_factor);
         let cases = bins
             .iter()
             .map(|b| match b {
-                CategoryBin::LessThan { value, code, .. } => {
-                    format!("\twhen {} <= {} then '{:03}'", rq.name, value, code)
+                CategoryBin::LessThan {
+                    value: bound, code, ..
+                } => {
+                    format!("\twhen {} <= {} then '{:03}'", value, bound, code)
                 }
-                CategoryBin::MoreThan { value, code, .. } => {
-                    format!("\twhen {} >= {} then '{:03}'", rq.name, value, code)
+                CategoryBin::MoreThan {
+                    value: bound, code, ..
+                } => {
+                    format!("\twhen {} >= {} then '{:03}'", value, bound, code)
                 }
                 CategoryBin::Range {
                     low, high, code, ..
                 } => format!(
                     "\twhen {} >= {} and {} <= {} then '{:03}'",
-                    rq.name, low, rq.name, high, code
+                    value, low, value, high, code
                 ),
             })
-            .collect::<Vec<String>>()
-            .join("\n");
-        sql.push_str(&cases);
-        sql.push_str("\nelse '999' end ");
-        sql.push_str(&format!("as {}_bucketed", &rq.name));
-        Ok(sql)
+            .collect::<Vec<String>>();
+        Ok(cases)
     }
 
     fn build_select_clause(
         &self,
         request_variables: &[RequestVariable],
         weight_name: Option<String>,
-        weight_divisor: Option<usize>,
+        weight_multiplier: u64,
+        monetary_factor: Option<Decimal>,
+        missing_code_handling: MissingCodeHandling,
     ) -> Result<String, MdError> {
         let mut select_clause = "count(*) as ct".to_string();
 
+        // Sum the raw weights and leave applying the divisor to the caller, which can do it
+        // exactly. Dividing each weight in SQL gives a floating point sum.
         if let Some(ref wt) = weight_name {
-            select_clause += &format!(
-                ", sum({}/{}) as weighted_ct",
-                wt,
-                weight_divisor.unwrap_or(1)
-            );
+            if weight_multiplier == 1 {
+                select_clause += &format!(", sum({}) as weighted_ct", wt);
+            } else {
+                select_clause += &format!(", sum({}) * {} as weighted_ct", wt, weight_multiplier);
+            }
         }
 
         for rq in request_variables {
@@ -165,39 +424,46 @@ impl TabBuilder {
                     "The variable {} can't be both a general variable and use category bins.",
                     &rq.name
                 );
-                return Err(MdError::Msg(msg));
+                return Err(MdError::ParsingError(msg));
             }
+            let value = Self::help_value_expr(rq, monetary_factor);
             select_clause += &if rq.is_general() {
+                format!(", {}//{} as {}", &value, &rq.general_divisor, &rq.name)
+            } else if rq.is_bucketed() {
                 format!(
-                    ", {}//{} as {}",
-                    &rq.variable.name, &rq.general_divisor, &rq.name
+                    ", {} ",
+                    &self.help_bucket(rq, monetary_factor, missing_code_handling)?
                 )
-            } else if rq.is_bucketed() {
-                format!(", {} ", &self.help_bucket(rq)?)
             } else {
-                format!(", {} as {}", &rq.variable.name, &rq.name)
+                format!(", {} as {}", &value, &rq.name)
             };
         }
 
         Ok(select_clause)
     }
 
-    fn should_use_sample_line_weights(&self, ctx: &Context) -> bool {
-        matches!(ctx.settings.name.to_lowercase().as_ref(), "usa")
-            && matches!(
-                self.dataset.to_ascii_lowercase().as_ref(),
-                "us1940b" | "us1950b" | "us1940a" | "us1950a"
-            )
+    /// Whether the tabulation involves sample line questions: the dataset has a sample line and
+    /// some of the variables are, or may be, sample line variables.
+    fn uses_sample_line(&self, ctx: &Context, variables: &[&IpumsVariable]) -> bool {
+        ctx.settings.has_sample_line(&self.dataset)
+            && IpumsVariable::any_sample_line(variables.iter().copied()) != Some(false)
     }
 
-    fn should_use_selfwtsl(&self, ctx: &Context) -> bool {
-        self.should_use_sample_line_weights(ctx)
-            && matches!(self.dataset.to_lowercase().as_ref(), "us1940a")
-            && self.unweighted_count_only
+    // It doesn't seem possible to return both an accurate unweighted count and an accurately
+    // weighted count of sample line variables in one request, so unweighted counts of them
+    // are restricted to the flat sample line subsample.
+    fn should_use_selfwtsl(
+        &self,
+        ctx: &Context,
+        weight_selection: &WeightSelection,
+        variables: &[&IpumsVariable],
+    ) -> bool {
+        *weight_selection == WeightSelection::Unweighted
+            && self.uses_sample_line(ctx, variables)
+            && ctx.settings.dataset_has_variable(&self.dataset, "SELFWTSL") == Some(true)
     }
 
-    // This only matters when doing an unweighted count on us1940a -- SELFWTSL is necessary
-    // to get a flat sample if you aren't applying weights.
+    // SELFWTSL is necessary to get a flat sample if you aren't applying weights.
     fn help_conditions_with_selfwtsl_filter(
         &self,
         ctx: &Context,
@@ -229,27 +495,56 @@ impl TabBuilder {
         }
     }
 
-    fn help_get_weight(&self, ctx: &Context, uoa: &str) -> (Option<String>, Option<usize>) {
-        let default_weight = (
-            ctx.settings.weight_for_rectype(uoa),
-            ctx.settings.weight_divisor(uoa),
-        );
-
-        // non-USA will be (None, None)
-        let sample_line_weight = (
-            ctx.settings.sample_line_weight_for_rectype(uoa),
-            ctx.settings.sample_line_weight_divisor(uoa),
-        );
-
-        // TODO: here is where, if we had full variable metadata, we could decide when to use
-        // SLWT or PERWT in USA, since each variable has a SLWT50 or SLWT40 value..
-        // It only matters on years with sample line questions: 1940 and 1950 (this could
-        // be set on IpumsDataset metadata but isn't yet.) For now we just need to
-        // use SLWT if the dataset names are 'us1940a' or 'us1950a' or 'us1940b' or 'us1950b'.
-        if self.should_use_sample_line_weights(ctx) {
-            sample_line_weight
-        } else {
-            default_weight
+    /// The weight variable and its divisor for a tabulation of the variables with the unit of
+    /// analysis. Automatic weights which the dataset doesn't have leave the counts unweighted.
+    fn help_get_weight(
+        &self,
+        ctx: &Context,
+        uoa: &str,
+        weight_selection: &WeightSelection,
+        variables: &[&IpumsVariable],
+    ) -> Result<(Option<String>, Option<usize>), MdError> {
+        let in_dataset =
+            |weight: &str| ctx.settings.dataset_has_variable(&self.dataset, weight) != Some(false);
+        match weight_selection {
+            WeightSelection::Automatic => {
+                // non-USA will be (None, None)
+                let sample_line_weight = (
+                    ctx.settings.sample_line_weight_for_rectype(uoa),
+                    ctx.settings.sample_line_weight_divisor(uoa),
+                );
+                if self.uses_sample_line(ctx, variables) {
+                    if let (Some(ref weight), _) = sample_line_weight {
+                        if in_dataset(weight) {
+                            return Ok(sample_line_weight);
+                        }
+                    }
+                }
+                match ctx.settings.weight_for_rectype(uoa) {
+                    Some(weight) if in_dataset(&weight) => {
+                        Ok((Some(weight), ctx.settings.weight_divisor(uoa)))
+                    }
+                    _ => Ok((None, None)),
+                }
+            }
+            WeightSelection::Unweighted => Ok((None, None)),
+            WeightSelection::Variable(weight) => {
+                if !in_dataset(weight) {
+                    return Err(metadata_error!(
+                        "weight variable {weight} isn't available in dataset {}",
+                        self.dataset
+                    ));
+                }
+                // Weights the record types know about have divisors; others are used as is.
+                let divisor = ctx.settings.record_types.values().find_map(|rt| {
+                    [&rt.weight, &rt.sample_weight]
+                        .into_iter()
+                        .flatten()
+                        .find(|w| &w.name == weight)
+                        .map(|w| w.divisor)
+                });
+                Ok((Some(weight.clone()), divisor))
+            }
         }
     }
 
@@ -269,16 +564,25 @@ impl TabBuilder {
     fn help_get_required_rectypes(
         request_variables: &[RequestVariable],
         conditions: &[Condition],
+        attached_variables: &[RequestVariable],
     ) -> HashSet<String> {
+        // Attached variables are joined on their own, through the unit of analysis.
+        let attached_columns: HashSet<String> = attached_variables
+            .iter()
+            .map(|v| v.source_column())
+            .collect();
+
         // Find all rectypes used by the requested variables
         let rectypes_from_vars: Vec<String> = request_variables
             .iter()
+            .filter(|v| !v.is_attached())
             .map(|v| &v.variable.record_type)
             .cloned()
             .collect();
 
         let rectypes_from_conds: Vec<String> = conditions
             .iter()
+            .filter(|c| !attached_columns.contains(&c.var.name))
             .map(|c| &c.var.record_type)
             .cloned()
             .collect();
@@ -291,70 +595,274 @@ impl TabBuilder {
         HashSet::from_iter(all_rectypes.iter().cloned())
     }
 
-    pub fn make_query(
+    /// The records a request counts in this dataset and how they're weighted: everything about a
+    /// tabulation query except what it selects and groups by.
+    fn help_record_selection(
         &self,
         ctx: &Context,
         abacus_request: &impl DataRequest,
-    ) -> Result<String, MdError> {
+    ) -> Result<RecordSelection, MdError> {
         let request_variables = abacus_request.get_request_variables();
         let requested_conditions = abacus_request.get_conditions();
         let case_select_logic = abacus_request.case_select_logic();
 
         if request_variables.is_empty() {
-            return Err(MdError::Msg(
-                "Must supply at least one request variable.".to_string(),
-            ));
+            return Err(parsing_error!("Must supply at least one request variable.")
+                .in_request("request_variables"));
         }
 
+        let uoa = abacus_request
+            .unit_of_analysis()
+            .unwrap_or_else(|| ctx.settings.default_unit_of_analysis.clone())
+            .value;
+
+        if !self.data_sources.contains_key(&uoa) {
+            return Err(metadata_error!(
+                "Can't use unit of analysis '{}' to generate 'from' clause, not in set of record types in '{}'",
+                uoa,
+                ctx.settings.name
+            )
+            .in_request("uoa"));
+        }
+
+        let weight_selection = abacus_request.weight_selection();
+        let variables: Vec<&IpumsVariable> = request_variables
+            .iter()
+            .map(|v| &v.variable)
+            .chain(requested_conditions.iter().flatten().map(|c| &c.var))
+            .collect();
+        let (weight_name, weight_divisor) =
+            self.help_get_weight(ctx, &uoa, &weight_selection, &variables)?;
+        let mut weight_divisor = weight_divisor.unwrap_or(1);
+        let use_selfwtsl = self.should_use_selfwtsl(ctx, &weight_selection, &variables);
+
         // Add a condition for SELFWTSL if needed. Yuck.
-        let conditions = if self.should_use_selfwtsl(ctx) {
+        let conditions = if use_selfwtsl {
             Some(self.help_conditions_with_selfwtsl_filter(ctx, requested_conditions)?)
         } else {
             requested_conditions
         };
 
-        let rectypes = TabBuilder::help_get_required_rectypes(
+        let attached_variables = abacus_request.get_attached_variables();
+        let mut rectypes = TabBuilder::help_get_required_rectypes(
             &request_variables,
             &conditions.clone().unwrap_or(Vec::new()),
+            &attached_variables,
         );
+        if let WeightSelection::Variable(ref weight) = weight_selection {
+            rectypes.insert(ctx.get_md_variable_by_name(weight)?.record_type);
+        }
 
-        // TODO: Decide the unit of analysis based on variable selection? Or, use the
-        // UOA in the incoming Request JSON
-        let uoa = ctx.settings.default_unit_of_analysis.value.clone();
+        let request_sample = abacus_request
+            .get_request_samples()
+            .into_iter()
+            .find(|rs| rs.name == self.dataset);
+        let subsample = request_sample.as_ref().and_then(|rs| rs.subsample.clone());
+
+        let monetary_factor = match (
+            abacus_request.get_monetary_standardization(),
+            &request_sample,
+        ) {
+            (Some(ms), Some(rs)) => Some(ms.factor_for_dataset(&rs.sample)?),
+            _ => None,
+        };
 
-        if !self.data_sources.contains_key(&uoa) {
-            let msg = format!("Can't use unit of analysis '{}' to generate 'from' clause, not in set of record types in '{}'", uoa, ctx.settings.name);
-            return Err(MdError::Msg(msg));
+        // A subsample of 1 in 10 households rescales weighted counts by 10 / 1.
+        let mut weight_multiplier = 1;
+        if let Some(ref subsample) = subsample {
+            if subsample.rescale_weights {
+                weight_multiplier = subsample.denominator;
+                weight_divisor *= subsample.numerator as usize;
+            }
         }
 
-        let (weight_name, weight_divisor) = self.help_get_weight(ctx, &uoa);
+        let from_clause = self.build_from_clause(
+            ctx,
+            &self.dataset,
+            &uoa,
+            &rectypes,
+            &attached_variables,
+            &abacus_request.get_rollup_variables(),
+        )?;
+
+        let subsample_clause = match subsample {
+            Some(ref subsample) => Some(self.help_subsample_condition(ctx, &uoa, subsample)?),
+            None => None,
+        };
 
-        let select_clause =
-            self.build_select_clause(&request_variables, weight_name, weight_divisor);
-        let from_clause = &self.build_from_clause(ctx, &self.dataset, &uoa, &rectypes)?;
+        let mut where_clauses = Vec::new();
+        if let Some(ref conds) = conditions {
+            where_clauses.push(self.build_where_clause(conds, case_select_logic)?);
+        }
+        if abacus_request.missing_code_handling() == MissingCodeHandling::Exclude {
+            for rq in request_variables
+                .iter()
+                .filter(|rq| !rq.missing_codes.is_empty())
+            {
+                where_clauses.push(Self::help_not_missing_condition(rq));
+            }
+        }
+        where_clauses.extend(subsample_clause);
+
+        Ok(RecordSelection {
+            weight_name,
+            weight_divisor,
+            weight_multiplier,
+            monetary_factor,
+            from_clause,
+            where_clauses,
+        })
+    }
+
+    pub fn make_query(
+        &self,
+        ctx: &Context,
+        abacus_request: &impl DataRequest,
+    ) -> Result<TabQuery, MdError> {
+        let RecordSelection {
+            weight_name,
+            weight_divisor,
+            weight_multiplier,
+            monetary_factor,
+            from_clause,
+            where_clauses,
+        } = self.help_record_selection(ctx, abacus_request)?;
+        let request_variables = abacus_request.get_request_variables();
+
+        let select_clause = self.build_select_clause(
+            &request_variables,
+            weight_name.clone(),
+            weight_multiplier,
+            monetary_factor,
+            abacus_request.missing_code_handling(),
+        );
 
         let vars_in_order = self.help_final_var_aliases(&request_variables);
 
-        /// The first column in the query that is a request variable. Column 1
-        /// is ct and column 2 is weighted_ct.
-        const FIRST_RQV_COLUMN: usize = 3;
+        // The first column in the query that is a request variable. Column 1 is ct and
+        // column 2 is weighted_ct when the query is weighted.
+        let first_rqv_column = if weight_name.is_some() { 3 } else { 2 };
         let group_by_columns: Vec<_> = (0..vars_in_order.len())
-            .map(|index| index + FIRST_RQV_COLUMN)
+            .map(|index| index + first_rqv_column)
             .map(|x| x.to_string())
             .collect();
         let group_by_clause = group_by_columns.join(", ");
         let order_by_clause = vars_in_order.join(", ");
 
-        if let Some(ref conds) = conditions {
-            let where_clause = &self.build_where_clause(conds, case_select_logic)?;
-            Ok(format!(
+        let sql = if let Some(ref where_clause) = Self::help_join_where_clauses(where_clauses) {
+            format!(
                 "select \n{}\nfrom {}\nwhere {}\ngroup by {}\norder by {}",
                 &select_clause?, &from_clause, &where_clause, &group_by_clause, &order_by_clause
-            ))
+            )
         } else {
-            Ok(format!(
+            format!(
                 "select \n{}\nfrom {}\ngroup by {}\norder by {}",
                 &select_clause?, &from_clause, &group_by_clause, &order_by_clause
+            )
+        };
+
+        Ok(TabQuery {
+            dataset: self.dataset.clone(),
+            sql,
+            weight_name,
+            weight_divisor,
+        })
+    }
+
+    /// Combine conditions on records into one where clause, or `None` when there aren't any.
+    fn help_join_where_clauses(mut where_clauses: Vec<String>) -> Option<String> {
+        match where_clauses.len() {
+            0 => None,
+            1 => where_clauses.pop(),
+            _ => Some(
+                where_clauses
+                    .iter()
+                    .map(|clause| format!("({clause})"))
+                    .collect::<Vec<_>>()
+                    .join(" and "),
+            ),
+        }
+    }
+
+    /// The condition selecting a subsample of households. Households are the record type at the
+    /// root of the hierarchy; other record types point to it with a foreign key.
+    fn help_subsample_condition(
+        &self,
+        ctx: &Context,
+        uoa: &str,
+        subsample: &Subsample,
+    ) -> Result<String, MdError> {
+        let household_rt = Self::help_get_household_record_type(ctx)?;
+        let household = &ctx.settings.record_types[&household_rt];
+        let Some(household_source) = self.data_sources.get(&household_rt) else {
+            return Err(metadata_error!(
+                "no data source for household record type '{household_rt}'"
+            ));
+        };
+        let Some(unit_source) = self.data_sources.get(uoa) else {
+            return Err(metadata_error!(
+                "no data source for unit of analysis '{uoa}'"
+            ));
+        };
+
+        let key = if uoa == household_rt {
+            household.unique_id.clone()
+        } else {
+            Self::help_get_connecting_foreign_key(ctx, uoa, &household_rt)?
+        };
+        Ok(subsample.to_sql(
+            &format!("{}.{}", unit_source.table_name(), key),
+            &household_source.for_platform(&self.platform),
+            &household.unique_id,
+        ))
+    }
+
+    /// Select a request variable's values and weights from the records the request counts,
+    /// leaving out the variable's missing and not in universe codes.
+    fn help_distribution_select(
+        &self,
+        ctx: &Context,
+        abacus_request: &impl DataRequest,
+        rq: &RequestVariable,
+    ) -> Result<String, MdError> {
+        let RecordSelection {
+            weight_name,
+            weight_divisor,
+            weight_multiplier,
+            monetary_factor,
+            from_clause,
+            mut where_clauses,
+        } = self.help_record_selection(ctx, abacus_request)?;
+        if !rq.missing_codes.is_empty() {
+            where_clauses.push(Self::help_not_missing_condition(rq));
+        }
+        let weight = match weight_name {
+            Some(weight_name) => format!(
+                "{} * {} / {}",
+                weight_name, weight_multiplier, weight_divisor
+            ),
+            None => "1".to_string(),
+        };
+        let mut select = format!(
+            "select {} as value, {} as weight from {}",
+            Self::help_value_expr(rq, monetary_factor),
+            weight,
+            from_clause
+        );
+        if let Some(where_clause) = Self::help_join_where_clauses(where_clauses) {
+            select += &format!(" where {where_clause}");
+        }
+        Ok(select)
+    }
+
+    /// Households are the record type at the root of the configured record hierarchy.
+    fn help_get_household_record_type(ctx: &Context) -> Result<String, MdError> {
+        let root = &ctx.settings.record_hierarchy.root;
+        if ctx.settings.record_types.contains_key(root) {
+            Ok(root.clone())
+        } else {
+            Err(metadata_error!(
+                "The root of the record hierarchy, '{root}', isn't one of the record types in the current context."
             ))
         }
     }
@@ -372,16 +880,14 @@ impl TabBuilder {
             if let Some(key_name) = fkey_name {
                 Ok(key_name.1.clone())
             } else {
-                Err(MdError::Msg(format!(
+                Err(metadata_error!(
                     "Cannot find a connection between '{}' and a parent record type of '{}'",
-                    from_rt, to_parent
-                )))
+                    from_rt,
+                    to_parent
+                ))
             }
         } else {
-            Err(MdError::Msg(format!(
-                "Cannot find a connection between '{}' and a parent record type of '{}'",
-                from_rt, to_parent
-            )))
+            Err(MdError::NotFound(Entity::RecordType(from_rt.to_string())))
         }
     }
 
@@ -389,11 +895,23 @@ impl TabBuilder {
         if let Some(record_type) = ctx.settings.record_types.get(rt) {
             Ok(record_type.unique_id.clone())
         } else {
-            Err(metadata_error!("No record type '{rt}' in current context.",))
+            Err(MdError::NotFound(Entity::RecordType(rt.to_string())))
         }
     }
 }
 
+/// A generated tabulation query for one dataset.
+///
+/// When the query is weighted, its `weighted_ct` column is the raw sum of the weight variable.
+/// Divide by `weight_divisor` to get the weighted count.
+#[derive(Clone, Debug)]
+pub struct TabQuery {
+    pub dataset: String,
+    pub sql: String,
+    pub weight_name: Option<String>,
+    pub weight_divisor: usize,
+}
+
 #[derive(Debug, Clone)]
 pub enum DataSource {
     Parquet { name: String, full_path: PathBuf },
@@ -413,7 +931,11 @@ impl DataSource {
         dataset: &str,
         input_format: &InputType,
     ) -> Result<HashMap<String, DataSource>, MdError> {
-        let paths_by_rectypes = ctx.paths_from_dataset_name(dataset, input_format)?;
+        // Tables in a database are named with the default table names, which are the aliases.
+        let paths_by_rectypes = match input_format {
+            InputType::NativeDb => HashMap::new(),
+            _ => ctx.paths_from_dataset_name(dataset, input_format)?,
+        };
         let mut data_sources = HashMap::new();
         for rt in ctx.settings.record_types.keys() {
             let table_alias = ctx.settings.default_table_name(dataset, rt)?;
@@ -561,11 +1083,45 @@ impl CompareOperation {
     }
 }
 
+/// The condition that a column has one of the codes in a recode group.
+///
+/// ```
+/// use cimdea::input_schema_tabulation::{RecodeCodes, RecodeGroup};
+/// use cimdea::query_gen::recode_group_condition;
+///
+/// let group = RecodeGroup {
+///     code: 4,
+///     value_label: "South".to_string(),
+///     codes: vec![RecodeCodes::Code(5), RecodeCodes::Code(10), RecodeCodes::Range { low: 11, high: 13 }],
+/// };
+/// assert_eq!(
+///     recode_group_condition("STATEFIP", &group),
+///     "(STATEFIP in (5,10) or STATEFIP between 11 and 13)"
+/// );
+/// ```
+pub fn recode_group_condition(column: &str, group: &RecodeGroup) -> String {
+    let mut codes = Vec::new();
+    let mut comparisons = Vec::new();
+    for c in &group.codes {
+        match c {
+            RecodeCodes::Code(code) => codes.push(code.to_string()),
+            RecodeCodes::Range { low, high } => comparisons
+                .push(CompareOperation::Between(low.to_string(), high.to_string()).to_sql(column)),
+        }
+    }
+    if !codes.is_empty() {
+        comparisons.insert(0, CompareOperation::In(codes).to_sql(column));
+    }
+    format!("({})", comparisons.join(" or "))
+}
+
 #[derive(Clone, Debug)]
 pub struct Condition {
     pub var: ipums_metadata_model::IpumsVariable,
     pub comparison: Vec<CompareOperation>,
     pub data_type: IpumsDataType,
+    /// Compare the variable's recoded values instead of its codes.
+    pub recode: Option<Vec<RecodeGroup>>,
 }
 
 impl Condition {
@@ -585,6 +1141,7 @@ impl Condition {
             var: var.clone(),
             comparison: comparison.to_vec(),
             data_type,
+            recode: None,
         })
     }
 
@@ -622,6 +1179,7 @@ impl Condition {
                 var: var.clone(),
                 comparison: comparisons,
                 data_type,
+                recode: None,
             }))
         }
     }
@@ -635,11 +1193,41 @@ impl Condition {
         }
     }
 
+    /// Apply the condition to the new codes of a recode rather than the variable's own codes.
+    pub fn with_recode(self, recode: &[RecodeGroup]) -> Self {
+        Self {
+            recode: Some(recode.to_vec()),
+            ..self
+        }
+    }
+
+    /// The left hand side of the comparisons. With a recode, codes outside of every group
+    /// become null, which no comparison matches.
+    fn lhs(&self) -> String {
+        match self.recode {
+            Some(ref groups) => {
+                let cases = groups
+                    .iter()
+                    .map(|g| {
+                        format!(
+                            "when {} then {}",
+                            recode_group_condition(&self.var.name, g),
+                            g.code
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                format!("case {} end", cases.join(" "))
+            }
+            None => self.var.name.clone(),
+        }
+    }
+
     // A helper method to generate part of an SQL  'where' clause.
     pub fn to_sql(&self) -> String {
+        let lhs = self.lhs();
         self.comparison
             .iter()
-            .map(|c| format!("({})", c.to_sql(&self.var.name)))
+            .map(|c| format!("({})", c.to_sql(&lhs)))
             .collect::<Vec<String>>()
             .join(" or ") // by the definition of Condition, 'or' is, always correct.
     }
@@ -653,7 +1241,7 @@ pub fn tab_queries<R>(
     request: R,
     input_format: &InputType,
     platform: &DataPlatform,
-) -> Result<Vec<String>, MdError>
+) -> Result<Vec<TabQuery>, MdError>
 where
     R: DataRequest,
 {
@@ -666,12 +1254,37 @@ where
     Ok(queries)
 }
 
+/// A query for the weighted distribution of a request variable's values across the request
+/// samples, with `value` and `weight` columns ordered by value.
+///
+/// The distribution is over the records the request's tabulation counts, with its
+/// subpopulation, subsample and weights, so bins generated from it fit the table. The variable's
+/// missing and not in universe codes are left out, and monetary variables are adjusted the same
+/// way they are in tabulations.
+pub fn distribution_query(
+    ctx: &Context,
+    request: &impl DataRequest,
+    rq: &RequestVariable,
+    input_format: &InputType,
+    platform: &DataPlatform,
+) -> Result<String, MdError> {
+    let mut selects = Vec::new();
+    for sample in request.get_request_samples() {
+        let tb = TabBuilder::new(ctx, &sample.name, platform, input_format)?;
+        selects.push(tb.help_distribution_select(ctx, request, rq)?);
+    }
+    Ok(format!(
+        "select value, cast(sum(weight) as double) as weight from (\n{}\n)\nwhere value is not null\ngroup by value\norder by value",
+        selects.join("\nunion all\n")
+    ))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::input_schema_tabulation;
     use crate::request::context_from_names_helper;
-    use crate::request::SimpleRequest;
+    use crate::request::{AbacusRequest, SimpleRequest};
 
     #[test]
     fn test_bucketing() {
@@ -727,7 +1340,8 @@ mod test {
 
         uhrswork_rq.category_bins = Some(bins);
 
-        let bucket_fragment_result = tab_builder.help_bucket(&uhrswork_rq);
+        let bucket_fragment_result =
+            tab_builder.help_bucket(&uhrswork_rq, None, MissingCodeHandling::Include);
         assert!(bucket_fragment_result.is_ok());
         if let Ok(sql) = bucket_fragment_result {
             let correct = r"case
@@ -873,7 +1487,59 @@ else '999' end as UHRSWORK_bucketed";
         assert!(queries.is_ok());
         if let Ok(qs) = queries {
             assert_eq!(1, qs.len());
-            assert!(qs[0].contains("from"));
+            assert!(qs[0].sql.contains("from"));
+            assert!(qs[0].sql.contains("sum(PERWT) as weighted_ct"));
+            assert_eq!(Some("PERWT".to_string()), qs[0].weight_name);
+            assert_eq!(100, qs[0].weight_divisor);
+        }
+    }
+
+    #[test]
+    fn test_weight_selection() {
+        let request = |weight: &str| {
+            let mut request: serde_json::Value = serde_json::from_str(include_str!(
+                "../tests/requests/incwage_category_bins_us1940a.json"
+            ))
+            .unwrap();
+            request["weight"] = serde_json::from_str(weight).unwrap();
+            AbacusRequest::try_from_json(&request.to_string()).unwrap()
+        };
+        let query = |ctx: &Context, rq: AbacusRequest| {
+            tab_queries(ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+                .unwrap()
+                .remove(0)
+        };
+
+        // us1940a has a sample line, and layouts don't say which variables are on it.
+        let (ctx, rq) = request(r#""automatic""#);
+        let q = query(&ctx, rq);
+        assert_eq!(Some("SLWT".to_string()), q.weight_name);
+        assert!(!q.sql.contains("SELFWTSL"));
+
+        let (ctx, rq) = request(r#""unweighted""#);
+        let q = query(&ctx, rq);
+        assert_eq!(None, q.weight_name);
+        assert!(q.sql.contains("(SELFWTSL = 2)"));
+
+        let (ctx, rq) = request(r#"{"variable": "PERWT"}"#);
+        let q = query(&ctx, rq);
+        assert_eq!(Some("PERWT".to_string()), q.weight_name);
+        assert_eq!(100, q.weight_divisor);
+
+        // Metadata which says none of the variables are on the sample line gets the person
+        // weight.
+        let (ctx, mut rq) = request(r#""automatic""#);
+        for v in rq.request_variables.iter_mut() {
+            v.variable.sample_line = Some(false);
         }
+        let q = query(&ctx, rq);
+        assert_eq!(Some("PERWT".to_string()), q.weight_name);
+
+        let mut bad: serde_json::Value = serde_json::from_str(include_str!(
+            "../tests/requests/incwage_category_bins_us1940a.json"
+        ))
+        .unwrap();
+        bad["weight"] = serde_json::json!({"variable": "NOSUCHWT"});
+        assert!(AbacusRequest::try_from_json(&bad.to_string()).is_err());
     }
 }
@@ -385,7 +385,7 @@ impl SshConnectionPool {
 
     /// Close all connections (called automatically on drop)
     fn close_all_connections(&mut self) {
-        for (_, state) in &self.connections {
+        for state in self.connections.values() {
             if let ConnectionState::Connected { ssh_target } = state {
                 let control_path = self.control_path(ssh_target);
                 let _ = Command::new("ssh")
@@ -11,14 +11,23 @@
 //use serde_json::{to_string, Error};
 use crate::ipums_data_model::{self, RecordType};
 use crate::{
-    conventions,
+    bin_generation, conventions,
     conventions::Context,
+    derived::DerivedVariable,
     input_schema_tabulation,
-    input_schema_tabulation::{CategoryBin, GeneralDetailedSelection},
-    ipums_metadata_model::{IpumsDataType, IpumsDataset, IpumsVariable},
-    mderror::{metadata_error, parsing_error, MdError},
+    input_schema_tabulation::{
+        CategoryBin, GeneralDetailedSelection, MissingCodeHandling, RecodeCodes, RecodeGroup,
+        WeightSelection,
+    },
+    ipums_metadata_model::{IpumsDataType, IpumsDataset, IpumsValue, IpumsVariable},
+    mderror::{metadata_error, parsing_error, Entity, MdError},
+    monetary::{CpiTable, MonetaryStandardization},
     query_gen::Condition,
+    request_builder::AbacusRequestBuilder,
+    rollup::RollupVariable,
 };
+use std::collections::BTreeMap;
+use std::path::Path;
 
 // Given a set of variable and dataset names and a product name, produce a context loaded
 // with metadata just for those named parts and return copies of the IpumsVariable and IpumsSample structs.
@@ -41,7 +50,7 @@ pub fn context_from_names_helper(
             if let Some(id) = md.variables_by_name.get(&*rv.to_ascii_uppercase()) {
                 loaded_vars.push(md.variables_index[*id].clone());
             } else {
-                return Err(metadata_error!("Variable {rv} not in any loaded metadata."));
+                return Err(MdError::NotFound(Entity::Variable(rv.to_string())));
             }
         }
         loaded_vars
@@ -55,9 +64,7 @@ pub fn context_from_names_helper(
             if let Some(id) = md.datasets_by_name.get(*rd) {
                 loaded_datasets.push(md.datasets_index[*id].clone());
             } else {
-                return Err(metadata_error!(
-                    "No dataset named {rd} found in metadata or layouts!"
-                ));
+                return Err(MdError::NotFound(Entity::Dataset(rd.to_string())));
             }
         }
         loaded_datasets
@@ -77,6 +84,16 @@ pub struct RequestVariable {
     pub case_selection: Option<Condition>,
     pub attached_variable_pointer: Option<IpumsVariable>,
     pub category_bins: Option<Vec<CategoryBin>>,
+    /// Collapse the variable's codes into the new codes of these groups.
+    pub recode: Option<Vec<RecodeGroup>>,
+    /// Compute the variable's values from an expression rather than reading them from the data.
+    pub derived: Option<DerivedVariable>,
+    /// Summarize child records to compute the variable's values.
+    pub rollup: Option<RollupVariable>,
+    /// Adjust the variable's dollar amounts with the request's [MonetaryStandardization].
+    pub monetary_standardized: bool,
+    /// Codes which mean the value is missing or not in universe.
+    pub missing_codes: Vec<i64>,
     // extract_start is only useful to help order the request variables and
     // for producing a fixed-width output which we generally don't want.
     extract_start: Option<usize>,
@@ -94,13 +111,29 @@ impl RequestVariable {
     fn try_from_input_request_variable(
         ctx: &Context,
         category_bins: &Option<&Vec<CategoryBin>>,
+        recode: Option<&Vec<RecodeGroup>>,
+        derived_variables: &[(DerivedVariable, IpumsVariable)],
+        rollups: &[(RollupVariable, IpumsVariable)],
         input_rq: input_schema_tabulation::RequestVariable,
     ) -> Result<Self, MdError> {
-        let mut var = ctx.get_md_variable_by_name(&input_rq.variable_mnemonic)?;
-        // Currently we determine the general width from the input request, not
-        // from metadata
-        var.general_width = Some(input_rq.extract_width);
+        let derived = derived_variables
+            .iter()
+            .find(|(_, v)| v.name == input_rq.variable_mnemonic);
+        let rollup = rollups
+            .iter()
+            .find(|(_, v)| v.name == input_rq.variable_mnemonic);
+        let mut var = match (derived, rollup) {
+            (Some((_, var)), _) | (None, Some((_, var))) => var.clone(),
+            (None, None) => ctx.get_md_variable_by_name(&input_rq.variable_mnemonic)?,
+        };
+        // The input request's extract width overrides the general width from metadata,
+        // when it gives one.
+        if input_rq.extract_width > 0 {
+            var.general_width = Some(input_rq.extract_width);
+        }
         let mut rq = Self::try_from_ipums_variable(&var, input_rq.general_detailed_selection)?;
+        rq.derived = derived.map(|(d, _)| d.clone());
+        rq.rollup = rollup.map(|(r, _)| r.clone());
 
         // This is optional; the category bins could have been attached already by way of the IpumsVariable from ctx. If
         // we pass Some() then we're asking to over-ride anything coming from context.
@@ -108,11 +141,57 @@ impl RequestVariable {
             rq.category_bins = Some(bins.to_vec().clone());
         }
 
+        if let Some(groups) = recode {
+            if rq.category_bins.is_some() {
+                return Err(metadata_error!(
+                    "{} can't have both category bins and a recode",
+                    var.name
+                ));
+            }
+            RecodeGroup::validate_recode(groups)?;
+            rq.recode = Some(groups.clone());
+        }
+
+        if let Some(pointer) = input_rq.attached_variable_pointer {
+            if rq.derived.is_some() || rq.rollup.is_some() {
+                return Err(metadata_error!(
+                    "can't attach the computed variable {} to a family member",
+                    var.name
+                ));
+            }
+            let pointer_var = ctx.get_md_variable_by_name(pointer.pointer_variable())?;
+            if pointer_var.record_type != var.record_type {
+                return Err(metadata_error!(
+                    "can't attach {} through {} because they have different record types",
+                    var.name,
+                    pointer_var.name
+                ));
+            }
+            // Requests may name the attached variable themselves; otherwise follow the IPUMS
+            // convention of a suffix like EDUC_MOM.
+            rq.name = if input_rq.mnemonic.is_empty() || input_rq.mnemonic == var.name {
+                format!("{}_{}", var.name, pointer.suffix())
+            } else {
+                input_rq.mnemonic.clone()
+            };
+            rq.attached_variable_pointer = Some(pointer_var);
+        }
+
         if input_rq.case_selection {
+            // Conditions on an attached variable apply to the column the family member's value
+            // is joined in as.
+            let condition_var = IpumsVariable {
+                name: rq.source_column(),
+                ..var.clone()
+            };
             rq.case_selection = Condition::try_from_request_case_selections(
-                &var,
+                &condition_var,
                 &input_rq.request_case_selections,
-            )?;
+            )?
+            .map(|c| match rq.recode {
+                Some(ref groups) => c.with_recode(groups),
+                None => c,
+            });
         } else {
             rq.case_selection = None;
         }
@@ -125,10 +204,10 @@ impl RequestVariable {
         use_general: GeneralDetailedSelection,
     ) -> Result<Self, MdError> {
         if use_general == GeneralDetailedSelection::General && var.general_width.is_none() {
-            return Err(MdError::Msg(format!(
+            return Err(metadata_error!(
                 "requested the general version of variable {} which has no general width",
                 var.name
-            )));
+            ));
         }
         let general_divisor: usize = if let Some((_, w)) = var.formatting {
             if let Some(general_width) = var.general_width {
@@ -165,11 +244,36 @@ impl RequestVariable {
             case_selection: None,
             attached_variable_pointer: None,
             category_bins: var.category_bins.clone(),
+            recode: None,
+            derived: None,
+            rollup: None,
+            monetary_standardized: false,
+            missing_codes: var.missing_codes(),
             extract_start: None,
             extract_width: var.general_width,
         })
     }
 
+    /// Whether the variable's value comes from a family member through a pointer variable.
+    pub fn is_attached(&self) -> bool {
+        self.attached_variable_pointer.is_some()
+    }
+
+    /// The column queries read the variable's values from. Attached variables and roll-ups
+    /// are joined in under their own name, derived variables are computed from their
+    /// expressions, and other variables are read directly.
+    pub fn source_column(&self) -> String {
+        if let Some(ref derived) = self.derived {
+            derived.to_sql()
+        } else if let Some(ref rollup) = self.rollup {
+            rollup.column_sql()
+        } else if self.is_attached() {
+            self.name.clone()
+        } else {
+            self.variable.name.clone()
+        }
+    }
+
     pub fn is_general(&self) -> bool {
         GeneralDetailedSelection::General == self.general_detailed_selection
     }
@@ -213,8 +317,46 @@ impl RequestVariable {
         self.variable.name.clone()
     }
 
+    /// Whether the variable's values are grouped by category bins or a recode. Either way,
+    /// tabulations report the codes of the groups.
     pub fn is_bucketed(&self) -> bool {
-        self.category_bins.is_some()
+        self.category_bins.is_some() || self.recode.is_some()
+    }
+
+    /// The codes a tabulation of the variable can report, when they're known ahead of time:
+    /// the codes of its recode groups or category bins, or else its categories from the
+    /// metadata. Missing codes are left out when they're excluded from the tabulation. Returns
+    /// `None` for variables without category bins, recodes or category metadata.
+    pub fn category_codes(&self, missing_code_handling: MissingCodeHandling) -> Option<Vec<i64>> {
+        let mut codes: Vec<i64> = if let Some(ref groups) = self.recode {
+            groups.iter().map(|g| g.code as i64).collect()
+        } else if let Some(ref bins) = self.category_bins {
+            bins.iter().map(|b| b.code() as i64).collect()
+        } else {
+            let categories = self.variable.categories.as_ref()?;
+            let divisor = if self.is_general() {
+                self.general_divisor.max(1) as i64
+            } else {
+                1
+            };
+            categories
+                .iter()
+                .filter(|c| {
+                    missing_code_handling != MissingCodeHandling::Exclude || !c.meaning.is_missing()
+                })
+                .filter_map(|c| match c.value {
+                    IpumsValue::Integer(code) => Some(code / divisor),
+                    _ => None,
+                })
+                .collect()
+        };
+        // Bucketed variables report missing codes as they are when they're kept separate.
+        if self.is_bucketed() && missing_code_handling == MissingCodeHandling::Separate {
+            codes.extend(&self.missing_codes);
+        }
+        codes.sort();
+        codes.dedup();
+        Some(codes)
     }
 }
 
@@ -222,6 +364,8 @@ impl RequestVariable {
 pub struct RequestSample {
     pub sample: IpumsDataset,
     pub name: String,
+    /// Restrict the sample to a systematic subsample of households.
+    pub subsample: Option<Subsample>,
 }
 
 impl RequestSample {
@@ -229,10 +373,128 @@ impl RequestSample {
         Self {
             sample: ds.clone(),
             name: ds.name.clone(),
+            subsample: None,
         }
     }
 }
 
+/// A deterministic, systematic subsample of the households in a dataset.
+///
+/// Households are numbered from 1 in order of their serial numbers. With a ratio of
+/// `numerator / denominator` the subsample takes `numerator` households out of every
+/// `denominator`, starting at household number `first_household`. A ratio of 1/10 starting at
+/// household 3 selects households 3, 13, 23 and so on. Every person in a selected household is
+/// in the subsample.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Subsample {
+    pub numerator: u64,
+    pub denominator: u64,
+    pub first_household: u64,
+    /// Scale weighted counts by `denominator / numerator` so that they estimate totals for the
+    /// full dataset.
+    pub rescale_weights: bool,
+}
+
+impl Subsample {
+    /// Build the subsample described by an incoming request sample, if it asks for one.
+    pub fn try_from_input_request_sample(
+        rs: &input_schema_tabulation::RequestSample,
+    ) -> Result<Option<Self>, MdError> {
+        let Some(ref ratio) = rs.custom_sampling_ratio else {
+            if rs.first_household_sampled.is_some() {
+                return Err(parsing_error!(
+                    "first_household_sampled for sample {} requires a custom_sampling_ratio",
+                    rs.name
+                ));
+            }
+            return Ok(None);
+        };
+
+        let (numerator, denominator) = Self::parse_ratio(ratio)?;
+        let first_household = rs.first_household_sampled.unwrap_or(1) as u64;
+        if first_household == 0 {
+            return Err(parsing_error!(
+                "first_household_sampled must be at least 1 but is 0 for sample {}",
+                rs.name
+            ));
+        }
+        Ok(Some(Self {
+            numerator,
+            denominator,
+            first_household,
+            rescale_weights: rs.rescale_weights,
+        }))
+    }
+
+    /// Parse a sampling ratio into a reduced fraction of households to keep. The ratio may be a
+    /// fraction like "1/10" or "1:10", a decimal below 1 like "0.1", or a number of 1 or more
+    /// like "10" or "4.88" meaning one household in every that many.
+    ///
+    /// ```
+    /// use cimdea::request::Subsample;
+    ///
+    /// assert_eq!((1, 10), Subsample::parse_ratio("1/10").unwrap());
+    /// assert_eq!((1, 4), Subsample::parse_ratio("0.25").unwrap());
+    /// assert_eq!((1, 5), Subsample::parse_ratio("5.0").unwrap());
+    /// assert_eq!((25, 122), Subsample::parse_ratio("4.88").unwrap());
+    /// assert!(Subsample::parse_ratio("3/2").is_err());
+    /// ```
+    pub fn parse_ratio(ratio: &str) -> Result<(u64, u64), MdError> {
+        let invalid = || parsing_error!("invalid custom_sampling_ratio '{ratio}'");
+        let ratio = ratio.trim();
+
+        let (numerator, denominator) = if let Some((n, d)) = ratio.split_once(['/', ':']) {
+            let n: u64 = n.trim().parse().map_err(|_| invalid())?;
+            let d: u64 = d.trim().parse().map_err(|_| invalid())?;
+            (n, d)
+        } else {
+            let number = rust_decimal::Decimal::from_str_exact(ratio).map_err(|_| invalid())?;
+            let mantissa = u64::try_from(number.mantissa()).map_err(|_| invalid())?;
+            let scale = 10u64.checked_pow(number.scale()).ok_or_else(invalid)?;
+            if number >= rust_decimal::Decimal::ONE {
+                // One household in every `number`
+                (scale, mantissa)
+            } else {
+                (mantissa, scale)
+            }
+        };
+
+        if numerator == 0 || denominator == 0 || numerator > denominator {
+            return Err(parsing_error!(
+                "custom_sampling_ratio '{ratio}' must be greater than 0 and at most 1"
+            ));
+        }
+        let divisor = gcd(numerator, denominator);
+        Ok((numerator / divisor, denominator / divisor))
+    }
+
+    /// A SQL condition selecting the subsample. `household_key` is the column of household
+    /// serial numbers on the unit of analysis, and `household_table` and `household_id` are
+    /// the household table and its serial number column.
+    pub fn to_sql(&self, household_key: &str, household_table: &str, household_id: &str) -> String {
+        format!(
+            "{key} in (select {id} from \
+             (select {id}, row_number() over (order by {id}) as household_number from {table}) \
+             where household_number >= {first} \
+             and ((household_number - {first}) * {n}) % {d} < {n})",
+            key = household_key,
+            id = household_id,
+            table = household_table,
+            first = self.first_household,
+            n = self.numerator,
+            d = self.denominator
+        )
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
 pub enum CaseSelectLogic {
     And,
     Or,
@@ -260,6 +522,56 @@ pub trait DataRequest {
     fn get_request_samples(&self) -> Vec<RequestSample>;
     fn get_conditions(&self) -> Option<Vec<Condition>>;
 
+    /// The variables which select the cases to count, for requests which have them.
+    fn get_subpopulation(&self) -> Vec<RequestVariable> {
+        Vec::new()
+    }
+
+    /// The adjustment of monetary variables to constant dollars, if the request asks for one.
+    fn get_monetary_standardization(&self) -> Option<MonetaryStandardization> {
+        None
+    }
+
+    /// What to do with request variables' missing and not in universe codes.
+    fn missing_code_handling(&self) -> MissingCodeHandling {
+        MissingCodeHandling::Include
+    }
+
+    /// Whether tables should have rows with zero counts for the combinations of categories
+    /// which have no cases.
+    fn include_empty_cells(&self) -> bool {
+        false
+    }
+
+    /// The weight to apply to the counts.
+    fn weight_selection(&self) -> WeightSelection {
+        WeightSelection::Automatic
+    }
+
+    /// The record type whose records the tabulation counts. Requests without one use the
+    /// context's default unit of analysis.
+    fn unit_of_analysis(&self) -> Option<ipums_data_model::RecordType> {
+        None
+    }
+
+    /// The variables in the request which take their values from family members, including
+    /// those used only in conditions.
+    fn get_attached_variables(&self) -> Vec<RequestVariable> {
+        self.get_request_variables()
+            .into_iter()
+            .filter(|v| v.is_attached())
+            .collect()
+    }
+
+    /// The variables in the request which summarize child records, including those used only
+    /// in conditions.
+    fn get_rollup_variables(&self) -> Vec<RequestVariable> {
+        self.get_request_variables()
+            .into_iter()
+            .filter(|v| v.rollup.is_some())
+            .collect()
+    }
+
     /// Convert to the Tractor / generic IPUMS representation
     fn serialize_to_ipums_json(&self) -> String;
 
@@ -373,6 +685,10 @@ pub struct AbacusRequest {
     pub output_format: OutputFormat,
     pub use_general_variables: bool,
     pub data_root: Option<String>,
+    pub monetary_standardization: Option<MonetaryStandardization>,
+    pub missing_code_handling: MissingCodeHandling,
+    pub include_empty_cells: bool,
+    pub weight: WeightSelection,
 }
 
 impl DataRequest for AbacusRequest {
@@ -392,6 +708,48 @@ impl DataRequest for AbacusRequest {
         self.request_samples.clone()
     }
 
+    fn get_subpopulation(&self) -> Vec<RequestVariable> {
+        self.subpopulation.clone()
+    }
+
+    fn get_attached_variables(&self) -> Vec<RequestVariable> {
+        self.request_variables
+            .iter()
+            .chain(&self.subpopulation)
+            .filter(|v| v.is_attached())
+            .cloned()
+            .collect()
+    }
+
+    fn get_rollup_variables(&self) -> Vec<RequestVariable> {
+        self.request_variables
+            .iter()
+            .chain(&self.subpopulation)
+            .filter(|v| v.rollup.is_some())
+            .cloned()
+            .collect()
+    }
+
+    fn get_monetary_standardization(&self) -> Option<MonetaryStandardization> {
+        self.monetary_standardization.clone()
+    }
+
+    fn missing_code_handling(&self) -> MissingCodeHandling {
+        self.missing_code_handling
+    }
+
+    fn include_empty_cells(&self) -> bool {
+        self.include_empty_cells
+    }
+
+    fn weight_selection(&self) -> WeightSelection {
+        self.weight.clone()
+    }
+
+    fn unit_of_analysis(&self) -> Option<ipums_data_model::RecordType> {
+        Some(self.unit_rectype.clone())
+    }
+
     fn get_conditions(&self) -> Option<Vec<Condition>> {
         let conditions = self
             .subpopulation
@@ -449,6 +807,114 @@ impl DataRequest for AbacusRequest {
             lines.push(format!("{}\t\t{} -- {}", v.name, &label, &general_detailed));
         }
 
+        let missing_code_handling = match self.missing_code_handling() {
+            MissingCodeHandling::Include => None,
+            MissingCodeHandling::Exclude => Some("excluded from the tabulation"),
+            MissingCodeHandling::Separate => Some("reported in separate rows"),
+        };
+        if let Some(handling) = missing_code_handling {
+            lines.push("\n\nMissing and not in universe codes:".to_string());
+            for v in self.get_request_variables() {
+                if !v.missing_codes.is_empty() {
+                    let codes = v
+                        .missing_codes
+                        .iter()
+                        .map(|code| code.to_string())
+                        .collect::<Vec<_>>();
+                    lines.push(format!("{}: {} {}", v.name, codes.join(", "), handling));
+                }
+            }
+        }
+
+        if self.include_empty_cells {
+            lines.push(
+                "\n\nTables include every combination of categories, with counts of zero for \
+                 combinations without any cases."
+                    .to_string(),
+            );
+        }
+
+        match self.weight {
+            WeightSelection::Automatic => (),
+            WeightSelection::Unweighted => lines.push("\n\nCounts are unweighted.".to_string()),
+            WeightSelection::Variable(ref weight) => {
+                lines.push(format!("\n\nCounts are weighted by {weight}."))
+            }
+        }
+
+        let derived = self
+            .get_request_variables()
+            .into_iter()
+            .chain(self.subpopulation.iter().cloned())
+            .filter_map(|v| v.derived.map(|d| (v.name, d)))
+            .collect::<Vec<_>>();
+        if !derived.is_empty() {
+            lines.push("\n\nDerived variables:".to_string());
+            for (name, d) in derived {
+                lines.push(format!("{name} = {}", d.expression));
+            }
+        }
+
+        let rollups = self.get_rollup_variables();
+        if !rollups.is_empty() {
+            lines.push("\n\nRoll-ups:".to_string());
+            for v in rollups {
+                lines.push(format!(
+                    "{}: {}",
+                    v.name,
+                    v.variable.label.unwrap_or_default()
+                ));
+            }
+        }
+
+        let recoded = self
+            .get_request_variables()
+            .into_iter()
+            .chain(self.subpopulation.iter().cloned())
+            .filter_map(|v| v.recode.map(|groups| (v.name, groups)))
+            .collect::<Vec<_>>();
+        if !recoded.is_empty() {
+            lines.push("\n\nRecodes:".to_string());
+            for (name, groups) in recoded {
+                lines.push(format!("{name}:"));
+                for g in groups {
+                    let codes = g
+                        .codes
+                        .iter()
+                        .map(|c| match c {
+                            RecodeCodes::Code(code) => code.to_string(),
+                            RecodeCodes::Range { low, high } => format!("{low}-{high}"),
+                        })
+                        .collect::<Vec<_>>();
+                    lines.push(format!(
+                        "\t{}\t{}: {}",
+                        g.code,
+                        g.value_label,
+                        codes.join(", ")
+                    ));
+                }
+            }
+        }
+
+        if let Some(ms) = self.get_monetary_standardization() {
+            lines.push("\n\nMonetary standardization:".to_string());
+            for v in self.get_request_variables() {
+                if v.monetary_standardized {
+                    lines.push(format!("{} {}", v.name, ms));
+                }
+            }
+            for s in self.get_request_samples() {
+                let adjustment = match MonetaryStandardization::income_year(&s.sample) {
+                    Ok(year) => match ms.factor_for_dataset(&s.sample) {
+                        Ok(factor) => format!("{year} dollars multiplied by {factor}"),
+                        Err(err) => err.to_string(),
+                    },
+                    Err(err) => err.to_string(),
+                };
+                lines.push(format!("{}: {}", &s.name, adjustment));
+            }
+        }
+
         lines.push("\n\nSubpopulation filters:\n".to_string());
         if let Some(ref conditions) = self.get_conditions() {
             if !conditions.is_empty() {
@@ -517,6 +983,10 @@ impl DataRequest for AbacusRequest {
                 subpopulation: Vec::new(),
                 use_general_variables: false,
                 data_root: optional_data_root,
+                monetary_standardization: None,
+                missing_code_handling: MissingCodeHandling::Include,
+                include_empty_cells: false,
+                weight: WeightSelection::Automatic,
             },
         ))
     }
@@ -527,40 +997,88 @@ impl DataRequest for AbacusRequest {
 }
 
 impl AbacusRequest {
+    /// Start building a request in code against a context with metadata loaded, checking names
+    /// as they're added.
+    ///
+    /// ```
+    /// use cimdea::conventions::Context;
+    /// use cimdea::input_schema_tabulation::CategoryBin;
+    /// use cimdea::request::AbacusRequest;
+    ///
+    /// let mut ctx =
+    ///     Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+    ///         .unwrap();
+    /// ctx.load_metadata_for_datasets(&["us1900m"]).unwrap();
+    ///
+    /// let request = AbacusRequest::builder(&ctx)
+    ///     .sample("us1900m")
+    ///     .var("MARST")
+    ///     .bin("AGE", CategoryBin::parse_list("0-17,18-64,65+").unwrap())
+    ///     .var("AGE")
+    ///     .filter("STATEFIP", ["48".parse().unwrap()])
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(request.request_variables.len(), 2);
+    ///
+    /// let err = AbacusRequest::builder(&ctx).sample("us1900m").var("MARTS").build();
+    /// assert_eq!(err.unwrap_err().field(), Some("request_variables[0]"));
+    /// ```
+    pub fn builder(ctx: &Context) -> AbacusRequestBuilder<'_> {
+        AbacusRequestBuilder::new(ctx)
+    }
+
     /// Parse an `AbacusRequest` from JSON.
     ///
     /// For example JSON inputs, check out the tests/requests/ directory.
     pub fn try_from_json(input: &str) -> Result<(conventions::Context, Self), MdError> {
-        let request: input_schema_tabulation::AbacusRequest = match serde_json::from_str(input) {
-            Ok(request) => request,
-            Err(err) => {
-                return Err(MdError::Msg(format!(
-                    "Error deserializing request: '{err}'"
-                )));
-            }
-        };
+        Self::try_from_input(Self::parse_json(input)?)
+    }
 
+    /// Create a context for an incoming request's product and samples, and resolve the request
+    /// against it. This is how to run a request built in code rather than parsed from JSON.
+    pub fn try_from_input(
+        request: input_schema_tabulation::AbacusRequest,
+    ) -> Result<(conventions::Context, Self), MdError> {
         let mut ctx = conventions::Context::from_ipums_collection_name(
             &request.product,
             None,
             request.data_root.clone(),
-        )?;
-
-        let requested_dataset_names: Vec<_> = request
-            .request_samples
-            .iter()
-            .map(|rs| rs.name.as_str())
-            .collect();
+        )
+        .map_err(|err| err.in_request("product"))?;
 
         // Use the names of the requested samples to load partial metadata
-        ctx.load_metadata_for_datasets(requested_dataset_names.as_slice())?;
+        ctx.load_metadata_for_datasets(request.sample_names().as_slice())?;
+
+        let abacus_request = Self::try_from_input_request(&ctx, request)?;
+        Ok((ctx, abacus_request))
+    }
+
+    /// Deserialize JSON into the incoming request model without resolving it against metadata.
+    ///
+    /// This is useful when you already have a [Context] with metadata loaded, for instance in a
+    /// long-running service. Pass the result to [try_from_input_request](Self::try_from_input_request).
+    pub fn parse_json(input: &str) -> Result<input_schema_tabulation::AbacusRequest, MdError> {
+        serde_json::from_str(input)
+            .map_err(|err| parsing_error!("Error deserializing request: '{err}'"))
+    }
 
+    /// Resolve an incoming request against a context which already has metadata loaded for the
+    /// requested samples.
+    pub fn try_from_input_request(
+        ctx: &Context,
+        request: input_schema_tabulation::AbacusRequest,
+    ) -> Result<Self, MdError> {
         // With metadata loaded, we can fully instantiate the RequestVariables and RequestSamples
         let uoa = if let Some(u) = ctx.settings.record_types.clone().get(&request.uoa) {
             u.clone()
         } else {
-            return Err(metadata_error!("No record type for uoa."));
+            return Err(MdError::NotFound(Entity::RecordType(request.uoa)).in_request("uoa"));
         };
+        if let WeightSelection::Variable(ref weight) = request.weight {
+            if let Err(err) = ctx.get_md_variable_by_name(weight) {
+                return Err(err.in_request("weight"));
+            }
+        }
 
         let Some(ref md) = &ctx.settings.metadata else {
             return Err(metadata_error!(
@@ -569,47 +1087,183 @@ impl AbacusRequest {
         };
 
         let mut rqs = Vec::new();
-        for p in request.request_samples {
+        for (index, p) in request.request_samples.into_iter().enumerate() {
+            let field = || format!("request_samples[{index}]");
+            let subsample = Subsample::try_from_input_request_sample(&p)
+                .map_err(|err| err.in_request(field()))?;
             let name = p.name;
             let Some(ipums_ds) = md.cloned_dataset_from_name(&name) else {
-                return Err(metadata_error!("No metadata for dataset named {name}"));
+                return Err(MdError::NotFound(Entity::Dataset(name)).in_request(field()));
             };
 
             rqs.push(RequestSample {
                 name: name.to_string(),
                 sample: ipums_ds,
+                subsample,
             });
         }
 
+        let derived_variables = request
+            .derived_variables
+            .iter()
+            .enumerate()
+            .map(|(index, d)| {
+                DerivedVariable::try_new(ctx, &d.name, &d.expression, d.label.clone())
+                    .map_err(|err| err.in_request(format!("derived_variables[{index}]")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let rollups = request
+            .rollups
+            .iter()
+            .enumerate()
+            .map(|(index, r)| {
+                RollupVariable::try_new(ctx, r)
+                    .map_err(|err| err.in_request(format!("rollups[{index}]")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (name, count) in derived_variables
+            .iter()
+            .map(|(_, v)| &v.name)
+            .chain(rollups.iter().map(|(_, v)| &v.name))
+            .fold(BTreeMap::new(), |mut counts, name| {
+                *counts.entry(name).or_insert(0) += 1;
+                counts
+            })
+        {
+            if count > 1 {
+                return Err(metadata_error!(
+                    "more than one derived variable or roll-up is named {name}"
+                ));
+            }
+        }
+
         let mut rqv = Vec::new();
-        for v in request.request_variables {
+        for (index, v) in request.request_variables.into_iter().enumerate() {
             // The category_bins can also come from the IpumsVariable as it's properly part of metadata. However in the request
             // for Abacus we pass category bins on each request for all request variables that need them.
             let bins = request.category_bins.get(&v.variable_mnemonic);
-            let request_var = RequestVariable::try_from_input_request_variable(&ctx, &bins, v)?;
+            let recode = request.recodes.get(&v.variable_mnemonic);
+            let mut request_var = RequestVariable::try_from_input_request_variable(
+                ctx,
+                &bins,
+                recode,
+                &derived_variables,
+                &rollups,
+                v,
+            )
+            .map_err(|err| err.in_request(format!("request_variables[{index}]")))?;
+            if let Some(codes) = request.missing_codes.get(&request_var.variable.name) {
+                request_var.missing_codes.extend(codes);
+                request_var.missing_codes.sort();
+                request_var.missing_codes.dedup();
+            }
+            if let Some(ref ms) = request.monetary_standardization {
+                request_var.monetary_standardized = request_var.variable.monetary
+                    || ms.variables.contains(&request_var.variable.name);
+            }
             rqv.push(request_var);
         }
 
         let mut subpop = Vec::new();
-        for s in request.subpopulation {
+        for (index, s) in request.subpopulation.into_iter().enumerate() {
             let bins = request.category_bins.get(&s.variable_mnemonic);
-            let spv = RequestVariable::try_from_input_request_variable(&ctx, &bins, s)?;
+            let recode = request.recodes.get(&s.variable_mnemonic);
+            let spv = RequestVariable::try_from_input_request_variable(
+                ctx,
+                &bins,
+                recode,
+                &derived_variables,
+                &rollups,
+                s,
+            )
+            .map_err(|err| err.in_request(format!("subpopulation[{index}]")))?;
             subpop.push(spv);
         }
 
-        Ok((
-            ctx,
-            Self {
-                product: request.product,
-                request_variables: rqv,
-                request_samples: rqs,
-                subpopulation: subpop,
-                output_format: OutputFormat::Json,
-                use_general_variables: true,
-                unit_rectype: uoa.clone(),
-                data_root: request.data_root,
-            },
-        ))
+        for name in request.recodes.keys() {
+            if !rqv.iter().chain(&subpop).any(|v| &v.variable.name == name) {
+                return Err(metadata_error!(
+                    "recode given for {name}, which isn't a request or subpopulation variable"
+                )
+                .in_request(format!("recodes.{name}")));
+            }
+        }
+
+        for name in request.missing_codes.keys() {
+            if !rqv.iter().any(|v| &v.variable.name == name) {
+                return Err(metadata_error!(
+                    "missing codes given for {name}, which isn't a request variable"
+                )
+                .in_request(format!("missing_codes.{name}")));
+            }
+        }
+
+        let monetary_standardization = match request.monetary_standardization {
+            Some(ms) => {
+                for name in &ms.variables {
+                    if !rqv.iter().any(|v| &v.variable.name == name) {
+                        return Err(metadata_error!(
+                            "can't standardize {name} because it isn't a request variable"
+                        )
+                        .in_request("monetary_standardization.variables"));
+                    }
+                }
+                // A relative CPI file path is relative to the current directory, like data_root.
+                let cpi = match ms.cpi_file {
+                    Some(ref cpi_file) => CpiTable::try_from_csv_file(Path::new(cpi_file))?,
+                    None => CpiTable::builtin(),
+                };
+                Some(MonetaryStandardization {
+                    base_year: ms.base_year,
+                    cpi,
+                })
+            }
+            None => None,
+        };
+
+        for name in request.generated_bins.keys() {
+            if request.category_bins.contains_key(name) {
+                return Err(metadata_error!(
+                    "{name} has both category bins and generated bins; give only one"
+                )
+                .in_request(format!("generated_bins.{name}")));
+            }
+            if !rqv.iter().any(|v| &v.variable.name == name) {
+                return Err(metadata_error!(
+                    "can't generate bins for {name} because it isn't a request variable"
+                )
+                .in_request(format!("generated_bins.{name}")));
+            }
+        }
+
+        let mut abacus_request = Self {
+            product: request.product,
+            request_variables: rqv,
+            request_samples: rqs,
+            subpopulation: subpop,
+            output_format: OutputFormat::Json,
+            use_general_variables: true,
+            unit_rectype: uoa.clone(),
+            data_root: request.data_root,
+            monetary_standardization,
+            missing_code_handling: request.missing_code_handling,
+            include_empty_cells: request.include_empty_cells,
+            weight: request.weight,
+        };
+
+        // Bins are generated from the records the finished request counts.
+        for (name, method) in &request.generated_bins {
+            for index in 0..abacus_request.request_variables.len() {
+                let v = &abacus_request.request_variables[index];
+                if &v.variable.name != name {
+                    continue;
+                }
+                let bins = bin_generation::generate_category_bins(ctx, &abacus_request, v, method)
+                    .map_err(|err| err.in_request(format!("generated_bins.{name}")))?;
+                abacus_request.request_variables[index].category_bins = Some(bins);
+            }
+        }
+        Ok(abacus_request)
     }
 }
 
@@ -708,6 +1362,10 @@ impl DataRequest for SimpleRequest {
         self.conditions.clone()
     }
 
+    fn unit_of_analysis(&self) -> Option<ipums_data_model::RecordType> {
+        Some(self.unit_rectype.clone())
+    }
+
     #[allow(refining_impl_trait)]
     fn deserialize_from_ipums_json(
         ctx: &conventions::Context,
@@ -716,12 +1374,7 @@ impl DataRequest for SimpleRequest {
     ) -> Result<Self, MdError> {
         let parsed: serde_json::Value = match serde_json::from_str(json_request) {
             Ok(parsed) => parsed,
-            Err(e) => {
-                return Err(MdError::Msg(format!(
-                    "Error deserializing request: '{}'",
-                    e
-                )))
-            }
+            Err(e) => return Err(parsing_error!("Error deserializing request: '{}'", e)),
         };
 
         let Some(product) = parsed["product"].as_str() else {
@@ -760,9 +1413,9 @@ impl DataRequest for SimpleRequest {
                 if let Some(var_value) = md.cloned_variable_from_name(variable_mnemonic) {
                     checked_vars.push(var_value);
                 } else {
-                    return Err(metadata_error!(
-                        "No variable '{variable_mnemonic}' in metadata."
-                    ));
+                    return Err(MdError::NotFound(Entity::Variable(
+                        variable_mnemonic.to_string(),
+                    )));
                 }
             }
             checked_vars
@@ -780,7 +1433,7 @@ impl DataRequest for SimpleRequest {
                 if let Some(ipums_ds) = md.cloned_dataset_from_name(ds_name) {
                     checked_samples.push(ipums_ds);
                 } else {
-                    return Err(metadata_error!("No dataset '{ds_name}' in metadata."));
+                    return Err(MdError::NotFound(Entity::Dataset(ds_name.to_string())));
                 }
             }
             checked_samples
@@ -821,6 +1474,7 @@ impl DataRequest for SimpleRequest {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::mderror::ErrorCode;
 
     #[test]
     pub fn test_deserialize_into_simple_request() {
@@ -898,6 +1552,19 @@ mod test {
         assert!(abacus_request.is_ok());
     }
 
+    #[test]
+    fn test_abacus_request_from_json_errors() {
+        let err = AbacusRequest::try_from_json("{\"product\": ").unwrap_err();
+        assert_eq!(ErrorCode::Parsing, err.code());
+
+        let json_request = include_str!("../tests/requests/usa_abacus_request.json");
+        let mut request: serde_json::Value = serde_json::from_str(json_request).unwrap();
+        request["product"] = serde_json::json!("nope");
+        let err = AbacusRequest::try_from_json(&request.to_string()).unwrap_err();
+        assert_eq!(ErrorCode::Parsing, err.code());
+        assert_eq!(Some("product"), err.field());
+    }
+
     /// It's an error if the given unit of analysis is not present as a record
     /// type in the context.
     #[test]
@@ -932,6 +1599,9 @@ mod test {
             general_width: Some(5),
             description: None,
             category_bins: None,
+            restrictions: None,
+            monetary: false,
+            sample_line: None,
         };
 
         let result =
@@ -952,6 +1622,9 @@ mod test {
             general_width: Some(2),
             description: None,
             category_bins: None,
+            restrictions: None,
+            monetary: false,
+            sample_line: None,
         };
 
         let rqv =
@@ -976,6 +1649,9 @@ mod test {
             general_width: Some(2),
             description: None,
             category_bins: None,
+            restrictions: None,
+            monetary: false,
+            sample_line: None,
         };
 
         let rqv =
@@ -1000,6 +1676,9 @@ mod test {
             general_width: Some(2),
             description: None,
             category_bins: None,
+            restrictions: None,
+            monetary: false,
+            sample_line: None,
         };
 
         let rqv =
@@ -1026,6 +1705,9 @@ mod test {
             general_width: None,
             description: None,
             category_bins: None,
+            restrictions: None,
+            monetary: false,
+            sample_line: None,
         };
 
         let rqv =
@@ -1055,13 +1737,61 @@ mod test {
             general_width: None,
             description: None,
             category_bins: None,
+            restrictions: None,
+            monetary: false,
+            sample_line: None,
         };
 
         let result =
             RequestVariable::try_from_ipums_variable(&variable, GeneralDetailedSelection::General);
-        result.expect_err(
+        let err = result.expect_err(
             "should not convert into a RequestVariable because we don't have a \
             general width but requested the general version of the variable",
         );
+        assert_eq!(ErrorCode::Metadata, err.code());
+    }
+
+    fn input_sample(
+        ratio: Option<&str>,
+        first: Option<usize>,
+    ) -> input_schema_tabulation::RequestSample {
+        input_schema_tabulation::RequestSample {
+            name: "us1900m".to_string(),
+            custom_sampling_ratio: ratio.map(|r| r.to_string()),
+            first_household_sampled: first,
+            rescale_weights: false,
+        }
+    }
+
+    #[test]
+    fn test_subsample_from_input_request_sample() {
+        let none = Subsample::try_from_input_request_sample(&input_sample(None, None)).unwrap();
+        assert_eq!(None, none);
+
+        let subsample =
+            Subsample::try_from_input_request_sample(&input_sample(Some("2:20"), Some(4)))
+                .unwrap()
+                .expect("should have a subsample");
+        assert_eq!(1, subsample.numerator);
+        assert_eq!(10, subsample.denominator);
+        assert_eq!(4, subsample.first_household);
+
+        let defaults = Subsample::try_from_input_request_sample(&input_sample(Some("0.3"), None))
+            .unwrap()
+            .expect("should have a subsample");
+        assert_eq!((3, 10), (defaults.numerator, defaults.denominator));
+        assert_eq!(1, defaults.first_household);
+    }
+
+    #[test]
+    fn test_subsample_from_input_request_sample_errors() {
+        Subsample::try_from_input_request_sample(&input_sample(None, Some(5)))
+            .expect_err("a first household without a ratio is an error");
+        Subsample::try_from_input_request_sample(&input_sample(Some("1/10"), Some(0)))
+            .expect_err("household numbers start at 1");
+        for ratio in ["0/5", "1/0", "3/2", "0", "-2", "one in ten", ""] {
+            Subsample::parse_ratio(ratio)
+                .expect_err(&format!("'{ratio}' should not be a valid ratio"));
+        }
     }
 }
@@ -0,0 +1,366 @@
+//! Build an [AbacusRequest] in code.
+//!
+//! The builder collects an incoming request, checking each name against the context's metadata
+//! as it's added, and resolves it with
+//! [AbacusRequest::try_from_input_request](crate::request::AbacusRequest::try_from_input_request)
+//! when it's built. Builder methods don't return errors, so that calls can be chained. The first
+//! problem is kept, with the path to the part of the request it's about, and returned by
+//! [build](AbacusRequestBuilder::build).
+use crate::conventions::Context;
+use crate::derived::DerivedVariable;
+use crate::input_schema_tabulation::{
+    self, BinGeneration, CategoryBin, GeneralDetailedSelection, MissingCodeHandling,
+    RequestCaseSelection, RequestSample, RequestVariable, WeightSelection,
+};
+use crate::mderror::{metadata_error, Entity, MdError};
+use crate::request::AbacusRequest;
+use crate::validation;
+
+/// Builds an [AbacusRequest] against a context with metadata loaded for the samples it will
+/// name. Create one with [AbacusRequest::builder].
+#[derive(Debug)]
+pub struct AbacusRequestBuilder<'a> {
+    ctx: &'a Context,
+    request: input_schema_tabulation::AbacusRequest,
+    error: Option<MdError>,
+}
+
+impl<'a> AbacusRequestBuilder<'a> {
+    /// A request for the context's product, counting its default unit of analysis with
+    /// automatic weights.
+    pub fn new(ctx: &'a Context) -> Self {
+        let mut request = input_schema_tabulation::AbacusRequest::new(
+            &ctx.name,
+            &ctx.settings.default_unit_of_analysis.value,
+        );
+        request.data_root = ctx
+            .data_root
+            .as_ref()
+            .map(|path| path.display().to_string());
+        Self {
+            ctx,
+            request,
+            error: None,
+        }
+    }
+
+    /// Keep the first problem found while building.
+    fn check(&mut self, result: Result<(), MdError>, field: String) {
+        if let (None, Err(err)) = (&self.error, result) {
+            self.error = Some(err.in_request(field));
+        }
+    }
+
+    /// Check that the name is a variable in the metadata or a derived variable already added.
+    fn check_variable(&self, name: &str) -> Result<(), MdError> {
+        if self
+            .request
+            .derived_variables
+            .iter()
+            .any(|d| d.name == name)
+        {
+            return Ok(());
+        }
+        self.ctx.get_md_variable_by_name(name).map(|_| ())
+    }
+
+    /// Count records of this record type, like "H" for households.
+    pub fn uoa(mut self, record_type: &str) -> Self {
+        let result = match self.ctx.settings.record_types.get(record_type) {
+            Some(_) => Ok(()),
+            None => Err(MdError::NotFound(Entity::RecordType(
+                record_type.to_string(),
+            ))),
+        };
+        self.check(result, "uoa".to_string());
+        self.request.uoa = record_type.to_string();
+        self
+    }
+
+    /// Tabulate this sample. The context must already have metadata for it.
+    pub fn sample(mut self, name: &str) -> Self {
+        let result = if self.ctx.has_metadata_for_dataset(name) {
+            Ok(())
+        } else {
+            Err(MdError::NotFound(Entity::Dataset(name.to_string())))
+        };
+        let field = format!("request_samples[{}]", self.request.request_samples.len());
+        self.check(result, field);
+        self.request.request_samples.push(RequestSample::new(name));
+        self
+    }
+
+    fn add_variable(mut self, variable: RequestVariable) -> Self {
+        let result = self.check_variable(&variable.variable_mnemonic);
+        let field = format!(
+            "request_variables[{}]",
+            self.request.request_variables.len()
+        );
+        self.check(result, field);
+        self.request.request_variables.push(variable);
+        self
+    }
+
+    /// Tabulate the detailed codes of a variable.
+    pub fn var(self, name: &str) -> Self {
+        self.add_variable(RequestVariable::new(name))
+    }
+
+    /// Tabulate the general codes of a variable, using the general width from the metadata.
+    pub fn general(mut self, name: &str) -> Self {
+        let result = match self.ctx.get_md_variable_by_name(name) {
+            Ok(var) if var.general_width.is_none() => Err(metadata_error!(
+                "{name} has no general width in the metadata; give one with general_with_width"
+            )),
+            Ok(_) => Ok(()),
+            // add_variable reports unknown variables.
+            Err(_) => Ok(()),
+        };
+        let field = format!(
+            "request_variables[{}]",
+            self.request.request_variables.len()
+        );
+        self.check(result, field);
+        let mut variable = RequestVariable::new(name);
+        variable.general_detailed_selection = GeneralDetailedSelection::General;
+        self.add_variable(variable)
+    }
+
+    /// Tabulate the general codes of a variable, which are its first `width` digits.
+    pub fn general_with_width(self, name: &str, width: usize) -> Self {
+        let mut variable = RequestVariable::new(name);
+        variable.general_detailed_selection = GeneralDetailedSelection::General;
+        variable.extract_width = width;
+        self.add_variable(variable)
+    }
+
+    /// Tabulate a variable in bins of values rather than by code.
+    pub fn bin(mut self, name: &str, bins: Vec<CategoryBin>) -> Self {
+        let result = self.check_variable(name);
+        self.check(result, format!("category_bins.{name}"));
+        self.request.category_bins.insert(name.to_string(), bins);
+        self
+    }
+
+    /// Tabulate a variable in bins generated from its distribution in the data.
+    pub fn generate_bins(mut self, name: &str, method: BinGeneration) -> Self {
+        let result = self.check_variable(name);
+        self.check(result, format!("generated_bins.{name}"));
+        self.request.generated_bins.insert(name.to_string(), method);
+        self
+    }
+
+    /// Only count records whose value of the variable is in one of the selections.
+    pub fn filter(
+        mut self,
+        name: &str,
+        selections: impl IntoIterator<Item = RequestCaseSelection>,
+    ) -> Self {
+        let mut variable = RequestVariable::new(name);
+        variable.case_selection = true;
+        variable.request_case_selections = selections.into_iter().collect();
+        let result = match self.check_variable(name) {
+            Ok(()) if variable.request_case_selections.is_empty() => Err(metadata_error!(
+                "the filter on {name} doesn't select any codes"
+            )),
+            result => result,
+        };
+        let field = format!("subpopulation[{}]", self.request.subpopulation.len());
+        self.check(result, field);
+        self.request.subpopulation.push(variable);
+        self
+    }
+
+    /// Define a variable from an expression over other variables, like `FTOTINC / FAMSIZE`.
+    /// Later calls can use it by name.
+    pub fn derived(mut self, name: &str, expression: &str) -> Self {
+        let result = DerivedVariable::try_new(self.ctx, name, expression, None).map(|_| ());
+        let field = format!(
+            "derived_variables[{}]",
+            self.request.derived_variables.len()
+        );
+        self.check(result, field);
+        self.request
+            .derived_variables
+            .push(input_schema_tabulation::DerivedVariable {
+                name: name.to_string(),
+                expression: expression.to_string(),
+                label: None,
+            });
+        self
+    }
+
+    /// Weight the counts, by default with the unit of analysis's weight variable.
+    pub fn weight(mut self, weight: WeightSelection) -> Self {
+        if let WeightSelection::Variable(ref name) = weight {
+            let result = self.ctx.get_md_variable_by_name(name).map(|_| ());
+            self.check(result, "weight".to_string());
+        }
+        self.request.weight = weight;
+        self
+    }
+
+    /// What to do with request variables' missing and not in universe codes.
+    pub fn missing_code_handling(mut self, handling: MissingCodeHandling) -> Self {
+        self.request.missing_code_handling = handling;
+        self
+    }
+
+    /// Report every combination of known categories, including those without any cases.
+    pub fn include_empty_cells(mut self, include: bool) -> Self {
+        self.request.include_empty_cells = include;
+        self
+    }
+
+    /// The incoming request built so far, for instance to serialize it to JSON.
+    pub fn input(&self) -> &input_schema_tabulation::AbacusRequest {
+        &self.request
+    }
+
+    /// Resolve the request against the context, or return the first problem found.
+    pub fn build(self) -> Result<AbacusRequest, MdError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        if self.request.request_samples.is_empty() {
+            return Err(metadata_error!("the request needs at least one sample")
+                .in_request("request_samples"));
+        }
+        if self.request.request_variables.is_empty() {
+            return Err(metadata_error!("the request needs at least one variable")
+                .in_request("request_variables"));
+        }
+        let request = AbacusRequest::try_from_input_request(self.ctx, self.request)?;
+
+        // Every variable must be on records the query can join to the unit of analysis.
+        let uoa = &request.unit_rectype.value;
+        let variables = request
+            .request_variables
+            .iter()
+            .enumerate()
+            .map(|(index, rq)| (format!("request_variables[{index}]"), rq))
+            .chain(
+                request
+                    .subpopulation
+                    .iter()
+                    .enumerate()
+                    .map(|(index, rq)| (format!("subpopulation[{index}]"), rq)),
+            );
+        for (field, rq) in variables {
+            let record_type = &rq.variable.record_type;
+            let attached = rq.attached_variable_pointer.is_some();
+            if !validation::is_joinable(self.ctx, record_type, attached, uoa) {
+                return Err(metadata_error!(
+                    "{} is on {record_type} records, which can't be joined to the {uoa} records counted by the unit of analysis",
+                    rq.name
+                )
+                .in_request(field));
+            }
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mderror::ErrorCode;
+    use crate::tabulate;
+
+    fn context() -> Context {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .unwrap();
+        ctx.load_metadata_for_datasets(&["us1900m", "us1940a"])
+            .unwrap();
+        ctx
+    }
+
+    #[test]
+    fn test_build_and_tabulate() {
+        let ctx = context();
+        let request = AbacusRequest::builder(&ctx)
+            .sample("us1900m")
+            .var("MARST")
+            .general_with_width("RELATE", 2)
+            .bin("AGE", CategoryBin::parse_list("0-17,18-64,65+").unwrap())
+            .var("AGE")
+            .filter("STATEFIP", ["48".parse().unwrap()])
+            .build()
+            .expect("should build the request");
+
+        let names: Vec<&str> = request
+            .request_variables
+            .iter()
+            .map(|v| v.name.as_str())
+            .collect();
+        assert_eq!(names, ["MARST", "RELATE", "AGE"]);
+        assert!(request.request_variables[1].is_general());
+        assert!(request.request_variables[2].is_bucketed());
+        assert_eq!(request.subpopulation[0].name, "STATEFIP");
+        assert_eq!(request.unit_rectype.value, "P");
+
+        let tab = tabulate::tabulate(&ctx, request).expect("should tabulate");
+        assert_eq!(tab.0.len(), 1);
+        assert!(!tab.0[0].rows.is_empty());
+    }
+
+    #[test]
+    fn test_keeps_the_first_problem() {
+        let ctx = context();
+        let err = AbacusRequest::builder(&ctx)
+            .sample("us1900m")
+            .var("MARST")
+            .var("AEG")
+            .sample("us1776a")
+            .build()
+            .expect_err("AEG isn't a variable");
+        assert_eq!(err.code(), ErrorCode::UnknownVariable);
+        assert_eq!(err.field(), Some("request_variables[1]"));
+
+        let err = AbacusRequest::builder(&ctx)
+            .uoa("X")
+            .sample("us1900m")
+            .var("MARST")
+            .build()
+            .expect_err("X isn't a record type");
+        assert_eq!(err.code(), ErrorCode::UnknownRecordType);
+
+        let err = AbacusRequest::builder(&ctx)
+            .sample("us1900m")
+            .general("RELATE")
+            .build()
+            .expect_err("layouts don't have general widths");
+        assert_eq!(err.field(), Some("request_variables[0]"));
+
+        let err = AbacusRequest::builder(&ctx)
+            .sample("us1900m")
+            .build()
+            .expect_err("there are no variables");
+        assert_eq!(err.field(), Some("request_variables"));
+    }
+
+    #[test]
+    fn test_derived_variables_and_weights() {
+        let ctx = context();
+        let builder = AbacusRequest::builder(&ctx)
+            .sample("us1940a")
+            .derived("HAS_KIDS", "NCHILD > 0")
+            .var("HAS_KIDS")
+            .weight(WeightSelection::Unweighted);
+        assert_eq!(builder.input().derived_variables[0].name, "HAS_KIDS");
+        let request = builder.build().expect("should build the request");
+        assert!(request.request_variables[0].derived.is_some());
+        assert_eq!(request.weight, WeightSelection::Unweighted);
+
+        // NCHILD is a person variable, so households can't be counted by it.
+        let err = AbacusRequest::builder(&ctx)
+            .uoa("H")
+            .sample("us1940a")
+            .derived("HAS_KIDS", "NCHILD > 0")
+            .var("HAS_KIDS")
+            .build()
+            .expect_err("person records can't be joined to households");
+        assert_eq!(err.field(), Some("request_variables[0]"));
+    }
+}
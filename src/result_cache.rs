@@ -0,0 +1,398 @@
+//! A cache of tabulation results.
+//!
+//! Results are cached as the JSON of the tabulation, keyed by a SHA-256 hash of the normalized
+//! request, the data root, the [DataVersion] of each requested dataset's data files, the contents
+//! of the request's CPI file if it has one, and any options which change the output, like
+//! disclosure rules. When a dataset is re-released its
+//! data files carry a new version, so results computed from the old files are no longer found.
+//! Data files without version information are identified by their sizes and modification times
+//! instead.
+//!
+//! The cache keeps the most recently used results in memory, and can also keep results in a
+//! directory, which outlasts the process and can be shared between processes.
+//!
+//! The cache has limits to keep in mind when deploying it:
+//!
+//! * The directory has no size bound and nothing is ever removed from it. Clear it out, or
+//!   remove old files, as needed.
+//! * The key doesn't include the layouts or any other metadata, only the data files. When the
+//!   metadata changes without the data, clear the directory.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::conventions::Context;
+use crate::data_version::{extract_version_from_parquet, DataVersion};
+use crate::input_schema_tabulation::AbacusRequest;
+use crate::mderror::MdError;
+use crate::request::InputType;
+
+/// The number of results kept in memory when no capacity is given.
+pub const DEFAULT_CAPACITY: usize = 128;
+
+/// Identifies a tabulation result: what it was computed from, and a hash of that.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CacheKey {
+    hash: String,
+    /// The canonical JSON of everything the result depends on.
+    description: String,
+}
+
+impl CacheKey {
+    /// The key for a request against a context. `options` holds any settings outside the
+    /// request which change the result.
+    pub fn new(ctx: &Context, request: &AbacusRequest, options: Value) -> Result<Self, MdError> {
+        let mut data = BTreeMap::new();
+        for sample in request.sample_names() {
+            data.insert(sample, data_version(ctx, sample)?);
+        }
+        // The request only names its CPI file, which can change under the same name.
+        let cpi = match request
+            .monetary_standardization
+            .as_ref()
+            .and_then(|ms| ms.cpi_file.as_ref())
+        {
+            Some(path) => Value::String(sha256_hex(&fs::read(path)?)),
+            None => Value::Null,
+        };
+
+        let mut request = serde_json::to_value(request)
+            .map_err(|err| MdError::Msg(format!("Cannot serialize request into json: {err}")))?;
+        // The context settles these, and the cache holds the JSON whatever the output format.
+        if let Some(fields) = request.as_object_mut() {
+            fields.remove("product");
+            fields.remove("data_root");
+            fields.remove("output_format");
+        }
+
+        let description = canonical(&json!({
+            "product": ctx.name.to_lowercase(),
+            "data_root": ctx.data_root,
+            "request": request,
+            "data": data,
+            "cpi": cpi,
+            "options": options,
+        }))
+        .to_string();
+        let hash = sha256_hex(description.as_bytes());
+        Ok(Self { hash, description })
+    }
+
+    /// The hex SHA-256 hash of the key.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The version of a dataset's parquet files, or their sizes and modification times when they
+/// have no version information. A dataset without data has no version.
+fn data_version(ctx: &Context, dataset: &str) -> Result<Value, MdError> {
+    let mut paths: Vec<PathBuf> = ctx
+        .paths_from_dataset_name(dataset, &InputType::Parquet)?
+        .into_values()
+        .filter(|path| path.exists())
+        .collect();
+    paths.sort();
+    let Some(directory) = paths.first().and_then(|path| path.parent()) else {
+        return Ok(Value::Null);
+    };
+
+    let version = extract_version_from_parquet(&directory.display().to_string())?;
+    if version.has_version_info() {
+        let DataVersion {
+            variable_count,
+            metadata,
+            ..
+        } = version;
+        return Ok(json!({ "variable_count": variable_count, "metadata": metadata }));
+    }
+
+    let mut files = Vec::new();
+    for path in &paths {
+        let md = fs::metadata(path)?;
+        let modified = md
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_nanos().to_string());
+        files.push(json!({ "path": path, "len": md.len(), "modified": modified }));
+    }
+    Ok(Value::Array(files))
+}
+
+/// The value with the fields of every object in sorted order.
+fn canonical(value: &Value) -> Value {
+    match value {
+        Value::Object(fields) => {
+            let sorted: BTreeMap<&String, Value> = fields
+                .iter()
+                .map(|(name, value)| (name, canonical(value)))
+                .collect();
+            Value::Object(
+                sorted
+                    .into_iter()
+                    .map(|(name, value)| (name.clone(), value))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.iter().map(canonical).collect()),
+        _ => value.clone(),
+    }
+}
+
+/// Results in memory, with their last uses so that the least recently used is dropped first.
+#[derive(Debug, Default)]
+struct Memory {
+    results: HashMap<String, (Arc<String>, u64)>,
+    /// Keys by their last use.
+    uses: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Memory {
+    fn get(&mut self, hash: &str) -> Option<Arc<String>> {
+        self.clock += 1;
+        let (result, used) = self.results.get_mut(hash)?;
+        self.uses.remove(used);
+        *used = self.clock;
+        self.uses.insert(self.clock, hash.to_string());
+        Some(Arc::clone(result))
+    }
+
+    fn insert(&mut self, hash: &str, result: Arc<String>, capacity: usize) {
+        self.clock += 1;
+        if let Some((_, used)) = self.results.insert(hash.to_string(), (result, self.clock)) {
+            self.uses.remove(&used);
+        }
+        self.uses.insert(self.clock, hash.to_string());
+        while self.results.len() > capacity {
+            let Some((_, oldest)) = self.uses.pop_first() else {
+                break;
+            };
+            self.results.remove(&oldest);
+        }
+    }
+}
+
+/// Tabulation results as JSON, in memory and optionally in a directory.
+///
+/// The cache is safe to share between threads.
+#[derive(Debug)]
+pub struct ResultCache {
+    capacity: usize,
+    memory: Mutex<Memory>,
+    directory: Option<PathBuf>,
+}
+
+impl ResultCache {
+    /// Keep up to `capacity` results in memory.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            memory: Mutex::new(Memory::default()),
+            directory: None,
+        }
+    }
+
+    /// Also keep results in files in the directory, which is created if it doesn't exist.
+    pub fn with_directory(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.directory = Some(directory.into());
+        self
+    }
+
+    /// The file for a result: its key on the first line and the result after it.
+    fn path(directory: &Path, key: &CacheKey) -> PathBuf {
+        directory
+            .join(&key.hash[..2])
+            .join(format!("{}.json", key.hash))
+    }
+
+    /// Get a cached result, from memory or else from the directory.
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<String>> {
+        if let Some(result) = self.memory.lock().ok()?.get(&key.hash) {
+            return Some(result);
+        }
+
+        let text = fs::read_to_string(Self::path(self.directory.as_ref()?, key)).ok()?;
+        // The description guards against a different key with the same hash.
+        let (description, result) = text.split_once('\n')?;
+        if description != key.description {
+            return None;
+        }
+        let result = Arc::new(result.to_string());
+        self.memory
+            .lock()
+            .ok()?
+            .insert(&key.hash, Arc::clone(&result), self.capacity);
+        Some(result)
+    }
+
+    /// Cache a result. The result is kept in memory even if writing it to the directory fails.
+    pub fn insert(&self, key: &CacheKey, result: String) -> Result<(), MdError> {
+        let result = Arc::new(result);
+        self.memory
+            .lock()
+            .map_err(|_| MdError::Msg("The result cache lock was poisoned.".to_string()))?
+            .insert(&key.hash, Arc::clone(&result), self.capacity);
+
+        let Some(ref directory) = self.directory else {
+            return Ok(());
+        };
+        let path = Self::path(directory, key);
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+        fs::create_dir_all(parent)?;
+        // Write to a temporary file and rename it, so that readers never see part of a result.
+        let mut file = tempfile::NamedTempFile::new_in(parent)?;
+        writeln!(file, "{}", key.description)?;
+        file.write_all(result.as_bytes())?;
+        file.persist(&path)
+            .map_err(|err| MdError::Msg(format!("Cannot write {}: {err}", path.display())))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(data_root: &str) -> Context {
+        Context::from_ipums_collection_name("usa", None, Some(data_root.to_string())).unwrap()
+    }
+
+    fn request(text: &str) -> AbacusRequest {
+        serde_json::from_str(text).unwrap()
+    }
+
+    const REQUEST: &str = include_str!("../tests/requests/race_hispan_subpop_statefip.json");
+
+    #[test]
+    fn test_key_ignores_formatting() {
+        let ctx = context("tests/data_root");
+        let key = CacheKey::new(&ctx, &request(REQUEST), Value::Null).unwrap();
+        assert_eq!(key.hash().len(), 64);
+
+        let compact = serde_json::from_str::<Value>(REQUEST).unwrap().to_string();
+        let same = CacheKey::new(&ctx, &request(&compact), Value::Null).unwrap();
+        assert_eq!(key, same);
+
+        let options = CacheKey::new(&ctx, &request(REQUEST), json!({"rounding": 10})).unwrap();
+        assert_ne!(key, options);
+
+        let mut other = request(REQUEST);
+        other.include_empty_cells = true;
+        let other = CacheKey::new(&ctx, &other, Value::Null).unwrap();
+        assert_ne!(key, other);
+    }
+
+    #[test]
+    fn test_key_changes_with_data_version() {
+        let data_root = tempfile::tempdir().unwrap();
+        let dataset = data_root.path().join("parquet").join("us1900m");
+        fs::create_dir_all(&dataset).unwrap();
+        for rt in ["H", "P"] {
+            let name = format!("us1900m_usa.{rt}.parquet");
+            fs::copy(
+                Path::new("tests/data_root/parquet/us1900m").join(&name),
+                dataset.join(&name),
+            )
+            .unwrap();
+        }
+        let ctx = context(&data_root.path().display().to_string());
+        let before = CacheKey::new(&ctx, &request(REQUEST), Value::Null).unwrap();
+        assert!(before.description.contains("dcp_release_number"));
+
+        // Re-release the household records with a new version.
+        let households = dataset.join("us1900m_usa.H.parquet");
+        let released = dataset.join("released.parquet");
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "copy (select * from '{}') to '{}' (format parquet, kv_metadata {{dcp_release_number: '90.0.0'}})",
+            households.display(),
+            released.display()
+        ))
+        .unwrap();
+        fs::rename(&released, &households).unwrap();
+
+        let after = CacheKey::new(&ctx, &request(REQUEST), Value::Null).unwrap();
+        assert_ne!(before, after);
+        assert!(after.description.contains("90.0.0"));
+    }
+
+    #[test]
+    fn test_key_changes_with_cpi_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let cpi_file = directory.path().join("cpi.csv");
+        fs::write(&cpi_file, "year,cpi\n1939,1.0\n1950,2.0\n").unwrap();
+        let mut rq = request(include_str!(
+            "../tests/requests/incwage_category_bins_us1940a.json"
+        ));
+        rq.monetary_standardization = Some(
+            serde_json::from_value(json!({
+                "base_year": 1950,
+                "cpi_file": cpi_file.display().to_string(),
+            }))
+            .unwrap(),
+        );
+        let ctx = context("tests/data_root");
+        let before = CacheKey::new(&ctx, &rq, Value::Null).unwrap();
+
+        fs::write(&cpi_file, "year,cpi\n1939,1.0\n1950,3.0\n").unwrap();
+        let after = CacheKey::new(&ctx, &rq, Value::Null).unwrap();
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn test_memory_drops_least_recently_used() {
+        let ctx = context("tests/data_root");
+        let keys: Vec<CacheKey> = (0..3)
+            .map(|n| CacheKey::new(&ctx, &request(REQUEST), json!(n)).unwrap())
+            .collect();
+        let cache = ResultCache::new(2);
+        cache.insert(&keys[0], "zero".to_string()).unwrap();
+        cache.insert(&keys[1], "one".to_string()).unwrap();
+        assert_eq!(cache.get(&keys[0]).unwrap().as_str(), "zero");
+        cache.insert(&keys[2], "two".to_string()).unwrap();
+
+        assert!(cache.get(&keys[1]).is_none());
+        assert_eq!(cache.get(&keys[0]).unwrap().as_str(), "zero");
+        assert_eq!(cache.get(&keys[2]).unwrap().as_str(), "two");
+    }
+
+    #[test]
+    fn test_directory_outlasts_memory() {
+        let directory = tempfile::tempdir().unwrap();
+        let ctx = context("tests/data_root");
+        let key = CacheKey::new(&ctx, &request(REQUEST), Value::Null).unwrap();
+        let result = "[{\"rows\": []}]\n".to_string();
+
+        ResultCache::new(1)
+            .with_directory(directory.path())
+            .insert(&key, result.clone())
+            .unwrap();
+        let cache = ResultCache::new(1).with_directory(directory.path());
+        assert_eq!(cache.get(&key).unwrap().as_str(), result);
+        assert!(ResultCache::new(1).get(&key).is_none());
+
+        // A file for a different key with the same hash isn't used.
+        let path = ResultCache::path(directory.path(), &key);
+        fs::write(&path, format!("{{}}\n{result}")).unwrap();
+        let cache = ResultCache::new(1).with_directory(directory.path());
+        assert!(cache.get(&key).is_none());
+    }
+}
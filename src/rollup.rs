@@ -0,0 +1,269 @@
+//! Roll child records up to their parents.
+//!
+//! Tabulation queries join from the unit of analysis up to parent records, so a person can be
+//! tabulated by household variables. A roll-up goes the other way: it summarizes a parent's
+//! child records into a variable on the parent, like the number of people in a household under
+//! 18 or whether anyone in the household is employed. The summary is computed in a grouped
+//! subquery over the child records, keyed by the parent's id, and joined to the parent record.
+//! After that it works like any other variable on the parent record type.
+use crate::conventions::Context;
+use crate::derived::{check_identifier, Expression, DERIVED_VARIABLE_WIDTH};
+use crate::input_schema_tabulation::{self, RollupFunction};
+use crate::ipums_metadata_model::{IpumsDataType, IpumsVariable, VariableRestrictions};
+use crate::mderror::{metadata_error, Entity, MdError};
+
+/// A variable on a parent record computed from its child records.
+#[derive(Clone, Debug)]
+pub struct RollupVariable {
+    pub name: String,
+    pub function: RollupFunction,
+    pub child_record_type: String,
+    pub parent_record_type: String,
+    pub value: Option<Expression>,
+    pub condition: Option<Expression>,
+}
+
+impl RollupVariable {
+    /// Check a roll-up against the context's record types and metadata.
+    ///
+    /// Returns the roll-up and a metadata variable on the parent record type to stand in for it
+    /// in requests.
+    pub fn try_new(
+        ctx: &Context,
+        rollup: &input_schema_tabulation::Rollup,
+    ) -> Result<(Self, IpumsVariable), MdError> {
+        let name = &rollup.name;
+        check_identifier(name).map_err(|err| err.in_request("name"))?;
+        if ctx.get_md_variable_by_name(name).is_ok() {
+            return Err(metadata_error!(
+                "roll-up {name} has the same name as a variable in the data"
+            ));
+        }
+
+        let child = &rollup.record_type;
+        let Some(child_rt) = ctx.settings.record_types.get(child) else {
+            return Err(
+                MdError::NotFound(Entity::RecordType(child.clone())).in_request("record_type")
+            );
+        };
+        let parent = match rollup.parent_record_type {
+            Some(ref parent) => parent.clone(),
+            None => match child_rt.foreign_keys.first() {
+                Some((parent, _)) => parent.clone(),
+                None => {
+                    return Err(
+                        metadata_error!("record type '{child}' has no parent record type")
+                            .in_request("record_type"),
+                    )
+                }
+            },
+        };
+        if !child_rt.foreign_keys.iter().any(|(rt, _)| rt == &parent) {
+            return Err(metadata_error!(
+                "record type '{child}' has no key to a parent record type '{parent}'"
+            )
+            .in_request("parent_record_type"));
+        }
+
+        let check = |text: &Option<String>, field: &str, want_condition: bool| {
+            let Some(text) = text else {
+                return Ok(None);
+            };
+            let expression = Expression::try_new(ctx, text).map_err(|err| err.in_request(field))?;
+            if expression.record_type() != child {
+                return Err(metadata_error!(
+                    "'{text}' uses {} variables, not variables from record type '{child}'",
+                    expression.record_type()
+                )
+                .in_request(field));
+            }
+            if expression.is_condition() != want_condition {
+                let want = if want_condition {
+                    "condition"
+                } else {
+                    "number"
+                };
+                return Err(metadata_error!("'{text}' must be a {want}").in_request(field));
+            }
+            Ok(Some(expression))
+        };
+        let value = check(&rollup.value, "value", false)?;
+        let condition = check(&rollup.condition, "condition", true)?;
+
+        let function = rollup.function;
+        match (function.needs_value(), &value) {
+            (true, None) => {
+                return Err(
+                    metadata_error!("{function:?} needs a value to summarize").in_request("value")
+                )
+            }
+            (false, Some(_)) => {
+                return Err(metadata_error!(
+                    "{function:?} doesn't use a value; give a condition instead"
+                )
+                .in_request("value"))
+            }
+            _ => (),
+        }
+        if function == RollupFunction::Any && condition.is_none() {
+            return Err(metadata_error!("Any needs a condition").in_request("condition"));
+        }
+
+        let data_type = match (function, &value) {
+            (RollupFunction::Mean, _) => IpumsDataType::Float,
+            (_, Some(value)) => value.data_type(),
+            (_, None) => IpumsDataType::Integer,
+        };
+        let restrictions = value
+            .iter()
+            .chain(&condition)
+            .try_fold(VariableRestrictions::default(), |all, e| {
+                Some(all.union(&e.restrictions()?))
+            });
+        let label = rollup.label.clone().unwrap_or_else(|| {
+            let function = format!("{function:?}").to_lowercase();
+            let mut label = format!("{function} of {child} records");
+            if let Some(ref value) = value {
+                label += &format!(" of {value}");
+            }
+            if let Some(ref condition) = condition {
+                label += &format!(" where {condition}");
+            }
+            label
+        });
+        let variable = IpumsVariable {
+            name: name.clone(),
+            data_type: Some(data_type),
+            label: Some(label),
+            record_type: parent.clone(),
+            categories: None,
+            formatting: Some((1, DERIVED_VARIABLE_WIDTH)),
+            general_width: None,
+            description: None,
+            category_bins: None,
+            restrictions,
+            monetary: false,
+            sample_line: IpumsVariable::any_sample_line(
+                value.iter().chain(&condition).flat_map(|e| &e.inputs),
+            ),
+            id: 0,
+        };
+        Ok((
+            Self {
+                name: name.clone(),
+                function,
+                child_record_type: child.clone(),
+                parent_record_type: parent,
+                value,
+                condition,
+            },
+            variable,
+        ))
+    }
+
+    /// The alias of the subquery which computes the roll-up.
+    pub fn table_alias(&self) -> String {
+        format!("rollup_{}", self.name)
+    }
+
+    /// The aggregate which the subquery computes from the child records.
+    pub fn aggregate_sql(&self) -> String {
+        let value = self.value.as_ref().map(|v| v.to_sql()).unwrap_or_default();
+        match self.function {
+            RollupFunction::Count => "count(*)".to_string(),
+            RollupFunction::Sum => format!("sum({value})"),
+            RollupFunction::Min => format!("min({value})"),
+            RollupFunction::Max => format!("max({value})"),
+            RollupFunction::Mean => format!("avg({value})"),
+            RollupFunction::Any => "1".to_string(),
+        }
+    }
+
+    /// The roll-up's value on the parent record. Parents without any matching child records
+    /// have counts and totals of 0, and no minimum, maximum or mean.
+    pub fn column_sql(&self) -> String {
+        let column = format!("{}.{}", self.table_alias(), self.name);
+        match self.function {
+            RollupFunction::Count | RollupFunction::Sum | RollupFunction::Any => {
+                format!("coalesce({column}, 0)")
+            }
+            RollupFunction::Min | RollupFunction::Max | RollupFunction::Mean => column,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mderror::ErrorCode;
+
+    fn rollup(json: &str) -> Result<(RollupVariable, IpumsVariable), MdError> {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .unwrap();
+        ctx.load_metadata_for_datasets(&["us1900m"]).unwrap();
+        let rollup: input_schema_tabulation::Rollup = serde_json::from_str(json).unwrap();
+        RollupVariable::try_new(&ctx, &rollup)
+    }
+
+    #[test]
+    fn test_rollup_sql() {
+        let (oldest, var) =
+            rollup(r#"{"name": "OLDEST", "record_type": "P", "function": "max", "value": "AGE"}"#)
+                .unwrap();
+        assert_eq!(oldest.parent_record_type, "H");
+        assert_eq!(var.record_type, "H");
+        assert_eq!(var.label.as_deref(), Some("max of P records of AGE"));
+        assert_eq!(oldest.column_sql(), "rollup_OLDEST.OLDEST");
+        assert!(oldest.aggregate_sql().starts_with("max("));
+
+        let (kids, var) = rollup(
+            r#"{"name": "NKIDS", "record_type": "P", "function": "count", "condition": "AGE < 18"}"#,
+        )
+        .unwrap();
+        assert_eq!(var.data_type, Some(IpumsDataType::Integer));
+        assert_eq!(kids.aggregate_sql(), "count(*)");
+        assert_eq!(kids.column_sql(), "coalesce(rollup_NKIDS.NKIDS, 0)");
+    }
+
+    #[test]
+    fn test_rollup_errors() {
+        // Household variables aren't on the child records.
+        let wrong_record_type =
+            rollup(r#"{"name": "X", "record_type": "P", "function": "sum", "value": "NUMPREC"}"#);
+        assert!(wrong_record_type.is_err());
+
+        let no_value = rollup(r#"{"name": "X", "record_type": "P", "function": "mean"}"#);
+        assert!(no_value.is_err());
+
+        let condition_as_value =
+            rollup(r#"{"name": "X", "record_type": "P", "function": "sum", "value": "AGE > 5"}"#);
+        assert!(condition_as_value.is_err());
+
+        let no_parent = rollup(r#"{"name": "X", "record_type": "H", "function": "count"}"#);
+        assert!(no_parent.is_err());
+
+        let existing_name = rollup(r#"{"name": "AGE", "record_type": "P", "function": "count"}"#);
+        assert!(existing_name.is_err());
+
+        // Names go into SQL, so they must be plain identifiers.
+        for bad in ["N KIDS", "X_key, 1 as Y", "1KIDS"] {
+            let json = serde_json::json!({"name": bad, "record_type": "P", "function": "count"});
+            let Err(err) = rollup(&json.to_string()) else {
+                panic!("expected '{bad}' to be an invalid name");
+            };
+            assert_eq!(err.code(), ErrorCode::Parsing);
+            assert_eq!(err.field(), Some("name"));
+        }
+
+        // Unknown variables keep their code and entity, with the field they're in.
+        let Err(unknown) =
+            rollup(r#"{"name": "X", "record_type": "P", "function": "sum", "value": "AEG"}"#)
+        else {
+            panic!("expected an error for an unknown variable");
+        };
+        assert_eq!(unknown.code(), ErrorCode::UnknownVariable);
+        assert_eq!(unknown.entity(), Some(&Entity::Variable("AEG".to_string())));
+        assert_eq!(unknown.field(), Some("value"));
+    }
+}
@@ -0,0 +1,175 @@
+//! JSON Schemas for Abacus requests and tabulation output.
+//!
+//! The schemas are generated from the types which read requests and write tables, so they can't
+//! drift from what cimdea accepts and produces. Copies are published in the `schemas` directory
+//! of the repository for clients which don't run Rust, and `abacus schema` prints them.
+use std::str::FromStr;
+
+use schemars::generate::SchemaSettings;
+use schemars::{schema_for, Schema};
+
+use crate::input_schema_tabulation::AbacusRequest;
+use crate::mderror::{parsing_error, MdError};
+use crate::tabulate::Table;
+
+/// The documents cimdea has schemas for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SchemaKind {
+    /// An Abacus request, as taken by `abacus request` and `POST /tabulate`.
+    Request,
+    /// The JSON output of a tabulation, a list of tables with one for each sample.
+    Tabulation,
+}
+
+impl SchemaKind {
+    pub const ALL: [SchemaKind; 2] = [SchemaKind::Request, SchemaKind::Tabulation];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Request => "request",
+            Self::Tabulation => "tabulation",
+        }
+    }
+
+    /// The name of the published schema file in the `schemas` directory.
+    pub fn file_name(&self) -> String {
+        format!("abacus_{}.schema.json", self.name())
+    }
+
+    pub fn schema(&self) -> Schema {
+        let mut schema = match self {
+            Self::Request => schema_for!(AbacusRequest),
+            // Output is only ever serialized, so fields which are always written are required.
+            Self::Tabulation => SchemaSettings::default()
+                .for_serialize()
+                .into_generator()
+                .into_root_schema_for::<Vec<Table>>(),
+        };
+        schema.insert(
+            "title".to_string(),
+            format!("Abacus {}", self.name()).into(),
+        );
+        schema
+    }
+
+    /// The schema as pretty-printed JSON, as published.
+    pub fn to_json(&self) -> Result<String, MdError> {
+        serde_json::to_string_pretty(&self.schema())
+            .map_err(|err| MdError::Msg(format!("Cannot serialize schema into json: {err}")))
+    }
+}
+
+impl FromStr for SchemaKind {
+    type Err = MdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| parsing_error!("unknown schema '{s}'; expected request or tabulation"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::TabulationService;
+
+    fn validator(kind: SchemaKind) -> jsonschema::Validator {
+        let schema = serde_json::to_value(kind.schema()).unwrap();
+        jsonschema::validator_for(&schema).expect("the schema should be a valid JSON Schema")
+    }
+
+    fn assert_valid(validator: &jsonschema::Validator, instance: &serde_json::Value, name: &str) {
+        let errors: Vec<String> = validator
+            .iter_errors(instance)
+            .map(|err| format!("{} at {}", err, err.instance_path))
+            .collect();
+        assert!(
+            errors.is_empty(),
+            "{name} doesn't match the schema: {errors:?}"
+        );
+    }
+
+    #[test]
+    fn test_published_schemas_are_current() {
+        for kind in SchemaKind::ALL {
+            let path = format!("schemas/{}", kind.file_name());
+            let published = std::fs::read_to_string(&path).expect("should read the schema");
+            assert_eq!(
+                published.trim_end(),
+                kind.to_json().unwrap(),
+                "{path} is out of date; update it with `abacus schema {}`",
+                kind.name()
+            );
+        }
+    }
+
+    /// Every Abacus request fixture should validate. usa_extract.json is an extract request,
+    /// not an Abacus request.
+    #[test]
+    fn test_request_fixtures_validate() {
+        let validator = validator(SchemaKind::Request);
+        let mut checked = 0;
+        for entry in std::fs::read_dir("tests/requests").unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.display().to_string();
+            let text = std::fs::read_to_string(&path).unwrap();
+            let requests: Vec<serde_json::Value> = match path.extension() {
+                Some(ext) if ext == "json" && !name.ends_with("usa_extract.json") => {
+                    vec![serde_json::from_str(&text)
+                        .unwrap_or_else(|err| panic!("{name} isn't valid JSON: {err}"))]
+                }
+                Some(ext) if ext == "jsonl" => text
+                    .lines()
+                    .enumerate()
+                    .filter(|(_, line)| !line.trim().is_empty())
+                    .map(|(index, line)| {
+                        serde_json::from_str(line).unwrap_or_else(|err| {
+                            panic!("{name} line {} isn't valid JSON: {err}", index + 1)
+                        })
+                    })
+                    .collect(),
+                _ => continue,
+            };
+            for request in requests {
+                assert_valid(&validator, &request, &name);
+                checked += 1;
+            }
+        }
+        assert!(checked > 20, "only checked {checked} requests");
+
+        let invalid = serde_json::json!({"product": "usa", "uoa": 1});
+        assert!(!validator.is_valid(&invalid));
+    }
+
+    #[test]
+    fn test_tabulation_output_validates() {
+        let service = TabulationService::new(Some("tests/data_root".to_string()));
+        let request = std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json")
+            .expect("should be able to read the test request");
+        let output = service
+            .tabulate_json(&request)
+            .and_then(|tab| tab.output(crate::tabulate::TableFormat::Json))
+            .unwrap();
+        let output: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_valid(
+            &validator(SchemaKind::Tabulation),
+            &output,
+            "the tabulation",
+        );
+    }
+
+    #[test]
+    fn test_schema_kind_from_str() {
+        assert_eq!(
+            "request".parse::<SchemaKind>().unwrap(),
+            SchemaKind::Request
+        );
+        assert_eq!(
+            "tabulation".parse::<SchemaKind>().unwrap(),
+            SchemaKind::Tabulation
+        );
+        assert!("extract".parse::<SchemaKind>().is_err());
+    }
+}
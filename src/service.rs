@@ -0,0 +1,911 @@
+//! A long-running tabulation service.
+//!
+//! The abacus binary computes one tabulation per process, which means it loads metadata and sets
+//! up the data platform again for every request. The [TabulationService] instead keeps a
+//! [Context] per product and data root warm across requests, loading metadata for additional
+//! datasets as requests name them, and runs tabulations on a [TabulationEngine]'s pooled
+//! connections. With a [ResultCache], it also keeps the output of tabulations to answer repeated
+//! requests without running them. [serve] exposes the service as a small local HTTP JSON API:
+//!
+//! * `POST /tabulate` takes an Abacus request in the body and returns the tabulation as JSON.
+//! * `POST /validate` takes an Abacus request and returns a list of the problems with it, which is
+//!   empty when the request is valid.
+//! * `GET /datasets?product=usa` lists the datasets with layouts in the data root.
+//! * `GET /variables?product=usa&dataset=us2015b` lists the variables available in a dataset.
+//!
+//! Every endpoint also accepts an optional `data_root`, which must be the service's default data
+//! root or one of the data roots it's allowed to serve, so that clients can't have it read
+//! whatever directory they name. Requests can only give a CPI file for monetary standardization
+//! when the service has a CPI directory, and the file must be in it. Errors
+//! come back as a JSON object with the message in an `error` field. Errors from cimdea also have a
+//! `code` field, and `entity` and `field` fields when they're about a particular variable, dataset
+//! or record type, or a particular part of the request.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use serde_json::json;
+
+use crate::conventions::Context;
+use crate::disclosure::DisclosureRules;
+use crate::engine::{PreparedTabulation, TabulationEngine};
+use crate::input_schema_tabulation;
+use crate::mderror::{parsing_error, Entity, MdError};
+use crate::request::AbacusRequest;
+use crate::result_cache::{CacheKey, ResultCache};
+use crate::tabulate::{TableFormat, Tabulation};
+use crate::validation::{self, Diagnostic};
+
+/// Cached contexts are keyed by lowercase product name and data root.
+type ContextKey = (String, Option<String>);
+
+/// The most contexts a [ContextCache] keeps. Requests can name any data root, so when it's full
+/// the least recently used context is dropped.
+const MAX_CONTEXTS: usize = 8;
+
+/// Contexts with metadata loaded, keyed by product name and data root.
+///
+/// A cached context is never modified once shared. When a request needs metadata for datasets a
+/// cached context doesn't have yet, the cache extends a copy and replaces its entry, so requests
+/// already running against the old context are unaffected.
+#[derive(Debug)]
+pub struct ContextCache {
+    default_data_root: Option<String>,
+    contexts: Mutex<Contexts>,
+}
+
+/// Cached contexts with their last uses.
+#[derive(Debug, Default)]
+struct Contexts {
+    contexts: HashMap<ContextKey, (Arc<Context>, u64)>,
+    clock: u64,
+}
+
+impl ContextCache {
+    /// Create an empty cache. When a request doesn't give a data root, use `default_data_root`,
+    /// and if that's `None` fall back to the product's default data root.
+    pub fn new(default_data_root: Option<String>) -> Self {
+        Self {
+            default_data_root,
+            contexts: Mutex::new(Contexts::default()),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Contexts>, MdError> {
+        self.contexts
+            .lock()
+            .map_err(|_| MdError::Msg("The context cache lock was poisoned.".to_string()))
+    }
+
+    /// Get a context for the product and data root which has metadata loaded for all of the given
+    /// datasets.
+    ///
+    /// Dataset names must be plain file names, since they name files in the data root. Metadata
+    /// loads without holding the cache's lock, so requests for loaded datasets don't wait on it.
+    pub fn context_for(
+        &self,
+        product: &str,
+        data_root: Option<String>,
+        datasets: &[&str],
+    ) -> Result<Arc<Context>, MdError> {
+        for dataset in datasets {
+            let mut components = Path::new(dataset).components();
+            let plain = matches!(components.next(), Some(Component::Normal(name)) if name == *dataset)
+                && components.next().is_none();
+            if !plain {
+                return Err(MdError::NotFound(Entity::Dataset(dataset.to_string())));
+            }
+        }
+
+        let data_root = data_root.or_else(|| self.default_data_root.clone());
+        let key = (product.to_lowercase(), data_root.clone());
+
+        let cached = self
+            .lock()?
+            .contexts
+            .get(&key)
+            .map(|(ctx, _)| Arc::clone(ctx));
+        let ctx = match cached {
+            Some(ctx) => ctx,
+            None => Arc::new(Context::from_ipums_collection_name(
+                product, None, data_root,
+            )?),
+        };
+
+        let missing: Vec<&str> = datasets
+            .iter()
+            .copied()
+            .filter(|ds| !ctx.has_metadata_for_dataset(ds))
+            .collect();
+
+        let ctx = if missing.is_empty() {
+            ctx
+        } else {
+            let mut extended = Context::clone(&ctx);
+            extended.extend_metadata_for_datasets(&missing)?;
+            Arc::new(extended)
+        };
+
+        let mut contexts = self.lock()?;
+        contexts.clock += 1;
+        let used = contexts.clock;
+        contexts.contexts.insert(key, (Arc::clone(&ctx), used));
+        if contexts.contexts.len() > MAX_CONTEXTS {
+            let oldest = contexts
+                .contexts
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                contexts.contexts.remove(&oldest);
+            }
+        }
+        Ok(ctx)
+    }
+}
+
+/// A status code and JSON body produced by [TabulationService::handle].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl ServiceResponse {
+    fn ok(body: String) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message }).to_string(),
+        }
+    }
+
+    /// An error response with the error's code, and its entity and request field when they're
+    /// known, alongside the message.
+    fn from_error(status: u16, err: &MdError) -> Self {
+        Self {
+            status,
+            body: json!(err).to_string(),
+        }
+    }
+}
+
+/// Computes tabulations and answers metadata questions, sharing loaded metadata between requests.
+///
+/// The service is safe to share between threads. Tabulations share the engine's pool of data
+/// platform connections, so requests run concurrently.
+#[derive(Debug)]
+pub struct TabulationService {
+    engine: TabulationEngine,
+    disclosure: DisclosureRules,
+    results: Option<ResultCache>,
+    /// The directory requests' CPI files must be in.
+    cpi_directory: Option<PathBuf>,
+    default_data_root: Option<String>,
+    /// The data roots besides the default which requests may name, or `None` for any.
+    allowed_data_roots: Option<Vec<String>>,
+}
+
+impl TabulationService {
+    /// Create a service which serves the data root, or the product's default data root when
+    /// it's `None`.
+    pub fn new(default_data_root: Option<String>) -> Self {
+        Self {
+            engine: TabulationEngine::new(default_data_root.clone()),
+            disclosure: DisclosureRules::default(),
+            results: None,
+            cpi_directory: None,
+            default_data_root,
+            allowed_data_roots: Some(Vec::new()),
+        }
+    }
+
+    /// Let requests name these data roots as well as the default.
+    pub fn with_allowed_data_roots(mut self, data_roots: Vec<String>) -> Self {
+        self.allowed_data_roots = Some(data_roots);
+        self
+    }
+
+    /// Let requests name any data root. Only use this when the requests are trusted, like those
+    /// in a local batch file.
+    pub fn with_any_data_root(mut self) -> Self {
+        self.allowed_data_roots = None;
+        self
+    }
+
+    /// Check a data root named by a request.
+    fn check_data_root(&self, data_root: Option<&str>) -> Result<(), MdError> {
+        let (Some(data_root), Some(allowed)) = (data_root, &self.allowed_data_roots) else {
+            return Ok(());
+        };
+        if self.default_data_root.as_deref() == Some(data_root)
+            || allowed.iter().any(|a| a == data_root)
+        {
+            return Ok(());
+        }
+        Err(
+            parsing_error!("this service doesn't serve the data root '{data_root}'")
+                .in_request("data_root"),
+        )
+    }
+
+    /// Apply the disclosure rules to every tabulation the service computes.
+    pub fn with_disclosure_rules(mut self, rules: DisclosureRules) -> Self {
+        self.disclosure = rules;
+        self
+    }
+
+    /// Keep the JSON output of tabulations in the cache, and answer `POST /tabulate` requests from
+    /// it when it has the result.
+    pub fn with_result_cache(mut self, cache: ResultCache) -> Self {
+        self.results = Some(cache);
+        self
+    }
+
+    /// Let requests give CPI files for monetary standardization, as paths relative to the
+    /// directory. Without a CPI directory, requests which give a CPI file are refused, so that
+    /// clients can't have the service read whatever file they name.
+    pub fn with_cpi_directory(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.cpi_directory = Some(directory.into());
+        self
+    }
+
+    /// Compute the tabulation described by a JSON Abacus request.
+    pub fn tabulate_json(&self, input: &str) -> Result<Tabulation, MdError> {
+        let request = self.parse_request(input)?;
+        let ctx = self.context_for_request(&request)?;
+        let prepared = self.prepare_request(&ctx, request)?;
+        self.tabulate_prepared(&prepared)
+    }
+
+    /// Parse a JSON Abacus request, and resolve its CPI file in the CPI directory.
+    fn parse_request(
+        &self,
+        input: &str,
+    ) -> Result<input_schema_tabulation::AbacusRequest, MdError> {
+        let mut request = AbacusRequest::parse_json(input)?;
+        self.check_data_root(request.data_root.as_deref())?;
+        if let Some(ref mut ms) = request.monetary_standardization {
+            if let Some(ref cpi_file) = ms.cpi_file {
+                let resolved = self
+                    .resolve_cpi_file(cpi_file)
+                    .map_err(|err| err.in_request("monetary_standardization.cpi_file"))?;
+                ms.cpi_file = Some(resolved.display().to_string());
+            }
+        }
+        Ok(request)
+    }
+
+    /// The path of a CPI file given relative to the CPI directory. The file must exist and be
+    /// inside the directory after following any links.
+    fn resolve_cpi_file(&self, cpi_file: &str) -> Result<PathBuf, MdError> {
+        let Some(ref directory) = self.cpi_directory else {
+            return Err(parsing_error!(
+                "This service only uses the built-in CPI-U table"
+            ));
+        };
+        let path = Path::new(cpi_file);
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(parsing_error!(
+                "CPI file '{cpi_file}' must be a path relative to the service's CPI directory"
+            ));
+        }
+        let directory = directory.canonicalize()?;
+        let not_found = || parsing_error!("There's no CPI file '{cpi_file}'");
+        let resolved = directory
+            .join(path)
+            .canonicalize()
+            .map_err(|_| not_found())?;
+        if !resolved.starts_with(&directory) || !resolved.is_file() {
+            return Err(not_found());
+        }
+        Ok(resolved)
+    }
+
+    fn tabulate_prepared(&self, prepared: &PreparedTabulation) -> Result<Tabulation, MdError> {
+        let tab = prepared.run()?;
+        if self.disclosure == DisclosureRules::default() {
+            Ok(tab)
+        } else {
+            self.disclosure.apply_to_tabulation(&tab)
+        }
+    }
+
+    /// A context with metadata loaded for the request's samples.
+    fn context_for_request(
+        &self,
+        request: &input_schema_tabulation::AbacusRequest,
+    ) -> Result<Arc<Context>, MdError> {
+        self.engine.contexts().context_for(
+            &request.product,
+            request.data_root.clone(),
+            &request.sample_names(),
+        )
+    }
+
+    /// The result cache key for an Abacus request, if the service has a result cache.
+    fn cache_key(
+        &self,
+        ctx: &Context,
+        request: &input_schema_tabulation::AbacusRequest,
+    ) -> Result<Option<CacheKey>, MdError> {
+        if self.results.is_none() {
+            return Ok(None);
+        }
+        let options = json!({ "disclosure": self.disclosure });
+        CacheKey::new(ctx, request, options).map(Some)
+    }
+
+    /// Compute a tabulation as JSON, or get it from the result cache.
+    ///
+    /// The result cache is only an optimization, so when the request's cache key can't be
+    /// computed, say because a data file can't be read, the tabulation runs without it.
+    fn tabulate_output(&self, request: input_schema_tabulation::AbacusRequest) -> ServiceResponse {
+        let ctx = match self.context_for_request(&request) {
+            Ok(ctx) => ctx,
+            Err(err) => return ServiceResponse::from_error(400, &err),
+        };
+        let key = self.cache_key(&ctx, &request).unwrap_or_else(|err| {
+            eprintln!("Error while computing a result cache key: {err}");
+            None
+        });
+        let cache = self.results.as_ref().zip(key.as_ref());
+        // Only requests which passed the disclosure checks have cached results, and the key
+        // includes the disclosure rules, so a cached result can be returned as it is.
+        if let Some(output) = cache.and_then(|(results, key)| results.get(key)) {
+            return ServiceResponse::ok(output.to_string());
+        }
+
+        let prepared = match self.prepare_request(&ctx, request) {
+            Ok(prepared) => prepared,
+            Err(err) => return ServiceResponse::from_error(400, &err),
+        };
+        let output = match self
+            .tabulate_prepared(&prepared)
+            .and_then(|tab| tab.output(TableFormat::Json))
+        {
+            Ok(output) => output,
+            Err(err) => return ServiceResponse::from_error(500, &err),
+        };
+        if let Some((results, key)) = cache {
+            if let Err(err) = results.insert(key, output.clone()) {
+                eprintln!("Error while caching a tabulation: {err}");
+            }
+        }
+        ServiceResponse::ok(output)
+    }
+
+    /// Compile an Abacus request on the engine, with a context from
+    /// [context_for_request](Self::context_for_request).
+    fn prepare_request(
+        &self,
+        ctx: &Context,
+        request: input_schema_tabulation::AbacusRequest,
+    ) -> Result<Arc<PreparedTabulation>, MdError> {
+        let weight = request.weight.clone();
+        let prepared = self.engine.prepare_input_in(ctx, request)?;
+        self.disclosure.check_variables(
+            prepared
+                .request_variables()
+                .iter()
+                .chain(prepared.subpopulation()),
+        )?;
+        self.disclosure.check_weight(ctx, &weight)?;
+        Ok(prepared)
+    }
+
+    /// Check a JSON Abacus request against metadata without running it. Returns every problem
+    /// found with the request.
+    pub fn validate_json(&self, input: &str) -> Result<Vec<Diagnostic>, MdError> {
+        let request = AbacusRequest::parse_json(input)?;
+        self.check_data_root(request.data_root.as_deref())?;
+        let ctx =
+            self.engine
+                .contexts()
+                .context_for(&request.product, request.data_root.clone(), &[])?;
+        let samples = validation::loadable_samples(&ctx, &request)?;
+        let ctx = self.engine.contexts().context_for(
+            &request.product,
+            request.data_root.clone(),
+            &samples,
+        )?;
+        Ok(validation::validate(&ctx, &request))
+    }
+
+    /// The names of the datasets available for the product, sorted by name.
+    pub fn dataset_names(
+        &self,
+        product: &str,
+        data_root: Option<String>,
+    ) -> Result<Vec<String>, MdError> {
+        self.check_data_root(data_root.as_deref())?;
+        self.engine
+            .contexts()
+            .context_for(product, data_root, &[])?
+            .dataset_names_from_layouts()
+    }
+
+    /// Describe the variables available in the dataset as a JSON array, sorted by name.
+    pub fn variables_for_dataset(
+        &self,
+        product: &str,
+        dataset: &str,
+        data_root: Option<String>,
+    ) -> Result<serde_json::Value, MdError> {
+        self.check_data_root(data_root.as_deref())?;
+        let ctx = self
+            .engine
+            .contexts()
+            .context_for(product, data_root, &[dataset])?;
+        let Some(ref md) = ctx.settings.metadata else {
+            return Err(MdError::MetadataError(format!(
+                "No metadata loaded for dataset {dataset}"
+            )));
+        };
+        let Some(ds_id) = md.datasets_by_name.get(dataset) else {
+            return Err(MdError::NotFound(Entity::Dataset(dataset.to_string())));
+        };
+
+        let mut variables: Vec<_> = md
+            .available_variables
+            .for_dataset(*ds_id)
+            .map(|ids| {
+                ids.iter()
+                    .map(|id| &md.variables_index[*id])
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        variables.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let described = variables
+            .iter()
+            .map(|var| {
+                json!({
+                    "name": var.name,
+                    "record_type": var.record_type,
+                    "label": var.label,
+                    "width": var.formatting.map(|(_, width)| width),
+                })
+            })
+            .collect();
+        Ok(serde_json::Value::Array(described))
+    }
+
+    /// Route one HTTP request to the service.
+    ///
+    /// This is independent of the HTTP server so that the API can be tested without opening a
+    /// socket. `url` is the path and query string of the request.
+    pub fn handle(&self, method: &str, url: &str, body: &str) -> ServiceResponse {
+        let (path, query) = match url.split_once('?') {
+            Some((path, query)) => (path, parse_query(query)),
+            None => (url, HashMap::new()),
+        };
+
+        match (method, path) {
+            ("POST", "/tabulate") => match self.parse_request(body) {
+                Ok(request) => self.tabulate_output(request),
+                Err(err) => ServiceResponse::from_error(400, &err),
+            },
+            ("POST", "/validate") => match self.validate_json(body) {
+                Ok(diagnostics) => ServiceResponse::ok(json!(diagnostics).to_string()),
+                Err(err) => ServiceResponse::from_error(400, &err),
+            },
+            ("GET", "/datasets") => {
+                let Some(product) = query.get("product") else {
+                    return ServiceResponse::error(400, "missing required parameter 'product'");
+                };
+                match self.dataset_names(product, query.get("data_root").cloned()) {
+                    Ok(names) => ServiceResponse::ok(json!(names).to_string()),
+                    Err(err) => ServiceResponse::from_error(400, &err),
+                }
+            }
+            ("GET", "/variables") => {
+                let (Some(product), Some(dataset)) = (query.get("product"), query.get("dataset"))
+                else {
+                    return ServiceResponse::error(
+                        400,
+                        "missing required parameters 'product' and 'dataset'",
+                    );
+                };
+                match self.variables_for_dataset(product, dataset, query.get("data_root").cloned())
+                {
+                    Ok(variables) => ServiceResponse::ok(variables.to_string()),
+                    Err(err) => ServiceResponse::from_error(400, &err),
+                }
+            }
+            (_, "/tabulate" | "/validate" | "/datasets" | "/variables") => {
+                ServiceResponse::error(405, &format!("method {method} not allowed on {path}"))
+            }
+            _ => ServiceResponse::error(404, &format!("no such endpoint {path}")),
+        }
+    }
+}
+
+/// Parse a URL query string into a map, decoding `+` and percent-encoded bytes.
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' => decoded.push(b' '),
+            b'%' if index + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[index + 1..index + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        index += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Serve the tabulation service over HTTP on `address` until the process is stopped.
+///
+/// `threads` worker threads accept and answer requests, so up to that many tabulations run at
+/// once.
+pub fn serve(
+    service: Arc<TabulationService>,
+    address: &str,
+    threads: usize,
+) -> Result<(), MdError> {
+    let server = tiny_http::Server::http(address)
+        .map_err(|err| MdError::Msg(format!("Cannot listen on {address}: {err}")))?;
+    let server = Arc::new(server);
+
+    let mut workers = Vec::new();
+    for _ in 0..threads.max(1) {
+        let server = Arc::clone(&server);
+        let service = Arc::clone(&service);
+        workers.push(thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                let response = if let Err(err) = request.as_reader().read_to_string(&mut body) {
+                    ServiceResponse::error(400, &format!("Cannot read request body: {err}"))
+                } else {
+                    service.handle(request.method().as_str(), request.url(), &body)
+                };
+
+                let content_type =
+                    tiny_http::Header::from_bytes("Content-Type", "application/json")
+                        .expect("the content type header should be valid");
+                let http_response = tiny_http::Response::from_string(response.body)
+                    .with_status_code(response.status)
+                    .with_header(content_type);
+                if let Err(err) = request.respond(http_response) {
+                    eprintln!("Error while sending response: {err}");
+                }
+            }
+        }));
+    }
+
+    for worker in workers {
+        if worker.join().is_err() {
+            return Err(MdError::Msg(
+                "A service worker thread panicked.".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_service() -> TabulationService {
+        TabulationService::new(Some("tests/data_root".to_string()))
+    }
+
+    #[test]
+    fn test_context_cache_reuses_contexts() {
+        let cache = ContextCache::new(Some("tests/data_root".to_string()));
+        let first = cache
+            .context_for("usa", None, &["us1900m"])
+            .expect("should load a context for us1900m");
+        let second = cache
+            .context_for("USA", None, &["us1900m"])
+            .expect("should reuse the context for us1900m");
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let extended = cache
+            .context_for("usa", None, &["us1940a"])
+            .expect("should extend the context with us1940a");
+        assert!(!Arc::ptr_eq(&first, &extended));
+        assert!(extended.has_metadata_for_dataset("us1900m"));
+        assert!(extended.has_metadata_for_dataset("us1940a"));
+        assert!(!first.has_metadata_for_dataset("us1940a"));
+    }
+
+    #[test]
+    fn test_context_cache_drops_least_recently_used() {
+        let cache = ContextCache::new(None);
+        // Each spelling of the data root is a different key.
+        let data_root = |i: usize| Some(format!("tests/data_root{}", "/.".repeat(i)));
+        let first = cache.context_for("usa", data_root(0), &[]).unwrap();
+        for i in 1..=MAX_CONTEXTS {
+            cache.context_for("usa", data_root(i), &[]).unwrap();
+        }
+        let contexts = cache.contexts.lock().unwrap();
+        assert_eq!(contexts.contexts.len(), MAX_CONTEXTS);
+        assert!(contexts
+            .contexts
+            .values()
+            .all(|(ctx, _)| !Arc::ptr_eq(ctx, &first)));
+    }
+
+    #[test]
+    fn test_only_serves_allowed_data_roots() {
+        let mut request: serde_json::Value = serde_json::from_str(include_str!(
+            "../tests/requests/race_hispan_subpop_statefip.json"
+        ))
+        .unwrap();
+        request["data_root"] = json!("tests/data_root/.");
+        let body = request.to_string();
+
+        let response = test_service().handle("POST", "/tabulate", &body);
+        assert_eq!(response.status, 400, "{}", response.body);
+        let error: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(error["field"], "data_root");
+        assert_eq!(
+            test_service().handle("POST", "/validate", &body).status,
+            400
+        );
+        let response = test_service().handle(
+            "GET",
+            "/datasets?product=usa&data_root=tests/data_root/.",
+            "",
+        );
+        assert_eq!(response.status, 400, "{}", response.body);
+
+        // An allowed data root is served.
+        let service = test_service().with_allowed_data_roots(vec!["tests/data_root/.".to_string()]);
+        let response = service.handle(
+            "GET",
+            "/datasets?product=usa&data_root=tests/data_root/.",
+            "",
+        );
+        assert_eq!(response.status, 200, "{}", response.body);
+    }
+
+    #[test]
+    fn test_dataset_names_must_be_plain() {
+        let service = test_service();
+        for dataset in ["../layouts/us1900m", "/etc/passwd", "us1900m/..", "."] {
+            let url = format!("/variables?product=usa&dataset={dataset}");
+            let response = service.handle("GET", &url, "");
+            assert_eq!(response.status, 400, "{dataset}: {}", response.body);
+            let error: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+            assert_eq!(error["code"], "unknown_dataset", "{dataset}");
+        }
+    }
+
+    #[test]
+    fn test_handle_tabulate() {
+        let service = test_service();
+        let body = std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json")
+            .expect("should be able to read the test request");
+        let response = service.handle("POST", "/tabulate", &body);
+        assert_eq!(response.status, 200, "{}", response.body);
+
+        let tables: serde_json::Value =
+            serde_json::from_str(&response.body).expect("response should be JSON");
+        let tables = tables
+            .as_array()
+            .expect("response should be a list of tables");
+        assert_eq!(tables.len(), 1);
+        assert!(!tables[0]["rows"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_handle_tabulate_with_disclosure_rules() {
+        let service = test_service().with_disclosure_rules(DisclosureRules {
+            min_cell_count: Some(1_000_000),
+            ..DisclosureRules::default()
+        });
+        let body = std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json")
+            .expect("should be able to read the test request");
+        let response = service.handle("POST", "/tabulate", &body);
+        assert_eq!(response.status, 200, "{}", response.body);
+
+        let tables: serde_json::Value =
+            serde_json::from_str(&response.body).expect("response should be JSON");
+        let table = &tables[0];
+        let rows = table["rows"].as_array().unwrap();
+        assert!(rows.iter().all(|row| row[0].is_null()));
+        assert_eq!(
+            table["disclosure"]["suppressed"].as_array().unwrap().len(),
+            rows.len()
+        );
+    }
+
+    #[test]
+    fn test_handle_tabulate_with_result_cache() {
+        let directory = tempfile::tempdir().unwrap();
+        let body = std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json")
+            .expect("should be able to read the test request");
+        let service =
+            test_service().with_result_cache(ResultCache::new(10).with_directory(directory.path()));
+        let response = service.handle("POST", "/tabulate", &body);
+        assert_eq!(response.status, 200, "{}", response.body);
+        assert_eq!(service.handle("POST", "/tabulate", &body), response);
+
+        // Another service finds the result in the directory.
+        let cached =
+            test_service().with_result_cache(ResultCache::new(10).with_directory(directory.path()));
+        let request = || cached.parse_request(&body).unwrap();
+        let ctx = cached.context_for_request(&request()).unwrap();
+        let key = cached.cache_key(&ctx, &request()).unwrap().unwrap();
+        assert_eq!(
+            cached.results.as_ref().unwrap().get(&key).unwrap().as_str(),
+            response.body
+        );
+        assert_eq!(cached.handle("POST", "/tabulate", &body), response);
+
+        // Different disclosure rules give a different result, so they don't share it.
+        let rules = DisclosureRules {
+            min_cell_count: Some(1_000_000),
+            ..DisclosureRules::default()
+        };
+        let suppressed = test_service()
+            .with_disclosure_rules(rules)
+            .with_result_cache(ResultCache::new(10).with_directory(directory.path()));
+        assert_ne!(
+            suppressed.cache_key(&ctx, &request()).unwrap().unwrap(),
+            key
+        );
+        assert_ne!(suppressed.handle("POST", "/tabulate", &body), response);
+
+        // Bad requests aren't answered from the cache.
+        let body = body.replace("\"RACE\"", "\"RACW\"");
+        assert_eq!(service.handle("POST", "/tabulate", &body).status, 400);
+    }
+
+    #[test]
+    fn test_handle_tabulate_cpi_file() {
+        let mut request: serde_json::Value = serde_json::from_str(include_str!(
+            "../tests/requests/incwage_category_bins_us1940a.json"
+        ))
+        .unwrap();
+        let with_cpi_file = |request: &mut serde_json::Value, cpi_file: &str| {
+            request["monetary_standardization"] = json!({
+                "base_year": 1950,
+                "cpi_file": cpi_file,
+                "variables": ["INCWAGE"]
+            });
+            request.to_string()
+        };
+
+        let body = with_cpi_file(&mut request, "cpi_doubling.csv");
+        let response = test_service().handle("POST", "/tabulate", &body);
+        assert_eq!(response.status, 400, "{}", response.body);
+        let error: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(error["field"], "monetary_standardization.cpi_file");
+        assert_eq!(error["code"], "parsing");
+
+        let service = test_service().with_cpi_directory("tests/requests");
+        let response = service.handle("POST", "/tabulate", &body);
+        assert_eq!(response.status, 200, "{}", response.body);
+
+        let outside = std::env::current_dir()
+            .unwrap()
+            .join("Cargo.toml")
+            .display()
+            .to_string();
+        for cpi_file in ["../../Cargo.toml", &outside, "no_such_cpi.csv", ""] {
+            let body = with_cpi_file(&mut request, cpi_file);
+            let response = service.handle("POST", "/tabulate", &body);
+            assert_eq!(response.status, 400, "{cpi_file}: {}", response.body);
+            assert!(!response.body.contains("[package]"), "{}", response.body);
+            let error: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+            assert_eq!(error["code"], "parsing", "{cpi_file}: {}", response.body);
+        }
+    }
+
+    #[test]
+    fn test_handle_validate() {
+        let service = test_service();
+        let body = std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json")
+            .expect("should be able to read the test request");
+        let response = service.handle("POST", "/validate", &body);
+        assert_eq!(response.status, 200, "{}", response.body);
+        assert_eq!(response.body, "[]");
+
+        let body = body.replace("\"RACE\"", "\"RACW\"");
+        let response = service.handle("POST", "/validate", &body);
+        assert_eq!(response.status, 200, "{}", response.body);
+        let diagnostics: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic["severity"], "error");
+        assert_eq!(diagnostic["kind"], "unknown_variable");
+        assert_eq!(diagnostic["variable"], "RACW");
+        assert_eq!(diagnostic["suggestions"][0], "RACE");
+        assert_eq!(service.handle("GET", "/validate", "").status, 405);
+    }
+
+    #[test]
+    fn test_handle_bad_request() {
+        let service = test_service();
+        let response = service.handle("POST", "/tabulate", "{ not json");
+        assert_eq!(response.status, 400);
+        let body: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert!(body["error"].is_string());
+        assert_eq!(body["code"], "parsing");
+
+        let body = std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json")
+            .expect("should be able to read the test request")
+            .replace("\"RACE\"", "\"RACW\"");
+        let response = service.handle("POST", "/tabulate", &body);
+        assert_eq!(response.status, 400);
+        let body: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(body["code"], "unknown_variable");
+        assert_eq!(body["entity"]["kind"], "variable");
+        assert_eq!(body["entity"]["name"], "RACW");
+        assert_eq!(body["field"], "request_variables[0]");
+    }
+
+    #[test]
+    fn test_handle_datasets_and_variables() {
+        let service = test_service();
+        let response = service.handle("GET", "/datasets?product=usa", "");
+        assert_eq!(response.status, 200, "{}", response.body);
+        let names: Vec<String> = serde_json::from_str(&response.body).unwrap();
+        assert!(names.contains(&"us1900m".to_string()));
+
+        let response = service.handle(
+            "GET",
+            "/variables?product=usa&dataset=us1900m&data_root=tests%2Fdata_root",
+            "",
+        );
+        assert_eq!(response.status, 200, "{}", response.body);
+        let variables: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        let names: Vec<_> = variables
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["name"].as_str().unwrap())
+            .collect();
+        assert!(names.contains(&"RACE"));
+
+        let response = service.handle("GET", "/variables?product=usa", "");
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn test_handle_unknown_routes() {
+        let service = test_service();
+        assert_eq!(service.handle("GET", "/nothing", "").status, 404);
+        assert_eq!(service.handle("GET", "/tabulate", "").status, 405);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query("product=usa&data_root=%2Fpkg%2Fipums+data&flag");
+        assert_eq!(query.get("product").unwrap(), "usa");
+        assert_eq!(query.get("data_root").unwrap(), "/pkg/ipums data");
+        assert_eq!(query.get("flag").unwrap(), "");
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+}
@@ -4,22 +4,33 @@
 //! carry some metadata information with them to be used by formatters or even codebook
 //! generators.
 //!
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::str::FromStr;
 
 use crate::conventions::Context;
+use crate::disclosure::DisclosureReport;
 use crate::ipums_metadata_model::IpumsDataType;
-use crate::mderror::{metadata_error, MdError};
+use crate::mderror::{metadata_error, parsing_error, MdError};
 use crate::query_gen::tab_queries;
 use crate::query_gen::DataPlatform;
+use crate::query_gen::DataSource;
+use crate::query_gen::TabQuery;
 use crate::request::DataRequest;
 use crate::request::InputType;
 use crate::request::RequestVariable;
 
+use duckdb::types::Value;
 use duckdb::Connection;
+use rust_decimal::prelude::*;
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
 use serde::ser::Error;
 use serde::Serialize;
 
-const DEBUG: bool = false;
+/// The most rows that filling in a table's empty cells may produce.
+const MAX_COMPLETED_ROWS: usize = 1_000_000;
 
 /// The format of an output table.
 #[derive(Clone, Copy, Debug)]
@@ -56,6 +67,197 @@ impl FromStr for TableFormat {
     }
 }
 
+/// How to round decimal values when formatting them to a fixed number of places.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Rounding {
+    /// Round half way values to the nearest even digit, sometimes called banker's rounding.
+    #[default]
+    HalfEven,
+    /// Round half way values away from zero, as taught in school.
+    HalfAwayFromZero,
+    /// Drop the extra digits.
+    TowardZero,
+}
+
+impl Rounding {
+    fn strategy(&self) -> RoundingStrategy {
+        match self {
+            Self::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Self::HalfAwayFromZero => RoundingStrategy::MidpointAwayFromZero,
+            Self::TowardZero => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+/// Options for formatting tabulation output.
+///
+/// By default values are formatted exactly, with no rounding.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FormatOptions {
+    /// Round decimal and floating point values to this many places after the decimal point.
+    pub decimal_places: Option<u32>,
+    pub rounding: Rounding,
+}
+
+/// One value in a [Table].
+///
+/// The type of a cell comes from the [IpumsDataType] of its column. Variables with type
+/// `Fixed(n)` have exact decimal values, as do weighted counts. A cell is `Null` when the
+/// data has no value, for example when a record has no match in a joined record type.
+///
+/// ```
+/// use cimdea::tabulate::Cell;
+/// use rust_decimal::Decimal;
+///
+/// assert_eq!(Cell::Integer(25).to_string(), "25");
+/// assert_eq!(Cell::Decimal(Decimal::new(10050, 2)).to_string(), "100.50");
+/// assert_eq!(Cell::Null.to_string(), "");
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cell {
+    Integer(i64),
+    Decimal(Decimal),
+    Float(f64),
+    String(String),
+    Null,
+}
+
+impl Cell {
+    /// Convert a value from the data platform into a cell for a column of the given type.
+    pub fn try_from_value(value: Value, data_type: &IpumsDataType) -> Result<Self, MdError> {
+        let integer = match value {
+            Value::Null => return Ok(Self::Null),
+            Value::Float(f) => return Ok(Self::Float(f.into())),
+            Value::Double(f) => return Ok(Self::Float(f)),
+            Value::Decimal(d) => return Ok(Self::Decimal(d)),
+            Value::Text(text) => return Ok(Self::from_text(text, data_type)),
+            Value::Boolean(b) => i64::from(b),
+            Value::TinyInt(i) => i.into(),
+            Value::SmallInt(i) => i.into(),
+            Value::Int(i) => i.into(),
+            Value::BigInt(i) => i,
+            Value::UTinyInt(i) => i.into(),
+            Value::USmallInt(i) => i.into(),
+            Value::UInt(i) => i.into(),
+            Value::UBigInt(i) => i64::try_from(i)
+                .map_err(|_| MdError::Msg(format!("value {i} is too large for a table cell")))?,
+            Value::HugeInt(i) => i64::try_from(i)
+                .map_err(|_| MdError::Msg(format!("value {i} is too large for a table cell")))?,
+            other => {
+                return Err(MdError::Msg(format!(
+                    "can't put a value of type {} in a table cell",
+                    other.data_type()
+                )))
+            }
+        };
+        Ok(Self::from_integer(integer, data_type))
+    }
+
+    /// Convert a sum of raw weights into an exact weighted count by dividing by the weight
+    /// divisor.
+    pub fn try_weighted_count(value: Value, divisor: usize) -> Result<Self, MdError> {
+        let divisor = Decimal::from(divisor);
+        let weighted = match Self::try_from_value(value, &IpumsDataType::Integer)? {
+            Self::Integer(sum) => Decimal::from(sum).checked_div(divisor),
+            Self::Decimal(sum) => sum.checked_div(divisor),
+            Self::Float(sum) => return Ok(Self::Float(sum / divisor.to_f64().unwrap_or(1.0))),
+            other => return Ok(other),
+        };
+        match weighted {
+            Some(weighted) => Ok(Self::Decimal(weighted.normalize())),
+            None => Err(MdError::Msg(format!(
+                "can't divide weighted count by weight divisor {divisor}"
+            ))),
+        }
+    }
+
+    fn from_integer(value: i64, data_type: &IpumsDataType) -> Self {
+        match data_type {
+            IpumsDataType::Integer => Self::Integer(value),
+            IpumsDataType::Fixed(places) => Self::Decimal(Decimal::new(value, *places as u32)),
+            IpumsDataType::Float => Self::Float(value as f64),
+            IpumsDataType::String => Self::String(value.to_string()),
+        }
+    }
+
+    // Category bin codes come back from the queries as strings like '003'.
+    fn from_text(text: String, data_type: &IpumsDataType) -> Self {
+        match data_type {
+            IpumsDataType::Integer | IpumsDataType::Fixed(_) => match text.trim().parse() {
+                Ok(value) => Self::from_integer(value, data_type),
+                Err(_) => Self::String(text),
+            },
+            IpumsDataType::Float => match text.trim().parse() {
+                Ok(value) => Self::Float(value),
+                Err(_) => Self::String(text),
+            },
+            IpumsDataType::String => Self::String(text),
+        }
+    }
+
+    /// Round decimal and floating point values according to the options. Other values are
+    /// unchanged.
+    pub fn rounded(&self, options: &FormatOptions) -> Self {
+        let Some(places) = options.decimal_places else {
+            return self.clone();
+        };
+        let value = match self {
+            Self::Decimal(d) => *d,
+            Self::Float(f) => match Decimal::from_f64(*f) {
+                Some(d) => d,
+                None => return self.clone(),
+            },
+            _ => return self.clone(),
+        };
+        let mut rounded = value.round_dp_with_strategy(places, options.rounding.strategy());
+        rounded.rescale(places);
+        Self::Decimal(rounded)
+    }
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Integer(i) => f.pad(&i.to_string()),
+            Self::Decimal(d) => f.pad(&d.to_string()),
+            Self::Float(x) => f.pad(&x.to_string()),
+            Self::String(s) => f.pad(s),
+            Self::Null => f.pad(""),
+        }
+    }
+}
+
+/// Cells serialize to JSON numbers, strings and nulls. Decimals always serialize as
+/// strings of their exact digits at their scale, like "998208.45" or "84.00", since a
+/// JSON number would go through floating point and could lose digits.
+impl Serialize for Cell {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Self::Integer(i) => serializer.serialize_i64(*i),
+            Self::Decimal(d) => serializer.serialize_str(&d.to_string()),
+            Self::Float(x) => serializer.serialize_f64(*x),
+            Self::String(s) => serializer.serialize_str(s),
+            Self::Null => serializer.serialize_none(),
+        }
+    }
+}
+
+impl JsonSchema for Cell {
+    fn schema_name() -> Cow<'static, str> {
+        "Cell".into()
+    }
+
+    fn json_schema(_generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "A value in a table. Null when the value is missing or its counts were suppressed. Decimals, like weighted counts, are always strings of their exact digits at their scale, such as \"84.00\".",
+            "type": ["number", "string", "null"]
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum OutputColumn {
     Constructed {
@@ -110,7 +312,48 @@ impl Serialize for OutputColumn {
     } // serialize trait
 } // impl
 
+/// Both variants serialize to the same fields, wrapped in an object keyed by the variant name.
+impl JsonSchema for OutputColumn {
+    fn schema_name() -> Cow<'static, str> {
+        "OutputColumn".into()
+    }
+
+    fn json_schema(_generator: &mut SchemaGenerator) -> Schema {
+        let column = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "width": { "type": "integer", "minimum": 0 },
+                "data_type": { "type": "string" }
+            },
+            "required": ["name", "width", "data_type"]
+        });
+        let variant = |name: &str| {
+            serde_json::json!({
+                "type": "object",
+                "properties": { name: column },
+                "required": [name],
+                "additionalProperties": false
+            })
+        };
+        json_schema!({
+            "description": "A column of a table. Constructed columns are counts and other values computed by the tabulation, and RequestVar columns are request variables.",
+            "oneOf": [variant("Constructed"), variant("RequestVar")]
+        })
+    }
+}
+
 impl OutputColumn {
+    /// The type of the values in this column. Bucketed and general versions of variables
+    /// are integer codes.
+    pub fn data_type(&self) -> IpumsDataType {
+        match self {
+            Self::Constructed { ref data_type, .. } => data_type.clone(),
+            Self::RequestVar(ref v) if v.is_bucketed() || v.is_general() => IpumsDataType::Integer,
+            Self::RequestVar(ref v) => v.data_type().unwrap_or(IpumsDataType::Integer),
+        }
+    }
+
     pub fn name(&self) -> String {
         match self {
             Self::Constructed { ref name, .. } => name.clone(),
@@ -131,10 +374,10 @@ impl OutputColumn {
                 } else if let Some(general_width) = v.variable.general_width {
                     Ok(general_width)
                 } else {
-                    Err(MdError::Msg(format!(
+                    Err(metadata_error!(
                         "cannot determine general width for variable {}",
                         self.name()
-                    )))
+                    ))
                 }
             }
         }
@@ -144,10 +387,14 @@ impl OutputColumn {
 // If we want we can use the IpumsVariable categories to replace the numbers in the results (rows)
 // with category labels and use the data type and width information to better format the table.
 
-#[derive(Clone, Debug, Serialize)]
+/// The result of a tabulation for one sample.
+#[derive(Clone, Debug, JsonSchema, Serialize)]
 pub struct Table {
     pub heading: Vec<OutputColumn>, // variable name columns
-    pub rows: Vec<Vec<String>>,
+    pub rows: Vec<Vec<Cell>>,
+    /// The disclosure rules applied to the table and the cells they suppressed, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disclosure: Option<DisclosureReport>,
 }
 
 impl Table {
@@ -174,6 +421,10 @@ impl Table {
             }
             out.push_str("|\n");
         }
+        if let Some(ref report) = self.disclosure {
+            out.push_str(&report.summary());
+            out.push('\n');
+        }
         Ok(out)
     }
 
@@ -209,15 +460,157 @@ impl Table {
 
     #[allow(unused)]
     fn width_from_data(&self, column: usize) -> Option<usize> {
-        self.rows.iter().map(|r| r[column].len()).max()
+        self.rows.iter().map(|r| r[column].to_string().len()).max()
+    }
+
+    /// A copy of the table with values rounded according to the options.
+    pub fn rounded(&self, options: &FormatOptions) -> Self {
+        Self {
+            heading: self.heading.clone(),
+            rows: self
+                .rows
+                .iter()
+                .map(|row| row.iter().map(|cell| cell.rounded(options)).collect())
+                .collect(),
+            disclosure: self.disclosure.clone(),
+        }
     }
 
     pub fn empty() -> Self {
         Self {
             rows: Vec::new(),
             heading: Vec::new(),
+            disclosure: None,
         }
     }
+
+    /// Fill in rows with zero counts for every combination of the categories of the table's
+    /// variables, which are the columns from `first_variable` on. The rows come in the order
+    /// of the categories; rows with values outside of the categories, like nulls, go last.
+    fn complete(&mut self, first_variable: usize, categories: &[Vec<Cell>]) {
+        // Zeros have the scale of their column, so that they format like its other cells.
+        let zeros: Vec<Cell> = self.heading[..first_variable]
+            .iter()
+            .map(|column| Cell::from_integer(0, &column.data_type()))
+            .collect();
+
+        let mut found: BTreeMap<Vec<usize>, Vec<Vec<Cell>>> = BTreeMap::new();
+        let mut others = Vec::new();
+        for row in std::mem::take(&mut self.rows) {
+            let positions: Option<Vec<usize>> = row[first_variable..]
+                .iter()
+                .zip(categories)
+                .map(|(cell, values)| {
+                    values
+                        .binary_search_by(|value| compare_cells(value, cell))
+                        .ok()
+                })
+                .collect();
+            match positions {
+                Some(positions) => found.entry(positions).or_default().push(row),
+                None => others.push(row),
+            }
+        }
+
+        if categories.iter().all(|values| !values.is_empty()) {
+            let mut positions = vec![0; categories.len()];
+            'rows: loop {
+                match found.remove(&positions) {
+                    Some(rows) => self.rows.extend(rows),
+                    None => {
+                        let mut row = zeros.clone();
+                        row.extend(
+                            positions
+                                .iter()
+                                .zip(categories)
+                                .map(|(&p, values)| values[p].clone()),
+                        );
+                        self.rows.push(row);
+                    }
+                }
+                // Step to the next combination, with the last variable changing fastest.
+                for column in (0..positions.len()).rev() {
+                    positions[column] += 1;
+                    if positions[column] < categories[column].len() {
+                        continue 'rows;
+                    }
+                    positions[column] = 0;
+                }
+                break;
+            }
+        }
+        self.rows.extend(others);
+    }
+}
+
+/// An order for the values of a column, for sorting categories.
+fn compare_cells(a: &Cell, b: &Cell) -> Ordering {
+    fn rank(cell: &Cell) -> u8 {
+        match cell {
+            Cell::Integer(_) => 0,
+            Cell::Decimal(_) => 1,
+            Cell::Float(_) => 2,
+            Cell::String(_) => 3,
+            Cell::Null => 4,
+        }
+    }
+    match (a, b) {
+        (Cell::Integer(a), Cell::Integer(b)) => a.cmp(b),
+        (Cell::Decimal(a), Cell::Decimal(b)) => a.cmp(b),
+        (Cell::Float(a), Cell::Float(b)) => a.total_cmp(b),
+        (Cell::String(a), Cell::String(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Fill in the empty cells of a tabulation's tables so that every table has the same rows.
+///
+/// A variable's categories are its known codes, from [RequestVariable::category_codes], or
+/// when it has none, every value which turns up in any of the tables.
+fn complete_tables(
+    tables: &mut [Table],
+    category_codes: &[Option<Vec<i64>>],
+) -> Result<(), MdError> {
+    let Some(first) = tables.first() else {
+        return Ok(());
+    };
+    let first_variable = first.heading.len() - category_codes.len();
+    let mut categories = Vec::new();
+    for (column, codes) in category_codes.iter().enumerate() {
+        let column_type = first.heading[first_variable + column].data_type();
+        let values = match codes {
+            Some(codes) if column_type == IpumsDataType::Integer => {
+                codes.iter().map(|&code| Cell::Integer(code)).collect()
+            }
+            _ => {
+                let mut observed: Vec<Cell> = tables
+                    .iter()
+                    .flat_map(|t| &t.rows)
+                    .map(|row| row[first_variable + column].clone())
+                    .filter(|cell| *cell != Cell::Null)
+                    .collect();
+                observed.sort_by(compare_cells);
+                observed.dedup();
+                observed
+            }
+        };
+        categories.push(values);
+    }
+
+    let rows = categories
+        .iter()
+        .try_fold(1usize, |rows, values| rows.checked_mul(values.len()));
+    if rows.is_none_or(|rows| rows > MAX_COMPLETED_ROWS) {
+        return Err(parsing_error!(
+            "filling in empty cells would make tables with more than {MAX_COMPLETED_ROWS} rows; \
+             use category bins or recodes to reduce the number of categories"
+        )
+        .in_request("include_empty_cells"));
+    }
+    for table in tables {
+        table.complete(first_variable, &categories);
+    }
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -250,6 +643,19 @@ impl Tabulation {
         Ok(output)
     }
 
+    /// Like [output](Self::output), but round values according to the options first.
+    pub fn output_with_options(
+        &self,
+        format: TableFormat,
+        options: &FormatOptions,
+    ) -> Result<String, MdError> {
+        if options.decimal_places.is_none() {
+            return self.output(format);
+        }
+        let rounded = Tabulation(self.0.iter().map(|t| t.rounded(options)).collect());
+        rounded.output(format)
+    }
+
     pub fn into_inner(self) -> Vec<Table> {
         self.0
     }
@@ -263,41 +669,83 @@ impl Tabulation {
 /// for performance implications. The `DataPlatform::DataFusion` alternative would require minor
 /// additions to the query generation module. `DataPlatform::Polars` is also planned and shouldn't
 /// require too many additional query gen updates, but it is unimplemented for now.
+///
+/// This sets up a new DuckDB connection for every call. To tabulate many requests, use a
+/// [TabulationEngine](crate::engine::TabulationEngine) instead.
 pub fn tabulate<R>(ctx: &Context, rq: R) -> Result<Tabulation, MdError>
 where
     R: DataRequest,
 {
-    let requested_output_columns = rq
-        .get_request_variables()
+    let compiled = CompiledTabulation::compile(ctx, rq, &InputType::Parquet)?;
+    let conn = Connection::open_in_memory()?;
+    let tables = compiled
+        .queries
         .iter()
-        .map(|v| OutputColumn::RequestVar(Box::new(v.clone())))
-        .collect::<Vec<OutputColumn>>();
+        .map(|q| compiled.run_query(&conn, q))
+        .collect::<Result<Vec<_>, _>>()?;
+    compiled.finish(tables)
+}
 
-    let mut tables: Vec<Table> = Vec::new();
-    let sql_queries = tab_queries(ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)?;
-    let conn = Connection::open_in_memory()?;
-    for q in sql_queries {
-        if DEBUG {
-            println!("{}", &q);
-        }
-        let mut stmt = conn.prepare(&q)?;
+/// A request resolved into the query for each dataset, with what's needed to turn the query
+/// results into tables.
+#[derive(Clone, Debug)]
+pub struct CompiledTabulation {
+    pub queries: Vec<TabQuery>,
+    pub request_variables: Vec<RequestVariable>,
+    /// The variables which select the cases to count.
+    pub subpopulation: Vec<RequestVariable>,
+    /// The known categories of each request variable, when the request fills in empty cells.
+    category_codes: Option<Vec<Option<Vec<i64>>>>,
+}
+
+impl CompiledTabulation {
+    pub(crate) fn compile<R>(
+        ctx: &Context,
+        rq: R,
+        input_format: &InputType,
+    ) -> Result<Self, MdError>
+    where
+        R: DataRequest,
+    {
+        let request_variables = rq.get_request_variables();
+        let subpopulation = rq.get_subpopulation();
+        let category_codes = if rq.include_empty_cells() {
+            let missing_code_handling = rq.missing_code_handling();
+            Some(
+                request_variables
+                    .iter()
+                    .map(|v| v.category_codes(missing_code_handling))
+                    .collect::<Vec<_>>(),
+            )
+        } else {
+            None
+        };
+        let queries = tab_queries(ctx, rq, input_format, &DataPlatform::Duckdb)?;
+        Ok(Self {
+            queries,
+            request_variables,
+            subpopulation,
+            category_codes,
+        })
+    }
+
+    /// Run one of the queries and collect its results into a table.
+    pub(crate) fn run_query(&self, conn: &Connection, q: &TabQuery) -> Result<Table, MdError> {
+        let requested_output_columns = self
+            .request_variables
+            .iter()
+            .map(|v| OutputColumn::RequestVar(Box::new(v.clone())))
+            .collect::<Vec<OutputColumn>>();
+        let mut stmt = conn.prepare(&q.sql)?;
         let mut rows = stmt.query([])?;
 
         let mut output = Table {
-            heading: Vec::new(),
+            heading: table_heading(q, &requested_output_columns),
             rows: Vec::new(),
+            disclosure: None,
         };
-        output.heading.push(OutputColumn::Constructed {
-            name: "ct".to_string(),
-            width: 10,
-            data_type: IpumsDataType::Integer,
-        });
-        output.heading.push(OutputColumn::Constructed {
-            name: "weighted_ct".to_string(),
-            width: 10,
-            data_type: IpumsDataType::Integer,
-        });
-        output.heading.extend(requested_output_columns.clone());
+        let weighted = q.weight_name.is_some();
+        let column_types: Vec<_> = output.heading.iter().map(|c| c.data_type()).collect();
 
         while let Some(row) = rows.next()? {
             let mut this_row = Vec::new();
@@ -316,8 +764,8 @@ where
 
                 }
                 */
-                let item: isize = match row.get(column_number) {
-                    Ok(i) => i,
+                let value: Value = match row.get(column_number) {
+                    Ok(v) => v,
                     Err(e) => {
                         return Err(MdError::Msg(format!(
                             "Can't extract value for '{}', error was '{}'",
@@ -325,14 +773,138 @@ where
                         )))
                     }
                 };
-                this_row.push(format!("{}", item));
+                let cell = if weighted && column_name == "weighted_ct" {
+                    Cell::try_weighted_count(value, q.weight_divisor)?
+                } else {
+                    let Some(data_type) = column_types.get(column_number) else {
+                        return Err(MdError::Msg(format!(
+                            "Query returned unexpected column '{column_name}'"
+                        )));
+                    };
+                    Cell::try_from_value(value, data_type)?
+                };
+                this_row.push(cell);
             }
             output.rows.push(this_row);
         }
-        tables.push(output);
+        Ok(output)
     }
 
-    Ok(Tabulation(tables))
+    /// Put together the tables from running each of the queries, in order.
+    pub(crate) fn finish(&self, mut tables: Vec<Table>) -> Result<Tabulation, MdError> {
+        if let Some(ref category_codes) = self.category_codes {
+            complete_tables(&mut tables, category_codes)?;
+        }
+        Ok(Tabulation(tables))
+    }
+}
+
+/// How a request would be tabulated for one dataset.
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryExplanation {
+    pub dataset: String,
+    /// The files or tables the query reads, by record type.
+    pub data_sources: BTreeMap<String, String>,
+    pub weight_name: Option<String>,
+    pub weight_divisor: usize,
+    pub sql: String,
+    /// DuckDB's plan for the query.
+    pub plan: String,
+}
+
+impl fmt::Display for QueryExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Dataset: {}", self.dataset)?;
+        writeln!(f, "Data sources:")?;
+        for (record_type, source) in &self.data_sources {
+            writeln!(f, "  {record_type}: {source}")?;
+        }
+        match self.weight_name {
+            Some(ref weight) => {
+                writeln!(f, "Weight: {weight}, divided by {}", self.weight_divisor)?
+            }
+            None => writeln!(f, "Weight: none, counts are unweighted")?,
+        }
+        writeln!(f, "SQL:\n{}", self.sql.trim())?;
+        write!(f, "Plan:\n{}", self.plan.trim_end())
+    }
+}
+
+/// Resolve a request into the query [tabulate] would run for each dataset, without running
+/// them. DuckDB plans each query, which only reads the data files' metadata.
+pub fn explain<R>(ctx: &Context, rq: R) -> Result<Vec<QueryExplanation>, MdError>
+where
+    R: DataRequest,
+{
+    let sql_queries = tab_queries(ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)?;
+    let conn = Connection::open_in_memory()?;
+    let mut explanations = Vec::new();
+    for q in sql_queries {
+        let data_sources = DataSource::for_dataset(ctx, &q.dataset, &InputType::Parquet)?
+            .into_iter()
+            .map(|(record_type, ds)| (record_type, ds.for_platform(&DataPlatform::Duckdb)))
+            .filter(|(_, source)| q.sql.contains(source.as_str()))
+            .map(|(record_type, source)| (record_type, source.trim_matches('\'').to_string()))
+            .collect();
+
+        let mut stmt = conn.prepare(&format!("explain {}", q.sql))?;
+        let mut rows = stmt.query([])?;
+        let mut plan = String::new();
+        while let Some(row) = rows.next()? {
+            // The rows are pairs of a plan type and the plan as text.
+            let text: String = row.get(1)?;
+            plan.push_str(&text);
+            plan.push('\n');
+        }
+
+        explanations.push(QueryExplanation {
+            dataset: q.dataset,
+            data_sources,
+            weight_name: q.weight_name,
+            weight_divisor: q.weight_divisor,
+            sql: q.sql,
+            plan,
+        });
+    }
+    Ok(explanations)
+}
+
+/// The columns of the table for a tabulation query: the count, the weighted count if the
+/// query is weighted, and then the requested variables.
+pub(crate) fn table_heading(
+    query: &TabQuery,
+    requested_output_columns: &[OutputColumn],
+) -> Vec<OutputColumn> {
+    let mut heading = vec![OutputColumn::Constructed {
+        name: "ct".to_string(),
+        width: 10,
+        data_type: IpumsDataType::Integer,
+    }];
+    if query.weight_name.is_some() {
+        heading.push(OutputColumn::Constructed {
+            name: "weighted_ct".to_string(),
+            width: 10,
+            data_type: weighted_count_data_type(query.weight_divisor),
+        });
+    }
+    heading.extend(requested_output_columns.iter().cloned());
+    heading
+}
+
+/// Weights with a divisor like 100 have an implied number of decimal places, so weighted
+/// counts are exact decimals with that many places.
+pub(crate) fn weighted_count_data_type(divisor: usize) -> IpumsDataType {
+    let mut places = 0;
+    let mut remaining = divisor;
+    while remaining >= 10 && remaining.is_multiple_of(10) {
+        remaining /= 10;
+        places += 1;
+    }
+    if remaining == 1 {
+        IpumsDataType::Fixed(places)
+    } else {
+        IpumsDataType::Float
+    }
 }
 
 #[cfg(test)]
@@ -450,11 +1022,17 @@ mod test {
                 // Previously I had '98' because I was wrongly applying
                 // the SELFWTSL == 2 filter before counting.
                 assert_eq!(
-                    "1118", t.rows[0][0],
+                    "1118",
+                    t.rows[0][0].to_string(),
                     "Should be the number of person records in the data for this category."
                 );
 
-                assert_eq!("76100", t.rows[0][1], "98 should get weighted to 42300");
+                assert_eq!(
+                    "76100",
+                    t.rows[0][1].to_string(),
+                    "98 should get weighted to 42300"
+                );
+                assert_eq!(Cell::Decimal(Decimal::from(76100)), t.rows[0][1]);
             }
         }
     }
@@ -495,12 +1073,13 @@ mod test {
                 assert_eq!(4, t.rows[0].len());
                 // The unweighted count of people in GQ == 1 in PR
                 assert_eq!(
-                    "29846", t.rows[0][0],
+                    Cell::Integer(29846),
+                    t.rows[0][0],
                     "Should be the number of person records in the data, not number of households."
                 );
 
                 // The STATEFIP code should be 72 for PR
-                assert_eq!("72", t.rows[0][3]);
+                assert_eq!(Cell::Integer(72), t.rows[0][3]);
             }
         }
     }
@@ -550,4 +1129,141 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_cell_from_value() {
+        let cell = Cell::try_from_value(Value::Int(25), &IpumsDataType::Integer).unwrap();
+        assert_eq!(Cell::Integer(25), cell);
+
+        let cell = Cell::try_from_value(Value::Int(10050), &IpumsDataType::Fixed(2)).unwrap();
+        assert_eq!("100.50", cell.to_string());
+
+        let cell =
+            Cell::try_from_value(Value::Text("003".to_string()), &IpumsDataType::Integer).unwrap();
+        assert_eq!(Cell::Integer(3), cell);
+
+        let cell =
+            Cell::try_from_value(Value::Text("AB".to_string()), &IpumsDataType::String).unwrap();
+        assert_eq!(Cell::String("AB".to_string()), cell);
+
+        let cell = Cell::try_from_value(Value::Null, &IpumsDataType::Integer).unwrap();
+        assert_eq!(Cell::Null, cell);
+    }
+
+    #[test]
+    fn test_weighted_count_is_exact() {
+        let cell = Cell::try_weighted_count(Value::HugeInt(99820845), 100).unwrap();
+        assert_eq!("998208.45", cell.to_string());
+
+        let cell = Cell::try_weighted_count(Value::HugeInt(7_610_000), 100).unwrap();
+        assert_eq!("76100", cell.to_string());
+        assert_eq!("\"76100\"", serde_json::to_string(&cell).unwrap());
+
+        // Too many digits for a float.
+        let cell =
+            Cell::try_weighted_count(Value::HugeInt(1_234_567_890_123_456_789), 100).unwrap();
+        assert_eq!(
+            "\"12345678901234567.89\"",
+            serde_json::to_string(&cell).unwrap()
+        );
+
+        assert_eq!(IpumsDataType::Fixed(2), weighted_count_data_type(100));
+        assert_eq!(IpumsDataType::Fixed(0), weighted_count_data_type(1));
+        assert_eq!(IpumsDataType::Float, weighted_count_data_type(3));
+    }
+
+    #[test]
+    fn test_format_options_rounding() {
+        let cell = Cell::Decimal(Decimal::new(12345, 3));
+        let half_even = FormatOptions {
+            decimal_places: Some(2),
+            rounding: Rounding::HalfEven,
+        };
+        let half_up = FormatOptions {
+            decimal_places: Some(2),
+            rounding: Rounding::HalfAwayFromZero,
+        };
+        assert_eq!("12.34", cell.rounded(&half_even).to_string());
+        assert_eq!("12.35", cell.rounded(&half_up).to_string());
+
+        let whole = Cell::Decimal(Decimal::from(84));
+        assert_eq!("84.00", whole.rounded(&half_even).to_string());
+        assert_eq!(Cell::Integer(7), Cell::Integer(7).rounded(&half_even));
+        assert_eq!(cell, cell.rounded(&FormatOptions::default()));
+    }
+
+    #[test]
+    fn test_cells_serialize_to_one_json_type_each() {
+        let row = vec![
+            Cell::Integer(76),
+            Cell::Decimal(Decimal::new(8400, 2)),
+            Cell::Decimal(Decimal::new(99820845, 2)),
+            Cell::Decimal(Decimal::from(84)),
+            Cell::Null,
+        ];
+        assert_eq!(
+            r#"[76,"84.00","998208.45","84",null]"#,
+            serde_json::to_string(&row).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_complete_table() {
+        let constructed = |name: &str, data_type| OutputColumn::Constructed {
+            name: name.to_string(),
+            width: 10,
+            data_type,
+        };
+        let mut table = Table {
+            heading: vec![
+                constructed("ct", IpumsDataType::Integer),
+                constructed("weighted_ct", IpumsDataType::Fixed(2)),
+                constructed("A", IpumsDataType::Integer),
+                constructed("B", IpumsDataType::Integer),
+            ],
+            rows: vec![
+                vec![
+                    Cell::Integer(1),
+                    Cell::Decimal(Decimal::new(150, 2)),
+                    Cell::Null,
+                    Cell::Integer(1),
+                ],
+                vec![
+                    Cell::Integer(4),
+                    Cell::Decimal(Decimal::new(400, 2)),
+                    Cell::Integer(2),
+                    Cell::Integer(1),
+                ],
+            ],
+            disclosure: None,
+        };
+        complete_tables(
+            std::slice::from_mut(&mut table),
+            &[Some(vec![1, 2]), Some(vec![1, 2])],
+        )
+        .unwrap();
+        let cells: Vec<String> = table
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect();
+        assert_eq!(
+            cells,
+            [
+                "0,0.00,1,1",
+                "0,0.00,1,2",
+                "4,4.00,2,1",
+                "0,0.00,2,2",
+                "1,1.50,,1"
+            ]
+        );
+
+        let too_many = vec![Some((0..10_000).collect()), Some((0..10_000).collect())];
+        assert!(complete_tables(std::slice::from_mut(&mut table), &too_many).is_err());
+    }
 }
@@ -0,0 +1,735 @@
+//! Check a request against metadata before running it.
+//!
+//! Resolving a request with [AbacusRequest::try_from_input_request](crate::request::AbacusRequest::try_from_input_request)
+//! stops at the first problem, and some problems, like a variable missing from one of the
+//! requested samples, only turn up as errors from the data platform partway through a
+//! tabulation. [validate] looks at the whole incoming request and reports every problem it
+//! finds as a [Diagnostic], with suggestions for names which look misspelled.
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::conventions::Context;
+use crate::derived::DerivedVariable;
+use crate::input_schema_tabulation::{
+    AbacusRequest, CategoryBin, GeneralDetailedSelection, RequestCaseSelection, RequestVariable,
+    WeightSelection,
+};
+use crate::ipums_metadata_model::{IpumsValue, IpumsVariable};
+use crate::mderror::MdError;
+use crate::rollup::RollupVariable;
+
+/// The most suggestions to give for a misspelled name.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Whether a problem stops the request from running.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The request can't run, or would fail partway through.
+    Error,
+    /// The request can run, but probably doesn't do what was intended.
+    Warning,
+}
+
+/// The kind of problem a [Diagnostic] reports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticKind {
+    UnknownRecordType,
+    UnknownSample,
+    UnknownVariable,
+    VariableNotInSample,
+    InvalidComputedVariable,
+    OverlappingBins,
+    BinGap,
+    DuplicateBinCode,
+    UnusedBins,
+    UnknownCategory,
+    EmptyCaseSelection,
+    MissingGeneralWidth,
+    InvalidGeneralWidth,
+    InconsistentGeneralWidth,
+    UnjoinableRecordType,
+}
+
+/// A problem with a request.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+    pub message: String,
+    /// The variable with the problem, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variable: Option<String>,
+    /// The sample with the problem, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample: Option<String>,
+    /// Names which might have been meant instead of a misspelled one.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<String>,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, kind: DiagnosticKind, message: String) -> Self {
+        Self {
+            severity,
+            kind,
+            message,
+            variable: None,
+            sample: None,
+            suggestions: Vec::new(),
+        }
+    }
+
+    fn error(kind: DiagnosticKind, message: String) -> Self {
+        Self::new(Severity::Error, kind, message)
+    }
+
+    fn warning(kind: DiagnosticKind, message: String) -> Self {
+        Self::new(Severity::Warning, kind, message)
+    }
+
+    fn for_variable(mut self, variable: &str) -> Self {
+        self.variable = Some(variable.to_string());
+        self
+    }
+
+    fn for_sample(mut self, sample: &str) -> Self {
+        self.sample = Some(sample.to_string());
+        self
+    }
+
+    fn with_suggestions(mut self, suggestions: Vec<String>) -> Self {
+        self.suggestions = suggestions;
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity}: {}", self.message)?;
+        if !self.suggestions.is_empty() {
+            write!(f, " (did you mean {}?)", self.suggestions.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The requested samples which have layouts in the context's data root, and so can have
+/// metadata loaded for validation. [validate] reports the others.
+pub fn loadable_samples<'a>(
+    ctx: &Context,
+    request: &'a AbacusRequest,
+) -> Result<Vec<&'a str>, MdError> {
+    let available = ctx.dataset_names_from_layouts()?;
+    Ok(request
+        .sample_names()
+        .into_iter()
+        .filter(|name| available.iter().any(|a| a == name))
+        .collect())
+}
+
+/// Create a context for the request's product and data root with metadata for the requested
+/// samples which have it, and validate the request against it.
+pub fn validate_input(request: &AbacusRequest) -> Result<Vec<Diagnostic>, MdError> {
+    let mut ctx =
+        Context::from_ipums_collection_name(&request.product, None, request.data_root.clone())?;
+    let samples = loadable_samples(&ctx, request)?;
+    ctx.load_metadata_for_datasets(&samples)?;
+    Ok(validate(&ctx, request))
+}
+
+/// Check a request against the metadata in the context, which should have metadata loaded for
+/// the requested samples. Returns every problem found, errors first.
+pub fn validate(ctx: &Context, request: &AbacusRequest) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let Some(ref md) = ctx.settings.metadata else {
+        diagnostics.push(Diagnostic::error(
+            DiagnosticKind::UnknownSample,
+            "No metadata is loaded for any of the requested samples".to_string(),
+        ));
+        return diagnostics;
+    };
+
+    let uoa_known = match ctx.settings.record_types.get(&request.uoa) {
+        Some(_) => true,
+        None => {
+            let record_types: Vec<&String> = ctx.settings.record_types.keys().collect();
+            diagnostics.push(
+                Diagnostic::error(
+                    DiagnosticKind::UnknownRecordType,
+                    format!(
+                        "There's no record type '{}' for the unit of analysis",
+                        request.uoa
+                    ),
+                )
+                .with_suggestions(suggestions(&request.uoa, record_types)),
+            );
+            false
+        }
+    };
+
+    let mut samples = Vec::new();
+    for name in request.sample_names() {
+        if ctx.has_metadata_for_dataset(name) {
+            samples.push(name);
+        } else {
+            let available = ctx.dataset_names_from_layouts().unwrap_or_default();
+            diagnostics.push(
+                Diagnostic::error(
+                    DiagnosticKind::UnknownSample,
+                    format!("There's no sample named {name}"),
+                )
+                .for_sample(name)
+                .with_suggestions(suggestions(name, &available)),
+            );
+        }
+    }
+
+    // Computed variables stand in for variables in the data, and their inputs must be in every
+    // sample.
+    let mut computed: BTreeMap<String, (IpumsVariable, Vec<IpumsVariable>)> = BTreeMap::new();
+    for derived in &request.derived_variables {
+        match DerivedVariable::try_new(ctx, &derived.name, &derived.expression, None) {
+            Ok((d, var)) => {
+                computed.insert(derived.name.clone(), (var, d.expression.inputs));
+            }
+            Err(err) => diagnostics.push(
+                Diagnostic::error(DiagnosticKind::InvalidComputedVariable, err.to_string())
+                    .for_variable(&derived.name),
+            ),
+        }
+    }
+    for rollup in &request.rollups {
+        match RollupVariable::try_new(ctx, rollup) {
+            Ok((r, var)) => {
+                let inputs = r
+                    .value
+                    .iter()
+                    .chain(&r.condition)
+                    .flat_map(|e| e.inputs.clone())
+                    .collect();
+                computed.insert(rollup.name.clone(), (var, inputs));
+            }
+            Err(err) => diagnostics.push(
+                Diagnostic::error(DiagnosticKind::InvalidComputedVariable, err.to_string())
+                    .for_variable(&rollup.name),
+            ),
+        }
+    }
+
+    let all_names: Vec<&String> = md.variables_by_name.keys().chain(computed.keys()).collect();
+    let mut reported = HashSet::new();
+    let mut unknown = HashSet::new();
+    let mut check_in_samples = |var: &IpumsVariable, diagnostics: &mut Vec<Diagnostic>| {
+        for sample in &samples {
+            let in_sample = md.datasets_by_name.get(*sample).is_some_and(|ds_id| {
+                md.available_datasets
+                    .for_variable(var.id)
+                    .is_some_and(|ids| ids.contains(ds_id))
+            });
+            if !in_sample && reported.insert((var.name.clone(), sample.to_string())) {
+                diagnostics.push(
+                    Diagnostic::error(
+                        DiagnosticKind::VariableNotInSample,
+                        format!("{} isn't available in sample {sample}", var.name),
+                    )
+                    .for_variable(&var.name)
+                    .for_sample(sample),
+                );
+            }
+        }
+    };
+
+    let mut general_widths: BTreeMap<&str, usize> = BTreeMap::new();
+    let variables = request
+        .request_variables
+        .iter()
+        .chain(&request.subpopulation);
+    for rq in variables.clone() {
+        let name = &rq.variable_mnemonic;
+        let var = match (computed.get(name), md.cloned_variable_from_name(name)) {
+            (Some((var, inputs)), _) => {
+                for input in inputs {
+                    check_in_samples(input, &mut diagnostics);
+                }
+                var.clone()
+            }
+            (None, Some(var)) => {
+                check_in_samples(&var, &mut diagnostics);
+                var
+            }
+            (None, None) => {
+                let is_computed_with_errors =
+                    request.derived_variables.iter().any(|d| &d.name == name)
+                        || request.rollups.iter().any(|r| &r.name == name);
+                if !is_computed_with_errors && unknown.insert(name.clone()) {
+                    diagnostics.push(
+                        Diagnostic::error(
+                            DiagnosticKind::UnknownVariable,
+                            format!("There's no variable named {name}"),
+                        )
+                        .for_variable(name)
+                        .with_suggestions(suggestions(name, all_names.iter().copied())),
+                    );
+                }
+                continue;
+            }
+        };
+
+        if uoa_known {
+            let attached = rq.attached_variable_pointer.is_some();
+            if !is_joinable(ctx, &var.record_type, attached, &request.uoa) {
+                diagnostics.push(
+                    Diagnostic::error(
+                        DiagnosticKind::UnjoinableRecordType,
+                        format!(
+                            "{name} is on {} records, which can't be joined to the {} records counted by the unit of analysis",
+                            var.record_type, request.uoa
+                        ),
+                    )
+                    .for_variable(name),
+                );
+            }
+        }
+
+        if rq.general_detailed_selection == GeneralDetailedSelection::General {
+            if let Some(width) = check_general_width(rq, &var, &mut diagnostics) {
+                match general_widths.insert(name, width) {
+                    Some(other) if other != width => diagnostics.push(
+                        Diagnostic::error(
+                            DiagnosticKind::InconsistentGeneralWidth,
+                            format!("{name} is requested with general widths {other} and {width}"),
+                        )
+                        .for_variable(name),
+                    ),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    for rq in &request.subpopulation {
+        let name = &rq.variable_mnemonic;
+        if rq.case_selection && rq.request_case_selections.is_empty() {
+            diagnostics.push(
+                Diagnostic::error(
+                    DiagnosticKind::EmptyCaseSelection,
+                    format!("{name} is marked for case selection but doesn't select any codes"),
+                )
+                .for_variable(name),
+            );
+        }
+        if rq.general_detailed_selection == GeneralDetailedSelection::Detailed
+            && !request.recodes.contains_key(name)
+        {
+            if let Some(var) = md.cloned_variable_from_name(name) {
+                check_case_selection_codes(rq, &var, &mut diagnostics);
+            }
+        }
+    }
+
+    for (name, bins) in &request.category_bins {
+        check_bins(name, bins, &mut diagnostics);
+        if !variables.clone().any(|v| &v.variable_mnemonic == name) {
+            diagnostics.push(
+                Diagnostic::warning(
+                    DiagnosticKind::UnusedBins,
+                    format!("Category bins are given for {name}, which isn't in the request"),
+                )
+                .for_variable(name),
+            );
+        }
+    }
+
+    if let WeightSelection::Variable(ref weight) = request.weight {
+        match md.cloned_variable_from_name(weight) {
+            Some(var) => check_in_samples(&var, &mut diagnostics),
+            None => diagnostics.push(
+                Diagnostic::error(
+                    DiagnosticKind::UnknownVariable,
+                    format!("There's no weight variable named {weight}"),
+                )
+                .for_variable(weight)
+                .with_suggestions(suggestions(weight, md.variables_by_name.keys())),
+            ),
+        }
+    }
+
+    diagnostics.sort_by_key(|d| d.severity);
+    diagnostics
+}
+
+/// Whether queries can join records of the record type to the records counted by the unit of
+/// analysis: the record type is the unit of analysis or above it, or the variable is attached
+/// and the record type points to the unit of analysis.
+pub(crate) fn is_joinable(ctx: &Context, record_type: &str, attached: bool, uoa: &str) -> bool {
+    joinable_record_types(ctx, uoa).contains(record_type)
+        || (attached && is_attachable_to(ctx, record_type, uoa))
+}
+
+/// The unit of analysis and the record types above it, which queries can join to it.
+fn joinable_record_types(ctx: &Context, uoa: &str) -> HashSet<String> {
+    let mut joinable = HashSet::new();
+    let mut next = vec![uoa.to_string()];
+    while let Some(rt) = next.pop() {
+        if let Some(record_type) = ctx.settings.record_types.get(&rt) {
+            next.extend(
+                record_type
+                    .foreign_keys
+                    .iter()
+                    .map(|(parent, _)| parent.clone())
+                    .filter(|parent| !joinable.contains(parent)),
+            );
+        }
+        joinable.insert(rt);
+    }
+    joinable
+}
+
+/// Whether attached variables on the record type can be counted by the unit of analysis. Their
+/// family member is found through the first record of the record type in each unit, so the
+/// record type must point to the unit of analysis.
+fn is_attachable_to(ctx: &Context, record_type: &str, uoa: &str) -> bool {
+    ctx.settings
+        .record_types
+        .get(record_type)
+        .is_some_and(|rt| rt.foreign_keys.iter().any(|(parent, _)| parent == uoa))
+}
+
+/// Check that a general variable has a general width shorter than its detailed width, and
+/// return the width.
+fn check_general_width(
+    rq: &RequestVariable,
+    var: &IpumsVariable,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<usize> {
+    let name = &rq.variable_mnemonic;
+    let width = match (rq.extract_width, var.general_width) {
+        (0, Some(width)) => width,
+        (0, None) => {
+            diagnostics.push(
+                Diagnostic::error(
+                    DiagnosticKind::MissingGeneralWidth,
+                    format!(
+                        "{name} is requested as general, but there's no general width in the metadata or the request's extract_width"
+                    ),
+                )
+                .for_variable(name),
+            );
+            return None;
+        }
+        (width, Some(md_width)) if width != md_width => {
+            diagnostics.push(
+                Diagnostic::warning(
+                    DiagnosticKind::InconsistentGeneralWidth,
+                    format!(
+                        "The request gives {name} a general width of {width}, but the metadata says {md_width}"
+                    ),
+                )
+                .for_variable(name),
+            );
+            width
+        }
+        (width, _) => width,
+    };
+    if let Some((_, detailed_width)) = var.formatting {
+        if width >= detailed_width {
+            diagnostics.push(
+                Diagnostic::error(
+                    DiagnosticKind::InvalidGeneralWidth,
+                    format!(
+                        "{name} has a general width of {width}, which isn't less than its detailed width of {detailed_width}"
+                    ),
+                )
+                .for_variable(name),
+            );
+        }
+    }
+    Some(width)
+}
+
+/// Check that each case selection matches at least one of the variable's categories, when
+/// the metadata has categories.
+fn check_case_selection_codes(
+    rq: &RequestVariable,
+    var: &IpumsVariable,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let Some(ref categories) = var.categories else {
+        return;
+    };
+    let codes: Vec<i64> = categories
+        .iter()
+        .filter_map(|c| match c.value {
+            IpumsValue::Integer(code) => Some(code),
+            _ => None,
+        })
+        .collect();
+    if codes.is_empty() {
+        return;
+    }
+    let name = &rq.variable_mnemonic;
+    for selection in &rq.request_case_selections {
+        let (low, high, text) = match *selection {
+            RequestCaseSelection::Between(low, high) if low == high => {
+                (low, high, format!("code {low}"))
+            }
+            RequestCaseSelection::Between(low, high) => {
+                (low, high, format!("codes {low} to {high}"))
+            }
+            RequestCaseSelection::LessEqual(high) => (0, high, format!("codes up to {high}")),
+            RequestCaseSelection::GreaterEqual(low) => {
+                (low, u64::MAX, format!("codes {low} and up"))
+            }
+        };
+        let matches = codes
+            .iter()
+            .any(|&code| code >= 0 && (code as u64) >= low && (code as u64) <= high);
+        if !matches {
+            diagnostics.push(
+                Diagnostic::error(
+                    DiagnosticKind::UnknownCategory,
+                    format!("The case selection {text} doesn't match any category of {name}"),
+                )
+                .for_variable(name),
+            );
+        }
+    }
+}
+
+/// Check that a variable's bins don't overlap or share codes, and warn about values between
+/// bins which no bin covers.
+fn check_bins(name: &str, bins: &[CategoryBin], diagnostics: &mut Vec<Diagnostic>) {
+    let mut codes = HashSet::new();
+    for bin in bins {
+        if !codes.insert(bin.code()) {
+            diagnostics.push(
+                Diagnostic::error(
+                    DiagnosticKind::DuplicateBinCode,
+                    format!("More than one of {name}'s bins has the code {}", bin.code()),
+                )
+                .for_variable(name),
+            );
+        }
+    }
+
+    // Bins as inclusive ranges, with None for no bound.
+    let mut ranges: Vec<(Option<i64>, Option<i64>)> = bins
+        .iter()
+        .map(|bin| match *bin {
+            CategoryBin::LessThan { value, .. } => (None, Some(value)),
+            CategoryBin::Range { low, high, .. } => (Some(low), Some(high)),
+            CategoryBin::MoreThan { value, .. } => (Some(value), None),
+        })
+        .collect();
+    ranges.sort();
+    let show = |bound: Option<i64>| bound.map_or("any".to_string(), |b| b.to_string());
+    for pair in ranges.windows(2) {
+        let ((low, high), (next_low, next_high)) = (pair[0], pair[1]);
+        match (high, next_low) {
+            (Some(high), Some(next_low)) if next_low > high.saturating_add(1) => {
+                diagnostics.push(
+                    Diagnostic::warning(
+                        DiagnosticKind::BinGap,
+                        format!(
+                            "No bin of {name} covers the values from {} to {}",
+                            high + 1,
+                            next_low - 1
+                        ),
+                    )
+                    .for_variable(name),
+                );
+            }
+            (Some(high), Some(next_low)) if next_low > high => (),
+            _ => diagnostics.push(
+                Diagnostic::error(
+                    DiagnosticKind::OverlappingBins,
+                    format!(
+                        "{name}'s bins from {} to {} and from {} to {} overlap",
+                        show(low),
+                        show(high),
+                        show(next_low),
+                        show(next_high)
+                    ),
+                )
+                .for_variable(name),
+            ),
+        }
+    }
+}
+
+/// The candidates closest to a misspelled name, ignoring case.
+fn suggestions<'a>(name: &str, candidates: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    let name = name.to_uppercase();
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut close: Vec<(usize, &String)> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let distance = edit_distance(&name, &candidate.to_uppercase());
+            (distance <= max_distance).then_some((distance, candidate))
+        })
+        .collect();
+    close.sort();
+    close.dedup();
+    close
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate.clone())
+        .collect()
+}
+
+/// The number of single character insertions, deletions, substitutions and swaps of neighbouring
+/// characters which turn one string into another.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances[i][j] is the distance between the first i characters of a and the first j of b.
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(json: &str) -> AbacusRequest {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn kinds(diagnostics: &[Diagnostic]) -> Vec<DiagnosticKind> {
+        diagnostics.iter().map(|d| d.kind).collect()
+    }
+
+    #[test]
+    fn test_edit_distance_and_suggestions() {
+        assert_eq!(edit_distance("MARST", "MARST"), 0);
+        assert_eq!(edit_distance("MRST", "MARST"), 1);
+        assert_eq!(edit_distance("INCWAEG", "INCWAGE"), 1);
+        assert_eq!(edit_distance("MARST", "AGE"), 4);
+        let candidates = ["MARST".to_string(), "RACE".to_string(), "AGE".to_string()];
+        assert_eq!(suggestions("marts", &candidates), ["MARST"]);
+        assert!(suggestions("EDUC", &candidates).is_empty());
+    }
+
+    #[test]
+    fn test_check_bins() {
+        let bins = CategoryBin::parse_list("0-10,5-20,30+").unwrap();
+        let mut diagnostics = Vec::new();
+        check_bins("AGE", &bins, &mut diagnostics);
+        assert_eq!(
+            kinds(&diagnostics),
+            [DiagnosticKind::OverlappingBins, DiagnosticKind::BinGap]
+        );
+        assert_eq!(
+            diagnostics[1].message,
+            "No bin of AGE covers the values from 21 to 29"
+        );
+
+        let mut diagnostics = Vec::new();
+        check_bins(
+            "AGE",
+            &CategoryBin::parse_list("0-17,18-64,65+").unwrap(),
+            &mut diagnostics,
+        );
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut rq = request(include_str!(
+            "../tests/requests/race_hispan_subpop_statefip.json"
+        ));
+        rq.request_samples
+            .push(crate::input_schema_tabulation::RequestSample {
+                name: "us1940a".to_string(),
+                custom_sampling_ratio: None,
+                first_household_sampled: None,
+                rescale_weights: false,
+            });
+        rq.request_samples
+            .push(crate::input_schema_tabulation::RequestSample {
+                name: "US1900M".to_string(),
+                custom_sampling_ratio: None,
+                first_household_sampled: None,
+                rescale_weights: false,
+            });
+        rq.request_variables.push(RequestVariable::new("INCWAGE"));
+        rq.request_variables.push(RequestVariable::new("MARTS"));
+        rq.subpopulation[0].request_case_selections.clear();
+
+        let diagnostics = validate_input(&rq).unwrap();
+        let unknown_sample = diagnostics
+            .iter()
+            .find(|d| d.kind == DiagnosticKind::UnknownSample)
+            .unwrap();
+        assert_eq!(unknown_sample.suggestions[0], "us1900m");
+
+        let missing = diagnostics
+            .iter()
+            .find(|d| d.kind == DiagnosticKind::VariableNotInSample)
+            .unwrap();
+        assert_eq!(missing.variable.as_deref(), Some("INCWAGE"));
+        assert_eq!(missing.sample.as_deref(), Some("us1900m"));
+
+        let unknown = diagnostics
+            .iter()
+            .find(|d| d.kind == DiagnosticKind::UnknownVariable)
+            .unwrap();
+        assert_eq!(unknown.suggestions, ["MARST"]);
+        assert!(kinds(&diagnostics).contains(&DiagnosticKind::EmptyCaseSelection));
+        assert!(diagnostics.iter().all(|d| d.is_error()));
+    }
+
+    #[test]
+    fn test_validate_record_types_and_general_widths() {
+        let mut rq = request(include_str!(
+            "../tests/requests/race_hispan_subpop_statefip.json"
+        ));
+        rq.uoa = "H".to_string();
+        rq.request_variables[1].extract_width = 0;
+        let diagnostics = validate_input(&rq).unwrap();
+        let kinds = kinds(&diagnostics);
+        assert!(kinds.contains(&DiagnosticKind::UnjoinableRecordType));
+        assert!(kinds.contains(&DiagnosticKind::MissingGeneralWidth));
+
+        let valid = request(include_str!(
+            "../tests/requests/race_hispan_subpop_statefip.json"
+        ));
+        assert_eq!(validate_input(&valid).unwrap(), []);
+
+        // Households can count the householder's spouse.
+        let mut attached = request(include_str!("../tests/requests/sex_attached_spouse.json"));
+        attached.uoa = "H".to_string();
+        attached.request_variables.remove(0);
+        assert_eq!(validate_input(&attached).unwrap(), []);
+    }
+}
@@ -44,6 +44,19 @@ fn test_tab_help() {
         .stderr(predicate::str::is_empty());
 }
 
+#[test]
+fn test_serve_help() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command.args(["serve", "--help"]).assert();
+
+    let pred = predicate::str::contains("Run a local HTTP service")
+        .and(predicate::str::contains("127.0.0.1:8080"));
+    assert
+        .success()
+        .stdout(pred)
+        .stderr(predicate::str::is_empty());
+}
+
 /// Abacus can process the incwage_marst_example.json example and outputs text by default.
 #[test]
 fn test_request_incwage_marst_example() {
@@ -180,3 +193,435 @@ fn test_tab_zero_variables() {
     let pred = predicate::str::contains("Must supply at least one request variable");
     assert.failure().stderr(pred);
 }
+
+/// Batch mode writes one result per request in input order, and a failed request
+/// makes abacus exit with an error without stopping the other requests.
+#[test]
+fn test_batch_continues_after_failed_request() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let output = command
+        .args([
+            "batch",
+            "tests/requests/batch_requests.jsonl",
+            "--jobs",
+            "2",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("1 of 2 requests failed"));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let results: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("each line should be JSON"))
+        .collect();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["request_id"], "race_hispan_texas");
+    assert!(results[0]["tables"].is_array());
+    assert!(results[0].get("error").is_none());
+    assert_eq!(results[1]["request_id"], "unknown_product");
+    assert!(results[1]["error"].is_string());
+    assert_eq!(results[1]["line"], 2);
+}
+
+/// Weighted counts are exact, and '--decimals' rounds them to a fixed number of places.
+#[test]
+fn test_tab_decimals() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1940a",
+            "VETSTAT",
+            "-d",
+            "tests/data_root",
+            "--decimals",
+            "2",
+        ])
+        .assert();
+
+    let pred = predicate::str::starts_with(
+        "|         ct | weighted_ct | VETSTAT |\n\
+         |------------------------------------|\n\
+         |       1118 |    76100.00 |       0 |\n",
+    );
+    assert.success().stdout(pred);
+}
+
+/// The disclosure control options suppress small cells along with a complementary cell, and
+/// a summary of the suppression follows the table.
+#[test]
+fn test_tab_min_cell_count() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1940a",
+            "VETSTAT",
+            "-d",
+            "tests/data_root",
+            "--min-cell-count",
+            "10",
+        ])
+        .assert();
+
+    let pred = predicate::str::contains(
+        "|            |             |      20 |\n\
+         |            |             |      99 |\n\
+         Suppressed 2 cells (1 primary, 1 complementary) with a minimum cell count of 10\n",
+    )
+    .and(predicate::str::contains("|       1118 |       76100 |       0 |"));
+    assert.success().stdout(pred);
+}
+
+/// Layouts don't record which variables are restricted, so --refuse-restricted can't check
+/// them and refuses the tabulation.
+#[test]
+fn test_tab_refuse_restricted_without_restriction_metadata() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1900m",
+            "SEX",
+            "-d",
+            "tests/data_root",
+            "--where",
+            "STATEFIP=48",
+            "--refuse-restricted",
+        ])
+        .assert();
+    let pred = predicate::str::contains("variable SEX has no restriction metadata");
+    assert.failure().stderr(pred);
+}
+
+/// 'abacus tab --bins' tabulates a continuous variable into bins generated from the data, and
+/// rejects methods it doesn't know.
+#[test]
+fn test_tab_generated_bins() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1940a",
+            "INCWAGE",
+            "-d",
+            "tests/data_root",
+            "--bins",
+            "INCWAGE=nice_numbers:5",
+        ])
+        .assert();
+    let pred = predicate::str::contains("|        997 |      102500 |       1 |");
+    assert.success().stdout(pred);
+
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1940a",
+            "INCWAGE",
+            "-d",
+            "tests/data_root",
+            "--bins",
+            "INCWAGE=deciles:10",
+        ])
+        .assert();
+    let pred = predicate::str::contains("unknown bin generation method 'deciles'");
+    assert.failure().stderr(pred);
+}
+
+#[test]
+fn test_tab_listed_bins_and_where() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1900m",
+            "AGE",
+            "-d",
+            "tests/data_root",
+            "--bins",
+            "AGE=0-17,18-64,65+",
+            "--where",
+            "STATEFIP=48",
+        ])
+        .assert();
+    let pred = predicate::str::contains("|        142 |         142 |   1 |").and(
+        predicate::str::contains("|          5 |           5 |   3 |"),
+    );
+    assert.success().stdout(pred);
+}
+
+#[test]
+fn test_tab_household_unit_of_analysis() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1900m",
+            "NUMPREC",
+            "-d",
+            "tests/data_root",
+            "--uoa",
+            "H",
+            "--where",
+            "NUMPREC=-3",
+        ])
+        .assert();
+    let pred = predicate::str::contains("|        223 |         223 |       2 |")
+        .and(predicate::str::contains("|       4 |").not());
+    assert.success().stdout(pred);
+}
+
+#[test]
+fn test_tab_unweighted_samples() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1900m,us1940a",
+            "SEX",
+            "-d",
+            "tests/data_root",
+            "--unweighted",
+        ])
+        .assert();
+    let pred = predicate::str::contains("|       3909 |   1 |")
+        .and(predicate::str::contains("|         89 |   1 |"))
+        .and(predicate::str::contains("weighted_ct").not());
+    assert.success().stdout(pred);
+}
+
+#[test]
+fn test_tab_general_requires_a_request_variable() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1900m",
+            "SEX",
+            "-d",
+            "tests/data_root",
+            "--general",
+            "AGE",
+        ])
+        .assert();
+    let pred = predicate::str::contains("--general AGE isn't one of");
+    assert.failure().stderr(pred);
+}
+
+#[test]
+fn test_tab_general_with_width() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1900m",
+            "RACE",
+            "-d",
+            "tests/data_root",
+            "--general",
+            "RACE=1",
+        ])
+        .assert();
+    let pred = predicate::str::contains("|       6816 |        6816 |    1 |")
+        .and(predicate::str::contains("|        865 |         865 |    2 |"))
+        .and(predicate::str::contains("|  100 |").not());
+    assert.success().stdout(pred);
+}
+
+#[test]
+fn test_tab_general_without_width_in_metadata() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1900m",
+            "RACE",
+            "-d",
+            "tests/data_root",
+            "--general",
+            "RACE",
+        ])
+        .assert();
+    let pred = predicate::str::contains("give one like --general RACE=WIDTH");
+    assert.failure().stderr(pred);
+}
+
+#[test]
+fn test_explain() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args(["explain", "tests/requests/race_hispan_subpop_statefip.json"])
+        .assert();
+    let pred = predicate::str::contains("Dataset: us1900m")
+        .and(predicate::str::contains("Weight: PERWT, divided by 100"))
+        .and(predicate::str::contains("where ((STATEFIP = 48))"));
+    assert.success().stdout(pred);
+
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1900m",
+            "SEX",
+            "-d",
+            "tests/data_root",
+            "--unweighted",
+            "--explain",
+        ])
+        .assert();
+    let pred = predicate::str::contains("Weight: none, counts are unweighted")
+        .and(predicate::str::contains("weighted_ct").not());
+    assert.success().stdout(pred);
+}
+
+#[test]
+fn test_json_errors() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "tab",
+            "usa",
+            "us1900m",
+            "AEG",
+            "-d",
+            "tests/data_root",
+            "--format",
+            "json",
+        ])
+        .assert();
+    let output = assert.failure().get_output().clone();
+    let error: serde_json::Value =
+        serde_json::from_slice(&output.stderr).expect("the error should be JSON");
+    assert_eq!(error["code"], "unknown_variable");
+    assert_eq!(error["entity"]["kind"], "variable");
+    assert_eq!(error["entity"]["name"], "AEG");
+    assert_eq!(error["field"], "request_variables[0]");
+}
+
+#[test]
+fn test_schema() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command.args(["schema", "request"]).assert();
+    let output = assert.success().get_output().clone();
+    let schema: serde_json::Value =
+        serde_json::from_slice(&output.stdout).expect("the schema should be JSON");
+    assert_eq!(schema["title"], "Abacus request");
+    assert!(schema["properties"]["request_variables"].is_object());
+
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    command.args(["schema", "extract"]).assert().failure();
+}
+
+#[test]
+fn test_validate() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    command
+        .args([
+            "validate",
+            "tests/requests/race_hispan_subpop_statefip.json",
+        ])
+        .assert()
+        .success();
+
+    let request = std::fs::read_to_string("tests/requests/race_hispan_subpop_statefip.json")
+        .unwrap()
+        .replace("\"RACE\"", "\"RACW\"");
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command.arg("validate").write_stdin(request).assert();
+    let pred =
+        predicate::str::contains("error: There's no variable named RACW (did you mean RACE?)");
+    assert.failure().stdout(pred);
+}
+
+#[test]
+fn test_datasets() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args(["datasets", "usa", "-d", "tests/data_root"])
+        .assert();
+    let pred = predicate::str::is_match(r"us1900m +yes +yes +no +no").unwrap();
+    assert.success().stdout(pred);
+}
+
+#[test]
+fn test_variables_json_output() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "variables",
+            "usa",
+            "us1940a",
+            "-d",
+            "tests/data_root",
+            "-f",
+            "json",
+        ])
+        .assert();
+    let assert = assert.success();
+    let variables: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    let incwage = variables
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|v| v["name"] == "INCWAGE")
+        .expect("us1940a should have INCWAGE");
+    assert_eq!(incwage["record_type"], "P");
+    assert_eq!(incwage["width"], 6);
+}
+
+#[test]
+fn test_search() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "search",
+            "usa",
+            "incwage",
+            "--samples",
+            "us1900m,us1940a",
+            "-d",
+            "tests/data_root",
+        ])
+        .assert();
+    let pred = predicate::str::is_match(r"\nINCWAGE +P +integer +6 +us1940a\n").unwrap();
+    assert.success().stdout(pred);
+}
+
+#[test]
+fn test_availability() {
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command
+        .args([
+            "availability",
+            "usa",
+            "--source",
+            "parquet",
+            "--pattern",
+            "*WAGE",
+            "-d",
+            "tests/data_root",
+            "-f",
+            "csv",
+        ])
+        .assert();
+    let pred = predicate::str::starts_with("variable,record_type,us1900m,us1940a\n")
+        .and(predicate::str::contains("\nINCWAGE,P,0,1\n"));
+    assert.success().stdout(pred);
+}
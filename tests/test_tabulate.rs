@@ -1,6 +1,7 @@
 //! Tabulation integration tests
-use cimdea::request::AbacusRequest;
-use cimdea::tabulate::{tabulate, Table};
+use cimdea::request::{AbacusRequest, DataRequest};
+use cimdea::tabulate::{explain, tabulate, Cell, Table};
+use rust_decimal::Decimal;
 
 /// This test tabulates a single P variable MARST, which does not have category
 /// bins. There are no subpopulations applied.
@@ -614,9 +615,9 @@ impl<'a, const W: usize, const H: usize> KeyTable<'a, W, H> {
             let column_name = self.column_names[column_index];
             for row_index in 0..H {
                 let key_entry = self.rows[row_index][column_index].to_string();
-                let table_entry = &table.rows[row_index][column_index];
+                let table_entry = table.rows[row_index][column_index].to_string();
                 assert_eq!(
-                    &key_entry, table_entry,
+                    key_entry, table_entry,
                     "entry in column {column_index} ('{column_name}') and row \
                     {row_index} differs: key has {key_entry}, table has {table_entry}"
                 );
@@ -624,3 +625,588 @@ impl<'a, const W: usize, const H: usize> KeyTable<'a, W, H> {
         }
     }
 }
+
+/// Total the unweighted counts in the first column of the table.
+fn total_count(table: &Table) -> i64 {
+    table
+        .rows
+        .iter()
+        .map(|row| match row[0] {
+            Cell::Integer(ct) => ct,
+            ref other => panic!("expected an integer count but got {other:?}"),
+        })
+        .sum()
+}
+
+/// Tabulate race_hispan_subpop_statefip.json with its us1900m sample replaced by the given
+/// sample JSON.
+fn tabulate_race_hispan_sample(sample_json: &str) -> Table {
+    let input_json = include_str!("requests/race_hispan_subpop_statefip.json").replace(
+        r#"{
+      "name": "us1900m",
+      "custom_sampling_ratio": null,
+      "first_household_sampled": null
+    }"#,
+        sample_json,
+    );
+    let (ctx, rq) =
+        AbacusRequest::try_from_json(&input_json).expect("should be able to parse input JSON");
+    let tab = tabulate(&ctx, rq).expect("tabulation should run without errors");
+    tab.into_inner().remove(0)
+}
+
+/// Systematic subsamples with different starting households partition the households, so
+/// their counts add up to the full sample's counts.
+#[test]
+fn test_subsample_households() {
+    let full = tabulate_race_hispan_sample(r#"{"name": "us1900m"}"#);
+    let everything =
+        tabulate_race_hispan_sample(r#"{"name": "us1900m", "custom_sampling_ratio": "1/1"}"#);
+    assert_eq!(full.rows, everything.rows);
+
+    let odd = tabulate_race_hispan_sample(
+        r#"{"name": "us1900m", "custom_sampling_ratio": "1/2", "first_household_sampled": 1}"#,
+    );
+    let even = tabulate_race_hispan_sample(
+        r#"{"name": "us1900m", "custom_sampling_ratio": "0.5", "first_household_sampled": 2}"#,
+    );
+    assert!(total_count(&odd) < total_count(&full));
+    assert!(total_count(&even) < total_count(&full));
+    assert_eq!(total_count(&full), total_count(&odd) + total_count(&even));
+}
+
+/// Rescaling weights multiplies the weighted counts by the inverse of the sampling ratio.
+#[test]
+fn test_subsample_rescale_weights() {
+    let sample = tabulate_race_hispan_sample(
+        r#"{"name": "us1900m", "custom_sampling_ratio": "1/3", "first_household_sampled": 2}"#,
+    );
+    let rescaled = tabulate_race_hispan_sample(
+        r#"{"name": "us1900m", "custom_sampling_ratio": "1/3", "first_household_sampled": 2,
+            "rescale_weights": true}"#,
+    );
+    assert_eq!(sample.rows.len(), rescaled.rows.len());
+    for (row, rescaled_row) in sample.rows.iter().zip(&rescaled.rows) {
+        assert_eq!(row[0], rescaled_row[0]);
+        let (Cell::Decimal(weighted), Cell::Decimal(rescaled_weighted)) =
+            (&row[1], &rescaled_row[1])
+        else {
+            panic!("expected decimal weighted counts");
+        };
+        assert_eq!(*weighted * Decimal::from(3), *rescaled_weighted);
+    }
+}
+
+/// Attaching the spouse's SEX joins each person to their spouse without duplicating or dropping
+/// anyone. People without a spouse in the household have no SEX_SP.
+#[test]
+fn test_attached_spouse_variable() {
+    let input_json = include_str!("requests/sex_attached_spouse.json");
+    let (ctx, rq) =
+        AbacusRequest::try_from_json(input_json).expect("should be able to parse input JSON");
+    let tab = tabulate(&ctx, rq).expect("tabulation should run without errors");
+    let table = tab.into_inner().remove(0);
+
+    let column_names: Vec<_> = table.heading.iter().map(|c| c.name()).collect();
+    assert_eq!(column_names, ["ct", "weighted_ct", "SEX", "SEX_SP"]);
+
+    let unattached = tabulate_race_hispan_sample(r#"{"name": "us1900m"}"#);
+    assert_eq!(total_count(&unattached), total_count(&table));
+
+    assert!(table.rows.iter().any(|row| row[3] == Cell::Null));
+    assert!(table.rows.iter().any(|row| row[3] != Cell::Null));
+}
+
+/// Counting households attaches the householder's spouse. Excluding SEX's missing codes keeps
+/// the households where the householder has no spouse, since a null isn't a missing code.
+#[test]
+fn test_attached_variable_counting_households() {
+    let tabulate_households = |handling: &str| {
+        let mut request: serde_json::Value =
+            serde_json::from_str(include_str!("requests/sex_attached_spouse.json")).unwrap();
+        request["uoa"] = serde_json::json!("H");
+        let attached = request["request_variables"][1].clone();
+        request["request_variables"] = serde_json::json!([attached]);
+        request["missing_code_handling"] = serde_json::json!(handling);
+        request["missing_codes"] = serde_json::json!({"SEX": [9]});
+        let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+            .expect("should be able to parse input JSON");
+        tabulate(&ctx, rq)
+            .expect("tabulation should run without errors")
+            .into_inner()
+            .remove(0)
+    };
+
+    let included = tabulate_households("include");
+    let excluded = tabulate_households("exclude");
+    assert_eq!(included.rows, excluded.rows);
+    assert!(excluded.rows.iter().any(|row| row[2] == Cell::Null));
+    assert!(excluded.rows.iter().any(|row| row[2] != Cell::Null));
+}
+
+/// Standardizing with a CPI which doubles 1939 dollars by 1950 puts everyone in the bins with
+/// doubled boundaries that they were in before.
+#[test]
+fn test_monetary_standardization() {
+    let input_json = include_str!("requests/incwage_category_bins_us1940a.json");
+    let (ctx, rq) =
+        AbacusRequest::try_from_json(input_json).expect("should be able to parse input JSON");
+    let nominal = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+
+    let mut request: serde_json::Value = serde_json::from_str(input_json).unwrap();
+    request["category_bins"]["INCWAGE"] = serde_json::json!([
+        {"code": 1, "value_label": "Less than $2,000", "low": null, "high": 1999},
+        {"code": 2, "value_label": "$2,000 to $3,999", "low": 2000, "high": 3999},
+        {"code": 3, "value_label": "$4,000 or more", "low": 4000, "high": null}
+    ]);
+    request["monetary_standardization"] = serde_json::json!({
+        "base_year": 1950,
+        "cpi_file": "tests/requests/cpi_doubling.csv",
+        "variables": ["INCWAGE"]
+    });
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+
+    let codebook = rq.print_codebook();
+    assert!(codebook.contains("INCWAGE adjusted to 1950 dollars"));
+    assert!(codebook.contains("us1940a: 1939 dollars multiplied by 2.000000"));
+
+    let standardized = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    assert!(nominal.rows.len() > 1);
+    assert_eq!(nominal.rows, standardized.rows);
+}
+
+/// Tabulate incwage_category_bins_us1940a.json treating the INCWAGE missing and NIU codes with
+/// the given handling.
+fn tabulate_incwage_missing_codes(handling: &str) -> Table {
+    let input_json = include_str!("requests/incwage_category_bins_us1940a.json");
+    let mut request: serde_json::Value = serde_json::from_str(input_json).unwrap();
+    request["missing_code_handling"] = serde_json::json!(handling);
+    request["missing_codes"] = serde_json::json!({"INCWAGE": [999998, 999999]});
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    let tab = tabulate(&ctx, rq).expect("tabulation should run without errors");
+    tab.into_inner().remove(0)
+}
+
+/// Separating missing codes takes them out of the top bin and gives them their own rows.
+/// Excluding them drops those rows and leaves the bins alone.
+#[test]
+fn test_missing_code_handling() {
+    let included = tabulate_incwage_missing_codes("include");
+    let separate = tabulate_incwage_missing_codes("separate");
+    let excluded = tabulate_incwage_missing_codes("exclude");
+
+    let is_missing = |row: &&Vec<Cell>| matches!(row[2], Cell::Integer(999998 | 999999));
+    assert!(!included.rows.iter().any(|row| is_missing(&row)));
+    assert!(separate.rows.iter().any(|row| is_missing(&row)));
+    assert_eq!(total_count(&included), total_count(&separate));
+    assert!(total_count(&excluded) < total_count(&separate));
+
+    let separate_bins: Vec<_> = separate
+        .rows
+        .iter()
+        .filter(|row| !is_missing(row))
+        .cloned()
+        .collect();
+    assert_eq!(separate_bins, excluded.rows);
+    assert_ne!(included.rows, excluded.rows);
+}
+
+/// Quantile bins generated from the data cover the same people as hand written bins, in no more
+/// bins than requested.
+#[test]
+fn test_generated_quantile_bins() {
+    let input_json = include_str!("requests/incwage_category_bins_us1940a.json");
+    let mut request: serde_json::Value = serde_json::from_str(input_json).unwrap();
+    request["missing_code_handling"] = serde_json::json!("exclude");
+    request["missing_codes"] = serde_json::json!({"INCWAGE": [999998, 999999]});
+    request["generated_bins"] = serde_json::json!({"INCWAGE": {"method": "quantiles", "bins": 4}});
+
+    // A variable can't have both kinds of bins.
+    let result = AbacusRequest::try_from_json(&request.to_string());
+    assert!(result.is_err(), "expected an error but got {result:?}");
+
+    request["category_bins"] = serde_json::json!({});
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    let bins = rq.request_variables[0]
+        .category_bins
+        .clone()
+        .expect("INCWAGE should have generated bins");
+    assert!(bins.len() > 1 && bins.len() <= 4, "got bins {bins:?}");
+
+    let generated = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    let excluded = tabulate_incwage_missing_codes("exclude");
+    assert_eq!(generated.rows.len(), bins.len());
+    assert_eq!(total_count(&generated), total_count(&excluded));
+}
+
+/// Bins are generated from the people the request counts, so two quantile bins for the AGE of
+/// children split the children in half, where bins from everyone's ages would put most children
+/// in the lower bin.
+#[test]
+fn test_generated_bins_follow_subpopulation() {
+    let mut request: serde_json::Value =
+        serde_json::from_str(include_str!("requests/sex_attached_spouse.json")).unwrap();
+    let mut age = request["request_variables"][0].clone();
+    age["variable_mnemonic"] = serde_json::json!("AGE");
+    age["mnemonic"] = serde_json::json!("AGE");
+    let mut children = request["subpopulation"][0].clone();
+    children["variable_mnemonic"] = serde_json::json!("RELATE");
+    children["mnemonic"] = serde_json::json!("RELATE");
+    children["request_case_selections"] =
+        serde_json::json!([{"low_code": "301", "high_code": "399"}]);
+    request["request_variables"] = serde_json::json!([age]);
+    request["subpopulation"] = serde_json::json!([children]);
+    request["generated_bins"] = serde_json::json!({"AGE": {"method": "quantiles", "bins": 2}});
+
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    let table = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    assert_eq!(table.rows.len(), 2);
+    let total = total_count(&table);
+    for row in &table.rows {
+        let Cell::Integer(count) = row[0] else {
+            panic!("expected an integer count but got {:?}", row[0]);
+        };
+        assert!(count * 10 >= total * 3, "got rows {:?}", table.rows);
+    }
+}
+
+/// Recoding detailed RACE into two groups gives the same counts as adding up the general
+/// RACE codes, and a subpopulation condition on a STATEFIP recode selects the same people as
+/// a condition on the state itself.
+#[test]
+fn test_recodes() {
+    let general = tabulate_race_hispan_sample(r#"{"name": "us1900m"}"#);
+    let mut expected = [0; 2];
+    for row in &general.rows {
+        let (Cell::Integer(ct), Cell::Integer(race)) = (&row[0], &row[2]) else {
+            panic!("expected integer cells but got {row:?}");
+        };
+        expected[(*race).min(2) as usize - 1] += ct;
+    }
+    assert!(expected.iter().all(|&ct| ct > 0), "got {expected:?}");
+
+    let input_json = include_str!("requests/race_hispan_subpop_statefip.json");
+    let mut request: serde_json::Value = serde_json::from_str(input_json).unwrap();
+    request["request_variables"][0]["general_detailed_selection"] = serde_json::json!("");
+    request["request_variables"][0]["extract_width"] = serde_json::json!(3);
+    request["request_variables"]
+        .as_array_mut()
+        .unwrap()
+        .truncate(1);
+    request["subpopulation"][0]["request_case_selections"] =
+        serde_json::json!([{"low_code": "1", "high_code": "1"}]);
+    request["recodes"] = serde_json::json!({
+        "RACE": [
+            {"code": 1, "value_label": "White", "codes": [{"low": 100, "high": 199}]},
+            {"code": 2, "value_label": "Not white", "codes": [{"low": 200, "high": 999}]}
+        ],
+        "STATEFIP": [
+            {"code": 1, "value_label": "Texas", "codes": [48]},
+            {"code": 2, "value_label": "Other states", "codes": [{"low": 1, "high": 47}, {"low": 49, "high": 99}]}
+        ]
+    });
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    assert!(rq.print_codebook().contains("\t1\tTexas: 48"));
+
+    let recoded = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    let mut actual = [0; 2];
+    for row in &recoded.rows {
+        let (Cell::Integer(ct), Cell::Integer(group)) = (&row[0], &row[2]) else {
+            panic!("expected integer cells but got {row:?}");
+        };
+        actual[*group as usize - 1] += ct;
+    }
+    assert_eq!(actual, expected);
+}
+
+/// The input JSON for a request variable tabulated in detail.
+fn detailed_request_variable(name: &str, width: usize) -> serde_json::Value {
+    serde_json::json!({
+        "variable_mnemonic": name,
+        "mnemonic": name,
+        "general_detailed_selection": "",
+        "standardization_index": null,
+        "attached_variable_pointer": null,
+        "case_selection": false,
+        "request_case_selections": [],
+        "include_dq_flags": false,
+        "extract_start": 1,
+        "extract_width": width
+    })
+}
+
+/// Sum the counts of a table's rows by the value of its third column, which is the first
+/// request variable in a weighted table.
+fn counts_by_value(table: &Table) -> std::collections::BTreeMap<i64, i64> {
+    let mut counts = std::collections::BTreeMap::new();
+    for row in &table.rows {
+        let (Cell::Integer(ct), Cell::Integer(value)) = (&row[0], &row[2]) else {
+            panic!("expected integer cells but got {row:?}");
+        };
+        *counts.entry(*value).or_default() += ct;
+    }
+    counts
+}
+
+/// A derived age group matches grouping the detailed ages by hand, and a derived condition
+/// works as a subpopulation filter.
+#[test]
+fn test_derived_variables() {
+    let input_json = include_str!("requests/race_hispan_subpop_statefip.json");
+    let mut request: serde_json::Value = serde_json::from_str(input_json).unwrap();
+    request["request_variables"] = serde_json::json!([detailed_request_variable("AGE", 3)]);
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    let ages = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    let mut expected = std::collections::BTreeMap::new();
+    for (age, ct) in counts_by_value(&ages) {
+        if age >= 18 {
+            *expected.entry(if age < 65 { 2 } else { 3 }).or_default() += ct;
+        }
+    }
+
+    request["derived_variables"] = serde_json::json!([
+        {"name": "AGEGRP", "expression": "case when AGE < 18 then 1 when AGE < 65 then 2 else 3 end"},
+        {"name": "ADULT", "expression": "age >= 18", "label": "Adult"}
+    ]);
+    request["request_variables"] = serde_json::json!([detailed_request_variable("AGEGRP", 1)]);
+    let mut adult = detailed_request_variable("ADULT", 1);
+    adult["case_selection"] = serde_json::json!(true);
+    adult["request_case_selections"] = serde_json::json!([{"low_code": "1", "high_code": "1"}]);
+    request["subpopulation"].as_array_mut().unwrap().push(adult);
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    assert!(rq.print_codebook().contains("AGEGRP = case when AGE < 18"));
+    let groups = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    assert_eq!(counts_by_value(&groups), expected);
+
+    // Ratios are decimal values.
+    request["derived_variables"][0]["expression"] = serde_json::json!("NCHILD / FAMSIZE");
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    let ratios = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    assert!(ratios
+        .rows
+        .iter()
+        .any(|row| matches!(row[2], Cell::Float(r) if r > 0.0 && r < 1.0)));
+
+    // Expressions are checked against the metadata.
+    request["derived_variables"][0]["expression"] = serde_json::json!("AGE + NOSUCHVAR");
+    let result = AbacusRequest::try_from_json(&request.to_string());
+    assert!(result.is_err(), "expected an error but got {result:?}");
+}
+
+/// Counting a household's person records reproduces NUMPREC, and roll-ups with conditions work
+/// as request variables and in the subpopulation.
+#[test]
+fn test_rollups() {
+    let input_json = include_str!("requests/race_hispan_subpop_statefip.json");
+    let mut request: serde_json::Value = serde_json::from_str(input_json).unwrap();
+    request["request_variables"] = serde_json::json!([detailed_request_variable("NUMPREC", 2)]);
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    let numprec = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+
+    request["rollups"] = serde_json::json!([
+        {"name": "NPERSONS", "record_type": "P", "function": "count"},
+        {"name": "NKIDS", "record_type": "P", "function": "count", "condition": "AGE < 18"},
+        {"name": "SUMKIDS", "record_type": "P", "function": "sum",
+         "value": "case when AGE < 18 then 1 else 0 end"}
+    ]);
+    request["request_variables"] = serde_json::json!([detailed_request_variable("NPERSONS", 2)]);
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    assert!(rq.print_codebook().contains("NPERSONS: count of P records"));
+    let persons = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    assert_eq!(counts_by_value(&persons), counts_by_value(&numprec));
+
+    // Counting the children matches summing a child indicator.
+    let mut kids_by_method = Vec::new();
+    for name in ["NKIDS", "SUMKIDS"] {
+        request["request_variables"] = serde_json::json!([detailed_request_variable(name, 2)]);
+        let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+            .expect("should be able to parse input JSON");
+        let kids = tabulate(&ctx, rq)
+            .expect("tabulation should run without errors")
+            .into_inner()
+            .remove(0);
+        kids_by_method.push(counts_by_value(&kids));
+    }
+    assert_eq!(kids_by_method[0], kids_by_method[1]);
+    assert!(kids_by_method[0].contains_key(&0));
+    let persons_with_kids: i64 = kids_by_method[0]
+        .iter()
+        .filter(|(n, _)| **n > 0)
+        .map(|(_, ct)| ct)
+        .sum();
+
+    // An "any" roll-up selects the people in households with at least one child.
+    request["rollups"] = serde_json::json!([
+        {"name": "HASKIDS", "record_type": "P", "function": "any", "condition": "AGE < 18"}
+    ]);
+    request["request_variables"] = serde_json::json!([detailed_request_variable("AGE", 3)]);
+    let mut has_kids = detailed_request_variable("HASKIDS", 1);
+    has_kids["case_selection"] = serde_json::json!(true);
+    has_kids["request_case_selections"] = serde_json::json!([{"low_code": "1", "high_code": "1"}]);
+    request["subpopulation"]
+        .as_array_mut()
+        .unwrap()
+        .push(has_kids);
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    let selected = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    assert_eq!(
+        counts_by_value(&selected).values().sum::<i64>(),
+        persons_with_kids
+    );
+
+    // Only sums, minimums, maximums and means take a value.
+    request["rollups"][0]["value"] = serde_json::json!("AGE");
+    let result = AbacusRequest::try_from_json(&request.to_string());
+    assert!(result.is_err(), "expected an error but got {result:?}");
+}
+
+/// With empty cells filled in, the tables for different samples have the same rows, including
+/// rows with zero counts, and the same totals as without them.
+#[test]
+fn test_include_empty_cells() {
+    let input_json = include_str!("requests/race_hispan_subpop_statefip.json");
+    let mut request: serde_json::Value = serde_json::from_str(input_json).unwrap();
+    request["request_samples"] = serde_json::json!([{"name": "us1900m"}, {"name": "us1940a"}]);
+    request["request_variables"] = serde_json::json!([
+        detailed_request_variable("RACE", 3),
+        detailed_request_variable("SEX", 1)
+    ]);
+    request["recodes"] = serde_json::json!({
+        "RACE": [
+            {"code": 1, "value_label": "White", "codes": [{"low": 100, "high": 199}]},
+            {"code": 2, "value_label": "Black", "codes": [{"low": 200, "high": 299}]},
+            {"code": 3, "value_label": "Other", "codes": [{"low": 300, "high": 999}]}
+        ]
+    });
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    let observed = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner();
+
+    request["include_empty_cells"] = serde_json::json!(true);
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    assert!(rq.print_codebook().contains("counts of zero"));
+    let completed = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner();
+
+    let cells = |table: &Table| -> Vec<(Cell, Cell)> {
+        table
+            .rows
+            .iter()
+            .map(|row| (row[2].clone(), row[3].clone()))
+            .collect()
+    };
+    assert_eq!(completed.len(), 2);
+    assert_eq!(completed[0].rows.len(), 6);
+    assert_eq!(cells(&completed[0]), cells(&completed[1]));
+    assert!(completed
+        .iter()
+        .flat_map(|t| &t.rows)
+        .any(|row| row[0] == Cell::Integer(0)));
+    for (observed, completed) in observed.iter().zip(&completed) {
+        let total = |table: &Table| -> i64 {
+            table
+                .rows
+                .iter()
+                .map(|row| match row[0] {
+                    Cell::Integer(ct) => ct,
+                    _ => panic!("expected an integer count but got {row:?}"),
+                })
+                .sum()
+        };
+        assert_eq!(total(observed), total(completed));
+    }
+}
+
+/// Unweighted counts of sample line variables come from the flat sample line subsample.
+#[test]
+fn test_unweighted_sample_line_counts() {
+    let input_json = include_str!("requests/incwage_category_bins_us1940a.json");
+    let mut request: serde_json::Value = serde_json::from_str(input_json).unwrap();
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    let weighted = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+
+    request["weight"] = serde_json::json!("unweighted");
+    let (ctx, rq) = AbacusRequest::try_from_json(&request.to_string())
+        .expect("should be able to parse input JSON");
+    assert!(rq.print_codebook().contains("Counts are unweighted."));
+    let unweighted = tabulate(&ctx, rq)
+        .expect("tabulation should run without errors")
+        .into_inner()
+        .remove(0);
+    assert_eq!(unweighted.heading.len(), weighted.heading.len() - 1);
+    assert!(total_count(&unweighted) > 0);
+    assert!(total_count(&unweighted) < total_count(&weighted));
+}
+
+#[test]
+fn test_explain() {
+    let input_json = include_str!("requests/race_hispan_subpop_statefip.json");
+    let (ctx, request) = AbacusRequest::try_from_json(input_json).unwrap();
+    let explanations = explain(&ctx, request).unwrap();
+    assert_eq!(explanations.len(), 1);
+
+    let explanation = &explanations[0];
+    assert_eq!(explanation.dataset, "us1900m");
+    assert_eq!(explanation.weight_name.as_deref(), Some("PERWT"));
+    assert_eq!(explanation.weight_divisor, 100);
+    assert!(explanation.sql.contains("STATEFIP = 48"));
+    // The subpopulation is on the household records, so the query reads both files.
+    assert_eq!(
+        explanation.data_sources.keys().collect::<Vec<_>>(),
+        ["H", "P"]
+    );
+    assert!(explanation.data_sources["P"].ends_with("us1900m_usa.P.parquet"));
+    assert!(explanation.plan.contains("PARQUET_SCAN"));
+}